@@ -1,13 +1,24 @@
+#![feature(core)]
+
 extern crate ao;
 extern crate audiostream;
 
-use audiostream::Sink;
-//use audiostream::ao::AOAutoSink;
+use audiostream::{Sink, Convert};
+use audiostream::ao::AOSink;
 use audiostream::vorbis::VorbisStream;
+use std::env;
 use std::io;
 
 #[allow(non_snake_case)]
 fn main() {
+    let path = match env::args().nth(1) {
+        Some(p) => p,
+        None => {
+            println!("Usage: oggdec OUTPUT.wav < INPUT.ogg");
+            return;
+        }
+    };
+
     let AO = ao::AO::init();
     let driver = match AO.get_driver("wav") {
         Some(d) => d,
@@ -17,12 +28,22 @@ fn main() {
         }
     };
 
-    // stdin -> VorbisStream -> AOAutoSink -> stdout
-    /*let pipeline = AOAutoSink::for_writer(
-        io::stdout(),
-        &driver,
-        VorbisStream::new(io::stdin())
-    );*/
+    let decoder = match VorbisStream::open(io::stdin()) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("Failed to open Vorbis stream: {}", e);
+            return;
+        }
+    };
 
-    //pipeline.run_to_end();
+    // stdin -> VorbisStream -> Convert -> AOSink -> file
+    let source = Convert::<f32, i16, _>::new(decoder);
+    let mut sink = match AOSink::new_file(source, &driver, path, true) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Failed to open output file: {}", e);
+            return;
+        }
+    };
+    while let Some(()) = sink.run_once() { }
 }
@@ -6,7 +6,7 @@ extern crate audiostream;
 extern crate docopt;
 extern crate rustc_serialize;
 
-use audiostream::{Sink, MonoSource, Source, Amplify};
+use audiostream::{Sink, MonoSource, Source};
 use audiostream::synth::{Null, Tone};
 use audiostream::ao::AOSink;
 use std::io::{self, BufRead};
@@ -62,10 +62,7 @@ fn main() {
                 }
                 Some(driver) => driver
             };
-            let sink = AOSink::<i16, _>::new(
-                Amplify::<_, _, f32>::new(generator, amplitude),
-                &driver
-            );
+            let sink = AOSink::<i16, _>::new(generator.amplify(amplitude), &driver);
 
             let mut sink = match sink {
                 Err(e) => {
@@ -30,11 +30,9 @@ fn main() {
     }
 
     let ee = ExecutionEngine::new(module);
-    let the_function = ee.get_function("increment_i8").expect("No function named \"increment_i8\"");
     let the_function = unsafe {
-        std::mem::transmute::<extern "C" fn() -> (),
-                              extern "C" fn(*mut i8) -> ()>(the_function)
-    };
+        ee.get_function_typed::<extern "C" fn(*mut i8), _>("increment_i8")
+    }.expect("No function named \"increment_i8\"");
     println!("&increment_i8 = {:?}", the_function as *const ());
 
     let mut the_value: i8 = 0;
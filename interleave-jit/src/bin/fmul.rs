@@ -0,0 +1,36 @@
+extern crate "interleave_jit" as ellell;
+
+use ellell::{Context, Module, Builder, Position, ExecutionEngine, Value};
+
+fn main() {
+    let ctxt = Context::new();
+    let module = Module::in_context(&ctxt, "multipliers");
+    {
+        let mut builder = Builder::in_context(&ctxt);
+
+        // double myfunction(double, double);
+        let double = ctxt.double_type();
+        let function_type = ctxt.function_type(double, &[double, double], false);
+        let func = module.add_function("mul_sub_f64", function_type);
+
+        let bb = ctxt.append_bb(func, "entry");
+        builder.position(Position::EndOf(bb));
+
+        // Return x * y - 0.5
+        let params = func.function_params().collect::<Vec<_>>();
+        let product = builder.build_fmul(params[0], params[1]);
+        let half = Value::const_real(&double, 0.5);
+        let result = builder.build_fsub(product, half);
+        builder.build_ret(result);
+        module.dump();
+    }
+
+    let ee = ExecutionEngine::new(module);
+    let the_function = unsafe {
+        ee.get_function_typed::<extern "C" fn(f64, f64) -> f64, _>("mul_sub_f64")
+    }.expect("No function named \"mul_sub_f64\"");
+
+    let result = the_function(1.5, 3.0);
+    println!("mul_sub_f64(1.5, 3.0) = {}", result);
+    assert_eq!(result, 4.0);
+}
@@ -0,0 +1,65 @@
+#![feature(simd)]
+
+extern crate "interleave_jit" as ellell;
+
+use ellell::{Context, Module, Builder, Position, ExecutionEngine};
+
+#[simd]
+#[derive(Copy)]
+#[allow(non_camel_case_types)]
+struct i32x4(i32, i32, i32, i32);
+#[simd]
+#[derive(Copy)]
+#[allow(non_camel_case_types)]
+struct i32x8(i32, i32, i32, i32, i32, i32, i32, i32);
+
+fn main() {
+    let ctxt = Context::new();
+    let module = Module::in_context(&ctxt, "interleavers");
+    {
+        let mut builder = Builder::in_context(&ctxt);
+
+        // void myfunction(<4 x i32>*, <4 x i32>*, <8 x i32>*);
+        let i32_type = ctxt.int_type(32);
+        let in_type = ctxt.pointer_type(ctxt.vector_type(i32_type, 4));
+        let out_type = ctxt.pointer_type(ctxt.vector_type(i32_type, 8));
+        let void = ctxt.void_type();
+        let function_type = ctxt.function_type(void, &[in_type, in_type, out_type], false);
+        let func = module.add_function("interleave_i32x4", function_type);
+
+        let bb = ctxt.append_bb(func, "entry");
+        builder.position(Position::EndOf(bb));
+
+        let params = func.function_params().collect::<Vec<_>>();
+        let left = builder.build_load(params[0]);
+        let right = builder.build_load(params[1]);
+        let interleaved = builder.build_shufflevector(left, right, &[0, 4, 1, 5, 2, 6, 3, 7]);
+        builder.build_store(interleaved, params[2]);
+        builder.build_ret_void();
+        module.dump();
+    }
+
+    let ee = ExecutionEngine::new(module);
+    let the_function = unsafe {
+        ee.get_function_typed::<extern "C" fn(*const i32x4, *const i32x4, *mut i32x8), _>(
+            "interleave_i32x4")
+    }.expect("No function named \"interleave_i32x4\"");
+
+    let left = [1, 2, 3, 4];
+    let right = [-1, -2, -3, -4];
+    let mut out = i32x8(0, 0, 0, 0, 0, 0, 0, 0);
+    the_function(&i32x4(left[0], left[1], left[2], left[3]) as *const _,
+                 &i32x4(right[0], right[1], right[2], right[3]) as *const _,
+                 &mut out as *mut _);
+
+    // Scalar reference
+    let mut expected = vec![];
+    for (&l, &r) in left.iter().zip(right.iter()) {
+        expected.push(l);
+        expected.push(r);
+    }
+    let i32x8(a, b, c, d, e, f, g, h) = out;
+    let out = vec![a, b, c, d, e, f, g, h];
+    println!("interleave_i32x4({:?}, {:?}) = {:?}", left, right, out);
+    assert_eq!(out, expected);
+}
@@ -40,11 +40,9 @@
 //! }
 //! 
 //! let ee = ExecutionEngine::new(module);
-//! let the_function = ee.get_function("increment_i8").expect("No function named \"increment_i8\"");
 //! let the_function = unsafe {
-//!     std::mem::transmute::<extern "C" fn() -> (),
-//!                           extern "C" fn(*mut i8) -> ()>(the_function)
-//! };
+//!     ee.get_function_typed::<extern "C" fn(*mut i8), _>("increment_i8")
+//! }.expect("No function named \"increment_i8\"");
 //! let mut the_value: i8 = 0;
 //! the_function(&mut the_value as *mut _);
 //! assert_eq!(the_value, 1);
@@ -53,7 +51,7 @@
 extern crate rustc;
 
 use rustc::lib::llvm;
-use std::ffi::{CString, IntoBytes};
+use std::ffi::{CStr, CString, IntoBytes};
 use std::ops::Deref;
 use std::marker::PhantomData;
 
@@ -91,6 +89,29 @@ impl Context {
         })
     }
 
+    /// Get a type representing a 32-bit IEEE 754 floating-point value, equivalent to C `float`.
+    pub fn float_type<'a>(&'a self) -> Type<'a> {
+        Type::generic(self, |cx| unsafe {
+            llvm::LLVMFloatTypeInContext(cx)
+        })
+    }
+
+    /// Get a type representing a 64-bit IEEE 754 floating-point value, equivalent to C `double`.
+    pub fn double_type<'a>(&'a self) -> Type<'a> {
+        Type::generic(self, |cx| unsafe {
+            llvm::LLVMDoubleTypeInContext(cx)
+        })
+    }
+
+    /// Get a type representing a SIMD vector of `count` elements of type `element`.
+    ///
+    /// `element` must be an integer, floating-point or pointer type.
+    pub fn vector_type<'a>(&'a self, element: Type<'a>, count: u32) -> Type<'a> {
+        Type::generic(self, |_| unsafe {
+            llvm::LLVMVectorType(*element, count)
+        })
+    }
+
     /// Get a type representing no value, equivalent to C `void`.
     pub fn void_type<'a>(&'a self) -> Type<'a> {
         Type::generic(self, |cx| unsafe {
@@ -180,6 +201,67 @@ impl<'a> Module<'a> {
         }
     }
 
+    /// Set the target triple code in this module is generated for, such as
+    /// `x86_64-unknown-linux-gnu`.
+    ///
+    /// Modules otherwise have no target, leaving it to the `ExecutionEngine` to guess. Use
+    /// `default_target_triple` for the host.
+    ///
+    /// Panics if `triple` contains null bytes.
+    ///
+    /// ```
+    /// use interleave_jit::{Context, Module, Builder, Position, Value, ExecutionEngine, OptLevel};
+    ///
+    /// let ctxt = Context::new();
+    /// let module = Module::in_context(&ctxt, "incremeters");
+    /// module.set_target(interleave_jit::default_target_triple());
+    /// if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+    ///     module.set_data_layout("e-m:e-i64:64-f80:128-n8:16:32:64-S128");
+    /// }
+    /// {
+    ///     let mut builder = Builder::in_context(&ctxt);
+    ///     let target_type = ctxt.int_type(8);
+    ///     let function_type = ctxt.function_type(ctxt.void_type(),
+    ///                                            &[ctxt.pointer_type(target_type)], false);
+    ///     let func = module.add_function("increment_i8", function_type);
+    ///     builder.position(Position::EndOf(ctxt.append_bb(func, "entry")));
+    ///
+    ///     let params = func.function_params().collect::<Vec<_>>();
+    ///     let load = builder.build_load(params[0]);
+    ///     let result = builder.build_add(load, Value::const_int(&target_type, 1, false));
+    ///     builder.build_store(result, params[0]);
+    ///     builder.build_ret_void();
+    /// }
+    ///
+    /// let ee = ExecutionEngine::with_opt_level(module, OptLevel::Aggressive).unwrap();
+    /// let the_function = unsafe {
+    ///     ee.get_function_typed::<extern "C" fn(*mut i8), _>("increment_i8")
+    /// }.expect("No function named \"increment_i8\"");
+    /// let mut the_value: i8 = 41;
+    /// the_function(&mut the_value as *mut _);
+    /// assert_eq!(the_value, 42);
+    /// ```
+    pub fn set_target<T: IntoBytes>(&self, triple: T) {
+        let triple = CString::new(triple).ok().expect("Target triple may not contain null bytes");
+        unsafe {
+            LLVMSetTarget(self.llmod, triple.as_ptr());
+        }
+    }
+
+    /// Set the data layout string describing the target's type sizes and alignments.
+    ///
+    /// See http://llvm.org/docs/LangRef.html#data-layout for the format. The layout must agree
+    /// with the target, or generated code will disagree with native code about memory layout.
+    /// The example for `set_target` sets both.
+    ///
+    /// Panics if `layout` contains null bytes.
+    pub fn set_data_layout<T: IntoBytes>(&self, layout: T) {
+        let layout = CString::new(layout).ok().expect("Data layout may not contain null bytes");
+        unsafe {
+            LLVMSetDataLayout(self.llmod, layout.as_ptr());
+        }
+    }
+
     /// Write a text representation of a module to standard output.
     ///
     /// Since this is implemented inside LLVM, the output cannot be redirected.
@@ -307,6 +389,16 @@ impl<'a> Value<'a> {
         FunctionParameters::Initial(*self)
     }
 
+    /// Set the alignment in bytes assumed by a load or store instruction.
+    ///
+    /// Vector loads and stores otherwise assume the vector type's natural alignment, which may
+    /// be larger than that of the memory actually accessed.
+    pub fn set_alignment(&self, bytes: u32) {
+        unsafe {
+            llvm::LLVMSetAlignment(self.llvalue, bytes);
+        }
+    }
+
     /// Get a contant integer value of specified type (as from `Context::int_type`).
     pub fn const_int(ty: &Type<'a>, value: u64, signed: bool) -> Value<'a> {
         unsafe {
@@ -315,6 +407,21 @@ impl<'a> Value<'a> {
             )
         }
     }
+
+    /// Get a constant vector of the given constant `values`, which must all be the same type.
+    pub fn const_vector(values: &[Value<'a>]) -> Value<'a> {
+        let raw_values: Vec<llvm::ValueRef> = values.iter().map(|v| v.llvalue).collect();
+        unsafe {
+            Value::build(llvm::LLVMConstVector(raw_values.as_ptr(), raw_values.len() as u32))
+        }
+    }
+
+    /// Get a constant floating-point value of specified type (as from `Context::float_type`).
+    pub fn const_real(ty: &Type<'a>, value: f64) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMConstReal(**ty, value))
+        }
+    }
 }
 
 /// A block of code with exactly one entry point.
@@ -396,6 +503,13 @@ impl<'a> Builder<'a> {
         }
     }
 
+    /// Build a `ret`, returning `value` from a function declared to return its type.
+    pub fn build_ret(&self, value: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildRet(**self, *value))
+        }
+    }
+
     /// Build a load from memory, yielding the value pointed to by `ptr`.
     pub fn build_load(&mut self, ptr: Value<'a>) -> Value<'a> {
         unsafe {
@@ -422,6 +536,67 @@ impl<'a> Builder<'a> {
             Value::build(llvm::LLVMBuildAdd(**self, *lhs, *rhs, self.get_name().as_ptr()))
         }
     }
+
+    /// Build an addition of two floating-point values, yielding their sum.
+    ///
+    /// Works for floating-point values and vectors of them.
+    pub fn build_fadd(&mut self, lhs: Value<'a>, rhs: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildFAdd(**self, *lhs, *rhs, self.get_name().as_ptr()))
+        }
+    }
+
+    /// Build a subtraction of two integer values, yielding `lhs - rhs`.
+    ///
+    /// For floating-point values, use `build_fsub`.
+    pub fn build_sub(&mut self, lhs: Value<'a>, rhs: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildSub(**self, *lhs, *rhs, self.get_name().as_ptr()))
+        }
+    }
+
+    /// Build a subtraction of two floating-point values, yielding `lhs - rhs`.
+    pub fn build_fsub(&mut self, lhs: Value<'a>, rhs: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildFSub(**self, *lhs, *rhs, self.get_name().as_ptr()))
+        }
+    }
+
+    /// Build a multiplication of two integer values, yielding their product.
+    ///
+    /// The product wraps on overflow. For floating-point values, use `build_fmul`.
+    pub fn build_mul(&mut self, lhs: Value<'a>, rhs: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildMul(**self, *lhs, *rhs, self.get_name().as_ptr()))
+        }
+    }
+
+    /// Build a multiplication of two floating-point values, yielding their product.
+    pub fn build_fmul(&mut self, lhs: Value<'a>, rhs: Value<'a>) -> Value<'a> {
+        unsafe {
+            Value::build(llvm::LLVMBuildFMul(**self, *lhs, *rhs, self.get_name().as_ptr()))
+        }
+    }
+
+    /// Build a shuffle of the elements of two vectors, yielding a new vector.
+    ///
+    /// `a` and `b` must be vectors of the same type, and the elements of both are numbered
+    /// consecutively starting with those of `a`. Each entry in `mask` selects the element of the
+    /// output at that position, so the output has as many elements as `mask`.
+    ///
+    /// See http://llvm.org/docs/LangRef.html#shufflevector-instruction
+    pub fn build_shufflevector(&mut self, a: Value<'a>, b: Value<'a>, mask: &[u32]) -> Value<'a> {
+        unsafe {
+            let i32_type = llvm::LLVMInt32TypeInContext(llvm::LLVMGetTypeContext(
+                llvm::LLVMTypeOf(*a)));
+            let mask: Vec<llvm::ValueRef> = mask.iter().map(|&i| {
+                llvm::LLVMConstInt(i32_type, i as u64, 0)
+            }).collect();
+            let mask = llvm::LLVMConstVector(mask.as_ptr(), mask.len() as u32);
+            Value::build(llvm::LLVMBuildShuffleVector(**self, *a, *b, mask,
+                                                      self.get_name().as_ptr()))
+        }
+    }
 }
 
 impl<'a> Deref for Builder<'a> {
@@ -441,6 +616,31 @@ impl<'a> Drop for Builder<'a> {
     }
 }
 
+/// Get the target triple of the host, for `Module::set_target`.
+pub fn default_target_triple() -> String {
+    unsafe {
+        let p = LLVMGetDefaultTargetTriple();
+        let triple = String::from_utf8_lossy(CStr::from_ptr(p as *const _).to_bytes()).into_owned();
+        LLVMDisposeMessage(p);
+        triple
+    }
+}
+
+/// How hard the code generator tries to optimize, as in `ExecutionEngine::with_opt_level`.
+///
+/// These correspond to `-O0` through `-O3` in compilers using LLVM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    /// No optimization.
+    None = 0,
+    /// Only quick optimizations.
+    Less = 1,
+    /// The default level.
+    Default = 2,
+    /// All optimizations, even those taking much longer to compile.
+    Aggressive = 3,
+}
+
 /// Runtime code generator.
 ///
 /// When given a `Module`, the `ExecutionEngine` will JIT-compile the module and
@@ -471,6 +671,41 @@ impl<'a> ExecutionEngine<'a> {
         }
     }
 
+    /// Construct a new execution engine generating code at the specified optimization level.
+    ///
+    /// This creates an MCJIT engine with the same memory manager as `new`, but through the
+    /// LLVM-C interface, so failure to create the engine (for instance if the module's target
+    /// isn't supported) is reported as an error message.
+    pub fn with_opt_level(module: Module<'a>, level: OptLevel)
+            -> Result<ExecutionEngine<'a>, String> {
+        unsafe {
+            let size = std::mem::size_of::<MCJITCompilerOptions>();
+            let mut options: MCJITCompilerOptions = std::mem::zeroed();
+            LLVMInitializeMCJITCompilerOptions(&mut options, size);
+            options.opt_level = level as u32;
+            // The engine owns the memory manager once created.
+            options.memory_manager = llvm::LLVMRustCreateJITMemoryManager(std::ptr::null());
+
+            let llmod = *module;
+            let mut llee = std::ptr::null_mut();
+            let mut error = std::ptr::null_mut();
+            if LLVMCreateMCJITCompilerForModule(&mut llee, llmod, &mut options, size,
+                                                &mut error) != 0 {
+                // The module is only consumed on success.
+                let message = String::from_utf8_lossy(CStr::from_ptr(error as *const _).to_bytes())
+                                     .into_owned();
+                LLVMDisposeMessage(error);
+                return Err(message);
+            }
+            std::mem::forget(module);
+
+            Ok(ExecutionEngine {
+                llee: llee,
+                module: PhantomData
+            })
+        }
+    }
+
     /// Get a pointer to the function with the given name, if any.
     ///
     /// The type of the returned value is a function pointer, which unfortunately
@@ -491,6 +726,27 @@ impl<'a> ExecutionEngine<'a> {
             }
         }
     }
+
+    /// Get the function with the given name as a function pointer of type `F`, if any.
+    ///
+    /// This saves transmuting the result of `get_function`:
+    ///
+    /// ```ignore
+    /// let increment = unsafe {
+    ///     ee.get_function_typed::<extern "C" fn(*mut i8), _>("increment_i8")
+    /// };
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `F` must be an `extern "C" fn` type whose signature exactly matches the generated
+    /// function. **Nothing checks this**: if it doesn't match, calling the returned pointer is
+    /// undefined behaviour, which will likely corrupt memory or crash. Panics if `F` isn't the
+    /// size of a pointer, which catches only the most obvious misuses.
+    pub unsafe fn get_function_typed<F: Copy, T: IntoBytes>(&self, name: T) -> Option<F> {
+        assert_eq!(std::mem::size_of::<F>(), std::mem::size_of::<extern "C" fn() -> ()>());
+        self.get_function(name).map(|f| std::mem::transmute_copy(&f))
+    }
 }
 
 impl<'a> Deref for ExecutionEngine<'a> {
@@ -510,6 +766,16 @@ impl<'a> Drop for ExecutionEngine<'a> {
     }
 }
 
+/// `LLVMMCJITCompilerOptions`, as passed to `LLVMCreateMCJITCompilerForModule`.
+#[repr(C)]
+struct MCJITCompilerOptions {
+    opt_level: u32,
+    code_model: u32,
+    no_frame_pointer_elim: i32,
+    enable_fast_isel: i32,
+    memory_manager: llvm::RustJITMemoryManagerRef,
+}
+
 // This is probably very wrong, not in the least because rustc uses its own LLVM and we'll
 // be using the system one.
 //
@@ -520,4 +786,13 @@ impl<'a> Drop for ExecutionEngine<'a> {
 #[link(name="LLVMMC")]
 extern "C" {
     fn LLVMGetFunctionAddress(ee: llvm::ExecutionEngineRef, name: *const i8) -> *const ();
+    fn LLVMInitializeMCJITCompilerOptions(options: *mut MCJITCompilerOptions, size: usize);
+    fn LLVMCreateMCJITCompilerForModule(ee: *mut llvm::ExecutionEngineRef,
+                                        module: llvm::ModuleRef,
+                                        options: *mut MCJITCompilerOptions, size: usize,
+                                        error: *mut *mut i8) -> i32;
+    fn LLVMSetTarget(module: llvm::ModuleRef, triple: *const i8);
+    fn LLVMSetDataLayout(module: llvm::ModuleRef, layout: *const i8);
+    fn LLVMGetDefaultTargetTriple() -> *mut i8;
+    fn LLVMDisposeMessage(message: *mut i8);
 }
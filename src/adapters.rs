@@ -1,31 +1,172 @@
+//! Adapters between source flavors.
 
-pub struct MonoFilter<S, F, T> {
+use std::cell::RefCell;
+use std::raw::Slice;
+use std::rc::Rc;
+use super::{Sample, Source, MonoSource, SourceResult, StreamError, channel_buffer};
+
+/// Applies a mono filter to each channel of a `Source` independently.
+///
+/// Mono filters are `MonoSource`s that take their input from another `MonoSource`. The builder
+/// is called once per channel with a `ChannelFeed` yielding that channel's samples and returns
+/// the filter to run over them, so filters keep their state from one buffer to the next. Filters
+/// are built when the first buffer arrives.
+///
+/// Each filter should pull from its feed once for every buffer it yields; a feed with nothing
+/// pending yields `None`. All filters must yield the same number of samples from each buffer,
+/// though that need not equal the number they were given. If any filter ends, so does the
+/// stream. A change in the number of channels is a stream error.
+pub struct MonoFilter<F, S, B, T> {
     source: S,
-    builder: F,
+    builder: B,
+    filters: Vec<T>,
+    feeds: Vec<Rc<RefCell<Option<Vec<F>>>>>,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
 }
 
-struct DummySource<T> {
-    data: &mut [T]
+impl<F, S, B, T> MonoFilter<F, S, B, T> where
+        F: Sample, S: Source<Output=F>, T: MonoSource<Output=F>, B: FnMut(ChannelFeed<F>) -> T {
+    /// Construct an adapter filtering each channel of `source` with filters made by `builder`.
+    pub fn new(source: S, builder: B) -> MonoFilter<F, S, B, T> {
+        MonoFilter {
+            source: source,
+            builder: builder,
+            filters: Vec::new(),
+            feeds: Vec::new(),
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
 }
 
-impl<S, F, T> Source for MonoFilter<S, F, T>
-        where T: MonoSource<Output=S::Output>,
-              F: FnMut(DummySource) -> T {
-    type Output = S::Output;
+impl<F, S, B, T> Source for MonoFilter<F, S, B, T> where
+        F: Sample, S: Source<Output=F>, T: MonoSource<Output=F>, B: FnMut(ChannelFeed<F>) -> T {
+    type Output = F;
 
-    fn next<'a>(&'a mut self) -> SourceResult<'a, Self::Output> {
-        let buffer = match self.source.next() {
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
             SourceResult::Buffer(b) => b,
             x => return x
         };
 
-        for channel in buffer.mut_iter() {
-            let filter = self.builder(DummySource { data: channel });
-            match filter.next() {
-                None => SourceResult::EndOfStream,
-                Some(b) => *channel = b
+        if self.filters.is_empty() {
+            for _ in 0..buf.len() {
+                let feed = Rc::new(RefCell::new(None));
+                self.filters.push((self.builder)(ChannelFeed {
+                    pending: feed.clone(),
+                    buf: Vec::new()
+                }));
+                self.feeds.push(feed);
+            }
+            self.channels = vec![Vec::new(); buf.len()];
+        } else if buf.len() != self.filters.len() {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "MonoFilter built {} filters, but got {} channels",
+                self.filters.len(), buf.len())));
+        }
+
+        for (i, channel) in buf.iter().enumerate() {
+            *self.feeds[i].borrow_mut() = Some(channel.to_vec());
+            let out = &mut self.channels[i];
+            out.clear();
+            match self.filters[i].next() {
+                Some(b) => out.extend(b.iter().cloned()),
+                None => return SourceResult::EndOfStream
+            }
+        }
+
+        let len = self.channels[0].len();
+        if self.channels.iter().any(|c| c.len() != len) {
+            return SourceResult::StreamError(StreamError::Other(
+                "MonoFilter filters yielded buffers of differing lengths".to_string()));
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Input to one of a `MonoFilter`'s filters.
+///
+/// Yields the samples of its channel from each buffer exactly once, or `None` if the filter has
+/// already taken the current buffer.
+pub struct ChannelFeed<F> {
+    pending: Rc<RefCell<Option<Vec<F>>>>,
+    buf: Vec<F>,
+}
+
+impl<F> MonoSource for ChannelFeed<F> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        match self.pending.borrow_mut().take() {
+            Some(b) => self.buf = b,
+            None => return None
+        }
+        Some(&mut self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use super::super::{Source, SourceResult, MonoSource, Pan};
+    use super::super::tests::ConstantSource;
+    use super::MonoFilter;
+
+    struct Gain<S> {
+        source: S,
+        gain: f64,
+    }
+
+    impl<S: MonoSource<Output=f64>> MonoSource for Gain<S> {
+        type Output = f64;
+
+        fn next<'a>(&'a mut self) -> Option<&'a mut [f64]> {
+            let buf = match self.source.next() {
+                Some(b) => b,
+                None => return None
+            };
+            for x in buf.iter_mut() {
+                *x *= self.gain;
+            }
+            Some(buf)
+        }
+    }
+
+    #[test]
+    fn filters_channels_independently() {
+        let data = vec![0.0, 0.25, -0.5, 0.125];
+        let mut reference = Pan::new(ConstantSource { data: data.clone(), sbuf: vec![] }.adapt(),
+                                     -0.5);
+        let expected = match reference.next() {
+            SourceResult::Buffer(b) => b.iter().map(|c| c.to_vec()).collect::<Vec<_>>(),
+            x => panic!("Unexpected result {:?}", x)
+        };
+
+        let built = Cell::new(0);
+        let stereo = Pan::new(ConstantSource { data: data, sbuf: vec![] }.adapt(), -0.5);
+        let mut src = MonoFilter::new(stereo, |feed| {
+            built.set(built.get() + 1);
+            Gain { source: feed, gain: 2f64 }
+        });
+
+        for _ in 0..3 {
+            match src.next() {
+                SourceResult::Buffer(b) => {
+                    assert_eq!(b.len(), 2);
+                    for (channel, expected) in b.iter().zip(expected.iter()) {
+                        let doubled: Vec<f64> = expected.iter().map(|x| x * 2f64).collect();
+                        assert_eq!(&channel[..], &doubled[..]);
+                    }
+                }
+                x => panic!("Unexpected result {:?}", x)
             }
         }
-        SourceResult::Buffer(buffer)
+        // Filters persist across buffers.
+        assert_eq!(built.get(), 2);
     }
 }
@@ -2,63 +2,329 @@
 
 extern crate ao;
 
-use std::mem;
-use super::{SourceResult, Sample, Source, Sink};
-use super::interleave::Interleave;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use super::{SourceResult, Sample, Source, Sink, StreamError, seconds, DEFAULT_SAMPLE_RATE};
+use super::interleave::{Interleave, interleave_into};
+
+/// Something which can open output devices for samples of format `F`.
+///
+/// This is implemented for `ao::Driver`, and mostly exists so sinks can be tested without
+/// real audio hardware.
+pub trait Driver<F> {
+    /// Device type opened by this driver.
+    type Device: Device<F>;
+
+    /// Open a device accepting samples in the specified format.
+    ///
+    /// If `file` is provided the device writes to that path, replacing any existing file if the
+    /// flag is set. Otherwise a live device is opened.
+    fn open(&self, format: &ao::SampleFormat<F, &'static str>,
+            file: Option<(&Path, bool)>) -> ao::AoResult<Self::Device>;
+}
+
+/// An open output device.
+pub trait Device<F> {
+    /// Play interleaved samples.
+    fn play(&self, samples: &[F]);
+}
+
+impl<'a, F: ao::Sample> Driver<F> for ao::Driver<'a> {
+    type Device = ao::Device<'a, F>;
+
+    fn open(&self, format: &ao::SampleFormat<F, &'static str>,
+            file: Option<(&Path, bool)>) -> ao::AoResult<ao::Device<'a, F>> {
+        // libao itself reports NotLive or NotFile if the driver is the wrong flavor.
+        match file {
+            None => self.open_live(format),
+            Some((path, overwrite)) => self.open_file(format, path, overwrite)
+        }
+    }
+}
+
+impl<'a, F: ao::Sample> Device<F> for ao::Device<'a, F> {
+    fn play(&self, samples: &[F]) {
+        ao::Device::play(self, samples)
+    }
+}
+
+/// Device options which don't depend on the stream.
+#[derive(Clone, Copy)]
+struct Options {
+    byte_order: ao::Endianness,
+    /// Channel matrix, used only for streams with a matching number of channels.
+    matrix: Option<&'static str>,
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            byte_order: ao::Endianness::Native,
+            matrix: None,
+        }
+    }
+}
+
+fn open<F: ao::Sample, D: Driver<F>>(driver: &D, rate: u32, channels: usize, options: Options,
+                                     file: Option<(&Path, bool)>) -> ao::AoResult<D::Device> {
+    let matrix = match options.matrix {
+        Some(m) if m.split(',').count() == channels => Some(m),
+        Some(m) => {
+            warn!("Ignoring channel matrix {:?} for stream with {} channels", m, channels);
+            None
+        }
+        None => None
+    };
+    let format = ao::SampleFormat::new(rate as usize, channels, options.byte_order, matrix);
+    driver.open(&format, file)
+}
 
 /// Sink writing to a libao device.
 ///
-/// Consumes samples of format `F` from a `Source` `R`.
-pub struct AOSink<'a, F, R> {
-    device: ao::Device<'a, F>,
+/// Consumes samples of format `F` from a `Source` `R`. The device is opened with the channel
+/// count of the source's buffers and the most recent sample rate it reported, and is reopened
+/// if either changes mid-stream. A sample rate change reopens the device as soon as it's reported,
+/// and playback continues with the following buffers.
+///
+/// File output devices can't change format once samples have been written to them, so a
+/// file sink stops with an error if the stream's format changes after the first buffer.
+///
+/// An error from the source stops the sink. It is logged, and kept for retrieval with `error`
+/// so callers can tell a failed stream from one which ended normally.
+///
+/// libao doesn't report the state of the device's own buffer, so underruns are inferred from
+/// timing. `play` returns once the device has accepted a buffer, so if the next buffer takes
+/// longer to arrive than the previous one takes to play, the device has run dry in between and an
+/// underrun is counted. The time measured includes everything upstream of the sink, so it
+/// reflects whether the whole pipeline keeps pace with real time.
+///
+/// A source may also keep up by yielding short buffers, which play but leave gaps. If an expected
+/// size is set with `set_buffer_frames`, any shorter buffer (including an empty one) is counted as
+/// an underrun too, and can optionally be padded with silence to the expected size so the device
+/// plays a steady stream of samples rather than runts. Each buffer counts at most once.
+pub struct AOSink<'a, F, R, D: 'a = ao::Driver<'a>> where D: Driver<F> {
+    driver: &'a D,
+    device: Option<D::Device>,
+    /// Format of the open device as (sample rate, channels).
+    format: (u32, usize),
+    rate: u32,
+    options: Options,
+    /// Output path and overwrite flag, for file drivers.
+    file: Option<(PathBuf, bool)>,
+    /// Whether any samples have been played on the current device.
+    played: bool,
+    /// When `play` last returned, and the duration of the buffer played in seconds.
+    last_play: Option<(Instant, f64)>,
+    /// Expected number of frames in each buffer, for underrun detection.
+    buffer_frames: Option<usize>,
+    fill_underruns: bool,
+    underruns: Arc<AtomicUsize>,
+    /// Error which stopped the stream, if any.
+    error: Option<StreamError>,
     interleave_buf: Vec<F>,
     source: R,
 }
 
-impl<'a, F, R> AOSink<'a, F, R>  where
+impl<'a, F, R, D> AOSink<'a, F, R, D>  where
         F: ao::Sample,
-        R: Source<Output=F> {
+        R: Source<Output=F>,
+        D: Driver<F> {
     /// Construct a libao sink.
-    pub fn new(source: R, driver: &ao::Driver<'a>) -> ao::AoResult<AOSink<'a, F, R>> {
-
-        // TODO permit user to specify these parameters
-        let format = ao::SampleFormat::<F, &str>::new(44100, 1, ao::Endianness::Native, None);
-
+    ///
+    /// The device is not opened until the first buffer arrives, at which point the format is
+    /// taken from the stream.
+    pub fn new(source: R, driver: &'a D) -> ao::AoResult<AOSink<'a, F, R, D>> {
         Ok(AOSink {
-            device: match driver.get_info().unwrap().flavor {
-                ao::DriverType::Live => {
-                    try!(driver.open_live(&format))
-                },
-                ao::DriverType::File => {
-                    panic!("Can't do file output yet.")
-                }
-            },
+            driver: driver,
+            device: None,
+            format: (0, 0),
+            rate: DEFAULT_SAMPLE_RATE,
+            options: Options::new(),
+            file: None,
+            played: false,
+            last_play: None,
+            buffer_frames: None,
+            fill_underruns: false,
+            underruns: Arc::new(AtomicUsize::new(0)),
+            error: None,
             interleave_buf: Vec::new(),
             source: source,
         })
     }
+
+    /// Construct a libao sink, opening the device immediately with the specified format.
+    ///
+    /// The device will still be reopened if the stream turns out to have a different format.
+    pub fn with_format(source: R, driver: &'a D, rate: u32,
+                       channels: usize) -> ao::AoResult<AOSink<'a, F, R, D>> {
+        let mut sink = try!(AOSink::new(source, driver));
+        sink.device = Some(try!(open(driver, rate, channels, sink.options, None)));
+        sink.format = (rate, channels);
+        sink.rate = rate;
+        Ok(sink)
+    }
+
+    /// Construct a libao sink, opening the device immediately with a caller-supplied format.
+    ///
+    /// The format's byte order and channel matrix are kept if the device is reopened because the
+    /// stream's format differs, but the matrix is only applied to streams with as many channels as
+    /// it names. For example, "L,R,C,LFE,BR,BL" places the channels of a 5.1 stream ordered as in
+    /// FLAC.
+    pub fn new_with_format(source: R, driver: &'a D, format: ao::SampleFormat<F, &'static str>)
+            -> ao::AoResult<AOSink<'a, F, R, D>> {
+        let mut sink = try!(AOSink::new(source, driver));
+        sink.options = Options {
+            byte_order: format.byte_order,
+            matrix: format.matrix,
+        };
+        let rate = format.sample_rate as u32;
+        sink.device = Some(try!(open(driver, rate, format.channels, sink.options, None)));
+        sink.format = (rate, format.channels);
+        sink.rate = rate;
+        Ok(sink)
+    }
+
+    /// Construct a sink writing to a file with a file-output driver such as libao's `wav`.
+    ///
+    /// The file is created immediately so any error opening it is reported here, and an existing
+    /// file is only replaced if `overwrite` is set. The device is reopened with the stream's actual
+    /// format when the first buffer arrives.
+    pub fn new_file<P: AsRef<Path>>(source: R, driver: &'a D, path: P,
+                                     overwrite: bool) -> ao::AoResult<AOSink<'a, F, R, D>> {
+        let path = path.as_ref().to_path_buf();
+        let mut sink = try!(AOSink::new(source, driver));
+        sink.device = Some(try!(open(driver, DEFAULT_SAMPLE_RATE, 1, sink.options,
+                                     Some((&path, overwrite)))));
+        sink.format = (DEFAULT_SAMPLE_RATE, 1);
+        sink.file = Some((path, overwrite));
+        Ok(sink)
+    }
+
+    /// Set the number of frames expected in each buffer, or `None` to disable short-buffer
+    /// detection.
+    ///
+    /// Short buffers are not detected by default. Late buffers are always detected.
+    pub fn set_buffer_frames(&mut self, frames: Option<usize>) {
+        self.buffer_frames = frames;
+    }
+
+    /// Select whether to pad buffers shorter than expected with silence.
+    ///
+    /// This has no effect unless an expected size has been set with `set_buffer_frames`.
+    pub fn set_fill_underruns(&mut self, fill: bool) {
+        self.fill_underruns = fill;
+    }
+
+    /// Get a counter of underruns detected so far.
+    ///
+    /// The counter is shared, so it may be read from another thread while the sink runs.
+    pub fn underruns(&self) -> Arc<AtomicUsize> {
+        self.underruns.clone()
+    }
+
+    /// Get the error reported by the source which stopped the sink, if any.
+    pub fn error(&self) -> Option<&StreamError> {
+        self.error.as_ref()
+    }
+
+    /// Open the device with a new format as (sample rate, channels), closing any open device.
+    ///
+    /// Returns `None` if the sink must stop.
+    fn reopen(&mut self, format: (u32, usize)) -> Option<()> {
+        if self.file.is_some() && self.played {
+            error!("libao sink can't change file output format from {:?} to {:?}",
+                   self.format, format);
+            return None;
+        }
+        // Close any existing device first; some drivers only permit one open device.
+        self.device = None;
+        // We created the file ourselves if it exists at this point, so it's always safe to
+        // replace it.
+        let file = self.file.as_ref().map(|&(ref path, _)| (&**path, true));
+        match open(self.driver, format.0, format.1, self.options, file) {
+            Ok(device) => {
+                self.device = Some(device);
+                self.format = format;
+                self.last_play = None;
+                Some(())
+            }
+            Err(e) => {
+                error!("Failed to open libao device: {}", e);
+                None
+            }
+        }
+    }
 }
 
-impl<'a, F: ao::Sample + Interleave, R: Source<Output=F>> Sink for AOSink<'a, F, R> {
+impl<'a, F, R, D> Sink for AOSink<'a, F, R, D> where
+        F: ao::Sample + Interleave,
+        R: Source<Output=F>,
+        D: Driver<F> {
     fn run_once(&mut self) -> Option<()> {
-        match self.source.next() {
-            SourceResult::Buffer(channels) => {
-                // Interleave channels
-                let len = channels[0].len();
-                self.interleave_buf.reserve(len);
-                unsafe {
-                    self.interleave_buf.set_len(len);
-                    // Transmute hack to lose `mut` on each channel.
-                    Interleave::interleave(mem::transmute(channels), &mut self.interleave_buf);
+        let channels = match self.source.next() {
+            SourceResult::Buffer(channels) => channels,
+            SourceResult::SampleRate(rate) => {
+                self.rate = rate;
+                // Mid-stream changes take effect immediately. Before anything has played the
+                // format is settled by the first buffer instead.
+                if self.played && self.device.is_some() && self.format.0 != rate {
+                    let channels = self.format.1;
+                    return self.reopen((rate, channels));
                 }
+                return Some(());
+            }
+            SourceResult::EndOfStream => return None,
+            SourceResult::StreamError(e) => {
+                error!("libao sink stopping on stream error: {}", e);
+                self.error = Some(e);
+                return None;
+            }
+        };
+        let arrived = Instant::now();
 
-                self.device.play(&self.interleave_buf);
-                // Drop all interleaved samples
-                self.interleave_buf.truncate(0);
-                Some(())
+        // Interleave channels first, so a malformed buffer (including one with no channels)
+        // stops the sink before it touches the device.
+        let nch = channels.len();
+        if let Err(e) = interleave_into(channels, &mut self.interleave_buf) {
+            error!("libao sink stopping on malformed buffer: {}", e);
+            self.error = Some(StreamError::Other(format!("Malformed buffer: {}", e)));
+            return None;
+        }
+        let frames = self.interleave_buf.len() / nch;
+
+        let format = (self.rate, nch);
+        if self.device.is_none() || self.format != format {
+            if self.reopen(format).is_none() {
+                return None;
+            }
+        }
+
+        let mut underrun = match self.last_play {
+            Some((played, duration)) => seconds(arrived.duration_since(played)) > duration,
+            None => false
+        };
+        if let Some(expected) = self.buffer_frames {
+            if frames < expected {
+                underrun = true;
+                if self.fill_underruns {
+                    let silence: F = Sample::from_float(0f64);
+                    self.interleave_buf.resize(expected * nch, silence);
+                }
             }
-            _ => None
         }
+        if underrun {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.device.as_ref().unwrap().play(&self.interleave_buf);
+        self.played = true;
+        let duration = (self.interleave_buf.len() / nch) as f64 / self.format.0 as f64;
+        self.last_play = Some((Instant::now(), duration));
+        // Drop all interleaved samples
+        self.interleave_buf.truncate(0);
+        Some(())
     }
 }
 
@@ -80,3 +346,294 @@ pub struct AOAutoWriterSink<'a, R, W, _S> {
         _S: Str {
 
 }*/
+
+#[cfg(test)]
+mod tests {
+    extern crate ao;
+
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use super::super::{Sample, Source, SourceResult, Sink, StreamError, BufferSource,
+                       channel_buffer};
+    use super::{AOSink, Driver, Device};
+    use std::raw::Slice;
+
+    /// Driver recording the format of every device it opens.
+    struct MockDriver {
+        opened: RefCell<Vec<(usize, usize)>>,
+        /// Overwrite flag for each file opened.
+        files: RefCell<Vec<bool>>,
+        /// Number of samples in each call to `play` on any device.
+        played: Rc<RefCell<Vec<usize>>>,
+        /// Every sample played on any device, as a float.
+        samples: Rc<RefCell<Vec<f64>>>,
+        /// Byte order and channel matrix of each device opened.
+        options: RefCell<Vec<(ao::Endianness, Option<&'static str>)>>,
+    }
+
+    impl MockDriver {
+        fn new() -> MockDriver {
+            MockDriver {
+                opened: RefCell::new(vec![]),
+                files: RefCell::new(vec![]),
+                played: Rc::new(RefCell::new(vec![])),
+                samples: Rc::new(RefCell::new(vec![])),
+                options: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    struct MockDevice {
+        played: Rc<RefCell<Vec<usize>>>,
+        samples: Rc<RefCell<Vec<f64>>>,
+    }
+
+    impl<F: ao::Sample> Driver<F> for MockDriver {
+        type Device = MockDevice;
+
+        fn open(&self, format: &ao::SampleFormat<F, &'static str>,
+                file: Option<(&Path, bool)>) -> ao::AoResult<MockDevice> {
+            if let Some((path, overwrite)) = file {
+                assert_eq!(path, Path::new("out.wav"));
+                if !overwrite && !self.files.borrow().is_empty() {
+                    return Err(ao::AoError::FileExists);
+                }
+                self.files.borrow_mut().push(overwrite);
+            }
+            self.opened.borrow_mut().push((format.sample_rate, format.channels));
+            self.options.borrow_mut().push((format.byte_order, format.matrix));
+            Ok(MockDevice { played: self.played.clone(), samples: self.samples.clone() })
+        }
+    }
+
+    impl<F: Sample> Device<F> for MockDevice {
+        fn play(&self, samples: &[F]) {
+            self.played.borrow_mut().push(samples.len());
+            self.samples.borrow_mut().extend(samples.iter().map(|&x| Sample::to_float::<f64>(x)));
+        }
+    }
+
+    /// Source replaying a script of sample rates (`Err`) and buffers of some channel count.
+    struct Script {
+        events: Vec<Result<usize, u32>>,
+        channels: Vec<Vec<i16>>,
+        slices: Vec<Slice<i16>>,
+    }
+
+    impl Source for Script {
+        type Output = i16;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, i16> {
+            if self.events.is_empty() {
+                return SourceResult::EndOfStream;
+            }
+            match self.events.remove(0) {
+                Err(rate) => SourceResult::SampleRate(rate),
+                Ok(n) => {
+                    self.channels = vec![vec![0; 16]; n];
+                    channel_buffer(&mut self.channels, &mut self.slices)
+                }
+            }
+        }
+    }
+
+    /// Delays each result from `source` by `delay`.
+    struct Slow<S> {
+        source: S,
+        delay: Duration,
+    }
+
+    impl<S: Source<Output=i16>> Source for Slow<S> {
+        type Output = i16;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, i16> {
+            thread::sleep(self.delay);
+            self.source.next()
+        }
+    }
+
+    fn opened_formats(events: Vec<Result<usize, u32>>) -> Vec<(usize, usize)> {
+        let driver = MockDriver::new();
+        {
+            let source = Script { events: events, channels: vec![], slices: vec![] };
+            let mut sink = AOSink::new(source, &driver).unwrap();
+            while let Some(()) = sink.run_once() { }
+        }
+        driver.opened.into_inner()
+    }
+
+    #[test]
+    fn opens_device_with_stream_format() {
+        assert_eq!(opened_formats(vec![Err(48000), Ok(2), Ok(2)]), vec![(48000, 2)]);
+        assert_eq!(opened_formats(vec![Ok(1)]), vec![(44100, 1)]);
+    }
+
+    #[test]
+    fn reopens_device_on_format_change() {
+        assert_eq!(opened_formats(vec![Err(48000), Ok(2), Err(22050), Ok(2), Ok(1), Err(22050)]),
+                   vec![(48000, 2), (22050, 2), (22050, 1)]);
+    }
+
+    #[test]
+    fn keeps_playing_across_rate_change() {
+        let driver = MockDriver::new();
+        {
+            let source = Script {
+                events: vec![Err(48000), Ok(2), Err(44100), Ok(2), Ok(2)],
+                channels: vec![],
+                slices: vec![]
+            };
+            let mut sink = AOSink::new(source, &driver).unwrap();
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(sink.run_once(), Some(()));
+            // The device is reopened at the new rate right away.
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(driver.opened.borrow().clone(), vec![(48000, 2), (44100, 2)]);
+            while let Some(()) = sink.run_once() { }
+        }
+        assert_eq!(driver.played.borrow().clone(), vec![32, 32, 32]);
+    }
+
+    /// Source which always fails.
+    struct Fails;
+
+    impl Source for Fails {
+        type Output = i16;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, i16> {
+            SourceResult::StreamError(StreamError::Decode("corrupt packet".to_string()))
+        }
+    }
+
+    #[test]
+    fn records_stream_error() {
+        let driver = MockDriver::new();
+        let source = Script { events: vec![Ok(2)], channels: vec![], slices: vec![] };
+        let mut sink = AOSink::new(source.chain(Fails), &driver).unwrap();
+        assert_eq!(sink.run_once(), Some(()));
+        assert_eq!(sink.error(), None);
+
+        assert_eq!(sink.run_once(), None);
+        assert_eq!(sink.error(), Some(&StreamError::Decode("corrupt packet".to_string())));
+    }
+
+    #[test]
+    fn rejects_buffer_without_channels() {
+        let driver = MockDriver::new();
+        {
+            let source = Script { events: vec![Ok(0)], channels: vec![], slices: vec![] };
+            let mut sink = AOSink::new(source, &driver).unwrap();
+            assert_eq!(sink.run_once(), None);
+            match sink.error() {
+                Some(&StreamError::Other(_)) => { }
+                e => panic!("Unexpected error {:?}", e)
+            }
+        }
+        // The device was never opened for a zero-channel stream.
+        assert!(driver.opened.into_inner().is_empty());
+    }
+
+    #[test]
+    fn plays_i32() {
+        let driver = MockDriver::new();
+        {
+            let source = BufferSource::new(vec![vec![1 << 30, -(1 << 30)], vec![0i32, 1 << 29]],
+                                           96000);
+            let mut sink = AOSink::new(source, &driver).unwrap();
+            while let Some(()) = sink.run_once() { }
+        }
+        assert_eq!(driver.opened.into_inner(), vec![(96000, 2)]);
+        let samples: Vec<f64> = driver.samples.borrow().iter()
+                                      .map(|x| (x * 8f64).round() / 8f64).collect();
+        assert_eq!(samples, vec![0.5, 0.0, -0.5, 0.25]);
+    }
+
+    #[test]
+    fn file_output_reopens_only_before_first_buffer() {
+        let driver = MockDriver::new();
+        {
+            let source = Script {
+                events: vec![Err(48000), Ok(2), Ok(2), Ok(1), Ok(1)],
+                channels: vec![],
+                slices: vec![]
+            };
+            let mut sink = AOSink::new_file(source, &driver, "out.wav", false).unwrap();
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(sink.run_once(), Some(()));
+            // Channel count changed after samples were written.
+            assert_eq!(sink.run_once(), None);
+        }
+        assert_eq!(driver.opened.into_inner(), vec![(44100, 1), (48000, 2)]);
+        assert_eq!(driver.files.into_inner(), vec![false, true]);
+    }
+
+    #[test]
+    fn counts_and_fills_underruns() {
+        let driver = MockDriver::new();
+        {
+            // At a low rate buffers last long enough that they're never late.
+            let source = Script {
+                events: vec![Err(1000), Ok(2), Ok(2), Ok(2)],
+                channels: vec![],
+                slices: vec![]
+            };
+            let mut sink = AOSink::new(source, &driver).unwrap();
+            assert_eq!(sink.run_once(), Some(()));
+            // Buffers are 16 frames.
+            sink.set_buffer_frames(Some(16));
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(sink.underruns().load(Ordering::Relaxed), 0);
+
+            sink.set_buffer_frames(Some(20));
+            assert_eq!(sink.run_once(), Some(()));
+            sink.set_fill_underruns(true);
+            assert_eq!(sink.run_once(), Some(()));
+            assert_eq!(sink.underruns().load(Ordering::Relaxed), 2);
+        }
+        assert_eq!(driver.played.borrow().clone(), vec![32, 32, 40]);
+    }
+
+    #[test]
+    fn counts_late_buffers() {
+        let driver = MockDriver::new();
+        // Each 16-frame buffer lasts 2 ms but takes 20 ms to arrive.
+        let source = Slow {
+            source: Script {
+                events: vec![Err(8000), Ok(1), Ok(1), Ok(1)],
+                channels: vec![],
+                slices: vec![]
+            },
+            delay: Duration::new(0, 20000000),
+        };
+        let mut sink = AOSink::new(source, &driver).unwrap();
+        assert_eq!(sink.run_once(), Some(()));
+        // Nothing was playing before the first buffer.
+        assert_eq!(sink.run_once(), Some(()));
+        assert_eq!(sink.underruns().load(Ordering::Relaxed), 0);
+        assert_eq!(sink.run_once(), Some(()));
+        assert_eq!(sink.run_once(), Some(()));
+        assert_eq!(sink.underruns().load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn forwards_caller_format() {
+        let driver = MockDriver::new();
+        {
+            let source = Script { events: vec![Ok(2), Ok(1)], channels: vec![], slices: vec![] };
+            let format = ao::SampleFormat::new(48000, 2, ao::Endianness::Big, Some("L,R"));
+            let mut sink = AOSink::new_with_format(source, &driver, format).unwrap();
+            while let Some(()) = sink.run_once() { }
+        }
+        assert_eq!(driver.opened.into_inner(), vec![(48000, 2), (48000, 1)]);
+
+        let options = driver.options.into_inner();
+        assert!(options.iter().all(|&(order, _)| order == ao::Endianness::Big));
+        // The matrix doesn't fit the mono stream.
+        assert_eq!(options.iter().map(|&(_, m)| m).collect::<Vec<_>>(), vec![Some("L,R"), None]);
+    }
+}
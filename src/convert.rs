@@ -0,0 +1,281 @@
+// Batch conversions between integer and floating-point samples.
+//
+// `Sample::to_float_slice` and `from_float_slice` try these before falling back to converting one
+// sample at a time. Only formats with a vectorized implementation are handled here; everything
+// else reports that it wasn't converted.
+//
+// The vectorized versions must give bit-identical results to `Sample::to_float` and `from_float`,
+// so they divide rather than multiply by a reciprocal and truncate rather than round.
+
+use std::any::{Any, TypeId};
+use std::mem;
+#[cfg(target_arch = "x86_64")] use super::cpu;
+use super::Sample;
+
+/// Reinterpret `xs` as a slice of `U`, if `T` is `U`.
+fn cast<T: Any, U: Any>(xs: &[T]) -> Option<&[U]> {
+    if TypeId::of::<T>() == TypeId::of::<U>() {
+        Some(unsafe { mem::transmute(xs) })
+    } else {
+        None
+    }
+}
+
+/// Reinterpret `xs` as a mutable slice of `U`, if `T` is `U`.
+fn cast_mut<T: Any, U: Any>(xs: &mut [T]) -> Option<&mut [U]> {
+    if TypeId::of::<T>() == TypeId::of::<U>() {
+        Some(unsafe { mem::transmute(xs) })
+    } else {
+        None
+    }
+}
+
+/// Convert `src` to floats in `dst` if there's a fast path for the formats.
+///
+/// Returns false without touching `dst` otherwise.
+pub fn to_float_fast<S: Sample, F: Sample>(src: &[S], dst: &mut [F]) -> bool {
+    match (cast::<S, i16>(src), cast_mut::<F, f32>(dst)) {
+        (Some(src), Some(dst)) => {
+            i16_to_f32(src, dst);
+            true
+        }
+        _ => false
+    }
+}
+
+/// Convert floats in `src` to samples in `dst` if there's a fast path for the formats.
+///
+/// Returns false without touching `dst` otherwise.
+pub fn from_float_fast<F: Sample, S: Sample>(src: &[F], dst: &mut [S]) -> bool {
+    match (cast::<F, f32>(src), cast_mut::<S, i16>(dst)) {
+        (Some(src), Some(dst)) => {
+            f32_to_i16(src, dst);
+            true
+        }
+        _ => false
+    }
+}
+
+fn i16_to_f32_arbitrary(xs: &[i16], ys: &mut [f32]) {
+    for (x, y) in xs.iter().zip(ys.iter_mut()) {
+        *y = Sample::to_float(*x);
+    }
+}
+
+fn f32_to_i16_arbitrary(xs: &[f32], ys: &mut [i16]) {
+    for (x, y) in xs.iter().zip(ys.iter_mut()) {
+        *y = Sample::from_float(*x);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn i16_to_f32(xs: &[i16], ys: &mut [f32]) {
+    if cpu::cpu_supports(cpu::SSE2) {
+        unsafe {
+            i16_to_f32_sse2(xs, ys);
+        }
+    } else {
+        i16_to_f32_arbitrary(xs, ys);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn i16_to_f32(xs: &[i16], ys: &mut [f32]) {
+    i16_to_f32_arbitrary(xs, ys);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn f32_to_i16(xs: &[f32], ys: &mut [i16]) {
+    if cpu::cpu_supports(cpu::SSE2) {
+        unsafe {
+            f32_to_i16_sse2(xs, ys);
+        }
+    } else {
+        f32_to_i16_arbitrary(xs, ys);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn f32_to_i16(xs: &[f32], ys: &mut [i16]) {
+    f32_to_i16_arbitrary(xs, ys);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn i16_to_f32_sse2(xs: &[i16], ys: &mut [f32]) {
+    let n = xs.len();
+    let a = xs.as_ptr();
+    let out = ys.as_mut_ptr();
+    let scale = [32767f32; 4];
+
+    // Take vectors 8 samples at a time
+    for i in 0..n/8 {
+        let input = a.offset(8 * i as isize);
+        let output = out.offset(8 * i as isize);
+
+        // Unpacking each word with itself then shifting right sign-extends it to a dword.
+        asm!{
+            "movdqu ($0), %xmm0
+             movdqa %xmm0, %xmm1
+             punpcklwd %xmm0, %xmm0
+             punpckhwd %xmm1, %xmm1
+             psrad $$16, %xmm0
+             psrad $$16, %xmm1
+             cvtdq2ps %xmm0, %xmm0
+             cvtdq2ps %xmm1, %xmm1
+             movups ($2), %xmm2
+             divps %xmm2, %xmm0
+             divps %xmm2, %xmm1
+             movups %xmm0, ($1)
+             movups %xmm1, 16($1)"
+            :                                              // Output
+            : "r"(input), "r"(output), "r"(scale.as_ptr()) // Input
+            : "{xmm0}", "{xmm1}", "{xmm2}"                 // Clobbers
+        };
+    }
+
+    // Non-multiple of 8 tail
+    i16_to_f32_arbitrary(&xs[n & !7..], &mut ys[n & !7..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn f32_to_i16_sse2(xs: &[f32], ys: &mut [i16]) {
+    let n = xs.len();
+    let a = xs.as_ptr();
+    let out = ys.as_mut_ptr();
+    // Scale, lower and upper clipping bounds.
+    let constants = [32767f32, 32767f32, 32767f32, 32767f32,
+                     -1f32, -1f32, -1f32, -1f32,
+                     1f32, 1f32, 1f32, 1f32];
+
+    // Take vectors 8 samples at a time
+    for i in 0..n/8 {
+        let input = a.offset(8 * i as isize);
+        let output = out.offset(8 * i as isize);
+
+        // Clipping to [-1, 1] first means packssdw never saturates.
+        asm!{
+            "movups ($0), %xmm0
+             movups 16($0), %xmm1
+             movups 16($2), %xmm2
+             maxps %xmm2, %xmm0
+             maxps %xmm2, %xmm1
+             movups 32($2), %xmm2
+             minps %xmm2, %xmm0
+             minps %xmm2, %xmm1
+             movups ($2), %xmm2
+             mulps %xmm2, %xmm0
+             mulps %xmm2, %xmm1
+             cvttps2dq %xmm0, %xmm0
+             cvttps2dq %xmm1, %xmm1
+             packssdw %xmm1, %xmm0
+             movdqu %xmm0, ($1)"
+            :                                                  // Output
+            : "r"(input), "r"(output), "r"(constants.as_ptr()) // Input
+            : "{xmm0}", "{xmm1}", "{xmm2}"                     // Clobbers
+        };
+    }
+
+    // Non-multiple of 8 tail
+    f32_to_i16_arbitrary(&xs[n & !7..], &mut ys[n & !7..]);
+}
+
+#[cfg(test)]
+mod test {
+    extern crate test;
+    use self::test::Bencher;
+    use super::super::Sample;
+
+    fn ramp() -> Vec<i16> {
+        // Odd length exercises the tail.
+        (0..1029).map(|i: i32| (i * 64 - 32768) as i16).collect()
+    }
+
+    fn floats() -> Vec<f32> {
+        // Covers values beyond full scale in both directions.
+        (0..1029).map(|i| (i as f32 - 514.5) / 500f32).collect()
+    }
+
+    #[test]
+    fn to_float_slice_matches_scalar() {
+        let src = ramp();
+        let mut batch = vec![0f32; src.len()];
+        Sample::to_float_slice(&src, &mut batch);
+        for (&x, &y) in src.iter().zip(batch.iter()) {
+            assert_eq!(y, Sample::to_float::<f32>(x));
+        }
+    }
+
+    #[test]
+    fn from_float_slice_matches_scalar() {
+        let src = floats();
+        let mut batch = vec![0i16; src.len()];
+        Sample::from_float_slice(&src, &mut batch);
+        for (&x, &y) in src.iter().zip(batch.iter()) {
+            assert_eq!(y, <i16 as Sample>::from_float(x));
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn sse2_matches_scalar() {
+        // As with interleaving, call the SSE2 versions directly so they're tested even if
+        // CPU_FEATURES_OVERRIDE disables them.
+        let src = ramp();
+        let mut fast = vec![0f32; src.len()];
+        let mut scalar = vec![0f32; src.len()];
+        unsafe {
+            super::i16_to_f32_sse2(&src, &mut fast);
+        }
+        super::i16_to_f32_arbitrary(&src, &mut scalar);
+        assert!(fast == scalar);
+
+        let src = floats();
+        let mut fast = vec![0i16; src.len()];
+        let mut scalar = vec![0i16; src.len()];
+        unsafe {
+            super::f32_to_i16_sse2(&src, &mut fast);
+        }
+        super::f32_to_i16_arbitrary(&src, &mut scalar);
+        assert!(fast == scalar);
+    }
+
+    #[bench]
+    fn bench_to_float_slice(bencher: &mut Bencher) {
+        let src = vec![1234i16; 4096];
+        let mut dst = vec![0f32; 4096];
+        bencher.iter(|| Sample::to_float_slice(&src, &mut dst));
+        bencher.bytes = 4096 * 2;
+    }
+
+    #[bench]
+    fn bench_to_float_elementwise(bencher: &mut Bencher) {
+        let src = vec![1234i16; 4096];
+        let mut dst = vec![0f32; 4096];
+        bencher.iter(|| {
+            for (x, y) in src.iter().zip(dst.iter_mut()) {
+                *y = Sample::to_float(*x);
+            }
+        });
+        bencher.bytes = 4096 * 2;
+    }
+
+    #[bench]
+    fn bench_from_float_slice(bencher: &mut Bencher) {
+        let src = vec![0.25f32; 4096];
+        let mut dst = vec![0i16; 4096];
+        bencher.iter(|| Sample::from_float_slice(&src, &mut dst));
+        bencher.bytes = 4096 * 4;
+    }
+
+    #[bench]
+    fn bench_from_float_elementwise(bencher: &mut Bencher) {
+        let src = vec![0.25f32; 4096];
+        let mut dst = vec![0i16; 4096];
+        bencher.iter(|| {
+            for (x, y) in src.iter().zip(dst.iter_mut()) {
+                *y = Sample::from_float(*x);
+            }
+        });
+        bencher.bytes = 4096 * 4;
+    }
+}
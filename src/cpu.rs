@@ -1,7 +1,12 @@
+//! CPU feature detection.
+//!
+//! This is used to select SIMD implementations at runtime, and is exposed so users can see what
+//! was selected when diagnosing problems.
+
 use std::str::FromStr;
 use std::env;
 
-pub use self::innards::Feature;
+pub use self::innards::{Feature, ALL_FEATURES};
 
 #[cfg(target_arch = "x86_64")]
 pub use self::innards::Feature::{Baseline, MMX, SSE, SSE2, SSE3, SSSE3, SSE41,
@@ -65,43 +70,98 @@ fn parse_env_overrides() -> (Vec<Feature>, Vec<Feature>) {
 /// features is.
 /// 
 /// Invalid feature specifications are ignored.
+///
+/// Detection results are cached, so this is cheap enough to call in hot paths.
 pub fn cpu_supports(feature: Feature) -> bool {
-    let (ref blacklist, ref whitelist) = *FEATURES_OVERRIDE;
+    overridden(feature, &*FEATURES_OVERRIDE).unwrap_or_else(|| innards::cpu_supports(feature))
+}
+
+/// Get every feature the CPU supports, after applying user overrides.
+///
+/// Features are listed in the same order as `ALL_FEATURES`.
+pub fn detected_features() -> Vec<Feature> {
+    ALL_FEATURES.iter().cloned().filter(|&f| cpu_supports(f)).collect()
+}
+
+/// Check `feature` against the (blacklist, whitelist) in `overrides`.
+///
+/// Returns `None` if the feature isn't overridden.
+fn overridden(feature: Feature, overrides: &(Vec<Feature>, Vec<Feature>)) -> Option<bool> {
+    let (ref blacklist, ref whitelist) = *overrides;
 
     if whitelist.iter().any(|f| *f == feature) {
-        true
+        Some(true)
     } else if blacklist.iter().any(|f| *f == feature) {
-        false
+        Some(false)
     } else {
-        innards::cpu_supports(feature)
+        None
     }
 }
 
 #[cfg(target_arch = "x86_64")]
 mod innards {
     use self::Feature::*;
+    use std::fmt;
     use std::str::FromStr;
 
+    /// An optional instruction set extension.
+    ///
+    /// Features are named by their variant names, both when formatted with `Display` and when
+    /// parsed with `FromStr`.
     #[derive(PartialEq, Eq, Debug, Clone, Copy)]
     pub enum Feature {
+        /// The base instruction set, which is always available.
         Baseline,
+        /// MMX.
         MMX,
+        /// Streaming SIMD Extensions.
         SSE,
+        /// SSE2, which every x86_64 processor supports.
         SSE2,
+        /// SSE3.
         SSE3,
+        /// Supplemental SSE3.
         SSSE3,
+        /// SSE4.1.
         SSE41,
+        /// SSE4.2.
         SSE42,
+        /// OS support for XSAVE, which is required to use AVX.
         OSXSAVE,
+        /// Advanced Vector Extensions, with OS support.
         AVX,
+        /// AVX2, with OS support.
         AVX2
     }
 
+    /// Every feature which may be detected, from least to most capable.
+    pub static ALL_FEATURES: [Feature; 11] = [Baseline, MMX, SSE, SSE2, SSE3, SSSE3, SSE41,
+                                              SSE42, OSXSAVE, AVX, AVX2];
+
+    impl fmt::Display for Feature {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(match *self {
+                Baseline => "Baseline",
+                MMX => "MMX",
+                SSE => "SSE",
+                SSE2 => "SSE2",
+                SSE3 => "SSE3",
+                SSSE3 => "SSSE3",
+                SSE41 => "SSE41",
+                SSE42 => "SSE42",
+                OSXSAVE => "OSXSAVE",
+                AVX => "AVX",
+                AVX2 => "AVX2",
+            })
+        }
+    }
+
     impl FromStr for Feature {
         type Err = ();
 
         fn from_str(s: &str) -> Result<Feature, <Self as FromStr>::Err> {
             Ok(match s {
+                "Baseline" => Baseline,
                 "MMX" => MMX,
                 "SSE" => SSE,
                 "SSE2" => SSE2,
@@ -122,21 +182,50 @@ mod innards {
     static EBX: usize = 1;
     static ECX: usize = 2;
     static EDX: usize = 3;
+
+    /// Raw CPUID and XCR0 values, which can't change while we're running.
+    struct Leaves {
+        /// Registers from EAX=1 ECX=0.
+        leaf1: [u32; 4],
+        /// Registers from EAX=7 ECX=0, or zero if that leaf isn't supported.
+        leaf7: [u32; 4],
+        /// Extended control register 0, or zero if the OS hasn't enabled XSAVE.
+        xcr0: u64,
+    }
+
+    lazy_static!{
+        static ref LEAVES: Leaves = read_leaves();
+    }
+
+    fn read_leaves() -> Leaves {
+        let mut max = [0u32; 4];
+        do_cpuid(0, 0, &mut max);
+
+        let mut leaves = Leaves {
+            leaf1: [0u32; 4],
+            leaf7: [0u32; 4],
+            xcr0: 0,
+        };
+        do_cpuid(1, 0, &mut leaves.leaf1);
+        if max[0] >= 7 {
+            do_cpuid(7, 0, &mut leaves.leaf7);
+        }
+        // XGETBV faults unless OSXSAVE is set.
+        if leaves.leaf1[ECX] & (1 << 27) != 0 {
+            leaves.xcr0 = do_xgetbv(0);
+        }
+        leaves
+    }
+
     macro_rules! feature(
-        // Select bit in output register from CPUID with specified input values
-        // of EAX and ECX.
-        ($p_eax:expr, $p_ecx:expr, $reg:expr, $bit:expr) => (
-            {
-                let mut regs = [0u32; 4];
-                do_cpuid($p_eax, $p_ecx, &mut regs);
-
-                regs[$reg] & (1 << $bit) != 0
-            }
+        // Select bit in output register from a cached CPUID leaf.
+        ($leaf:ident, $reg:expr, $bit:expr) => (
+            LEAVES.$leaf[$reg] & (1 << $bit) != 0
         );
 
         // Implicit features leaf EAX=1 ECX=0
         ($reg:expr, $bit:expr) => (
-            feature!(1, 0, $reg, $bit)
+            feature!(leaf1, $reg, $bit)
         )
     );
 
@@ -154,12 +243,12 @@ mod innards {
             AVX => {
                 // Requires that OS support for XSAVE be in use and enabled for AVX
                 cpu_supports(OSXSAVE)
-                && (do_xgetbv(0) & 6 == 6)
+                && (LEAVES.xcr0 & 6 == 6)
                 && feature!(ECX, 28)
             }
             AVX2 => {
                 // Need OS support for AVX and AVX2 feature flag
-                cpu_supports(AVX) && feature!(7, 0, EBX, 5)
+                cpu_supports(AVX) && feature!(leaf7, EBX, 5)
             }
         }
     }
@@ -201,12 +290,18 @@ mod innards {
 mod innards {
     use std::from_str::FromStr;
 
+    /// An optional instruction set extension.
     #[derive(PartialEq, Eq, Debug, Clone, Copy)]
     pub enum Feature {
+        /// The base instruction set, which is always available.
         Baseline,
+        /// Advanced SIMD.
         NEON
     }
 
+    /// Every feature which may be detected, from least to most capable.
+    pub static ALL_FEATURES: [Feature; 2] = [Baseline, NEON];
+
     impl FromStr for Feature {
         fn from_str(s: &str) -> Option<Feature> {
             Some(match s {
@@ -225,3 +320,46 @@ mod innards {
         }
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::{overridden, innards, detected_features, ALL_FEATURES};
+    use super::Feature::{Baseline, SSE2, AVX, AVX2};
+    use std::str::FromStr;
+
+    #[test]
+    fn detection_is_stable() {
+        // Every x86_64 processor has SSE2.
+        assert!(innards::cpu_supports(Baseline));
+        assert!(innards::cpu_supports(SSE2));
+        for &feature in [SSE2, AVX, AVX2].iter() {
+            assert_eq!(innards::cpu_supports(feature), innards::cpu_supports(feature));
+        }
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let overrides = (vec![SSE2], vec![AVX2]);
+        assert_eq!(overridden(SSE2, &overrides), Some(false));
+        assert_eq!(overridden(AVX2, &overrides), Some(true));
+        assert_eq!(overridden(AVX, &overrides), None);
+        // Whitelisting wins if a feature is in both lists.
+        assert_eq!(overridden(SSE2, &(vec![SSE2], vec![SSE2])), Some(true));
+    }
+
+    #[test]
+    fn feature_names_roundtrip() {
+        for &feature in ALL_FEATURES.iter() {
+            let name = format!("{}", feature);
+            assert_eq!(<super::Feature as FromStr>::from_str(&name), Ok(feature));
+        }
+        assert!(<super::Feature as FromStr>::from_str("SSE5").is_err());
+    }
+
+    #[test]
+    fn detected_features_include_baseline() {
+        let features = detected_features();
+        assert!(features.contains(&Baseline));
+        assert!(features.iter().all(|&f| super::cpu_supports(f)));
+    }
+}
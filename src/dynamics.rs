@@ -0,0 +1,334 @@
+//! Dynamic range processing.
+
+use std::marker::PhantomData;
+use super::{Sample, Source, SourceResult, DEFAULT_SAMPLE_RATE};
+
+/// Smoothing coefficient for a one-pole filter with time constant `time` seconds.
+fn time_coefficient(time: f64, rate: u32) -> f64 {
+    (-1f64 / (time * rate as f64)).exp()
+}
+
+/// Convert a linear level to decibels relative to full scale.
+fn to_db(x: f64) -> f64 {
+    20f64 * x.log10()
+}
+
+/// Convert decibels to a linear gain.
+fn from_db(db: f64) -> f64 {
+    10f64.powf(db / 20f64)
+}
+
+/// Dynamic range compressor.
+///
+/// The level of each frame is the largest magnitude of any channel, so all channels receive the
+/// same gain and the stereo image is preserved. Wherever the level exceeds the threshold, the
+/// excess is divided by the ratio, and the resulting gain reduction is smoothed with the attack
+/// time constant while it is increasing and the release time constant while it is decreasing.
+/// Makeup gain is applied to the output afterwards.
+///
+/// An infinite ratio with zero attack is a brick-wall limiter. Time constants are resolved
+/// against the stream's sample rate, or 44100 Hz until the source specifies one. Processing is
+/// done in `f64`.
+pub struct Compressor<F, S> {
+    source: S,
+    threshold: f64,
+    ratio: f64,
+    attack: f64,
+    release: f64,
+    makeup: f64,
+    attack_coeff: f64,
+    release_coeff: f64,
+    /// Current gain reduction in dB.
+    reduction: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Compressor<F, S> {
+    /// Construct a compressor.
+    ///
+    /// `threshold` and `makeup` are in dB, and `attack` and `release` in seconds.
+    pub fn new(source: S, threshold: f64, ratio: f64, attack: f64, release: f64,
+               makeup: f64) -> Compressor<F, S> {
+        assert!(ratio >= 1f64, "Compression ratio must be at least 1");
+        let mut compressor = Compressor {
+            source: source,
+            threshold: threshold,
+            ratio: ratio,
+            attack: attack,
+            release: release,
+            makeup: makeup,
+            attack_coeff: 0f64,
+            release_coeff: 0f64,
+            reduction: 0f64,
+            format: PhantomData
+        };
+        compressor.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        compressor
+    }
+
+    /// Construct a brick-wall limiter with output ceiling `ceiling` dB.
+    pub fn limiter(source: S, ceiling: f64, release: f64) -> Compressor<F, S> {
+        Compressor::new(source, ceiling, ::std::f64::INFINITY, 0f64, release, 0f64)
+    }
+
+    /// Current gain reduction in dB, not including makeup gain.
+    ///
+    /// This is zero or positive.
+    pub fn gain_reduction(&self) -> f64 {
+        self.reduction
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        self.attack_coeff = time_coefficient(self.attack, rate);
+        self.release_coeff = time_coefficient(self.release, rate);
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Compressor<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        let slope = 1f64 - 1f64 / self.ratio;
+        let (threshold, makeup) = (self.threshold, self.makeup);
+        let (attack, release) = (self.attack_coeff, self.release_coeff);
+        let mut reduction = self.reduction;
+        for i in 0..buf[0].len() {
+            let level = buf.iter().fold(0f64, |a, c| {
+                a.max(Sample::to_float::<f64>(c[i]).abs())
+            });
+            let over = to_db(level) - threshold;
+            let target = if over > 0f64 { over * slope } else { 0f64 };
+
+            let coeff = if target > reduction { attack } else { release };
+            reduction = target + coeff * (reduction - target);
+
+            let gain = from_db(makeup - reduction);
+            for channel in buf.iter_mut() {
+                let x: f64 = Sample::to_float(channel[i]);
+                channel[i] = Sample::from_float(x * gain);
+            }
+        }
+        self.reduction = reduction;
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Downward expander and noise gate.
+///
+/// The level of each frame is the largest magnitude of any channel, as for `Compressor`. While the
+/// level is at or above the threshold the gate is open and the signal passes unchanged. Once it
+/// falls below, the gate stays open for the hold time, after which every dB of level below the
+/// threshold is expanded to `ratio` dB; an infinite ratio closes the gate completely. The gain is
+/// smoothed with the attack time constant while opening and the release time constant while
+/// closing.
+///
+/// The hold time should be longer than the period of the lowest frequency of interest, or the
+/// gate will begin to close on zero crossings. The gate starts open, with the hold time about to
+/// run. Time constants are resolved against the stream's sample rate, or 44100 Hz until the
+/// source specifies one. Processing is done in `f64`.
+pub struct Gate<F, S> {
+    source: S,
+    threshold: f64,
+    ratio: f64,
+    attack: f64,
+    hold: f64,
+    release: f64,
+    attack_coeff: f64,
+    release_coeff: f64,
+    /// Hold time in samples.
+    hold_samples: usize,
+    /// Samples of hold time remaining.
+    held: usize,
+    /// Current linear gain.
+    gain: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Gate<F, S> {
+    /// Construct a downward expander.
+    ///
+    /// `threshold` is in dB, and `attack`, `hold` and `release` in seconds.
+    pub fn new(source: S, threshold: f64, ratio: f64, attack: f64, hold: f64,
+               release: f64) -> Gate<F, S> {
+        assert!(ratio >= 1f64, "Expansion ratio must be at least 1");
+        let mut gate = Gate {
+            source: source,
+            threshold: threshold,
+            ratio: ratio,
+            attack: attack,
+            hold: hold,
+            release: release,
+            attack_coeff: 0f64,
+            release_coeff: 0f64,
+            hold_samples: 0,
+            held: 0,
+            gain: 1f64,
+            format: PhantomData
+        };
+        gate.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        gate.held = gate.hold_samples;
+        gate
+    }
+
+    /// Construct a gate which silences the signal entirely when closed.
+    pub fn hard(source: S, threshold: f64, attack: f64, hold: f64, release: f64) -> Gate<F, S> {
+        Gate::new(source, threshold, ::std::f64::INFINITY, attack, hold, release)
+    }
+
+    /// Current gain in dB.
+    ///
+    /// This is zero or negative, and negative infinity when a hard gate is fully closed.
+    pub fn gain(&self) -> f64 {
+        to_db(self.gain)
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        self.attack_coeff = time_coefficient(self.attack, rate);
+        self.release_coeff = time_coefficient(self.release, rate);
+        self.hold_samples = (self.hold * rate as f64).round() as usize;
+        self.held = ::std::cmp::min(self.held, self.hold_samples);
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Gate<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        let (threshold, exponent) = (from_db(self.threshold), self.ratio - 1f64);
+        let (attack, release) = (self.attack_coeff, self.release_coeff);
+        let (mut gain, mut held) = (self.gain, self.held);
+        for i in 0..buf[0].len() {
+            let level = buf.iter().fold(0f64, |a, c| {
+                a.max(Sample::to_float::<f64>(c[i]).abs())
+            });
+            let target = if level >= threshold {
+                held = self.hold_samples;
+                1f64
+            } else if held > 0 {
+                held -= 1;
+                1f64
+            } else {
+                (level / threshold).powf(exponent)
+            };
+
+            let coeff = if target > gain { attack } else { release };
+            gain = target + coeff * (gain - target);
+
+            for channel in buf.iter_mut() {
+                let x: f64 = Sample::to_float(channel[i]);
+                channel[i] = Sample::from_float(x * gain);
+            }
+        }
+        self.gain = gain;
+        self.held = held;
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI_2;
+    use super::super::{Source, SourceResult, MonoSource, IterSource};
+    use super::super::tests::ConstantSource;
+    use super::{Compressor, Gate};
+
+    /// Output level after processing a constant input for a while.
+    fn settled_output(x: f64, ratio: f64) -> f64 {
+        let src = ConstantSource { data: vec![x; 1024], sbuf: vec![] };
+        let mut compressor = Compressor::new(src.adapt(), -20f64, ratio, 0.001, 0.1, 0f64);
+        let mut y = 0f64;
+        for _ in 0..16 {
+            match compressor.next() {
+                SourceResult::Buffer(b) => y = b[0][1023],
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        y
+    }
+
+    #[test]
+    fn attenuates_above_threshold() {
+        // 0.5 is about -6 dBFS, 14 dB over the threshold. 4:1 leaves 3.5 dB of that.
+        let y = settled_output(0.5, 4f64);
+        assert!((20f64 * y.log10() - -16.5).abs() < 0.1, "Output was {}", y);
+
+        // Limiting holds it to the threshold.
+        let y = settled_output(0.5, ::std::f64::INFINITY);
+        assert!((20f64 * y.log10() - -20f64).abs() < 0.1, "Output was {}", y);
+    }
+
+    #[test]
+    fn passes_below_threshold() {
+        assert_eq!(settled_output(0.05, 4f64), 0.05);
+    }
+
+    #[test]
+    fn gate_closes_after_hold() {
+        // A loud burst then a quiet tail, both 1 kHz.
+        let input: Vec<f64> = (0..22050).map(|i| {
+            let x = (PI_2 * 1000f64 * i as f64 / 44100f64).sin();
+            if i < 4410 { x } else { 0.001 * x }
+        }).collect();
+        // 50 ms of hold and 1 ms of release.
+        let src = IterSource::new(input.clone().into_iter(), 1000);
+        let mut gate = Gate::hard(src.adapt(), -40f64, 0.0001, 0.05, 0.001);
+
+        let mut output = vec![];
+        loop {
+            match gate.next() {
+                SourceResult::Buffer(b) => output.extend(b[0].iter().cloned()),
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(output.len(), input.len());
+
+        // Untouched through the burst and most of the hold time.
+        assert_eq!(&output[..4410 + 2150], &input[..4410 + 2150]);
+        // Closed once the hold and release have elapsed.
+        for n in 4410 + 2205 + 500..input.len() {
+            assert!(output[n].abs() <= 1e-3 * input[n].abs(), "Sample {} was {}", n, output[n]);
+        }
+    }
+
+    #[test]
+    fn gate_expands_below_threshold() {
+        // -60 dB with a -40 dB threshold and 2:1 expansion comes out at -80 dB.
+        let src = ConstantSource { data: vec![0.001f64; 1024], sbuf: vec![] };
+        let mut gate = Gate::new(src.adapt(), -40f64, 2f64, 0.001, 0.001, 0.001);
+        let mut y = 0f64;
+        for _ in 0..16 {
+            match gate.next() {
+                SourceResult::Buffer(b) => y = b[0][1023],
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert!((20f64 * y.log10() - -80f64).abs() < 0.1, "Output was {}", y);
+    }
+}
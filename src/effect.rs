@@ -0,0 +1,468 @@
+//! Time-domain effects.
+
+use std::f64::consts::PI_2;
+use std::marker::PhantomData;
+use super::{Sample, Source, SourceResult, DEFAULT_SAMPLE_RATE};
+
+/// Transfer functions for `Clipper`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipCurve {
+    /// Clip to full scale with `Sample::clip`, leaving smaller values untouched.
+    Hard,
+    /// Hyperbolic tangent, which approaches full scale asymptotically.
+    Tanh,
+    /// Cubic polynomial `x - 4x³/27`, which reaches full scale smoothly at an input of 1.5 and is
+    /// flat beyond that.
+    Cubic,
+}
+
+impl ClipCurve {
+    /// Apply this transfer function to `x`.
+    ///
+    /// All the curves have unity gain for small inputs and never exceed full scale.
+    pub fn shape(&self, x: f64) -> f64 {
+        match *self {
+            ClipCurve::Hard => x.clip(),
+            ClipCurve::Tanh => x.tanh(),
+            ClipCurve::Cubic => {
+                if x >= 1.5 {
+                    1f64
+                } else if x <= -1.5 {
+                    -1f64
+                } else {
+                    x - 4f64 * x * x * x / 27f64
+                }
+            }
+        }
+    }
+}
+
+/// Overdrive distortion.
+///
+/// Each sample is amplified by the pre-gain, passed through a clipping curve and scaled by the
+/// output level. Processing is done in `f64`.
+pub struct Clipper<F, S> {
+    source: S,
+    pre_gain: f64,
+    curve: ClipCurve,
+    level: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Clipper<F, S> {
+    /// Construct a clipper with the specified linear pre-gain, curve, and linear output level.
+    pub fn new(source: S, pre_gain: f64, curve: ClipCurve, level: f64) -> Clipper<F, S> {
+        Clipper {
+            source: source,
+            pre_gain: pre_gain,
+            curve: curve,
+            level: level,
+            format: PhantomData
+        }
+    }
+
+    /// Change the pre-gain.
+    pub fn set_pre_gain(&mut self, pre_gain: f64) {
+        self.pre_gain = pre_gain;
+    }
+
+    /// Change the clipping curve.
+    pub fn set_curve(&mut self, curve: ClipCurve) {
+        self.curve = curve;
+    }
+
+    /// Change the output level.
+    pub fn set_level(&mut self, level: f64) {
+        self.level = level;
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Clipper<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        let (pre_gain, curve, level) = (self.pre_gain, self.curve, self.level);
+        for channel in buf.iter_mut() {
+            for sample in channel.iter_mut() {
+                let x: f64 = Sample::to_float(*sample);
+                *sample = Sample::from_float(curve.shape(x * pre_gain) * level);
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Echo effect.
+///
+/// The input is delayed by a fixed time and fed back into the delay line scaled by a feedback
+/// coefficient, so each echo is quieter than the last by that factor. The output is a mix of the
+/// dry input and the delayed signal.
+///
+/// Delay lines are stored in the stream's sample format and the feedback is added with
+/// `Sample::mix`, so hard-clipped formats saturate rather than wrapping if the echoes build up.
+/// The delay time is resolved against the stream's sample rate, or 44100 Hz if the source doesn't
+/// specify one before its first buffer. A change in sample rate clears the delay lines.
+pub struct Delay<F, S> {
+    source: S,
+    /// Delay time in seconds.
+    time: f64,
+    feedback: f64,
+    mix: f64,
+    rate: Option<u32>,
+    /// Per-channel delay lines, allocated when the first buffer arrives.
+    lines: Vec<Vec<F>>,
+    pos: usize,
+}
+
+impl<F: Sample, S: Source<Output=F>> Delay<F, S> {
+    /// Construct a delay of `time` seconds.
+    ///
+    /// `mix` is the fraction of the output taken from the delayed signal, with the rest from the
+    /// dry input.
+    pub fn new(source: S, time: f64, feedback: f64, mix: f64) -> Delay<F, S> {
+        Delay {
+            source: source,
+            time: time,
+            feedback: feedback,
+            mix: mix,
+            rate: None,
+            lines: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Delay<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                if self.rate != Some(r) {
+                    self.rate = Some(r);
+                    self.lines.clear();
+                }
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        if self.lines.len() != buf.len() {
+            let rate = self.rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+            let length = ::std::cmp::max(1, (self.time * rate as f64).round() as usize);
+            let zero: F = Sample::from_float(0f64);
+            self.lines = vec![vec![zero; length]; buf.len()];
+            self.pos = 0;
+        }
+
+        let (feedback, mix) = (self.feedback, self.mix);
+        let length = self.lines[0].len();
+        let start = self.pos;
+        for (channel, line) in buf.iter_mut().zip(self.lines.iter_mut()) {
+            let mut pos = start;
+            for sample in channel.iter_mut() {
+                let delayed = line[pos];
+                let d: f64 = Sample::to_float(delayed);
+                let x: f64 = Sample::to_float(*sample);
+
+                line[pos] = sample.mix(&Sample::from_float(d * feedback));
+                *sample = Sample::from_float(x * (1f64 - mix) + d * mix);
+                pos = (pos + 1) % length;
+            }
+        }
+        self.pos = (start + buf[0].len()) % length;
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Sine low-frequency oscillator.
+struct Lfo {
+    frequency: f64,
+    /// Current position within the cycle, in [0, 1).
+    phase: f64,
+    increment: f64,
+}
+
+impl Lfo {
+    fn new(frequency: f64) -> Lfo {
+        let mut lfo = Lfo {
+            frequency: frequency,
+            phase: 0f64,
+            increment: 0f64,
+        };
+        lfo.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        lfo
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        self.increment = self.frequency / rate as f64;
+    }
+
+    /// Get the next value, in [-1, 1].
+    fn next(&mut self) -> f64 {
+        let x = (PI_2 * self.phase).sin();
+        self.phase += self.increment;
+        if self.phase >= 1f64 {
+            self.phase -= 1f64;
+        }
+        x
+    }
+}
+
+/// Periodic amplitude modulation.
+///
+/// The gain follows a sine LFO between 1 at its peak and `1 - depth` at its trough, so a depth of
+/// 1 silences the signal once per cycle. The LFO starts midway between the two and rises first.
+/// Its rate is resolved against the stream's sample rate, or 44100 Hz until the source specifies
+/// one.
+pub struct Tremolo<F, S> {
+    source: S,
+    lfo: Lfo,
+    depth: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Tremolo<F, S> {
+    /// Construct a tremolo with LFO frequency `rate_hz` and linear modulation depth `depth`.
+    pub fn new(source: S, rate_hz: f64, depth: f64) -> Tremolo<F, S> {
+        Tremolo {
+            source: source,
+            lfo: Lfo::new(rate_hz),
+            depth: depth,
+            format: PhantomData
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Tremolo<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.lfo.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        let depth = self.depth;
+        for i in 0..buf[0].len() {
+            let gain = 1f64 - depth * (1f64 - self.lfo.next()) / 2f64;
+            for channel in buf.iter_mut() {
+                let x: f64 = Sample::to_float(channel[i]);
+                channel[i] = Sample::from_float(x * gain);
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Periodic pitch modulation.
+///
+/// The signal is delayed by an amount following a sine LFO between zero and `depth` seconds,
+/// which raises the pitch while the delay is shrinking and lowers it while growing. Fractional
+/// delays are linearly interpolated. The LFO rate and delay length are resolved against the
+/// stream's sample rate, or 44100 Hz until the source specifies one.
+pub struct Vibrato<F, S> {
+    source: S,
+    lfo: Lfo,
+    /// Maximum delay in seconds.
+    depth: f64,
+    rate: u32,
+    /// Per-channel delay lines of input samples, as floats.
+    lines: Vec<Vec<f64>>,
+    pos: usize,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Vibrato<F, S> {
+    /// Construct a vibrato with LFO frequency `rate_hz` and maximum delay `depth` seconds.
+    pub fn new(source: S, rate_hz: f64, depth: f64) -> Vibrato<F, S> {
+        Vibrato {
+            source: source,
+            lfo: Lfo::new(rate_hz),
+            depth: depth,
+            rate: DEFAULT_SAMPLE_RATE,
+            lines: Vec::new(),
+            pos: 0,
+            format: PhantomData
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Vibrato<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.lfo.set_sample_rate(r);
+                if r != self.rate {
+                    self.rate = r;
+                    self.lines.clear();
+                }
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        let depth = self.depth * self.rate as f64;
+        if self.lines.len() != buf.len() {
+            // Room for the current sample, the maximum delay and the sample after it.
+            let length = depth.ceil() as usize + 2;
+            self.lines = vec![vec![0f64; length]; buf.len()];
+            self.pos = 0;
+        }
+
+        let length = self.lines[0].len();
+        for i in 0..buf[0].len() {
+            let delay = depth * (1f64 + self.lfo.next()) / 2f64;
+            let whole = delay.floor() as usize;
+            let frac = delay - whole as f64;
+            let pos = self.pos;
+
+            for (channel, line) in buf.iter_mut().zip(self.lines.iter_mut()) {
+                line[pos] = Sample::to_float(channel[i]);
+                let a = line[(pos + length - whole) % length];
+                let b = line[(pos + length - whole - 1) % length];
+                channel[i] = Sample::from_float(a + (b - a) * frac);
+            }
+            self.pos = (pos + 1) % length;
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Source, SourceResult, MonoSource};
+    use super::super::tests::{ConstantSource, Impulse};
+    use super::super::synth::Tone;
+    use super::{Clipper, ClipCurve, Delay, Tremolo, Vibrato};
+
+    fn clip(curve: ClipCurve, pre_gain: f64, x: f64) -> f64 {
+        let src = ConstantSource { data: vec![x, -x], sbuf: vec![] };
+        let mut clipper = Clipper::new(src.adapt(), pre_gain, curve, 1f64);
+        match clipper.next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(b[0][0], -b[0][1]);
+                b[0][0]
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn small_signals_are_linear() {
+        for &curve in [ClipCurve::Hard, ClipCurve::Tanh, ClipCurve::Cubic].iter() {
+            let y = clip(curve, 1f64, 0.01);
+            assert!((y - 0.01).abs() < 1e-5, "{:?} gave {}", curve, y);
+        }
+    }
+
+    #[test]
+    fn large_signals_saturate() {
+        for &curve in [ClipCurve::Hard, ClipCurve::Tanh, ClipCurve::Cubic].iter() {
+            let y = clip(curve, 10f64, 0.9);
+            assert!(y <= 1f64 && y > 0.999, "{:?} gave {}", curve, y);
+        }
+    }
+
+    #[test]
+    fn delay_echoes_impulse() {
+        // 100 samples at the default rate, in buffers that don't divide it evenly.
+        let impulse = Impulse::<f64>::new(64);
+        let mut delay = Delay::new(impulse.adapt(), 100f64 / 44100f64, 0.5, 0.5);
+
+        let mut out = vec![];
+        for _ in 0..5 {
+            match delay.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+
+        for (i, &x) in out.iter().enumerate() {
+            let expected = match i {
+                0 => 0.5,
+                100 => 0.5,
+                200 => 0.25,
+                300 => 0.125,
+                _ => 0.0
+            };
+            assert!((x - expected).abs() < 1e-9, "Sample {} was {}", i, x);
+        }
+    }
+
+    #[test]
+    fn unsigned_delay_starts_silent() {
+        // Silence in u8 is the midpoint, not zero.
+        let src = ConstantSource { data: vec![128u8; 64], sbuf: vec![] };
+        let mut delay = Delay::new(src.adapt(), 100f64 / 44100f64, 0.5, 0.5);
+        for _ in 0..5 {
+            match delay.next() {
+                SourceResult::Buffer(b) => assert!(b[0].iter().all(|&x| x == 128), "{:?}", b[0]),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn tremolo_silences_trough() {
+        // With a 1 Hz LFO the trough is three quarters of a second in, at the start of the fourth
+        // buffer.
+        let src = ConstantSource { data: vec![1f64; 11025], sbuf: vec![] };
+        let mut tremolo = Tremolo::new(src.adapt(), 1f64, 1f64);
+        for i in 0..4 {
+            let gain = match tremolo.next() {
+                SourceResult::Buffer(b) => b[0][0],
+                x => panic!("Unexpected result {:?}", x)
+            };
+            match i {
+                0 => assert!((gain - 0.5).abs() < 1e-6, "Gain at start was {}", gain),
+                3 => assert!(gain < 1e-6, "Gain at trough was {}", gain),
+                _ => { }
+            }
+        }
+    }
+
+    #[test]
+    fn vibrato_without_depth_is_transparent() {
+        let mut reference = Tone::<f64, f64>::with_frequency(1000, 440f64, 44100);
+        let tone = Tone::<f64, f64>::with_frequency(1000, 440f64, 44100);
+        let mut vibrato = Vibrato::new(tone.adapt(), 5f64, 0f64);
+
+        for _ in 0..4 {
+            let expected = reference.next().unwrap().to_vec();
+            match vibrato.next() {
+                SourceResult::Buffer(b) => assert_eq!(&b[0][..], &expected[..]),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+}
@@ -1,52 +1,346 @@
+//! Frequency-domain analysis.
+
 use num::Complex;
+use std::f64::consts::PI_2;
+use std::iter;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use super::{Sample, MonoSource, Source, SourceResult, DEFAULT_SAMPLE_RATE};
+use super::frame::{Framer, OverlapAdd};
+
+/// Window functions applied to time-domain samples before transformation.
+///
+/// Windowing reduces spectral leakage for signals that are not periodic in the analysis frame,
+/// at the cost of widening the main lobe of each frequency component.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    /// No windowing; all samples are weighted equally.
+    Rectangular,
+    /// Raised cosine window reaching zero at both ends.
+    Hann,
+    /// Raised cosine window optimized to minimize the nearest side lobe.
+    Hamming,
+    /// Three-term cosine window with low side lobes.
+    Blackman,
+}
 
+impl Window {
+    /// Compute the coefficients of this window for a frame of `n` samples.
+    ///
+    /// The windows are periodic (they are symmetric over `n + 1` samples with the last sample
+    /// dropped), which is appropriate for spectral analysis.
+    pub fn coefficients(&self, n: usize) -> Vec<f64> {
+        (0..n).map(|i| {
+            let x = PI_2 * i as f64 / n as f64;
+            match *self {
+                Window::Rectangular => 1f64,
+                Window::Hann => 0.5 - 0.5 * x.cos(),
+                Window::Hamming => 0.54 - 0.46 * x.cos(),
+                Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2f64 * x).cos(),
+            }
+        }).collect()
+    }
+}
+
+/// Compute the discrete Fourier transform of `input` into `output`.
+///
+/// The transform is unnormalized, as computed by FFTW. Panics if the slices differ in length.
+pub fn forward(input: &[Complex<f64>], output: &mut [Complex<f64>]) {
+    ::fftw3::c2c_1d(input, output, true).unwrap();
+}
+
+/// Compute the inverse discrete Fourier transform of `input` into `output`.
+///
+/// FFTW's inverse transform is unnormalized, so the result is divided by the length of the
+/// transform here. This makes `inverse` exactly undo `forward`, aside from rounding.
+pub fn inverse(input: &[Complex<f64>], output: &mut [Complex<f64>]) {
+    ::fftw3::c2c_1d(input, output, false).unwrap();
+    let n = output.len() as f64;
+    for x in output.iter_mut() {
+        *x = Complex::new(x.re / n, x.im / n);
+    }
+}
+
+/// Computes the spectrum of a stream.
+///
+/// Samples pass through unchanged, so this can sit transparently in a pipeline. Each buffer is
+/// transformed as a single FFT frame and the magnitude of each frequency bin is stored in a
+/// shared vector readable via `get_buckets`, with one bucket per input sample.
+///
+/// Samples are multiplied by a window function before transformation. The window coefficients are
+/// computed for the first buffer and reused for each subsequent buffer of the same length.
+///
+/// `MonoSource`s don't report their sample rate, so it must be set with `set_sample_rate` for
+/// `bin_frequencies` to be accurate. It is assumed to be 44100 Hz otherwise.
 pub struct FrequencyData<S> {
     source: S,
+    window: Window,
+    rate: u32,
+    coefficients: Vec<f64>,
     buckets: Arc<RwLock<Vec<f64>>>,
-    cplx_in: Vec<Complex>,
-    cplx_out: Vec<Complex>
+    cplx_in: Vec<Complex<f64>>,
+    cplx_out: Vec<Complex<f64>>
 }
 
-impl<S> FrequencyData<S> {
-    pub fn new(source: S, nbuckets: usize) -> FrequencyData {
+impl<S: MonoSource> FrequencyData<S> {
+    /// Create a spectrum analyzer over `source`, applying `window` to each frame.
+    pub fn new(source: S, window: Window) -> FrequencyData<S> {
         FrequencyData {
             source: source,
-            buckets: Vec::with_capacity(nbuckets),
+            window: window,
+            rate: DEFAULT_SAMPLE_RATE,
+            coefficients: Vec::new(),
+            buckets: Arc::new(RwLock::new(Vec::new())),
             cplx_in: Vec::new(),
             cplx_out: Vec::new()
         }
     }
 
-    pub fn get_buckets(&self) -> &RwLock {
-        &*self.buckets
+    /// Get a handle to the magnitudes of the most recently analyzed buffer.
+    ///
+    /// Bucket `i` corresponds to the frequency `i * rate / n` for a buffer of `n` samples at
+    /// sample rate `rate`.
+    pub fn get_buckets(&self) -> Arc<RwLock<Vec<f64>>> {
+        self.buckets.clone()
+    }
+
+    /// Set the sample rate of the source, in Hz.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.rate = rate;
+    }
+
+    /// Get the center frequency in Hz of each bucket of the most recently analyzed buffer.
+    ///
+    /// For real input the spectrum is symmetric, so bucket `n - i` mirrors bucket `i`. Only the
+    /// first `n / 2 + 1` buckets (from DC to the Nyquist frequency) are distinct, so only their
+    /// frequencies are returned. This is empty until a buffer has been analyzed.
+    pub fn bin_frequencies(&self) -> Vec<f64> {
+        let n = self.buckets.read().unwrap().len();
+        if n == 0 {
+            return Vec::new();
+        }
+        let step = self.rate as f64 / n as f64;
+        (0..n / 2 + 1).map(|i| i as f64 * step).collect()
     }
 }
 
-impl MonoSource<F> for FrequencyData<S> where S: MonoSource<Output=F> {
+impl<S> MonoSource for FrequencyData<S> where S: MonoSource, S::Output: Sample {
+    type Output = S::Output;
+
     fn next<'a>(&'a mut self) -> Option<&'a mut [S::Output]> {
         let samples = match self.source.next() {
             Some(s) => s,
             None => return None
         };
 
+        if self.coefficients.len() != samples.len() {
+            self.coefficients = self.window.coefficients(samples.len());
+        }
+
         // Input samples convert to complex for fftw
-        self.cplx_in.empty();
-        self.cplx_in.extend(samples.iter().map(|s| Complex::new(s.to_float::<f64>(), 0)));
+        self.cplx_in.clear();
+        self.cplx_in.extend(samples.iter().zip(self.coefficients.iter()).map(|(&s, &w)| {
+            Complex::new(Sample::to_float::<f64>(s) * w, 0f64)
+        }));
 
         // Output samples initially zero
         // TODO we can save some cycles by being uninitialized, which might turn out to
         // be safe in all cases (even if Complex implements Drop).
-        self.cplx_out.empty();
-        self.cplx_out.extend(iter::repeat(Complex::new(0, 0)).taken(samples.len()));
+        self.cplx_out.clear();
+        self.cplx_out.extend(iter::repeat(Complex::new(0f64, 0f64)).take(samples.len()));
 
         // Do the FFT and push into buckets
-        fftw3::c2c_1d(&input[..], &mut output[..], true).unwrap();
+        forward(&self.cplx_in, &mut self.cplx_out);
         {
             let mut buckets = self.buckets.write().unwrap();
-            buckets.empty();
-            buckets.extend(self.cplx_out.iter().map(|e| e.re));
+            buckets.clear();
+            buckets.extend(self.cplx_out.iter().map(|e| e.norm()));
         }
 
         Some(samples)
     }
 }
+
+/// Modifies each buffer of a stream in the frequency domain.
+///
+/// Every channel of each buffer is transformed with `forward` and the spectrum passed to a
+/// function, which may modify it in place. The modified spectrum is transformed back with
+/// `inverse` and its real part replaces the buffer's samples. Buffers are transformed whole, so
+/// this is normally applied to frames from a `Framer` and the result reassembled with
+/// `OverlapAdd`; `stft` builds that pipeline.
+///
+/// Bin `i` of the spectrum corresponds to the frequency `i * rate / n` for a buffer of `n`
+/// samples at sample rate `rate`. For the output to be real, modifications should keep bins `i`
+/// and `n - i` complex conjugates of each other.
+pub struct Spectral<F, S, G> {
+    source: S,
+    f: G,
+    cplx_in: Vec<Complex<f64>>,
+    cplx_out: Vec<Complex<f64>>,
+    format: PhantomData<F>,
+}
+
+impl<F, S, G> Spectral<F, S, G> where
+        F: Sample,
+        S: Source<Output=F>,
+        G: FnMut(&mut [Complex<f64>]) {
+    /// Construct an adapter calling `f` with the spectrum of each channel of every buffer.
+    pub fn new(source: S, f: G) -> Spectral<F, S, G> {
+        Spectral {
+            source: source,
+            f: f,
+            cplx_in: Vec::new(),
+            cplx_out: Vec::new(),
+            format: PhantomData
+        }
+    }
+}
+
+impl<F, S, G> Source for Spectral<F, S, G> where
+        F: Sample,
+        S: Source<Output=F>,
+        G: FnMut(&mut [Complex<f64>]) {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        for channel in buf.iter_mut() {
+            let n = channel.len();
+            self.cplx_in.clear();
+            self.cplx_in.extend(channel.iter().map(|&x| {
+                Complex::new(Sample::to_float::<f64>(x), 0f64)
+            }));
+            self.cplx_out.clear();
+            self.cplx_out.extend(iter::repeat(Complex::new(0f64, 0f64)).take(n));
+
+            forward(&self.cplx_in, &mut self.cplx_out);
+            (self.f)(&mut self.cplx_out);
+            inverse(&self.cplx_out, &mut self.cplx_in);
+
+            for (x, y) in channel.iter_mut().zip(self.cplx_in.iter()) {
+                *x = Sample::from_float(y.re);
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Build a short-time Fourier transform pipeline, modifying `source` in the frequency domain.
+///
+/// The stream is split into frames of `size` samples every `hop` samples with `window` applied,
+/// each frame's spectrum is passed to `f` as in `Spectral`, and the frames are reassembled by
+/// overlap-add. With no modification the output reproduces the input, followed by up to one
+/// frame of silence from padding the end of the stream.
+pub fn stft<F, S, G>(source: S, size: usize, hop: usize, window: Window,
+                     f: G) -> OverlapAdd<F, Spectral<F, Framer<F, S>, G>> where
+        F: Sample,
+        S: Source<Output=F>,
+        G: FnMut(&mut [Complex<f64>]) {
+    let mut framer = Framer::new(source, size, hop);
+    framer.set_window(window);
+    OverlapAdd::new(Spectral::new(framer, f), size, hop, window)
+}
+
+#[test]
+fn tone_peaks_at_expected_bin() {
+    use super::synth::Tone;
+
+    let (n, period) = (1024, 16);
+    let mut fd = FrequencyData::new(Tone::<f64, f64>::new(n, period), Window::Rectangular);
+    let buckets = fd.get_buckets();
+    fd.next().unwrap();
+
+    let buckets = buckets.read().unwrap();
+    assert_eq!(buckets.len(), n);
+    // Only the first half is distinct for real input.
+    let (peak, _) = buckets[..n / 2].iter().enumerate().fold((0, 0f64), |(i, m), (j, &x)| {
+        if x > m { (j, x) } else { (i, m) }
+    });
+    assert_eq!(peak, n / period);
+}
+
+#[test]
+fn hann_window_reduces_leakage() {
+    use super::synth::Tone;
+
+    // Energy outside the bins near an off-bin tone, as a fraction of the total.
+    fn leakage(window: Window) -> f64 {
+        let n = 1024;
+        let mut fd = FrequencyData::new(Tone::<f64, f64>::with_frequency(n, 64.5, n as u32),
+                                        window);
+        let buckets = fd.get_buckets();
+        fd.next().unwrap();
+
+        let buckets = buckets.read().unwrap();
+        let energy = |xs: &[f64]| xs.iter().fold(0f64, |a, &x| a + x * x);
+        let total = energy(&buckets[..n / 2]);
+        let side = energy(&buckets[..60]) + energy(&buckets[70..n / 2]);
+        side / total
+    }
+
+    let rect = leakage(Window::Rectangular);
+    let hann = leakage(Window::Hann);
+    assert!(hann < rect / 10f64, "Hann leakage {} not much less than rectangular {}", hann, rect);
+}
+
+#[test]
+fn bin_frequencies_follow_rate() {
+    use super::synth::Tone;
+
+    let mut fd = FrequencyData::new(Tone::<f64, f64>::new(1024, 16), Window::Rectangular);
+    assert!(fd.bin_frequencies().is_empty());
+    fd.set_sample_rate(44100);
+    fd.next().unwrap();
+
+    let bins = fd.bin_frequencies();
+    assert_eq!(bins.len(), 513);
+    assert!((bins[1] - 43.066).abs() < 1e-3, "Bin 1 is centered at {} Hz", bins[1]);
+    assert_eq!(bins[512], 22050f64);
+}
+
+#[test]
+fn inverse_undoes_forward() {
+    let input: Vec<Complex<f64>> = (0..100).map(|i| {
+        Complex::new((i as f64 * 0.3).sin(), (i as f64 * 0.7).cos())
+    }).collect();
+    let mut spectrum = vec![Complex::new(0f64, 0f64); 100];
+    let mut output = spectrum.clone();
+
+    forward(&input, &mut spectrum);
+    inverse(&spectrum, &mut output);
+    for (x, y) in input.iter().zip(output.iter()) {
+        assert!((*x - *y).norm() < 1e-12, "Expected {:?}, got {:?}", x, y);
+    }
+}
+
+#[test]
+fn unmodified_stft_is_transparent() {
+    use super::BufferSource;
+
+    let n = 3000;
+    let input: Vec<f64> = (0..n).map(|i| (i as f64 * 0.05).sin() * 0.5).collect();
+    let mut src = stft(BufferSource::new(vec![input.clone()], 44100), 256, 128, Window::Hann,
+                       |_| { });
+
+    let mut output = vec![];
+    loop {
+        match src.next() {
+            SourceResult::Buffer(b) => output.extend(b[0].iter().cloned()),
+            SourceResult::SampleRate(_) => { }
+            SourceResult::EndOfStream => break,
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+    // The Hann window is zero at the very first sample, so it can't be recovered.
+    for (i, (x, y)) in input.iter().zip(output.iter()).enumerate().skip(1) {
+        assert!((x - y).abs() < 1e-9, "Sample {} is {}, expected {}", i, y, x);
+    }
+}
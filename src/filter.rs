@@ -0,0 +1,270 @@
+//! Frequency-domain shaping filters.
+
+use std::f64::consts::PI_2;
+use super::{Sample, Source, SourceResult, DEFAULT_SAMPLE_RATE};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Response {
+    Lowpass,
+    Highpass,
+    Bandpass,
+}
+
+/// A second-order IIR filter.
+///
+/// Coefficients are computed as described in Robert Bristow-Johnson's "Cookbook formulae for
+/// audio EQ biquad filter coefficients", and are recomputed whenever the source reports a new
+/// sample rate. Until the first `SampleRate` is seen the stream is assumed to be at 44100 Hz.
+///
+/// Processing is done in `f64`, with filter state carried across buffers for each channel.
+pub struct Biquad<F, S> {
+    source: S,
+    response: Response,
+    cutoff: f64,
+    q: f64,
+    /// Normalized coefficients b0, b1, b2, a1, a2.
+    coeffs: [f64; 5],
+    /// Per-channel delay state x[n-1], x[n-2], y[n-1], y[n-2].
+    state: Vec<[f64; 4]>,
+    format: ::std::marker::PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Biquad<F, S> {
+    fn new(source: S, response: Response, cutoff_hz: f64, q: f64) -> Biquad<F, S> {
+        let mut filter = Biquad {
+            source: source,
+            response: response,
+            cutoff: cutoff_hz,
+            q: q,
+            coeffs: [0f64; 5],
+            state: Vec::new(),
+            format: ::std::marker::PhantomData
+        };
+        filter.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        filter
+    }
+
+    /// Construct a low-pass filter with cutoff frequency `cutoff_hz` and quality factor `q`.
+    ///
+    /// A `q` of `1/sqrt(2)` gives a maximally flat (Butterworth) response.
+    pub fn lowpass(source: S, cutoff_hz: f64, q: f64) -> Biquad<F, S> {
+        Biquad::new(source, Response::Lowpass, cutoff_hz, q)
+    }
+
+    /// Construct a high-pass filter with cutoff frequency `cutoff_hz` and quality factor `q`.
+    pub fn highpass(source: S, cutoff_hz: f64, q: f64) -> Biquad<F, S> {
+        Biquad::new(source, Response::Highpass, cutoff_hz, q)
+    }
+
+    /// Construct a band-pass filter with center frequency `center_hz` and quality factor `q`.
+    ///
+    /// The gain at the center frequency is unity.
+    pub fn bandpass(source: S, center_hz: f64, q: f64) -> Biquad<F, S> {
+        Biquad::new(source, Response::Bandpass, center_hz, q)
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        let w0 = PI_2 * self.cutoff / rate as f64;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / (2f64 * self.q);
+
+        let (b0, b1, b2) = match self.response {
+            Response::Lowpass => ((1f64 - cos) / 2f64, 1f64 - cos, (1f64 - cos) / 2f64),
+            Response::Highpass => ((1f64 + cos) / 2f64, -(1f64 + cos), (1f64 + cos) / 2f64),
+            Response::Bandpass => (alpha, 0f64, -alpha),
+        };
+        let (a0, a1, a2) = (1f64 + alpha, -2f64 * cos, 1f64 - alpha);
+
+        self.coeffs = [b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0];
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Biquad<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        if self.state.len() != buf.len() {
+            self.state.resize(buf.len(), [0f64; 4]);
+        }
+
+        let [b0, b1, b2, a1, a2] = self.coeffs;
+        for (channel, state) in buf.iter_mut().zip(self.state.iter_mut()) {
+            let [mut x1, mut x2, mut y1, mut y2] = *state;
+            for sample in channel.iter_mut() {
+                let x: f64 = Sample::to_float(*sample);
+                let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+                x2 = x1;
+                x1 = x;
+                y2 = y1;
+                y1 = y;
+                *sample = Sample::from_float(y);
+            }
+            *state = [x1, x2, y1, y2];
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Pole radius used by `DcBlock::new`.
+///
+/// This places the -3 dB point near 35 Hz at 44.1 kHz.
+pub const DEFAULT_DC_POLE: f64 = 0.995;
+
+/// Removes DC offset with a one-pole, one-zero high-pass filter.
+///
+/// Computes `y[n] = x[n] - x[n-1] + R * y[n-1]` in `f64` with state carried across buffers for
+/// each channel. `R` is the pole radius, just below 1: values closer to 1 give a lower cutoff but
+/// take longer to settle.
+pub struct DcBlock<F, S> {
+    source: S,
+    pole: f64,
+    /// Cutoff frequency to recompute the pole from when the sample rate changes.
+    cutoff: Option<f64>,
+    /// Per-channel state x[n-1], y[n-1].
+    state: Vec<[f64; 2]>,
+    format: ::std::marker::PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> DcBlock<F, S> {
+    /// Construct a DC blocker with the default pole radius `DEFAULT_DC_POLE`.
+    pub fn new(source: S) -> DcBlock<F, S> {
+        DcBlock::with_pole(source, DEFAULT_DC_POLE)
+    }
+
+    /// Construct a DC blocker with pole radius `pole`, which is not affected by the sample rate.
+    pub fn with_pole(source: S, pole: f64) -> DcBlock<F, S> {
+        DcBlock {
+            source: source,
+            pole: pole,
+            cutoff: None,
+            state: Vec::new(),
+            format: ::std::marker::PhantomData
+        }
+    }
+
+    /// Construct a DC blocker with an approximate cutoff frequency of `cutoff_hz`.
+    ///
+    /// The pole radius is recomputed whenever the source reports a new sample rate.
+    pub fn with_cutoff(source: S, cutoff_hz: f64) -> DcBlock<F, S> {
+        let mut filter = DcBlock::with_pole(source, DEFAULT_DC_POLE);
+        filter.cutoff = Some(cutoff_hz);
+        filter.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        filter
+    }
+
+    fn set_sample_rate(&mut self, rate: u32) {
+        if let Some(cutoff) = self.cutoff {
+            self.pole = (-PI_2 * cutoff / rate as f64).exp();
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for DcBlock<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        if self.state.len() != buf.len() {
+            self.state.resize(buf.len(), [0f64; 2]);
+        }
+
+        let pole = self.pole;
+        for (channel, state) in buf.iter_mut().zip(self.state.iter_mut()) {
+            let [mut x1, mut y1] = *state;
+            for sample in channel.iter_mut() {
+                let x: f64 = Sample::to_float(*sample);
+                let y = x - x1 + pole * y1;
+                x1 = x;
+                y1 = y;
+                *sample = Sample::from_float(y);
+            }
+            *state = [x1, y1];
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Source, SourceResult, MonoSource};
+    use super::super::synth::Tone;
+    use super::super::tests::{ConstantSource, Impulse};
+    use super::{Biquad, DcBlock};
+
+    #[test]
+    fn lowpass_has_unity_dc_gain() {
+        let impulse = Impulse::<f64>::new(1024);
+        let mut filter = Biquad::lowpass(impulse.adapt(), 1000f64, 0.707);
+
+        // DC gain is the sum of the impulse response.
+        let mut gain = 0f64;
+        for _ in 0..64 {
+            if let SourceResult::Buffer(b) = filter.next() {
+                gain += b[0].iter().fold(0f64, |a, &x| a + x);
+            } else {
+                unreachable!();
+            }
+        }
+        assert!((gain - 1f64).abs() < 1e-6, "DC gain was {}", gain);
+    }
+
+    #[test]
+    fn dcblock_removes_offset() {
+        let dc = ConstantSource { data: vec![0.5f64; 1024], sbuf: vec![] };
+        let mut filter = DcBlock::new(dc.adapt());
+
+        let mut last = 1f64;
+        for _ in 0..8 {
+            if let SourceResult::Buffer(b) = filter.next() {
+                last = b[0][1023];
+            } else {
+                unreachable!();
+            }
+        }
+        assert!(last.abs() < 1e-6, "DC output was {}", last);
+    }
+
+    #[test]
+    fn dcblock_passes_midband() {
+        let tone = Tone::<f64, f64>::with_frequency(1024, 1000f64, 44100);
+        let mut filter = DcBlock::with_cutoff(tone.adapt(), 20f64);
+
+        let mut peak = 0f64;
+        for i in 0..16 {
+            if let SourceResult::Buffer(b) = filter.next() {
+                // Skip the initial transient.
+                if i >= 8 {
+                    peak = b[0].iter().fold(peak, |a, &x| a.max(x.abs()));
+                }
+            } else {
+                unreachable!();
+            }
+        }
+        assert!(peak > 0.99 && peak < 1.01, "Peak amplitude was {}", peak);
+    }
+}
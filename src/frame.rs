@@ -0,0 +1,287 @@
+//! Overlapping frames for block processing.
+//!
+//! `Framer` splits a stream into fixed-size frames which overlap by a chosen amount, as used for
+//! short-time Fourier analysis. Frames may be processed (for example in the frequency domain)
+//! then reassembled into a continuous stream with `OverlapAdd`.
+
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, StreamError, channel_buffer, consume};
+use super::fft::Window;
+
+/// Splits a stream into overlapping frames.
+///
+/// Each buffer yielded is one frame of `size` samples per channel, starting `hop` samples after
+/// the start of the previous frame. Samples are carried across the source's buffer boundaries, so
+/// the source may yield buffers of any size. Each frame is multiplied by a window, which is
+/// rectangular (leaving samples unchanged) unless set with `set_window`.
+///
+/// When the source ends, any samples not yet included in a frame are emitted in a final frame
+/// padded with silence. Sample rate changes pass through as soon as they are seen, so may
+/// arrive before frames containing samples from before the change. A change in the number of
+/// channels is a stream error.
+pub struct Framer<F, S> {
+    source: S,
+    size: usize,
+    hop: usize,
+    coefficients: Vec<f64>,
+    /// Samples from the start of the next frame onward, per channel.
+    pending: Vec<Vec<F>>,
+    /// Number of samples at the start of `pending` already included in a frame.
+    covered: usize,
+    ended: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Framer<F, S> {
+    /// Construct a framer yielding frames of `size` samples every `hop` samples.
+    ///
+    /// `hop` must be between 1 and `size`.
+    pub fn new(source: S, size: usize, hop: usize) -> Framer<F, S> {
+        assert!(hop > 0 && hop <= size, "Hop must be between 1 and the frame size");
+        Framer {
+            source: source,
+            size: size,
+            hop: hop,
+            coefficients: Window::Rectangular.coefficients(size),
+            pending: Vec::new(),
+            covered: 0,
+            ended: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Set the window applied to each frame.
+    pub fn set_window(&mut self, window: Window) {
+        self.coefficients = window.coefficients(self.size);
+    }
+
+    fn frame_ready(&self) -> bool {
+        match self.pending.first() {
+            Some(p) if self.ended => p.len() > self.covered,
+            Some(p) => p.len() >= self.size,
+            None => false
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Framer<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        while !self.frame_ready() {
+            if self.ended {
+                return SourceResult::EndOfStream;
+            }
+            let buf = match self.source.next() {
+                SourceResult::Buffer(b) => b,
+                SourceResult::EndOfStream => {
+                    self.ended = true;
+                    continue;
+                }
+                x => return x
+            };
+
+            if self.pending.is_empty() {
+                self.pending = vec![Vec::new(); buf.len()];
+            } else if buf.len() != self.pending.len() {
+                return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                    "Framer got {} channels after {}", buf.len(), self.pending.len())));
+            }
+            for (p, channel) in self.pending.iter_mut().zip(buf.iter()) {
+                p.extend(channel.iter().cloned());
+            }
+        }
+
+        let (size, hop) = (self.size, self.hop);
+        let silence: F = Sample::from_float(0f64);
+        self.channels.resize(self.pending.len(), Vec::new());
+        for (out, pending) in self.channels.iter_mut().zip(self.pending.iter_mut()) {
+            out.clear();
+            out.extend(pending.iter().take(size).zip(self.coefficients.iter()).map(|(&x, &w)| {
+                Sample::from_float(Sample::to_float::<f64>(x) * w)
+            }));
+            out.resize(size, silence);
+
+            let n = ::std::cmp::min(hop, pending.len());
+            consume(pending, n);
+        }
+        self.covered = size - hop;
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Reassembles overlapping frames into a continuous stream.
+///
+/// This is the inverse of `Framer`: each buffer from the source must be one frame of `size`
+/// samples, starting `hop` samples after the previous one. Overlapping frames are summed and the
+/// result divided by the sum of the analysis window over the same frames, so frames produced by a
+/// `Framer` with the same parameters reconstruct the original stream. Samples where the window
+/// sums to zero (such as the very first sample under a Hann window) can't be recovered and are
+/// silent.
+///
+/// One buffer of `hop` samples is yielded for each frame. When the source ends, the remainder of
+/// the last frame is yielded, which includes any padding `Framer` added. Frames of the wrong size
+/// are a stream error.
+pub struct OverlapAdd<F, S> {
+    source: S,
+    hop: usize,
+    coefficients: Vec<f64>,
+    /// Sums of overlapping frames, per channel, from the next sample to be yielded.
+    sums: Vec<Vec<f64>>,
+    /// Sum of window coefficients over the frames in `sums`.
+    weights: Vec<f64>,
+    ended: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> OverlapAdd<F, S> {
+    /// Construct a reconstructor for frames of `size` samples every `hop` samples, which were
+    /// analyzed with `window`.
+    pub fn new(source: S, size: usize, hop: usize, window: Window) -> OverlapAdd<F, S> {
+        assert!(hop > 0 && hop <= size, "Hop must be between 1 and the frame size");
+        OverlapAdd {
+            source: source,
+            hop: hop,
+            coefficients: window.coefficients(size),
+            sums: Vec::new(),
+            weights: Vec::new(),
+            ended: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Move the first `n` reconstructed samples of each channel into the output buffers.
+    fn emit<'a>(&'a mut self, n: usize) -> SourceResult<'a, F> {
+        for (out, sums) in self.channels.iter_mut().zip(self.sums.iter_mut()) {
+            out.clear();
+            out.extend(sums.iter().zip(self.weights.iter()).take(n).map(|(&x, &w)| {
+                Sample::from_float(if w > 1e-9 { x / w } else { 0f64 })
+            }));
+            consume(sums, n);
+        }
+        consume(&mut self.weights, n);
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for OverlapAdd<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.ended {
+            return SourceResult::EndOfStream;
+        }
+        let size = self.coefficients.len();
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::EndOfStream => {
+                self.ended = true;
+                if self.weights.is_empty() {
+                    return SourceResult::EndOfStream;
+                }
+                let n = self.weights.len();
+                return self.emit(n);
+            }
+            x => return x
+        };
+
+        if buf[0].len() != size {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "OverlapAdd expects frames of {} samples, but got {}", size, buf[0].len())));
+        }
+        if self.sums.is_empty() {
+            self.sums = vec![Vec::new(); buf.len()];
+            self.channels = vec![Vec::new(); buf.len()];
+        } else if buf.len() != self.sums.len() {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "OverlapAdd got {} channels after {}", buf.len(), self.sums.len())));
+        }
+
+        for (sums, frame) in self.sums.iter_mut().zip(buf.iter()) {
+            sums.resize(size, 0f64);
+            for (s, &x) in sums.iter_mut().zip(frame.iter()) {
+                *s += Sample::to_float::<f64>(x);
+            }
+        }
+        self.weights.resize(size, 0f64);
+        for (w, &c) in self.weights.iter_mut().zip(self.coefficients.iter()) {
+            *w += c;
+        }
+
+        let hop = self.hop;
+        self.emit(hop)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Source, SourceResult, BufferSource};
+    use super::super::fft::Window;
+    use super::{Framer, OverlapAdd};
+
+    fn ramp(n: usize) -> BufferSource<f64> {
+        let mut src = BufferSource::new(vec![(0..n).map(|i| i as f64 / n as f64).collect()],
+                                        44100);
+        // Not a multiple of the frame size or hop.
+        src.set_chunk(37);
+        src
+    }
+
+    #[test]
+    fn frames_overlap_and_pad() {
+        let mut framer = Framer::new(ramp(11), 4, 2);
+        assert_eq!(framer.next(), SourceResult::SampleRate(44100));
+
+        let mut starts = vec![];
+        loop {
+            match framer.next() {
+                SourceResult::Buffer(b) => {
+                    assert_eq!(b[0].len(), 4);
+                    starts.push((b[0][0] * 11f64).round() as usize);
+                    if starts.len() == 5 {
+                        // Final frame holds the last three samples and padding.
+                        assert_eq!(b[0][3], 0f64);
+                    }
+                }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(starts, vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn rectangular_half_hop_reconstructs() {
+        let n = 1000;
+        let expected: Vec<f64> = (0..n).map(|i| i as f64 / n as f64).collect();
+        let mut src = OverlapAdd::new(Framer::new(ramp(n), 64, 32), 64, 32, Window::Rectangular);
+
+        let mut out = vec![];
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::SampleRate(_) => { }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert!(out.len() >= n);
+        for (i, (x, y)) in out.iter().zip(expected.iter()).enumerate() {
+            assert!((x - y).abs() < 1e-12, "Sample {} is {}, expected {}", i, x, y);
+        }
+        // Anything after the original signal is padding.
+        assert!(out[n..].iter().all(|&x| x == 0f64));
+    }
+}
@@ -0,0 +1,167 @@
+//! Observing streams in passing.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::{Sample, Source, SourceResult};
+
+/// Calls a function with every result from a source before passing it on unchanged.
+///
+/// Constructed with `Source::inspect`. The function sees buffers as well as sample rate changes,
+/// the end of the stream and errors, so it can be used to monitor or log any point in a pipeline.
+/// It only gets a shared reference to each result, so can't modify the data.
+pub struct Inspect<F, S, G> {
+    source: S,
+    f: G,
+    format: PhantomData<F>,
+}
+
+impl<F, S, G> Inspect<F, S, G> where
+        F: Sample,
+        S: Source<Output=F>,
+        G: FnMut(&SourceResult<F>) {
+    /// Call `f` with each result from `source`.
+    pub fn new(source: S, f: G) -> Inspect<F, S, G> {
+        Inspect {
+            source: source,
+            f: f,
+            format: PhantomData
+        }
+    }
+}
+
+impl<F, S, G> Source for Inspect<F, S, G> where
+        F: Sample,
+        S: Source<Output=F>,
+        G: FnMut(&SourceResult<F>) {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let result = self.source.next();
+        (self.f)(&result);
+        result
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Counts the sample frames passing through a point in a stream.
+///
+/// Buffers pass through unchanged, and the number of samples per channel in each is added to a
+/// shared counter which may be read from another thread (for example, to show progress) with
+/// `position`. By default the count runs for the whole stream; with `set_reset_on_rate` it
+/// restarts from zero at each sample rate change, so it can always be converted to seconds at the
+/// current rate. The counter is a `usize`, so on 32-bit targets it wraps after about 27 hours at
+/// 44.1 kHz.
+pub struct Counter<F, S> {
+    source: S,
+    frames: Arc<AtomicUsize>,
+    reset_on_rate: bool,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Counter<F, S> {
+    /// Count frames yielded by `source`.
+    pub fn new(source: S) -> Counter<F, S> {
+        Counter {
+            source: source,
+            frames: Arc::new(AtomicUsize::new(0)),
+            reset_on_rate: false,
+            format: PhantomData
+        }
+    }
+
+    /// Select whether to reset the count to zero when the sample rate changes.
+    pub fn set_reset_on_rate(&mut self, reset: bool) {
+        self.reset_on_rate = reset;
+    }
+
+    /// Get the counter of frames passed so far.
+    pub fn position(&self) -> Arc<AtomicUsize> {
+        self.frames.clone()
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Counter<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        match self.source.next() {
+            SourceResult::Buffer(b) => {
+                self.frames.fetch_add(b[0].len(), Ordering::Relaxed);
+                SourceResult::Buffer(b)
+            }
+            SourceResult::SampleRate(r) => {
+                if self.reset_on_rate {
+                    self.frames.store(0, Ordering::Relaxed);
+                }
+                SourceResult::SampleRate(r)
+            }
+            x => x
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::super::{Source, SourceResult, MonoSource, BufferSource};
+    use super::super::tests::FiniteSource;
+    use super::Counter;
+
+    #[test]
+    fn sees_everything_unchanged() {
+        let mut seen = vec![];
+        let mut out = vec![];
+        {
+            let src = FiniteSource { data: vec![1i16, 2, 3], count: 2, sbuf: vec![] }.adapt()
+                      .chain(FiniteSource { data: vec![4i16], count: 1, sbuf: vec![] }.adapt());
+            let mut src = src.inspect(|r| seen.push(match *r {
+                SourceResult::Buffer(ref b) => Ok(b[0].to_vec()),
+                SourceResult::EndOfStream => Err(()),
+                _ => unreachable!()
+            })).map(|x| x * 10);
+
+            loop {
+                match src.next() {
+                    SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                    SourceResult::EndOfStream => break,
+                    x => panic!("Unexpected result {:?}", x)
+                }
+            }
+        }
+        assert_eq!(seen, vec![Ok(vec![1, 2, 3]), Ok(vec![1, 2, 3]), Ok(vec![4]), Err(())]);
+        assert_eq!(out, vec![10, 20, 30, 10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn counts_frames() {
+        let stream = || {
+            let mut src = BufferSource::new(vec![vec![0i16; 3072]; 2], 44100);
+            src.set_chunk(1024);
+            src
+        };
+        let mut src = Counter::new(stream().chain(stream()));
+        let position = src.position();
+
+        assert_eq!(src.next(), SourceResult::SampleRate(44100));
+        for _ in 0..3 {
+            match src.next() {
+                SourceResult::Buffer(b) => assert_eq!(b[0].len(), 1024),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(position.load(Ordering::Relaxed), 3072);
+
+        // The second stream reports its rate again, restarting the count if requested.
+        src.set_reset_on_rate(true);
+        assert_eq!(src.next(), SourceResult::SampleRate(44100));
+        assert_eq!(position.load(Ordering::Relaxed), 0);
+    }
+}
@@ -9,6 +9,8 @@
 //                                 i32 2, i32 6, i32 3, i32 7>
 
 #[cfg(target_arch = "arm")] use std::cmp;
+#[cfg(target_arch = "x86_64")] use std::cell::Cell;
+use std::fmt;
 use std::ptr;
 #[cfg(target_arch="x86_64")] use super::cpu;
 
@@ -27,8 +29,17 @@ struct i16x8(i16, i16, i16, i16, i16, i16, i16, i16);
 /// 256-bit vector
 struct i16x16(i16, i16, i16, i16, i16, i16, i16, i16,
               i16, i16, i16, i16, i16, i16, i16, i16);
+#[simd]
+#[allow(non_camel_case_types, dead_code)]
+/// 128-bit vector
+struct f32x4(f32, f32, f32, f32);
+#[simd]
+#[allow(non_camel_case_types, dead_code)]
+/// 256-bit vector
+struct f32x8(f32, f32, f32, f32, f32, f32, f32, f32);
 
-fn interleave_arbitrary<T: Copy>(channels: &[&[T]], out: &mut [T]) {
+/// Interleave any number of channels one sample at a time.
+pub fn interleave_arbitrary<T: Copy>(channels: &[&[T]], out: &mut [T]) {
     let width = channels.len();
     for (i, p) in out.iter_mut().enumerate() {
         unsafe {
@@ -37,6 +48,58 @@ fn interleave_arbitrary<T: Copy>(channels: &[&[T]], out: &mut [T]) {
     }
 }
 
+/// Interleave with a kernel compiled at runtime if possible, otherwise with the scalar loop.
+#[cfg(feature = "jit")]
+fn interleave_generic<T: Copy>(channels: &[&[T]], out: &mut [T]) {
+    if !super::jit::interleave(channels, out) {
+        interleave_arbitrary(channels, out);
+    }
+}
+
+/// Interleave with the scalar loop.
+#[cfg(not(feature = "jit"))]
+fn interleave_generic<T: Copy>(channels: &[&[T]], out: &mut [T]) {
+    interleave_arbitrary(channels, out);
+}
+
+/// Reasons a set of channels can't be interleaved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterleaveError {
+    /// There are no channels.
+    NoChannels,
+    /// Channel `channel` has `len` samples, but the first channel has `expected`.
+    ChannelLength {
+        /// Index of the mismatched channel.
+        channel: usize,
+        /// Length of the first channel.
+        expected: usize,
+        /// Length of the mismatched channel.
+        len: usize,
+    },
+    /// The output has `len` samples, but the channels contain `expected` in total.
+    OutputLength {
+        /// Total number of samples in all channels.
+        expected: usize,
+        /// Length of the output.
+        len: usize,
+    },
+}
+
+impl fmt::Display for InterleaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InterleaveError::NoChannels => f.write_str("no channels to interleave"),
+            InterleaveError::ChannelLength { channel, expected, len } => {
+                write!(f, "channel {} has {} samples, but channel 0 has {}",
+                       channel, len, expected)
+            }
+            InterleaveError::OutputLength { expected, len } => {
+                write!(f, "output has room for {} samples, but channels contain {}", len, expected)
+            }
+        }
+    }
+}
+
 /// Types which can be interleaved.
 ///
 /// Interleaving two slices `[a0, a1, a2]` and `[b0, b1, b2]` yields the output slice
@@ -50,7 +113,7 @@ pub trait Interleave : super::Sample {
     /// are uninitialized.
     fn interleave(channels: &[&[Self]], out: &mut [Self]) {
         Interleave::validate(channels, out);
-        interleave_arbitrary(channels, out);
+        interleave_generic(channels, out);
     }
     /// Convenience method to sanity check parameters.
     ///
@@ -67,11 +130,80 @@ pub trait Interleave : super::Sample {
         assert_eq!(len * channels.len(), out.len());
     }
 
+    /// Interleave all channels in `input` into output, if they are consistent.
+    ///
+    /// Returns an error rather than panicking if the channels differ in length or `out` is the
+    /// wrong size, so sinks can stop cleanly when given a malformed buffer. `out` is left
+    /// untouched in that case.
+    fn try_interleave(channels: &[&[Self]], out: &mut [Self]) -> Result<(), InterleaveError> {
+        let len = match channels.first() {
+            Some(c) => c.len(),
+            None => return Err(InterleaveError::NoChannels)
+        };
+        for (i, channel) in channels.iter().enumerate() {
+            if channel.len() != len {
+                return Err(InterleaveError::ChannelLength {
+                    channel: i,
+                    expected: len,
+                    len: channel.len()
+                });
+            }
+        }
+        if len * channels.len() != out.len() {
+            return Err(InterleaveError::OutputLength {
+                expected: len * channels.len(),
+                len: out.len()
+            });
+        }
+
+        Interleave::interleave(channels, out);
+        Ok(())
+    }
+
+}
+
+/// Most channels `interleave_into` can reborrow without allocating.
+const STACK_CHANNELS: usize = 8;
+
+/// Interleave the channels of a source's buffer into `out`, replacing its contents.
+///
+/// Buffers from a `Source` hold mutable channel slices, so each is reborrowed as a shared slice
+/// to interleave them. Up to `STACK_CHANNELS` reborrows are kept on the stack, so common layouts
+/// don't allocate. On error `out` is left empty.
+pub fn interleave_into<F: Interleave>(channels: &[&mut [F]],
+                                      out: &mut Vec<F>) -> Result<(), InterleaveError> {
+    if channels.len() <= STACK_CHANNELS {
+        let mut views: [&[F]; STACK_CHANNELS] = [&[]; STACK_CHANNELS];
+        for (view, channel) in views.iter_mut().zip(channels.iter()) {
+            *view = &channel[..];
+        }
+        interleave_views(&views[..channels.len()], out)
+    } else {
+        let views: Vec<&[F]> = channels.iter().map(|c| &c[..]).collect();
+        interleave_views(&views, out)
+    }
+}
+
+fn interleave_views<F: Interleave>(views: &[&[F]],
+                                   out: &mut Vec<F>) -> Result<(), InterleaveError> {
+    let len = views.first().map_or(0, |c| c.len() * views.len());
+    out.clear();
+    out.reserve(len);
+    let result = unsafe {
+        // Interleaving writes every element without reading or dropping the old values.
+        out.set_len(len);
+        Interleave::try_interleave(views, out)
+    };
+    if result.is_err() {
+        out.clear();
+    }
+    result
 }
 
 #[cfg(target_arch = "x86_64")]
-static FEATURES: [cpu::Feature; 2] = [
+static FEATURES: [cpu::Feature; 3] = [
     cpu::AVX,
+    cpu::SSE2,
     cpu::Baseline
 ];
 #[cfg(target_arch = "x86_64")]
@@ -90,20 +222,50 @@ lazy_static!(
     static ref CPU_BEST_FEATURE: cpu::Feature = prioritize_features();
 );
 
+#[cfg(target_arch = "x86_64")]
+thread_local!(static FORCED_FEATURE: Cell<Option<cpu::Feature>> = Cell::new(None));
+
+/// Force the implementation used to interleave `i16` and `f32` samples on this thread.
+///
+/// With `Some(feature)` the implementation for that feature is used (falling back to the scalar
+/// version for formats with no implementation for it), regardless of what the CPU supports best.
+/// `None` restores automatic selection. Unlike `CPU_FEATURES_OVERRIDE` this affects nothing else,
+/// and since it's per-thread, tests comparing implementations can run concurrently.
+///
+/// Panics if the CPU doesn't support `feature`.
+#[cfg(target_arch = "x86_64")]
+pub fn force_path(feature: Option<cpu::Feature>) {
+    if let Some(f) = feature {
+        assert!(cpu::cpu_supports(f), "Cannot force interleave path {:?} on this CPU", f);
+    }
+    FORCED_FEATURE.with(|forced| forced.set(feature));
+}
+
+/// The feature whose implementation should be used for interleaving on this thread.
+#[cfg(target_arch = "x86_64")]
+fn selected_feature() -> cpu::Feature {
+    FORCED_FEATURE.with(|forced| forced.get()).unwrap_or(*CPU_BEST_FEATURE)
+}
+
 impl Interleave for i16 {
     #[cfg(target_arch = "x86_64")]
     fn interleave(channels: &[&[i16]], out: &mut [i16]) {
         Interleave::validate(channels, out);
 
-        match (*CPU_BEST_FEATURE, channels) {
+        match (selected_feature(), channels) {
             (cpu::AVX, [left, right]) => {
                 // No particular alignment restrictions here
                 unsafe {
                     i16x2_fast_avx(left, right, out);
                 }
             }
+            (cpu::SSE2, [left, right]) => {
+                unsafe {
+                    i16x2_fast_sse2(left, right, out);
+                }
+            }
             (_, channels) => {
-                interleave_arbitrary(channels, out)
+                interleave_generic(channels, out)
             }
         }
     }
@@ -131,16 +293,36 @@ impl Interleave for i16 {
                 }
             }
             channels => {
-                interleave_arbitrary(channels, out);
+                interleave_generic(channels, out);
             }
         }
     }
 }
 
 impl Interleave for i8 { }
+impl Interleave for u8 { }
 // i16 optimized
 impl Interleave for i32 { }
-impl Interleave for f32 { }
+impl Interleave for super::I24 { }
+
+impl Interleave for f32 {
+    #[cfg(target_arch = "x86_64")]
+    fn interleave(channels: &[&[f32]], out: &mut [f32]) {
+        Interleave::validate(channels, out);
+
+        match (selected_feature(), channels) {
+            (cpu::AVX, [left, right]) => {
+                unsafe {
+                    f32x2_fast_avx(left, right, out);
+                }
+            }
+            (_, channels) => {
+                interleave_generic(channels, out)
+            }
+        }
+    }
+}
+
 impl Interleave for f64 { }
 
 #[cfg(target_arch = "x86_64")]
@@ -176,6 +358,69 @@ unsafe fn i16x2_fast_avx(xs: &[i16], ys: &[i16], zs: &mut [i16]) {
                          &mut zs[2 * (n & !7)..]);
 }
 
+#[cfg(target_arch = "x86_64")]
+unsafe fn i16x2_fast_sse2(xs: &[i16], ys: &[i16], zs: &mut [i16]) {
+    let n = xs.len();
+    let a = xs.as_ptr();
+    let b = ys.as_ptr();
+    let out = zs.as_mut_ptr();
+
+    // Take vectors 8 samples at a time from each channel
+    for i in 0..n/8 {
+        let left: *const i16x8 = (a as *const i16x8).offset(i as isize);
+        let right: *const i16x8 = (b as *const i16x8).offset(i as isize);
+        let mixed: *mut i16x16 = (out as *mut i16x16).offset(i as isize);
+
+        asm!{
+            "movdqu ($0), %xmm0
+             movdqu ($1), %xmm1
+             movdqa %xmm0, %xmm2
+             punpcklwd %xmm1, %xmm0
+             punpckhwd %xmm1, %xmm2
+             movdqu %xmm0, ($2)
+             movdqu %xmm2, 16($2)"
+            :                                   // Output
+            : "r"(left), "r"(right), "r"(mixed) // Input
+            : "{xmm0}", "{xmm1}", "{xmm2}"      // Clobbers
+        };
+    }
+
+    // Non-multiple of 8 tail
+    interleave_arbitrary(&[&xs[n & !7..], &ys[n & !7..]],
+                         &mut zs[2 * (n & !7)..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn f32x2_fast_avx(xs: &[f32], ys: &[f32], zs: &mut [f32]) {
+    let n = xs.len();
+    let a = xs.as_ptr();
+    let b = ys.as_ptr();
+    let out = zs.as_mut_ptr();
+
+    // Take vectors 4 samples at a time from each channel
+    for i in 0..n/4 {
+        let left: *const f32x4 = (a as *const f32x4).offset(i as isize);
+        let right: *const f32x4 = (b as *const f32x4).offset(i as isize);
+        let mixed: *mut f32x8 = (out as *mut f32x8).offset(i as isize);
+
+        asm!{
+            "vmovups ($0), %xmm0
+             vmovups ($1), %xmm1
+             vunpckhps %xmm1, %xmm0, %xmm2
+             vunpcklps %xmm1, %xmm0, %xmm0
+             vinsertf128 $$1, %xmm2, %ymm0, %ymm0
+             vmovups %ymm0, ($2)"
+            :                                   // Output
+            : "r"(left), "r"(right), "r"(mixed) // Input
+            : "{ymm0}", "{xmm1}", "{xmm2}"      // Clobbers
+        };
+    }
+
+    // Non-multiple of 4 tail
+    interleave_arbitrary(&[&xs[n & !3..], &ys[n & !3..]],
+                         &mut zs[2 * (n & !3)..]);
+}
+
 #[cfg(all(target_arch = "arm", arm_vector = "neon"))]
 fn i16x2_fast_arm(xs: &[i16], ys: &[i16], zs: &mut [i16]) {
     let n = xs.len();
@@ -226,7 +471,7 @@ fn i16x2_fast_arm(xs: &[i16], ys: &[i16], zs: &mut [i16]) {
 mod test {
     extern crate test;
     use self::test::Bencher;
-    use super::Interleave;
+    use super::{Interleave, InterleaveError, interleave_into, STACK_CHANNELS};
 
     #[test]
     fn test_interleave_2x2x1024() {
@@ -247,6 +492,52 @@ mod test {
         }
     }
 
+    #[test]
+    fn try_interleave_rejects_mismatches() {
+        let a = [1i16, 2, 3];
+        let b = [4i16, 5];
+        let mut out = [0i16; 6];
+
+        assert_eq!(Interleave::try_interleave(&[&a, &b], &mut out),
+                   Err(InterleaveError::ChannelLength { channel: 1, expected: 3, len: 2 }));
+        assert_eq!(Interleave::try_interleave(&[&a, &a], &mut out[..5]),
+                   Err(InterleaveError::OutputLength { expected: 6, len: 5 }));
+        assert_eq!(Interleave::try_interleave(&[], &mut out[..0]),
+                   Err(InterleaveError::NoChannels));
+        // Nothing was written.
+        assert_eq!(out, [0i16; 6]);
+
+        assert_eq!(Interleave::try_interleave(&[&a, &a], &mut out), Ok(()));
+        assert_eq!(out, [1, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn interleave_into_any_channel_count() {
+        // Either side of the limit on channels reborrowed on the stack.
+        for &n in [2, STACK_CHANNELS, STACK_CHANNELS + 2].iter() {
+            let mut data: Vec<Vec<i16>> = (0..n).map(|c| vec![c as i16, (c + n) as i16]).collect();
+            let channels: Vec<&mut [i16]> = data.iter_mut().map(|c| &mut c[..]).collect();
+            let mut out = vec![-1i16; 3];
+            assert_eq!(interleave_into(&channels, &mut out), Ok(()));
+            assert_eq!(out, (0..2 * n as i16).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn interleave_into_f64() {
+        // f64 has no specialized kernel, so this covers the generic path on both sides of the
+        // limit.
+        for &n in [3, STACK_CHANNELS + 1].iter() {
+            let mut data: Vec<Vec<f64>> = (0..n).map(|c| {
+                (0..5).map(|i| (i * n + c) as f64 * 0.5).collect()
+            }).collect();
+            let channels: Vec<&mut [f64]> = data.iter_mut().map(|c| &mut c[..]).collect();
+            let mut out = vec![];
+            assert_eq!(interleave_into(&channels, &mut out), Ok(()));
+            assert_eq!(out, (0..5 * n).map(|i| i as f64 * 0.5).collect::<Vec<_>>());
+        }
+    }
+
     #[bench]
     fn bench_interleave_2x2(bencher: &mut Bencher) {
         let mut a = [0i16; 2048];
@@ -262,4 +553,146 @@ mod test {
         bencher.iter(|| Interleave::interleave(&[&mut a, &mut b], &mut i));
         bencher.bytes = 4096;
     }
+
+    #[test]
+    fn test_interleave_f32_2x1027() {
+        // Odd length exercises the tail.
+        let mut a = [0f32; 1027];
+        let mut b = [0f32; 1027];
+        for (i, (p, q)) in a.iter_mut().zip(b.iter_mut()).enumerate() {
+            *p = i as f32;
+            *q = -(i as f32);
+        }
+
+        let mut i = [0f32; 2054];
+        Interleave::interleave(&[&a, &b], &mut i);
+
+        for idx in 0 .. i.len() / 2 {
+            assert_eq!(i[idx * 2], idx as f32);
+            assert_eq!(i[idx * 2 + 1], -(idx as f32));
+        }
+    }
+
+    #[bench]
+    fn bench_interleave_f32_2x2(bencher: &mut Bencher) {
+        let mut a = [0f32; 2048];
+        for (i, p) in a.iter_mut().enumerate() {
+            *p = i as f32;
+        }
+        let mut b = a;
+
+        let mut i = unsafe {
+            ::std::mem::uninitialized::<[f32; 4096]>()
+        };
+
+        bencher.iter(|| Interleave::interleave(&[&mut a, &mut b], &mut i));
+        bencher.bytes = 4096 * 4;
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn sse2_matches_scalar() {
+        use super::super::cpu;
+
+        // Dispatch never picks SSE2 on a CPU with AVX, so force it to make sure it's always
+        // tested. Every x86-64 CPU has SSE2.
+        let mut a = [0i16; 1029];
+        let mut b = [0i16; 1029];
+        for (i, (p, q)) in a.iter_mut().zip(b.iter_mut()).enumerate() {
+            *p = i as i16;
+            *q = !(i as i16);
+        }
+
+        let mut scalar = [0i16; 2058];
+        super::interleave_arbitrary(&[&a, &b], &mut scalar);
+
+        super::force_path(Some(cpu::SSE2));
+        assert_eq!(super::selected_feature(), cpu::SSE2);
+        let mut fast = vec![];
+        let result = interleave_into(&[&mut a[..], &mut b[..]], &mut fast);
+        super::force_path(None);
+
+        assert_eq!(result, Ok(()));
+        assert!(&fast[..] == &scalar[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn jit_matches_scalar() {
+        for &n in [2usize, 3, 6, 8].iter() {
+            // Odd length exercises the tail.
+            let data: Vec<Vec<i16>> = (0..n).map(|c| {
+                (0..1029).map(|i| (i * n + c) as i16).collect()
+            }).collect();
+            let channels: Vec<&[i16]> = data.iter().map(|c| &c[..]).collect();
+
+            let mut jit = vec![0i16; 1029 * n];
+            let mut scalar = vec![0i16; 1029 * n];
+            assert!(super::super::jit::interleave(&channels, &mut jit));
+            super::interleave_arbitrary(&channels, &mut scalar);
+            assert!(jit == scalar, "JIT disagrees with scalar for {} channels", n);
+        }
+
+        // LLVM can't align three-byte samples, so they're left to the scalar loop.
+        let data = vec![vec![super::super::I24::new(1); 16]; 2];
+        let channels: Vec<&[super::super::I24]> = data.iter().map(|c| &c[..]).collect();
+        let mut out = vec![super::super::I24::new(0); 32];
+        assert!(!super::super::jit::interleave(&channels, &mut out));
+    }
+
+    #[bench]
+    #[cfg(feature = "jit")]
+    fn bench_interleave_jit_2x2(bencher: &mut Bencher) {
+        let mut a = [0i16; 2048];
+        for (i, p) in a.iter_mut().enumerate() {
+            *p = i as i16;
+        }
+        let b = a;
+
+        let mut i = unsafe {
+            ::std::mem::uninitialized::<[i16; 4096]>()
+        };
+
+        bencher.iter(|| super::super::jit::interleave(&[&a, &b], &mut i));
+        bencher.bytes = 4096;
+    }
+
+    #[bench]
+    fn bench_interleave_8x2(bencher: &mut Bencher) {
+        let data: Vec<Vec<i16>> = (0..8).map(|c| (0..2048).map(|i| (i + c) as i16).collect())
+                                        .collect();
+        let channels: Vec<&[i16]> = data.iter().map(|c| &c[..]).collect();
+        let mut i = vec![0i16; 2048 * 8];
+
+        bencher.iter(|| Interleave::interleave(&channels, &mut i));
+        bencher.bytes = 2048 * 8 * 2;
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn forced_paths_agree() {
+        use super::super::cpu;
+
+        let mut a = [0i16; 1029];
+        let mut b = [0i16; 1029];
+        for (i, (p, q)) in a.iter_mut().zip(b.iter_mut()).enumerate() {
+            *p = i as i16;
+            *q = !(i as i16);
+        }
+
+        super::force_path(Some(cpu::Baseline));
+        let mut scalar = [0i16; 2058];
+        Interleave::interleave(&[&a, &b], &mut scalar);
+
+        for &feature in [cpu::SSE2, cpu::AVX].iter() {
+            if !cpu::cpu_supports(feature) {
+                continue;
+            }
+            super::force_path(Some(feature));
+            let mut fast = [0i16; 2058];
+            Interleave::interleave(&[&a, &b], &mut fast);
+            assert!(&fast[..] == &scalar[..], "{:?} path disagrees with scalar", feature);
+        }
+        super::force_path(None);
+    }
 }
@@ -0,0 +1,142 @@
+//! Interleaving with kernels compiled at runtime.
+//!
+//! With the `jit` feature, channel counts and formats without a hand-written kernel are
+//! interleaved by code generated with `interleave_jit` for the exact number of channels and
+//! sample width. Each kernel takes `BLOCK` frames copied channel-major into a staging buffer,
+//! loads them as one vector and interleaves them with a single `shufflevector`, so LLVM can pick
+//! the best shuffles for the host CPU.
+//!
+//! The staging copy is overhead the hand-written AVX and SSE2 stereo kernels don't have, so they
+//! are still preferred where they apply. The JIT is meant for other channel counts, where the
+//! only alternative is the scalar loop.
+//!
+//! None of this has been measured in-tree: how the JIT compares with the scalar loop, and
+//! whether it ever beats the AVX or SSE2 kernels, is unknown. To find out on a given machine,
+//! run `bench_interleave_2x2` with `CPU_FEATURES_OVERRIDE` set to force AVX and then SSE2, and
+//! compare each with `bench_interleave_jit_2x2`; `bench_interleave_8x2` covers a channel count
+//! with no hand-written kernel.
+//!
+//! Kernels are compiled the first time they're needed on each thread and cached for the life of
+//! the thread. Interleaving only moves samples, so formats of the same width share a kernel.
+//! Only widths of 1, 2, 4 and 8 bytes are compiled; others, like `I24`, use the scalar loop.
+
+extern crate interleave_jit;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use self::interleave_jit::{Context, Module, Builder, Position, ExecutionEngine};
+use super::interleave::interleave_arbitrary;
+
+/// Frames interleaved by each call to a kernel.
+const BLOCK: usize = 8;
+
+/// A kernel's signature: the staging buffer, then the output.
+type KernelFn = extern "C" fn(*const u8, *mut u8);
+
+struct Kernel {
+    function: KernelFn,
+    /// Owns the generated code.
+    _engine: ExecutionEngine<'static>,
+    /// The engine refers to its context, so this must be dropped after it.
+    _context: Box<Context>,
+}
+
+// Kernels by sample width in bytes and channel count, or `None` if compilation failed.
+thread_local!(
+    static KERNELS: RefCell<HashMap<(usize, usize), Option<Kernel>>> =
+        RefCell::new(HashMap::new())
+);
+
+/// Generate a kernel interleaving `channels` channels of `width`-byte samples.
+fn compile(width: usize, channels: usize) -> Option<Kernel> {
+    // The context is kept with the engine in the kernel, and outlives it.
+    let context = Box::new(Context::new());
+    let ctxt: &'static Context = unsafe {
+        mem::transmute(&*context)
+    };
+    let module = Module::in_context(ctxt, "interleave");
+    let name = format!("interleave_i{}x{}", width * 8, channels);
+    {
+        let mut builder = Builder::in_context(ctxt);
+
+        // void kernel(<BLOCK*channels x iN>*, <BLOCK*channels x iN>*);
+        let lanes = BLOCK * channels;
+        let vector = ctxt.vector_type(ctxt.int_type(width as u32 * 8), lanes as u32);
+        let pointer = ctxt.pointer_type(vector);
+        let function_type = ctxt.function_type(ctxt.void_type(), &[pointer, pointer], false);
+        let func = module.add_function(&name[..], function_type);
+        builder.position(Position::EndOf(ctxt.append_bb(func, "entry")));
+
+        // Neither buffer is any more aligned than a single sample.
+        let params = func.function_params().collect::<Vec<_>>();
+        let staged = builder.build_load(params[0]);
+        staged.set_alignment(width as u32);
+        // Output lane `f * channels + c` is frame `f` of channel `c`.
+        let mask: Vec<u32> = (0..lanes).map(|i| {
+            ((i % channels) * BLOCK + i / channels) as u32
+        }).collect();
+        let interleaved = builder.build_shufflevector(staged, staged, &mask);
+        builder.build_store(interleaved, params[1]).set_alignment(width as u32);
+        builder.build_ret_void();
+    }
+
+    let engine = ExecutionEngine::new(module);
+    let function = unsafe {
+        engine.get_function_typed::<KernelFn, _>(&name[..])
+    };
+    match function {
+        Some(f) => Some(Kernel { function: f, _engine: engine, _context: context }),
+        // The engine is dropped before the context it was declared after.
+        None => None
+    }
+}
+
+/// Interleave `channels` into `out` with a generated kernel.
+///
+/// Parameters must already be validated. Returns false without touching `out` if there's no
+/// kernel for this format, in which case the caller must interleave some other way.
+pub fn interleave<T: Copy>(channels: &[&[T]], out: &mut [T]) -> bool {
+    let n = channels.len();
+    let width = mem::size_of::<T>();
+    // LLVM requires alignments to be powers of two, and kernels are aligned to the sample width.
+    if n < 2 || !(width == 1 || width == 2 || width == 4 || width == 8) {
+        return false;
+    }
+    let key = (width, n);
+    let function = KERNELS.with(|kernels| {
+        let mut kernels = kernels.borrow_mut();
+        if !kernels.contains_key(&key) {
+            let kernel = compile(key.0, n);
+            if kernel.is_none() {
+                warn!("Failed to compile interleave kernel for {} channels of {} bytes",
+                      n, key.0);
+            }
+            kernels.insert(key, kernel);
+        }
+        kernels[&key].as_ref().map(|k| k.function)
+    });
+    let function = match function {
+        Some(f) => f,
+        None => return false
+    };
+
+    let blocks = channels[0].len() / BLOCK;
+    if blocks > 0 {
+        let mut staging = vec![channels[0][0]; BLOCK * n];
+        for i in 0..blocks {
+            for (stage, channel) in staging.chunks_mut(BLOCK).zip(channels.iter()) {
+                for (s, &x) in stage.iter_mut().zip(channel[i * BLOCK..].iter()) {
+                    *s = x;
+                }
+            }
+            function(staging.as_ptr() as *const u8,
+                     out[i * BLOCK * n..].as_mut_ptr() as *mut u8);
+        }
+    }
+
+    // Non-multiple of BLOCK tail
+    let tails: Vec<&[T]> = channels.iter().map(|c| &c[blocks * BLOCK..]).collect();
+    interleave_arbitrary(&tails, &mut out[blocks * BLOCK * n..]);
+    true
+}
@@ -62,27 +62,78 @@ extern crate fftw3;
 extern crate num;
 extern crate rand;
 
-use num::{NumCast, Float, FromPrimitive};
+use num::{Num, NumCast, Float, FromPrimitive, ToPrimitive, One};
+use rand::{Rng, XorShiftRng};
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
 use std::marker::PhantomData;
 use std::mem;
+use std::num::wrapping::OverflowingOps;
 use std::ops::{Add, Mul, Div};
-use std::raw;
-use std::raw::Repr;
+use std::raw::{Repr, Slice};
+use std::rc::Rc;
 use std::slice::mut_ref_slice;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
+pub mod adapters;
 #[cfg(feature = "ao")] pub mod ao;
+#[cfg(target_arch = "x86_64")] pub mod cpu;
+pub mod dynamics;
+pub mod effect;
 pub mod fft;
+pub mod filter;
+pub mod frame;
+pub mod inspect;
+pub mod meter;
+pub mod mixer;
+pub mod normalize;
+pub mod pace;
+pub mod probe;
+pub mod raw;
+pub mod resample;
+pub mod reverb;
+pub mod ringbuf;
+pub mod stereo;
 pub mod synth;
+pub mod trim;
 #[cfg(feature = "vorbisfile")] pub mod vorbis;
+pub mod wav;
 
+mod convert;
 mod interleave;
-#[cfg(target_arch = "x86_64")] mod cpu;
+#[cfg(feature = "jit")] mod jit;
+
+#[cfg(target_arch = "x86_64")] pub use interleave::force_path as force_interleave_path;
+
+/// Handling of overflow when mixing samples in hard-clipped formats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixMode {
+    /// Clip to the nearest representable value.
+    Saturate,
+    /// Wrap around, as in two's complement arithmetic.
+    ///
+    /// This allows lossless accumulation where the result will be scaled back into range later.
+    Wrap,
+    /// Panic.
+    Panic,
+}
 
 /// Type bound for sample formats.
 pub trait Sample : Add<Self> + Mul<Self> + Div<Self> + OverflowingOps
                  + NumCast + FromPrimitive + ::std::fmt::Debug
-                 + Copy + Send {
+                 + Copy + Send + Any {
+
+    /// A wider format for accumulating sums of many samples.
+    ///
+    /// For hard-clipped formats this has enough headroom that summing many full-scale samples
+    /// can't overflow, so they can be summed and scaled back into range without clipping partway.
+    type Wide: Num + NumCast + PartialOrd + Copy;
 
     /// Maximum value of a valid sample.
     fn max() -> Self;
@@ -97,29 +148,61 @@ pub trait Sample : Add<Self> + Mul<Self> + Div<Self> + OverflowingOps
     fn clip(&self) -> Self;
 
     /// Add two samples together, clipping if necessary (in hard-clipped formats).
+    ///
+    /// This is `mix_with` in `MixMode::Saturate`.
     fn mix(&self, other: &Self) -> Self {
+        self.mix_with(other, MixMode::Saturate)
+    }
+
+    /// Add two samples together, handling overflow in hard-clipped formats as specified by `mode`.
+    ///
+    /// Soft-clipped formats can't overflow, so `mode` has no effect on them.
+    fn mix_with(&self, other: &Self, mode: MixMode) -> Self {
         if !self.clips_hard() {
             return self + other;
         }
 
         let (overflowed, result) = self.overflowing_add(other);
         if !overflowed {
-            result
-        } else {
+            return result;
+        }
+        match mode {
             // Overflow can only occur if both values have the same sign, so
             // examining the sign of `self` only is correct.
-            if self.is_positive() {
+            MixMode::Saturate => if self.is_positive() {
                 self.max()
             } else {
                 self.min()
+            },
+            MixMode::Wrap => result,
+            MixMode::Panic => panic!("Overflow mixing samples {:?} and {:?}", self, other)
+        }
+    }
+
+    /// Convert a sample to the wider format, without scaling.
+    fn widen(self) -> Self::Wide {
+        NumCast::from(self).unwrap()
+    }
+
+    /// Convert a sample back from the wider format, clipping if necessary (in hard-clipped
+    /// formats).
+    fn narrow(x: Self::Wide) -> Self {
+        if <Self as Sample>::clips_hard() {
+            let (min, max): (Self, Self) = (Sample::min(), Sample::max());
+            if x < min.widen() {
+                return min;
+            } else if x > max.widen() {
+                return max;
             }
         }
+        NumCast::from(x).unwrap()
     }
 
     /// Get a floating-point representation of a sample.
     ///
     /// Full-scale output is in the range -1 to 1. Soft-clipped types may
-    /// yield values outside this range.
+    /// yield values outside this range. Integer formats are scaled by their
+    /// largest value, so their most negative value is slightly below -1.
     fn to_float<F: Float + Sample>(x: Self) -> F {
         let f: F = NumCast::from(x).unwrap();
         let self_max: Self = Sample::max();
@@ -144,6 +227,60 @@ pub trait Sample : Add<Self> + Mul<Self> + Div<Self> + OverflowingOps
         out
     }
 
+    /// Convert a floating-point sample to any other format with triangular dither.
+    ///
+    /// When converting to a hard-clipped format, noise spanning ±1 step of the output format is
+    /// added and the result rounded to the nearest step. This decorrelates the quantization error
+    /// from the signal, turning distortion of quiet material into a constant low noise floor.
+    /// Soft-clipped formats aren't quantized meaningfully, so for them this is the same as
+    /// `from_float`.
+    fn from_float_dithered<F: Float + Sample, R: Rng>(x: F, rng: &mut R) -> Self {
+        if !<Self as Sample>::clips_hard() {
+            return Sample::from_float(x);
+        }
+
+        // One step of this format as a float.
+        let zero: Self = NumCast::from(0).unwrap();
+        let one: Self = NumCast::from(1).unwrap();
+        let lsb = Sample::to_float::<F>(one) - Sample::to_float::<F>(zero);
+        // The difference of two uniform variables has a triangular distribution.
+        let tpdf: F = NumCast::from(rng.gen::<f64>() - rng.gen::<f64>()).unwrap();
+        let y = x + tpdf * lsb;
+
+        // from_float truncates toward zero, so offset by half a step to round.
+        let (zero, half): (F, F) = (NumCast::from(0).unwrap(), NumCast::from(0.5).unwrap());
+        let offset = if y < zero { -half * lsb } else { half * lsb };
+        Sample::from_float(y + offset)
+    }
+
+    /// Convert a slice of samples to floating-point, as if by `to_float` on each.
+    ///
+    /// This is faster than converting samples one at a time for some formats (currently `i16` to
+    /// `f32` on x86_64), and gives the same results. Panics if `src` and `dst` differ in length.
+    fn to_float_slice<F: Float + Sample>(src: &[Self], dst: &mut [F]) {
+        assert_eq!(src.len(), dst.len());
+        if convert::to_float_fast(src, dst) {
+            return;
+        }
+        for (x, y) in src.iter().zip(dst.iter_mut()) {
+            *y = Sample::to_float(*x);
+        }
+    }
+
+    /// Convert a slice of floating-point samples to this format, as if by `from_float` on each.
+    ///
+    /// As with `to_float_slice`, some formats (currently `f32` to `i16` on x86_64) are converted
+    /// faster than one sample at a time. Panics if `src` and `dst` differ in length.
+    fn from_float_slice<F: Float + Sample>(src: &[F], dst: &mut [Self]) {
+        assert_eq!(src.len(), dst.len());
+        if convert::from_float_fast(src, dst) {
+            return;
+        }
+        for (x, y) in src.iter().zip(dst.iter_mut()) {
+            *y = Sample::from_float(*x);
+        }
+    }
+
     /// Convert from `Self` to an arbitrary other sample format.
     ///
     /// The default intermediate format here is `f64`, capable of losslessly
@@ -152,11 +289,43 @@ pub trait Sample : Add<Self> + Mul<Self> + Div<Self> + OverflowingOps
     fn convert<X: Sample, I: Float + Sample = f64>(a: Self) -> X {
         <X as Sample>::from_float(Sample::to_float::<I>(a))
     }
+
+    /// Write the little-endian encoding of this sample to the start of `out`.
+    ///
+    /// Every format is encoded in `mem::size_of::<Self>()` bytes. Panics if `out` is shorter.
+    fn write_le(&self, out: &mut [u8]);
+    /// Write the big-endian encoding of this sample to the start of `out`.
+    fn write_be(&self, out: &mut [u8]);
+    /// Decode a little-endian sample from the start of `bytes`.
+    fn read_le(bytes: &[u8]) -> Self;
+    /// Decode a big-endian sample from the start of `bytes`.
+    fn read_be(bytes: &[u8]) -> Self;
+
+    /// Write the encoding of this sample in the specified byte order to the start of `out`.
+    fn write_bytes(&self, out: &mut [u8], endianness: Endianness) {
+        if endianness.is_little() {
+            self.write_le(out)
+        } else {
+            self.write_be(out)
+        }
+    }
+
+    /// Decode a sample in the specified byte order from the start of `bytes`.
+    fn read_bytes(bytes: &[u8], endianness: Endianness) -> Self {
+        if endianness.is_little() {
+            Sample::read_le(bytes)
+        } else {
+            Sample::read_be(bytes)
+        }
+    }
 }
 
+// `$bits` is an unsigned integer of the same size as `$t`, used to serialize it.
 macro_rules! sample_impl(
-    ($t:ty, $range:expr, $hard:expr) => (
+    ($t:ty, $bits:ty, $wide:ty, $range:expr, $hard:expr) => (
         impl Sample for $t {
+            type Wide = $wide;
+
             #[inline]
             fn max() -> $t { $range.end }
             #[inline]
@@ -173,25 +342,285 @@ macro_rules! sample_impl(
                     *self
                 }
             }
+
+            fn write_le(&self, out: &mut [u8]) {
+                let x = unsafe { mem::transmute::<$t, $bits>(*self) };
+                for (i, b) in out[..mem::size_of::<$t>()].iter_mut().enumerate() {
+                    *b = (x >> (8 * i)) as u8;
+                }
+            }
+
+            fn write_be(&self, out: &mut [u8]) {
+                let x = unsafe { mem::transmute::<$t, $bits>(*self) };
+                for (i, b) in out[..mem::size_of::<$t>()].iter_mut().rev().enumerate() {
+                    *b = (x >> (8 * i)) as u8;
+                }
+            }
+
+            fn read_le(bytes: &[u8]) -> $t {
+                let mut x: $bits = 0;
+                for (i, &b) in bytes[..mem::size_of::<$t>()].iter().enumerate() {
+                    x |= (b as $bits) << (8 * i);
+                }
+                unsafe { mem::transmute::<$bits, $t>(x) }
+            }
+
+            fn read_be(bytes: &[u8]) -> $t {
+                let mut x: $bits = 0;
+                for (i, &b) in bytes[..mem::size_of::<$t>()].iter().rev().enumerate() {
+                    x |= (b as $bits) << (8 * i);
+                }
+                unsafe { mem::transmute::<$bits, $t>(x) }
+            }
         }
     );
     // Implicitly soft-clipped by specified range
-    ($t:ty, $range:expr) => (
-        sample_impl!($t, $range, false);
+    ($t:ty, $bits:ty, $wide:ty, $range:expr) => (
+        sample_impl!($t, $bits, $wide, $range, false);
     );
     // Implicitly hard-clipped by type's range
-    ($t:ident) => (
-        sample_impl!($t, $t::min_value()
-                      .. $t::max_value(), true);
+    ($t:ident, $bits:ty, $wide:ty) => (
+        sample_impl!($t, $bits, $wide, $t::min_value()
+                                    .. $t::max_value(), true);
     );
 );
-sample_impl!(i8);
-sample_impl!(i16);
-// Conspicuously missing: i24. Probably not a big deal, if we follow ffmpeg's
-// precedent and sign-extend i24 for input.
-sample_impl!(i32);
-sample_impl!(f32, -1.0 .. 1.0);
-sample_impl!(f64, -1.0 .. 1.0);
+sample_impl!(i8, u8, i16);
+sample_impl!(i16, u16, i32);
+sample_impl!(i32, u32, i64);
+sample_impl!(f32, u32, f64, -1.0 .. 1.0);
+sample_impl!(f64, u64, f64, -1.0 .. 1.0);
+
+/// Unsigned 8-bit samples are biased by 128, so 128 is the nominal zero.
+///
+/// This is the format used by 8-bit WAV files and much telephony hardware. Conversions to and from
+/// float remove the bias, so silence is always 0.0.
+impl Sample for u8 {
+    /// Widened samples are unbiased, so silence is 0.
+    type Wide = i16;
+
+    #[inline]
+    fn max() -> u8 { 255 }
+    #[inline]
+    fn min() -> u8 { 0 }
+    #[inline]
+    fn clips_hard() -> bool { true }
+    #[inline]
+    fn clip(&self) -> u8 { *self }
+
+    fn mix_with(&self, other: &u8, mode: MixMode) -> u8 {
+        // Unbias and sum in a wider type, since the default implementation's overflow handling
+        // assumes a signed format.
+        let x = (*self as i16 - 128) + (*other as i16 - 128);
+        if x >= -128 && x <= 127 {
+            return (x + 128) as u8;
+        }
+        match mode {
+            MixMode::Saturate => if x > 127 { 255 } else { 0 },
+            // Truncation wraps modulo 256.
+            MixMode::Wrap => (x + 128) as u8,
+            MixMode::Panic => panic!("Overflow mixing samples {} and {}", self, other)
+        }
+    }
+
+    fn widen(self) -> i16 {
+        self as i16 - 128
+    }
+
+    fn narrow(x: i16) -> u8 {
+        (if x < -128 { -128 } else if x > 127 { 127 } else { x } + 128) as u8
+    }
+
+    /// Like the signed formats, this is scaled by the largest value, so 255 is 1 and 0 is slightly
+    /// below -1 (-128/127).
+    fn to_float<F: Float + Sample>(x: u8) -> F {
+        let f: F = NumCast::from(x as i16 - 128).unwrap();
+        f / NumCast::from(127).unwrap()
+    }
+
+    fn from_float<F: Float + Sample>(x: F) -> u8 {
+        let scaled = (x.clip() * NumCast::from(127).unwrap()).round();
+        let unbiased: i16 = NumCast::from(scaled).unwrap();
+        (unbiased + 128) as u8
+    }
+
+    fn from_float_dithered<F: Float + Sample, R: Rng>(x: F, rng: &mut R) -> u8 {
+        // from_float already rounds, so the dither alone is needed.
+        let tpdf: F = NumCast::from((rng.gen::<f64>() - rng.gen::<f64>()) / 127f64).unwrap();
+        Sample::from_float(x + tpdf)
+    }
+
+    fn write_le(&self, out: &mut [u8]) { out[0] = *self }
+    fn write_be(&self, out: &mut [u8]) { out[0] = *self }
+    fn read_le(bytes: &[u8]) -> u8 { bytes[0] }
+    fn read_be(bytes: &[u8]) -> u8 { bytes[0] }
+}
+
+/// A packed 24-bit signed sample.
+///
+/// Values are stored as three little-endian bytes so buffers of `I24` take three quarters of the
+/// space of the equivalent `i32` buffers. Arithmetic sign-extends to `i32` and wraps back into 24
+/// bits, like the primitive integer types do.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct I24([u8; 3]);
+
+const I24_MAX: i32 = (1 << 23) - 1;
+const I24_MIN: i32 = -(1 << 23);
+
+impl I24 {
+    /// Construct from the low 24 bits of `x`.
+    ///
+    /// Higher bits are discarded, so values outside the 24-bit range wrap.
+    #[inline]
+    pub fn new(x: i32) -> I24 {
+        I24([x as u8, (x >> 8) as u8, (x >> 16) as u8])
+    }
+
+    /// Get the sign-extended value of this sample.
+    #[inline]
+    pub fn value(&self) -> i32 {
+        let I24(b) = *self;
+        // Shift the top byte into the sign bit then back down to sign-extend.
+        ((b[0] as i32) << 8 | (b[1] as i32) << 16 | (b[2] as i32) << 24) >> 8
+    }
+}
+
+impl ::std::fmt::Debug for I24 {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "I24({})", self.value())
+    }
+}
+
+impl PartialOrd for I24 {
+    fn partial_cmp(&self, other: &I24) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for I24 {
+    fn cmp(&self, other: &I24) -> CmpOrdering {
+        self.value().cmp(&other.value())
+    }
+}
+
+macro_rules! i24_binop(
+    ($tr:ident, $f:ident, $op:ident) => (
+        impl $tr<I24> for I24 {
+            type Output = I24;
+
+            #[inline]
+            fn $f(self, other: I24) -> I24 {
+                I24::new(self.value().$op(other.value()))
+            }
+        }
+    )
+);
+i24_binop!(Add, add, wrapping_add);
+i24_binop!(Mul, mul, wrapping_mul);
+i24_binop!(Div, div, wrapping_div);
+
+impl OverflowingOps for I24 {
+    fn overflowing_add(self, rhs: I24) -> (I24, bool) {
+        let x = self.value() + rhs.value();
+        (I24::new(x), x > I24_MAX || x < I24_MIN)
+    }
+
+    fn overflowing_sub(self, rhs: I24) -> (I24, bool) {
+        let x = self.value() - rhs.value();
+        (I24::new(x), x > I24_MAX || x < I24_MIN)
+    }
+
+    fn overflowing_mul(self, rhs: I24) -> (I24, bool) {
+        let x = self.value() as i64 * rhs.value() as i64;
+        (I24::new(x as i32), x > I24_MAX as i64 || x < I24_MIN as i64)
+    }
+}
+
+impl ToPrimitive for I24 {
+    fn to_i64(&self) -> Option<i64> {
+        Some(self.value() as i64)
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        let x = self.value();
+        if x < 0 { None } else { Some(x as u64) }
+    }
+}
+
+impl FromPrimitive for I24 {
+    fn from_i64(n: i64) -> Option<I24> {
+        if n > I24_MAX as i64 || n < I24_MIN as i64 {
+            None
+        } else {
+            Some(I24::new(n as i32))
+        }
+    }
+
+    fn from_u64(n: u64) -> Option<I24> {
+        if n > I24_MAX as u64 {
+            None
+        } else {
+            Some(I24::new(n as i32))
+        }
+    }
+}
+
+impl NumCast for I24 {
+    fn from<T: ToPrimitive>(n: T) -> Option<I24> {
+        n.to_i64().and_then(FromPrimitive::from_i64)
+    }
+}
+
+impl Sample for I24 {
+    type Wide = i32;
+
+    #[inline]
+    fn max() -> I24 { I24::new(I24_MAX) }
+    #[inline]
+    fn min() -> I24 { I24::new(I24_MIN) }
+    #[inline]
+    fn clips_hard() -> bool { true }
+    #[inline]
+    fn clip(&self) -> I24 {
+        // Every representable value is in range.
+        *self
+    }
+
+    fn mix_with(&self, other: &I24, mode: MixMode) -> I24 {
+        // The sum of two 24-bit values can't overflow an i32, so check for overflow there.
+        let x = self.value() + other.value();
+        if x >= I24_MIN && x <= I24_MAX {
+            return I24::new(x);
+        }
+        match mode {
+            MixMode::Saturate => if x > I24_MAX { Sample::max() } else { Sample::min() },
+            // Construction truncates to 24 bits.
+            MixMode::Wrap => I24::new(x),
+            MixMode::Panic => panic!("Overflow mixing samples {:?} and {:?}", self, other)
+        }
+    }
+
+    fn write_le(&self, out: &mut [u8]) {
+        let I24(b) = *self;
+        out[0] = b[0];
+        out[1] = b[1];
+        out[2] = b[2];
+    }
+
+    fn write_be(&self, out: &mut [u8]) {
+        let I24(b) = *self;
+        out[0] = b[2];
+        out[1] = b[1];
+        out[2] = b[0];
+    }
+
+    fn read_le(bytes: &[u8]) -> I24 {
+        I24([bytes[0], bytes[1], bytes[2]])
+    }
+
+    fn read_be(bytes: &[u8]) -> I24 {
+        I24([bytes[2], bytes[1], bytes[0]])
+    }
+}
 
 #[test]
 fn test_impl_ranges() {
@@ -215,6 +644,180 @@ fn float_roundtrip_is_lossless(x: i16) -> bool {
     x == Sample::from_float(Sample::to_float::<f32>(x))
 }
 
+/// Mean quantization error and its correlation with the signal, converting a sine of amplitude
+/// 2.5 LSBs to `i16` with the specified conversion.
+#[cfg(test)]
+fn quantization_error<G: FnMut(f64) -> i16>(mut convert: G) -> (f64, f64) {
+    let n = 100000;
+    let (mut sum_e, mut sum_xe, mut sum_xx, mut sum_ee) = (0f64, 0f64, 0f64, 0f64);
+    for i in 0..n {
+        let x = 2.5 / 32767f64 * (::std::f64::consts::PI_2 * i as f64 / 100f64).sin();
+        let e = Sample::to_float::<f64>(convert(x)) - x;
+        sum_e += e;
+        sum_xe += x * e;
+        sum_xx += x * x;
+        sum_ee += e * e;
+    }
+    (sum_e / n as f64, sum_xe / (sum_xx * sum_ee).sqrt())
+}
+
+#[test]
+fn dither_decorrelates_error() {
+    let mut rng = ::rand::XorShiftRng::new_unseeded();
+    let (mean, correlation) = quantization_error(|x| Sample::from_float_dithered(x, &mut rng));
+    assert!(mean.abs() < 0.02 / 32767f64, "Dithered error has DC bias {}", mean * 32767f64);
+    assert!(correlation.abs() < 0.02, "Dithered error correlation was {}", correlation);
+
+    // Plain truncation's error follows the signal.
+    let (_, correlation) = quantization_error(|x| Sample::from_float(x));
+    assert!(correlation.abs() > 0.3, "Truncation error correlation was {}", correlation);
+
+    // Floats are untouched.
+    assert_eq!(<f32 as Sample>::from_float_dithered(0.1f64, &mut rng), 0.1f32);
+}
+
+#[test]
+fn test_mix_modes() {
+    assert_eq!(30000i16.mix_with(&10000, MixMode::Saturate), 32767);
+    assert_eq!((-30000i16).mix_with(&-10000, MixMode::Saturate), -32768);
+    assert_eq!(30000i16.mix_with(&10000, MixMode::Wrap), -25536);
+    assert_eq!((-30000i16).mix_with(&-10000, MixMode::Wrap), 25536);
+    // Modes only matter on overflow.
+    assert_eq!(30000i16.mix_with(&-10000, MixMode::Panic), 20000);
+    assert_eq!(30000i16.mix(&10000), 32767);
+
+    assert_eq!(200u8.mix_with(&200, MixMode::Wrap), 16);
+    assert_eq!(I24::new(I24_MAX).mix_with(&I24::new(1), MixMode::Wrap), I24::new(I24_MIN));
+}
+
+#[test]
+#[should_panic]
+fn test_mix_mode_panics() {
+    30000i16.mix_with(&10000, MixMode::Panic);
+}
+
+#[test]
+fn test_widen_narrow() {
+    assert_eq!(30000i16.widen() + 30000i16.widen(), 60000i32);
+    assert_eq!(<i16 as Sample>::narrow(60000), 32767);
+    assert_eq!(<i16 as Sample>::narrow(-60000), -32768);
+    assert_eq!(<i16 as Sample>::narrow(1234), 1234);
+    assert_eq!(<I24 as Sample>::narrow(I24_MAX + 1), I24::new(I24_MAX));
+    assert_eq!(200u8.widen(), 72);
+    assert_eq!(<u8 as Sample>::narrow(300), 255);
+    // Soft-clipped formats aren't clipped.
+    assert_eq!(<f32 as Sample>::narrow(1.5f32.widen()), 1.5f32);
+}
+
+#[test]
+fn test_u8_bias() {
+    assert_eq!(Sample::to_float::<f32>(128u8), 0f32);
+    assert_eq!(Sample::to_float::<f32>(255u8), 1f32);
+    // Full scale is asymmetric, as for i16, so the lowest value is clipped on the way back.
+    assert_eq!(Sample::to_float::<f64>(0u8), -128f64 / 127f64);
+    assert_eq!(<u8 as Sample>::from_float(Sample::to_float::<f64>(0u8)), 1);
+    assert_eq!(<u8 as Sample>::from_float(Sample::to_float::<f64>(255u8)), 255);
+    assert_eq!(<u8 as Sample>::from_float(0f32), 128);
+    assert_eq!(200u8.mix(&200), 255);
+    assert_eq!(50u8.mix(&50), 0);
+    assert_eq!(200u8.mix(&56), 128);
+}
+
+#[test]
+fn u8_sine_roundtrip_has_no_dc_offset() {
+    let period = 64;
+    let xs: Vec<u8> = (0..period).map(|i| {
+        let t = i as f64 / period as f64;
+        (128f64 + (t * ::std::f64::consts::PI_2).sin() * 127f64).round() as u8
+    }).collect();
+
+    let fs: Vec<f32> = xs.iter().map(|&x| Sample::to_float::<f32>(x)).collect();
+    let peak = fs.iter().fold(0f32, |a, &b| a.max(b));
+    let trough = fs.iter().fold(0f32, |a, &b| a.min(b));
+    let mean = fs.iter().fold(0f32, |a, &b| a + b) / period as f32;
+    assert_eq!(peak, 1f32);
+    assert_eq!(trough, -1f32);
+    assert!(mean.abs() < 1e-6, "DC offset of {} in converted sine", mean);
+
+    let back: Vec<u8> = fs.iter().map(|&f| Sample::from_float(f)).collect();
+    assert_eq!(back, xs);
+}
+
+#[test]
+fn test_i24_packing() {
+    assert_eq!(I24::new(0).value(), 0);
+    assert_eq!(I24::new(-1).value(), -1);
+    assert_eq!(I24::new(I24_MAX).value(), I24_MAX);
+    assert_eq!(I24::new(I24_MIN).value(), I24_MIN);
+    // Wraps like a primitive
+    assert_eq!(I24::new(I24_MAX + 1).value(), I24_MIN);
+    assert_eq!(::std::mem::size_of::<I24>(), 3);
+
+    let max: I24 = Sample::max();
+    let min: I24 = Sample::min();
+    assert_eq!(max.mix(&max), max);
+    assert_eq!(min.mix(&min), min);
+    assert_eq!(I24::new(100).mix(&I24::new(-50)), I24::new(50));
+}
+
+#[quickcheck]
+fn i24_float_roundtrip_is_lossless(x: i32) -> bool {
+    // Truncating covers the full 24-bit range.
+    let x = I24::new(x);
+    x == Sample::from_float(Sample::to_float::<f32>(x))
+}
+
+/// Check `x` survives serialization in both byte orders, and that they are mirror images.
+///
+/// Decoded samples are compared by re-encoding them, so NaNs compare equal.
+#[cfg(test)]
+fn bytes_roundtrip<F: Sample>(x: F) -> bool {
+    let size = mem::size_of::<F>();
+    let (mut le, mut be, mut again) = ([0u8; 8], [0u8; 8], [0u8; 8]);
+    x.write_le(&mut le);
+    x.write_be(&mut be);
+    let reversed: Vec<u8> = be[..size].iter().rev().cloned().collect();
+    if &le[..size] != &reversed[..] {
+        return false;
+    }
+
+    <F as Sample>::read_le(&le).write_le(&mut again);
+    if again != le {
+        return false;
+    }
+    <F as Sample>::read_be(&be).write_be(&mut again);
+    again == be
+}
+
+#[test]
+fn test_bytes_layout() {
+    let mut out = [0u8; 4];
+    0x0102i16.write_le(&mut out);
+    assert_eq!(&out[..2], &[2, 1][..]);
+    0x0102i16.write_be(&mut out);
+    assert_eq!(&out[..2], &[1, 2][..]);
+    I24::new(0x010203).write_be(&mut out);
+    assert_eq!(&out[..3], &[1, 2, 3][..]);
+    1f32.write_bytes(&mut out, Endianness::Big);
+    assert_eq!(&out[..], &[0x3f, 0x80, 0, 0][..]);
+    assert_eq!(<i16 as Sample>::read_bytes(&[1, 2], Endianness::Little), 0x0201);
+}
+
+#[quickcheck]
+fn u8_bytes_roundtrip(x: u8) -> bool { bytes_roundtrip(x) }
+#[quickcheck]
+fn i8_bytes_roundtrip(x: i8) -> bool { bytes_roundtrip(x) }
+#[quickcheck]
+fn i16_bytes_roundtrip(x: i16) -> bool { bytes_roundtrip(x) }
+#[quickcheck]
+fn i24_bytes_roundtrip(x: i32) -> bool { bytes_roundtrip(I24::new(x)) }
+#[quickcheck]
+fn i32_bytes_roundtrip(x: i32) -> bool { bytes_roundtrip(x) }
+#[quickcheck]
+fn f32_bytes_roundtrip(x: f32) -> bool { bytes_roundtrip(x) }
+#[quickcheck]
+fn f64_bytes_roundtrip(x: f64) -> bool { bytes_roundtrip(x) }
+
 /// Output from `Source` pull.
 #[derive(Debug, PartialEq)]
 pub enum SourceResult<'a, T:'a> {
@@ -228,17 +831,169 @@ pub enum SourceResult<'a, T:'a> {
     /// Reached stream end.
     EndOfStream,
     /// There was an error in the stream.
-    StreamError(String),
+    StreamError(StreamError),
+}
+
+/// Errors reported by `Source`s.
+#[derive(Debug)]
+pub enum StreamError {
+    /// Reading the underlying data failed.
+    Io(io::Error),
+    /// The stream's data is corrupt or can't be decoded.
+    Decode(String),
+    /// The stream's format (such as its sample format or number of channels) isn't supported by
+    /// the component reporting the error.
+    UnsupportedFormat(String),
+    /// Any other error.
+    Other(String),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Io(ref e) => write!(f, "I/O error: {}", e),
+            StreamError::Decode(ref s) => write!(f, "decode error: {}", s),
+            StreamError::UnsupportedFormat(ref s) => write!(f, "unsupported format: {}", s),
+            StreamError::Other(ref s) => f.write_str(s),
+        }
+    }
+}
+
+impl Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::Io(ref e) => e.description(),
+            StreamError::Decode(ref s) | StreamError::UnsupportedFormat(ref s)
+                | StreamError::Other(ref s) => s
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            StreamError::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// I/O errors are equal if they are of the same kind, since `io::Error` can't be compared.
+impl PartialEq for StreamError {
+    fn eq(&self, other: &StreamError) -> bool {
+        match (self, other) {
+            (&StreamError::Io(ref a), &StreamError::Io(ref b)) => a.kind() == b.kind(),
+            (&StreamError::Decode(ref a), &StreamError::Decode(ref b)) => a == b,
+            (&StreamError::UnsupportedFormat(ref a),
+             &StreamError::UnsupportedFormat(ref b)) => a == b,
+            (&StreamError::Other(ref a), &StreamError::Other(ref b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> StreamError {
+        StreamError::Io(e)
+    }
 }
 
 /// A source of samples with defined sample rate.
 ///
 /// Generates buffers of samples of type `T` and passes them to a consumer.
+///
+/// Adapters can be chained with the provided methods, so pipelines read in the order samples
+/// flow through them:
+///
+/// ```
+/// use audiostream::{MonoSource, Source, Sink, NullSink};
+/// use audiostream::synth::Tone;
+///
+/// // One second of a 441 Hz tone at half amplitude, in stereo 16-bit samples.
+/// let source = Tone::<f32>::new(1024, 100).adapt()
+///     .amplify(0.5f32)
+///     .copy_channel(0, 1)
+///     .take(44100)
+///     .convert::<i16>();
+///
+/// let mut sink = NullSink::new(source);
+/// while let Some(()) = sink.run_once() { }
+/// assert_eq!(sink.samples(), 2 * 44100);
+/// ```
 pub trait Source {
     /// The sample format emitted by this source.
     type Output: Sample;
     /// Emit the next buffer.
     fn next<'a>(&'a mut self) -> SourceResult<'a, Self::Output>;
+
+    /// Get the sample rate of this source's output, if known.
+    ///
+    /// This is the rate most recently reported by `next`, or the rate it will report if that is
+    /// known in advance, so consumers can prepare for the stream before pulling from it. Sources
+    /// which don't track their rate return `None`, which is the default.
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Apply `f` to every sample of every channel.
+    ///
+    /// Results other than buffers pass through untouched.
+    fn map<G>(self, f: G) -> Map<Self, G> where
+            Self: Sized,
+            G: FnMut(Self::Output) -> Self::Output {
+        Map {
+            source: self,
+            f: f
+        }
+    }
+
+    /// Continue with `next` once this source ends.
+    fn chain<B>(self, next: B) -> Chain<Self, B> where
+            Self: Sized,
+            B: Source<Output=Self::Output> {
+        Chain {
+            a: self,
+            b: next,
+            a_done: false
+        }
+    }
+
+    /// Limit this source to the first `n` samples of each channel.
+    fn take(self, n: usize) -> Take<Self> where Self: Sized {
+        Take {
+            source: self,
+            remaining: n
+        }
+    }
+
+    /// Call `f` with a reference to every result from this source, then pass it on unchanged.
+    fn inspect<G>(self, f: G) -> inspect::Inspect<Self::Output, Self, G> where
+            Self: Sized,
+            G: FnMut(&SourceResult<Self::Output>) {
+        inspect::Inspect::new(self, f)
+    }
+
+    /// Scale every sample by `factor`, computed in format `P`.
+    ///
+    /// See `Amplify`.
+    fn amplify<P>(self, factor: P) -> Amplify<Self::Output, Self, P> where
+            Self: Sized,
+            P: Float + Sample {
+        Amplify::new(self, factor)
+    }
+
+    /// Copy channel `from` over channel `to`, or into a new channel if `to` is one past the last.
+    ///
+    /// See `CopyChannel`.
+    fn copy_channel(self, from: usize, to: usize) -> CopyChannel<Self::Output, Self> where
+            Self: Sized {
+        CopyChannel::new(from, to, self)
+    }
+
+    /// Convert every sample to format `T`.
+    ///
+    /// See `Convert`.
+    fn convert<T: Sample>(self) -> Convert<Self::Output, T, Self> where Self: Sized {
+        Convert::new(self)
+    }
 }
 
 impl<'z, F: Sample> Source for Box<Source<Output=F> + 'z> {
@@ -247,6 +1002,37 @@ impl<'z, F: Sample> Source for Box<Source<Output=F> + 'z> {
     fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
         (**self).next()
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        (**self).sample_rate()
+    }
+}
+
+/// Yield owned per-channel sample vectors as a `SourceResult::Buffer`.
+///
+/// `slices` is scratch space for the outer slice. Both it and `channels` are borrowed for `'a`, so
+/// the returned buffer can't outlive either.
+fn channel_buffer<'a, F>(channels: &'a mut [Vec<F>],
+                         slices: &'a mut Vec<Slice<F>>) -> SourceResult<'a, F> {
+    slices.clear();
+    slices.extend(channels.iter().map(|c| (&c[..]).repr()));
+    SourceResult::Buffer(unsafe {
+        mem::transmute::<&mut [Slice<F>], &'a mut [&'a mut [F]]>(&mut slices[..])
+    })
+}
+
+/// Read until `buf` is full or the reader is exhausted, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(x) => n += x,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(n)
 }
 
 /// The result of pulling from a `DynamicSource`.
@@ -276,10 +1062,11 @@ pub trait DynamicSource {
 }
 
 /// Adapts a normal `Source` into a `DynamicSource`.
-#[warn(dead_code)]
 pub struct DynAdapter<S> {
     sample_rate: u32,
-    source: S
+    source: S,
+    /// Byte views of the most recent buffer's channels.
+    slices: Vec<Slice<u8>>
 }
 
 impl<S: Source> DynAdapter<S> {
@@ -287,40 +1074,45 @@ impl<S: Source> DynAdapter<S> {
     pub fn from_source(source: S) -> DynAdapter<S> {
         DynAdapter {
             sample_rate: 0,
-            source: source
+            source: source,
+            slices: Vec::new()
         }
     }
 }
 
-/*impl<S> DynamicSource for DynAdapter<S> where S: Source {
-    fn next_dyn<'a>(&'a mut self) -> Option<DynBuffer> {
+impl<S> DynamicSource for DynAdapter<S> where S: Source {
+    fn next_dyn<'a>(&'a mut self) -> Option<DynBuffer<'a>> {
+        let size = mem::size_of::<<S as Source>::Output>();
         loop {
-            match self.source.next() {
+            // Pulling again after a sample rate change would borrow the source twice for 'a,
+            // which borrowck can't see is fine since the first borrow has ended by then.
+            let source: *mut S = &mut self.source;
+            match unsafe { (*source).next() } {
                 SourceResult::EndOfStream |
                 SourceResult::StreamError(_) => return None,
                 SourceResult::SampleRate(sr) => self.sample_rate = sr,
-                SourceResult::Buffer(b) => unsafe {
-                    // Get bytes only. This transmute makes the len field
-                    // of the inner slices wrong becasuse we're changing the
-                    // contained type.
-                    let mut b = mem::transmute::<&'a mut [&'a mut [<S as Source>::Output]],
-                                                 &'a mut [raw::Slice<u8>]>(b);
-                    // Correct the len field of channel buffers
-                    for i in 0 .. b.len() {
-                        b[i].len *= mem::size_of::<<S as Source>::Output>();
-                    }
-                    
+                SourceResult::Buffer(b) => {
+                    // Build byte slices over each channel in our own storage, rather than
+                    // rewriting the lengths of the source's slices in place.
+                    self.slices.clear();
+                    self.slices.extend(b.iter().map(|channel| Slice {
+                        data: channel.as_ptr() as *const u8,
+                        len: channel.len() * size
+                    }));
+
                     return Some(DynBuffer {
-                        bytes: mem::transmute::<&'a mut [raw::Slice<u8>],
-                                                &'a mut [&'a mut [u8]]>(b),
-                        sample_size: mem::size_of::<<S as Source>::Output>() as u8,
+                        bytes: unsafe {
+                            mem::transmute::<&mut [Slice<u8>],
+                                             &'a mut [&'a mut [u8]]>(&mut self.slices)
+                        },
+                        sample_size: (size * 8) as u8,
                         sample_rate: self.sample_rate
-                    })
+                    });
                 }
             }
         }
     }
-}*/
+}
 
 /// A `Source` that only generates one channel at an indeterminate sample rate.
 ///
@@ -336,18 +1128,33 @@ pub trait MonoSource : Sized {
     fn adapt(self) -> MonoAdapter<Self::Output, Self> {
         MonoAdapter {
             src: self,
-            bp: raw::Slice {
+            bp: Slice {
                 data: ::std::ptr::null(),
                 len: 0
             }
         }
     }
+
+    /// Play this source's entire output `count` times, or forever if `None`.
+    ///
+    /// This source must be finite. It is only run once; its output is cached as it passes through
+    /// and later passes replay the cache.
+    fn repeat(self, count: Option<usize>) -> Repeat<Self> {
+        Repeat {
+            source: self,
+            passes: count,
+            first_pass: true,
+            cache: Vec::new(),
+            index: 0,
+            out: Vec::new()
+        }
+    }
 }
 
 /// Generalizes a `MonoSource` into `Source`.
 pub struct MonoAdapter<F, T> {
     src: T,
-    bp: raw::Slice<F>
+    bp: Slice<F>
 }
 
 impl<F, T> Source for MonoAdapter<F, T> where
@@ -367,7 +1174,7 @@ impl<F, T> Source for MonoAdapter<F, T> where
         };
         
         SourceResult::Buffer(unsafe {
-            mem::transmute::<&mut [raw::Slice<F>], &'a mut [&'a mut [F]]>(
+            mem::transmute::<&mut [Slice<F>], &'a mut [&'a mut [F]]>(
                 mut_ref_slice(&mut self.bp)
             )
         })
@@ -382,13 +1189,44 @@ impl<F, T> ::std::ops::Deref for MonoAdapter<F, T> {
     }
 }
 
-/// A thing.
-pub trait Sink {
-    /// Process a single buffer.
-    ///
-    /// Returns `Some` if there will be more buffers to process, or `None`
-    /// otherwise.
-    fn run_once(&mut self) -> Option<()>;
+/// Byte order of serialized samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    /// Least significant byte first.
+    Little,
+    /// Most significant byte first.
+    Big,
+    /// Whichever order the host uses.
+    Native
+}
+
+impl Endianness {
+    /// True if this byte order is the same as the host's.
+    pub fn is_native(&self) -> bool {
+        match *self {
+            Endianness::Native => true,
+            Endianness::Little => cfg!(target_endian = "little"),
+            Endianness::Big => cfg!(target_endian = "big"),
+        }
+    }
+
+    /// True if this is little-endian byte order, once `Native` is resolved.
+    pub fn is_little(&self) -> bool {
+        match *self {
+            Endianness::Little => true,
+            Endianness::Big => false,
+            Endianness::Native => cfg!(target_endian = "little"),
+        }
+    }
+}
+
+/// A thing.
+pub trait Sink {
+    /// Process a single buffer.
+    ///
+    /// Returns `Some` if there will be more buffers to process, or `None`
+    /// otherwise.
+    fn run_once(&mut self) -> Option<()>;
 
     /// Process buffers indefinitely, until end of stream or terminated.
     ///
@@ -399,10 +1237,126 @@ pub trait Sink {
     /// If `term_cond` is never modified, this is equivalent to repeatedly
     /// calling `run_once` until it returns `None`.
     fn run(&mut self, term_cond: &AtomicBool) {
+        self.run_batched(term_cond, 1)
+    }
+
+    /// Process buffers like `run`, but only check `term_cond` every `n` buffers.
+    ///
+    /// Up to `n - 1` buffers may be processed after `term_cond` is set. With very small buffers
+    /// this trades responsiveness to termination for reduced per-buffer overhead.
+    fn run_batched(&mut self, term_cond: &AtomicBool, n: usize) {
+        assert!(n > 0, "Batches must contain at least one buffer");
         loop {
-            if term_cond.load(Ordering::Acquire) || self.run_once().is_none() {
+            if term_cond.load(Ordering::Acquire) {
                 return;
             }
+            for _ in 0..n {
+                if self.run_once().is_none() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Distributes one `Source` to several consumers.
+///
+/// Each call to `run_once` pulls one result from the source and passes a read-only view of it to
+/// every registered consumer in the order they were added, so all consumers see identical
+/// sequences. A consumer returning `None` is detached and will not be called again, while the
+/// others continue. The tee ends when the source ends or fails, or when no consumers remain.
+pub struct Tee<'c, F, S> {
+    source: S,
+    consumers: Vec<Box<FnMut(&SourceResult<F>) -> Option<()> + 'c>>,
+}
+
+impl<'c, F: Sample, S: Source<Output=F>> Tee<'c, F, S> {
+    /// Create a tee over `source` with no consumers.
+    pub fn new(source: S) -> Tee<'c, F, S> {
+        Tee {
+            source: source,
+            consumers: Vec::new(),
+        }
+    }
+
+    /// Register a consumer.
+    pub fn add<C>(&mut self, consumer: C) where C: FnMut(&SourceResult<F>) -> Option<()> + 'c {
+        self.consumers.push(Box::new(consumer));
+    }
+}
+
+impl<'c, F: Sample, S: Source<Output=F>> Sink for Tee<'c, F, S> {
+    fn run_once(&mut self) -> Option<()> {
+        if self.consumers.is_empty() {
+            return None;
+        }
+
+        let result = self.source.next();
+        let mut i = 0;
+        while i < self.consumers.len() {
+            if (self.consumers[i])(&result).is_none() {
+                self.consumers.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        match result {
+            SourceResult::EndOfStream | SourceResult::StreamError(_) => None,
+            _ if self.consumers.is_empty() => None,
+            _ => Some(())
+        }
+    }
+}
+
+/// Discards everything from a `Source`.
+///
+/// The sink-side counterpart of `synth::Null`: each call to `run_once` pulls one result from the
+/// source and drops it, with no device or pacing overhead, so a whole pipeline can be benchmarked
+/// by running it into a `NullSink`. The number of buffers and samples consumed is counted.
+pub struct NullSink<F, R> {
+    source: R,
+    buffers: u64,
+    samples: u64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, R: Source<Output=F>> NullSink<F, R> {
+    /// Construct a sink discarding the output of `source`.
+    pub fn new(source: R) -> NullSink<F, R> {
+        NullSink {
+            source: source,
+            buffers: 0,
+            samples: 0,
+            format: PhantomData
+        }
+    }
+
+    /// Get the number of buffers consumed so far.
+    pub fn buffers(&self) -> u64 {
+        self.buffers
+    }
+
+    /// Get the number of samples consumed so far, summed over all channels.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+}
+
+impl<F: Sample, R: Source<Output=F>> Sink for NullSink<F, R> {
+    fn run_once(&mut self) -> Option<()> {
+        match self.source.next() {
+            SourceResult::Buffer(b) => {
+                self.buffers += 1;
+                self.samples += b.iter().fold(0, |n, c| n + c.len() as u64);
+                Some(())
+            }
+            SourceResult::SampleRate(_) => Some(()),
+            SourceResult::EndOfStream => None,
+            SourceResult::StreamError(e) => {
+                error!("Null sink stopping on stream error: {}", e);
+                None
+            }
         }
     }
 }
@@ -417,7 +1371,9 @@ pub trait Sink {
 /// generally not useful to library users. It may be useful, however,
 /// for building custom sources.
 pub struct UninitializedSource<F> {
-    buffer: Vec<F>
+    buffer: Vec<F>,
+    /// Samples left to yield before ending, if limited.
+    remaining: Option<usize>
 }
 
 impl<F: Sample> UninitializedSource<F> {
@@ -426,16 +1382,293 @@ impl<F: Sample> UninitializedSource<F> {
     /// The yielded buffers will have `size` items.
     pub fn new(size: usize) -> UninitializedSource<F> {
         UninitializedSource {
-            buffer: (0..size).map(|_| FromPrimitive::from_usize(0).unwrap()).collect()
+            buffer: (0..size).map(|_| FromPrimitive::from_usize(0).unwrap()).collect(),
+            remaining: None
         }
     }
+
+    /// End the stream after `samples` more samples, or never if `None`.
+    ///
+    /// The final buffer is shortened to end on exactly that many samples.
+    pub fn set_limit(&mut self, samples: Option<usize>) {
+        self.remaining = samples;
+    }
+
+    /// Change the number of items in subsequent buffers.
+    ///
+    /// Items added by growing the buffer are zero.
+    pub fn set_size(&mut self, size: usize) {
+        self.buffer.resize(size, FromPrimitive::from_usize(0).unwrap());
+        // Don't hold on to memory from a larger size.
+        self.buffer.shrink_to_fit();
+    }
 }
 
 impl<F> MonoSource for UninitializedSource<F> {
     type Output = F;
 
     fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
-        Some(&mut self.buffer)
+        let n = match self.remaining {
+            Some(0) => return None,
+            Some(r) => ::std::cmp::min(r, self.buffer.len()),
+            None => self.buffer.len()
+        };
+        if let Some(ref mut r) = self.remaining {
+            *r -= n;
+        }
+        Some(&mut self.buffer[..n])
+    }
+}
+
+/// Sample rate in Hz assumed by nodes which need one before their source reports it.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Buffer size used by `from_iter`.
+pub const DEFAULT_ITER_CHUNK: usize = 1024;
+
+/// A `MonoSource` yielding the items of an iterator.
+///
+/// Each buffer holds up to a fixed number of samples, with the final buffer shorter if the
+/// iterator runs out partway through.
+pub struct IterSource<I: Iterator> {
+    iter: I,
+    chunk: usize,
+    buffer: Vec<I::Item>
+}
+
+impl<F: Sample, I: Iterator<Item=F>> IterSource<I> {
+    /// Create a source yielding buffers of up to `chunk` items from `iter`.
+    pub fn new(iter: I, chunk: usize) -> IterSource<I> {
+        assert!(chunk > 0, "Chunk size must be nonzero");
+        IterSource {
+            iter: iter,
+            chunk: chunk,
+            buffer: Vec::with_capacity(chunk)
+        }
+    }
+}
+
+/// Create a source yielding the items of `iter` in buffers of `DEFAULT_ITER_CHUNK` samples.
+///
+/// ```ignore
+/// let ramp = from_iter((0..1000).map(|x| x as f32 / 1000.0)).adapt();
+/// ```
+pub fn from_iter<F: Sample, I: Iterator<Item=F>>(iter: I) -> IterSource<I> {
+    IterSource::new(iter, DEFAULT_ITER_CHUNK)
+}
+
+impl<F: Sample, I: Iterator<Item=F>> MonoSource for IterSource<I> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        self.buffer.clear();
+        self.buffer.extend(self.iter.by_ref().take(self.chunk));
+        if self.buffer.is_empty() {
+            None
+        } else {
+            Some(&mut self.buffer)
+        }
+    }
+}
+
+/// Plays channel-major sample data from memory.
+///
+/// The sample rate is reported by the first call to `next`, followed by buffers of up to
+/// `DEFAULT_ITER_CHUNK` samples per channel. The data is copied into each buffer so it's
+/// unaffected by anything downstream. At the end of the data the stream ends, or if looping
+/// starts again from the beginning; buffers never span the loop point, so the last buffer of each
+/// pass may be short.
+pub struct BufferSource<F> {
+    data: Vec<Vec<F>>,
+    rate: u32,
+    chunk: usize,
+    looping: bool,
+    started: bool,
+    /// Index of the next sample to yield, per channel.
+    position: usize,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample> BufferSource<F> {
+    /// Create a source playing `data` once at `rate` Hz.
+    ///
+    /// Panics if there are no channels or they aren't all the same length.
+    pub fn new(data: Vec<Vec<F>>, rate: u32) -> BufferSource<F> {
+        assert!(!data.is_empty(), "BufferSource must have at least one channel");
+        assert!(data.iter().all(|c| c.len() == data[0].len()),
+                "BufferSource channels must all be the same length");
+        BufferSource {
+            channels: vec![Vec::new(); data.len()],
+            data: data,
+            rate: rate,
+            chunk: DEFAULT_ITER_CHUNK,
+            looping: false,
+            started: false,
+            position: 0,
+            slices: Vec::new(),
+        }
+    }
+
+    /// Set the maximum number of samples per channel in each buffer.
+    pub fn set_chunk(&mut self, chunk: usize) {
+        assert!(chunk > 0, "Buffers must contain at least one sample");
+        self.chunk = chunk;
+    }
+
+    /// Select whether to start again at the end of the data, rather than ending the stream.
+    ///
+    /// Looping empty data ends the stream regardless.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+}
+
+impl<F: Sample> Source for BufferSource<F> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if !self.started {
+            self.started = true;
+            return SourceResult::SampleRate(self.rate);
+        }
+
+        let len = self.data[0].len();
+        if self.position >= len {
+            if !self.looping || len == 0 {
+                return SourceResult::EndOfStream;
+            }
+            self.position = 0;
+        }
+
+        let start = self.position;
+        let end = ::std::cmp::min(start + self.chunk, len);
+        for (out, channel) in self.channels.iter_mut().zip(self.data.iter()) {
+            out.clear();
+            out.extend(channel[start..end].iter().cloned());
+        }
+        self.position = end;
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.rate)
+    }
+}
+
+/// Plays channel-major buffers received over a channel.
+///
+/// This bridges audio produced elsewhere, such as by another thread, into a pipeline. The sample
+/// rate is reported by the first call to `next`, then each call blocks until a buffer is
+/// received and yields it unchanged. The stream ends when every sender has been dropped and all
+/// sent buffers have been yielded. A buffer with no channels or channels of differing lengths is
+/// a stream error.
+pub struct ChannelSource<F> {
+    receiver: Receiver<Vec<Vec<F>>>,
+    rate: u32,
+    started: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample + Send> ChannelSource<F> {
+    /// Create a source playing buffers from `receiver` at `rate` Hz.
+    pub fn new(receiver: Receiver<Vec<Vec<F>>>, rate: u32) -> ChannelSource<F> {
+        ChannelSource {
+            receiver: receiver,
+            rate: rate,
+            started: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+}
+
+impl<F: Sample + Send> Source for ChannelSource<F> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if !self.started {
+            self.started = true;
+            return SourceResult::SampleRate(self.rate);
+        }
+
+        let data = match self.receiver.recv() {
+            Ok(data) => data,
+            Err(_) => return SourceResult::EndOfStream
+        };
+        if data.is_empty() || data.iter().any(|c| c.len() != data[0].len()) {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(
+                "ChannelSource received a buffer with mismatched channels".to_string()));
+        }
+        self.channels = data;
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.rate)
+    }
+}
+
+/// Repeats the output of a finite `MonoSource`.
+///
+/// Constructed with `MonoSource::repeat`. Buffers are replayed with the same lengths as the
+/// source originally yielded them.
+pub struct Repeat<S: MonoSource> {
+    source: S,
+    /// Passes remaining, including the current one.
+    passes: Option<usize>,
+    first_pass: bool,
+    /// Buffers yielded by the source.
+    cache: Vec<Vec<S::Output>>,
+    /// Index of the next buffer to replay from `cache`.
+    index: usize,
+    out: Vec<S::Output>,
+}
+
+impl<S: MonoSource> Repeat<S> {
+    fn end_pass(&mut self) {
+        if let Some(ref mut n) = self.passes {
+            *n -= 1;
+        }
+    }
+}
+
+impl<S: MonoSource> MonoSource for Repeat<S> where S::Output: Clone {
+    type Output = S::Output;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [S::Output]> {
+        if self.passes == Some(0) {
+            return None;
+        }
+
+        if self.first_pass {
+            match self.source.next() {
+                Some(b) => {
+                    // There's no need to keep anything if this is the only pass.
+                    if self.passes != Some(1) {
+                        self.cache.push(b.to_vec());
+                    }
+                    return Some(b);
+                }
+                None => {
+                    self.first_pass = false;
+                    self.end_pass();
+                }
+            }
+        }
+
+        if self.passes == Some(0) || self.cache.is_empty() {
+            return None;
+        }
+        self.out.clear();
+        self.out.extend(self.cache[self.index].iter().cloned());
+        self.index += 1;
+        if self.index == self.cache.len() {
+            self.index = 0;
+            self.end_pass();
+        }
+        Some(&mut self.out)
     }
 }
 
@@ -445,7 +1678,9 @@ impl<F> MonoSource for UninitializedSource<F> {
 /// channel (in which case the original data is lost) or one more than the highest
 /// valid channel (in which case a new channel is created).
 ///
-/// Due to mutability requirements for channel data, this always makes a copy.
+/// Overwriting an existing channel copies directly between the source's buffers. Due to
+/// mutability requirements for channel data, creating a new channel copies into a buffer owned by
+/// the adapter.
 pub struct CopyChannel<F, S> {
     /// Channel index (from 0) to copy from.
     from: usize,
@@ -455,7 +1690,7 @@ pub struct CopyChannel<F, S> {
     // Contents of `slices` must never outlive the scope in which they are
     // assigned to maintain safety. Covariant lifetime is used to allow the
     // concrete lifetime in `next<'a>()` to be stored within the struct.
-    slices: Vec<raw::Slice<F>>,
+    slices: Vec<Slice<F>>,
     samples: Vec<F>,
 }
 
@@ -485,155 +1720,1911 @@ impl<F: Sample, S: Source<Output=F>> Source for CopyChannel<F, S> {
         assert!(self.to <= b.len(), "CopyChannel cannot copy from {} to {} with only {} channels",
                                     self.from, self.to, b.len());
 
+        if self.to < b.len() {
+            if self.to != self.from {
+                let (src, dst) = if self.from < self.to {
+                    let (head, tail) = b.split_at_mut(self.to);
+                    (&*head[self.from], &mut *tail[0])
+                } else {
+                    let (head, tail) = b.split_at_mut(self.from);
+                    (&*tail[0], &mut *head[self.to])
+                };
+                for (d, s) in dst.iter_mut().zip(src.iter()) {
+                    *d = *s;
+                }
+            }
+            return SourceResult::Buffer(b);
+        }
+
         self.slices.clear();
         self.slices.extend(b.iter().map(|x: &&mut [F]| (*x).repr()));
 
         self.samples.clear();
         self.samples.extend(b[self.from].iter().map(|x| *x));
-        if self.to == b.len() {
-            self.slices.push(unsafe {
-                mem::transmute::<&'a mut [F], raw::Slice<F>>(&mut self.samples)
-            });
-        } else {
-            self.slices[self.to] = self.slices[self.from];
-        }
+        self.slices.push(unsafe {
+            mem::transmute::<&'a mut [F], Slice<F>>(&mut self.samples)
+        });
         SourceResult::Buffer(unsafe {
-            mem::transmute::<&mut [raw::Slice<F>],&'a mut [&'a mut [F]]>(&mut self.slices)
+            mem::transmute::<&mut [Slice<F>],&'a mut [&'a mut [F]]>(&mut self.slices)
         })
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
 }
 
-/// Adjust the amplitude of the input stream by a constant factor.
+/// Applies a function to each sample of a `Source`.
 ///
-/// A factor greater than one increases amplitude, less than one reduced
-/// amplitude.
-#[allow(dead_code)]
-pub struct Amplify<F, S, P> {
-    factor: P,
+/// Constructed with `Source::map`.
+pub struct Map<S, G> {
     source: S,
-    format: PhantomData<F>
+    f: G
 }
 
-impl<F, S, P> Amplify<F, S, P> {
-    /// Create a new amplifier (or attenuator).
-    pub fn new(source: S, factor: P) -> Amplify<F, S, P> {
-        Amplify {
-            factor: factor,
-            source: source,
-            format: PhantomData
-        }
-    }
-}
-
-impl<F: Sample, S: Source<Output=F>, P: Float + Sample> Source for Amplify<F, S, P> {
-    type Output = F;
+impl<S: Source, G> Source for Map<S, G> where G: FnMut(S::Output) -> S::Output {
+    type Output = S::Output;
 
-    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+    fn next<'a>(&'a mut self) -> SourceResult<'a, S::Output> {
         let buf = match self.source.next() {
             SourceResult::Buffer(b) => b,
             x => return x
         };
 
-        // TODO must handle clipping somehow
         for channel in buf.iter_mut() {
             for sample in channel.iter_mut() {
-                let samp_f: P = Sample::to_float::<P>(*sample);
-                *sample = Sample::from_float(samp_f * self.factor);
+                *sample = (self.f)(*sample);
             }
         }
         SourceResult::Buffer(buf)
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
 }
 
-pub struct Mix<A, B> {
-    sources: (A, B),
+/// Converts a `Source` of one sample format to another.
+///
+/// Each sample is converted with `Sample::convert` through the intermediate format `I`. Since
+/// the formats differ the input buffers can't be reused, so converted samples are written to
+/// buffers owned by the adapter. Results other than buffers pass through untouched.
+///
+/// If `A` and `B` are the same format the source's buffers are passed through as they are, since
+/// a round trip through `I` would cost time and may lose precision.
+pub struct Convert<A, B, S, I=f64> {
+    source: S,
+    channels: Vec<Vec<B>>,
+    slices: Vec<Slice<B>>,
+    format: PhantomData<(A, I)>
 }
 
-impl<A, B, F> Mix<A, B> where
-        A: MonoSource<Output=F>, B: MonoSource<Output=F> {
-    pub fn new(a: A, b: B) -> Mix<A, B> {
-        Mix {
-            sources: (a, b)
+impl<A, B, S, I = f64> Convert<A, B, S, I> where
+        A: Sample, B: Sample, S: Source<Output=A>, I: Float + Sample {
+    /// Create a converter from the output format of `source` to `B`.
+    pub fn new(source: S) -> Convert<A, B, S, I> {
+        Convert {
+            source: source,
+            channels: Vec::new(),
+            slices: Vec::new(),
+            format: PhantomData
         }
     }
 }
 
-impl<A, B, F> MonoSource for Mix<A, B> where
-        A: MonoSource<Output=F>, B: MonoSource<Output=F>, F: Sample {
-    type Output = F;
+impl<A, B, S, I> Source for Convert<A, B, S, I> where
+        A: Sample, B: Sample, S: Source<Output=A>, I: Float + Sample {
+    type Output = B;
 
-    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
-        let a_buf = match self.sources.0.next() {
-            Some(b) => b,
-            x => return x
-        };
-        let b_buf = match self.sources.1.next() {
-            Some(b) => b,
-            x => return x
+    fn next<'a>(&'a mut self) -> SourceResult<'a, B> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => return SourceResult::SampleRate(r),
+            SourceResult::EndOfStream => return SourceResult::EndOfStream,
+            SourceResult::StreamError(e) => return SourceResult::StreamError(e)
         };
 
-        // TODO buffers must be the same length. Should either document that requirement
-        // or make it handle irregular buffers.
-        for i in 0..a_buf.len() {
-            a_buf[i] = a_buf[i].mix(b_buf[i]);
+        if TypeId::of::<A>() == TypeId::of::<B>() {
+            // A and B are the same type, so this only changes the name.
+            return SourceResult::Buffer(unsafe {
+                mem::transmute::<&'a mut [&'a mut [A]], &'a mut [&'a mut [B]]>(buf)
+            });
         }
 
-        a_buf
-}
-
-#[cfg(test)]
-mod tests {
-    use super::{Sample, Source, SourceResult, MonoSource, Amplify};
+        self.channels.resize(buf.len(), Vec::new());
+        for (out, channel) in self.channels.iter_mut().zip(buf.iter()) {
+            out.clear();
+            out.extend(channel.iter().map(|&x| Sample::convert::<B, I>(x)));
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
 
-    struct ConstantSource<F> {
-        data: Vec<F>,
-        sbuf: Vec<F>
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
     }
+}
 
-    impl<F: Sample + Clone> MonoSource for ConstantSource<F> {
-        type Output = F;
+/// Plays one `Source` to completion, then another.
+///
+/// Constructed with `Source::chain`. The end of the first source is not reported; sample rates
+/// from either are passed through so downstream nodes can adapt if they differ. An error from
+/// either source is passed through immediately.
+pub struct Chain<A, B> {
+    a: A,
+    b: B,
+    a_done: bool
+}
 
-        fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
-            self.sbuf = self.data.clone();
-            Some(&mut self.sbuf)
-        }
-    }
+impl<A: Source, B: Source<Output=A::Output>> Source for Chain<A, B> {
+    type Output = A::Output;
 
-    impl<F> ::std::default::Default for ConstantSource<F> {
-        fn default() -> ConstantSource<F> {
-            ConstantSource {
-                data: vec![],
-                sbuf: vec![]
+    fn next<'a>(&'a mut self) -> SourceResult<'a, A::Output> {
+        if !self.a_done {
+            match self.a.next() {
+                SourceResult::EndOfStream => {
+                    self.a_done = true;
+                }
+                x => return x
             }
         }
+        self.b.next()
     }
 
-
-    #[quickcheck]
-    fn copychannel_copies_channels(xs: Vec<i16>) -> bool {
-        let mut src = super::CopyChannel::new(0, 1, ConstantSource::<i16> {
-            data: xs.clone(),
-            sbuf: vec![]
-        }.adapt());
-        if let SourceResult::Buffer(out) = src.next() {
-            out[1] == &xs[..] && out[0] == out[1]
+    fn sample_rate(&self) -> Option<u32> {
+        if self.a_done {
+            self.b.sample_rate()
         } else {
-            unreachable!();
+            self.a.sample_rate()
         }
     }
+}
+
+/// Ends a `Source` after a fixed number of samples per channel.
+///
+/// Constructed with `Source::take`. The final buffer is shortened if necessary to yield exactly
+/// the requested number of samples.
+pub struct Take<S> {
+    source: S,
+    remaining: usize
+}
+
+impl<S: Source> Source for Take<S> {
+    type Output = S::Output;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, S::Output> {
+        if self.remaining == 0 {
+            return SourceResult::EndOfStream;
+        }
+
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        let len = buf[0].len();
+        if len > self.remaining {
+            let n = self.remaining;
+            for channel in buf.iter_mut() {
+                // Shorten each channel in place, keeping the original lifetime.
+                *channel = unsafe {
+                    ::std::slice::from_raw_parts_mut(channel.as_mut_ptr(), n)
+                };
+            }
+            self.remaining = 0;
+        } else {
+            self.remaining -= len;
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Default number of samples over which `Amplify::set_factor` ramps to a new factor.
+pub const DEFAULT_GAIN_RAMP: usize = 256;
+
+/// Adjust the amplitude of the input stream by a constant factor.
+///
+/// A factor greater than one increases amplitude, less than one reduced
+/// amplitude.
+///
+/// In hard-clipped formats, samples pushed beyond the representable range are
+/// clipped to it, and those beyond full scale (±1) are counted. Integer formats reach slightly
+/// below -1, so for `i16` both -32768 at unity gain and -16384 doubled count as clips even though
+/// they're representable. Soft-clipped formats never clip.
+///
+/// The factor may be changed while the stream runs with `set_factor`, which ramps linearly to the
+/// new value over a number of samples rather than stepping, to avoid clicks.
+#[allow(dead_code)]
+pub struct Amplify<F, S, P> {
+    factor: P,
+    /// Per-sample step, target factor and samples remaining of a ramp in progress.
+    ramp: Option<(P, P, usize)>,
+    ramp_length: usize,
+    source: S,
+    /// Samples clipped over the life of this amplifier.
+    clips: usize,
+    /// Samples clipped in the most recent buffer.
+    last_clips: usize,
+    /// Noise source for dithering, if enabled.
+    dither: Option<XorShiftRng>,
+    format: PhantomData<F>
+}
+
+impl<F, S, P> Amplify<F, S, P> {
+    /// Create a new amplifier (or attenuator).
+    pub fn new(source: S, factor: P) -> Amplify<F, S, P> {
+        Amplify {
+            factor: factor,
+            ramp: None,
+            ramp_length: DEFAULT_GAIN_RAMP,
+            source: source,
+            clips: 0,
+            last_clips: 0,
+            dither: None,
+            format: PhantomData
+        }
+    }
+
+    /// Select whether to dither output with `Sample::from_float_dithered`.
+    ///
+    /// This is off by default, and has no effect on soft-clipped formats.
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = if dither { Some(::rand::weak_rng()) } else { None };
+    }
+
+    /// Total number of samples clipped so far, over all channels.
+    pub fn clip_count(&self) -> usize {
+        self.clips
+    }
+
+    /// Number of samples clipped in the most recent buffer, over all channels.
+    pub fn last_clip_count(&self) -> usize {
+        self.last_clips
+    }
+
+    /// Set the number of samples over which `set_factor` ramps to a new factor.
+    ///
+    /// Zero makes changes take effect immediately. Defaults to `DEFAULT_GAIN_RAMP`.
+    pub fn set_ramp_length(&mut self, samples: usize) {
+        self.ramp_length = samples;
+    }
+}
+
+impl<F, S, P: Float> Amplify<F, S, P> {
+    /// Change the amplification factor, ramping from the current factor.
+    ///
+    /// The ramp starts with the next buffer and runs for the configured ramp length in samples
+    /// per channel. Changing the factor mid-ramp starts a new ramp from wherever the last one had
+    /// reached.
+    pub fn set_factor(&mut self, factor: P) {
+        if self.ramp_length == 0 {
+            self.factor = factor;
+            self.ramp = None;
+        } else {
+            let n: P = NumCast::from(self.ramp_length).unwrap();
+            self.ramp = Some(((factor - self.factor) / n, factor, self.ramp_length));
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>, P: Float + Sample> Source for Amplify<F, S, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        // from_float clips for us, so only need to count.
+        let clips_hard = <F as Sample>::clips_hard();
+        let max: P = One::one();
+        // Full scale is symmetric, even though integer formats can represent slightly below -1.
+        let min: P = -max;
+        let mut clips = 0;
+        let (factor, ramp) = (self.factor, self.ramp);
+        for channel in buf.iter_mut() {
+            for (i, sample) in channel.iter_mut().enumerate() {
+                let gain = match ramp {
+                    None => factor,
+                    Some((step, _, remaining)) if i < remaining => {
+                        factor + step * NumCast::from(i + 1).unwrap()
+                    }
+                    Some((_, target, _)) => target
+                };
+                let samp_f: P = Sample::to_float::<P>(*sample);
+                let y = samp_f * gain;
+                if clips_hard && (y > max || y < min) {
+                    clips += 1;
+                }
+                *sample = match self.dither {
+                    Some(ref mut rng) => Sample::from_float_dithered(y, rng),
+                    None => Sample::from_float(y)
+                };
+            }
+        }
+        self.last_clips = clips;
+        self.clips += clips;
+
+        if let Some((step, target, remaining)) = ramp {
+            let len = buf[0].len();
+            if len >= remaining {
+                self.factor = target;
+                self.ramp = None;
+            } else {
+                self.factor = factor + step * NumCast::from(len).unwrap();
+                self.ramp = Some((step, target, remaining - len));
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Position a single-channel stream in a stereo field.
+///
+/// Uses a constant-power pan law, so perceived loudness is the same at all positions. Input with
+/// more than one channel is a stream error.
+pub struct Pan<F, S> {
+    source: S,
+    /// Gain applied to the left and right channels.
+    gains: (f64, f64),
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Pan<F, S> {
+    /// Create a panner with the specified position.
+    ///
+    /// `position` ranges from -1 (hard left) through 0 (center) to 1 (hard right).
+    pub fn new(source: S, position: f64) -> Pan<F, S> {
+        let mut pan = Pan {
+            source: source,
+            gains: (0f64, 0f64),
+            channels: vec![Vec::new(), Vec::new()],
+            slices: Vec::new(),
+        };
+        pan.set_position(position);
+        pan
+    }
+
+    /// Change the pan position, in range [-1, 1].
+    pub fn set_position(&mut self, position: f64) {
+        let position = position.max(-1f64).min(1f64);
+        let angle = (position + 1f64) * ::std::f64::consts::FRAC_PI_4;
+        self.gains = (angle.cos(), angle.sin());
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Pan<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() != 1 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Pan requires a mono source, but got {} channels", buf.len())));
+        }
+
+        let (l, r) = self.gains;
+        let (left, right) = self.channels.split_at_mut(1);
+        let (left, right) = (&mut left[0], &mut right[0]);
+        left.clear();
+        right.clear();
+        for &x in buf[0].iter() {
+            let x: f64 = Sample::to_float(x);
+            left.push(Sample::from_float(x * l));
+            right.push(Sample::from_float(x * r));
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Collapse all channels of a stream into one.
+///
+/// Each output sample is the average of the input channels at that position. Single-channel
+/// input passes through untouched.
+pub struct Downmix<F, S> {
+    source: S,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Downmix<F, S> {
+    /// Create a downmixer over `source`.
+    pub fn new(source: S) -> Downmix<F, S> {
+        Downmix {
+            source: source,
+            channels: vec![Vec::new()],
+            slices: Vec::new(),
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Downmix<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() == 1 {
+            return SourceResult::Buffer(buf);
+        }
+
+        let n = buf.len() as f64;
+        let out = &mut self.channels[0];
+        out.clear();
+        out.extend((0..buf[0].len()).map(|i| {
+            let sum = buf.iter().fold(0f64, |a, c| a + Sample::to_float::<f64>(c[i]));
+            Sample::from_float(sum / n)
+        }));
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Route channels through a matrix of gains.
+///
+/// Output channel `i` at each sample is the sum over input channels `j` of `matrix[i][j]` times
+/// that input sample, computed in `f64`. A permutation matrix reorders channels, so
+/// `vec![vec![0.0, 1.0], vec![1.0, 0.0]]` swaps left and right, while a single row of equal
+/// weights downmixes. Input with a channel count other than the matrix's width is a stream error.
+pub struct ChannelMatrix<F, S> {
+    source: S,
+    /// Gains, one row per output channel.
+    matrix: Vec<Vec<f64>>,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> ChannelMatrix<F, S> {
+    /// Create a router with an `out_channels × in_channels` gain matrix.
+    ///
+    /// Panics if the matrix is empty or its rows differ in length.
+    pub fn new(source: S, matrix: Vec<Vec<f64>>) -> ChannelMatrix<F, S> {
+        assert!(!matrix.is_empty(), "ChannelMatrix must produce at least one channel");
+        assert!(matrix.iter().all(|row| row.len() == matrix[0].len()),
+                "ChannelMatrix rows must all be the same length");
+        ChannelMatrix {
+            source: source,
+            channels: vec![Vec::new(); matrix.len()],
+            matrix: matrix,
+            slices: Vec::new(),
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for ChannelMatrix<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() != self.matrix[0].len() {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "ChannelMatrix expects {} input channels, but got {}",
+                self.matrix[0].len(), buf.len())));
+        }
+
+        for (out, row) in self.channels.iter_mut().zip(self.matrix.iter()) {
+            out.clear();
+            out.extend((0..buf[0].len()).map(|i| {
+                let sum = buf.iter().zip(row.iter()).fold(0f64, |a, (c, &gain)| {
+                    a + gain * Sample::to_float::<f64>(c[i])
+                });
+                Sample::from_float(sum)
+            }));
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Shape of the gain ramp applied by `Fade`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FadeCurve {
+    /// Gain changes linearly with time.
+    Linear,
+    /// Gain follows a quarter sine wave, so the power changes linearly.
+    EqualPower,
+}
+
+impl FadeCurve {
+    /// Gain at sample `i` of a fade-in lasting `length` samples.
+    fn gain(&self, i: usize, length: usize) -> f64 {
+        if i >= length {
+            return 1f64;
+        }
+        let x = i as f64 / length as f64;
+        match *self {
+            FadeCurve::Linear => x,
+            FadeCurve::EqualPower => (x * ::std::f64::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+/// Ramps amplitude at the start or end of a stream.
+///
+/// A fade-in applies the ramp to the first `n` samples of each channel, reaching unity gain at
+/// sample `n`. A fade-out can't know where the stream ends until it sees `EndOfStream`, so it
+/// holds back the most recent `n` samples of each channel and applies the ramp to them once the
+/// source ends. This introduces `n` samples of latency.
+pub struct Fade<F, S> {
+    source: S,
+    length: usize,
+    curve: FadeCurve,
+    fade_out: bool,
+    /// Number of samples per channel passed so far, for fade-in.
+    position: usize,
+    /// Samples held back for fade-out.
+    held: Vec<Vec<F>>,
+    /// Set once the final faded samples have been yielded.
+    ended: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Fade<F, S> {
+    fn new(source: S, samples: usize, fade_out: bool) -> Fade<F, S> {
+        Fade {
+            source: source,
+            length: samples,
+            curve: FadeCurve::Linear,
+            fade_out: fade_out,
+            position: 0,
+            held: Vec::new(),
+            ended: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Fade in over the first `samples` samples of the stream.
+    pub fn fade_in(source: S, samples: usize) -> Fade<F, S> {
+        Fade::new(source, samples, false)
+    }
+
+    /// Fade out over the last `samples` samples of the stream.
+    pub fn fade_out(source: S, samples: usize) -> Fade<F, S> {
+        Fade::new(source, samples, true)
+    }
+
+    /// Select the shape of the fade, which is linear by default.
+    pub fn set_curve(&mut self, curve: FadeCurve) {
+        self.curve = curve;
+    }
+
+    fn next_in<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        let (curve, length, position) = (self.curve, self.length, self.position);
+        if position < length {
+            for channel in buf.iter_mut() {
+                for (i, sample) in channel.iter_mut().enumerate() {
+                    let x: f64 = Sample::to_float(*sample);
+                    *sample = Sample::from_float(x * curve.gain(position + i, length));
+                }
+            }
+        }
+        self.position += buf[0].len();
+        SourceResult::Buffer(buf)
+    }
+
+    fn next_out<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.ended {
+            return SourceResult::EndOfStream;
+        }
+
+        match self.source.next() {
+            SourceResult::Buffer(b) => {
+                self.held.resize(b.len(), Vec::new());
+                self.channels.resize(b.len(), Vec::new());
+                for (held, src) in self.held.iter_mut().zip(b.iter()) {
+                    held.extend(src.iter().cloned());
+                }
+            }
+            SourceResult::EndOfStream => {
+                // Apply the ramp to whatever has been held, ending at zero gain.
+                self.ended = true;
+                let len = self.held.get(0).map_or(0, |c| c.len());
+                if len == 0 {
+                    return SourceResult::EndOfStream;
+                }
+                let (curve, length) = (self.curve, self.length);
+                for (held, out) in self.held.iter_mut().zip(self.channels.iter_mut()) {
+                    out.clear();
+                    out.extend(held.iter().enumerate().map(|(i, &x)| {
+                        let x: f64 = Sample::to_float(x);
+                        Sample::from_float(x * curve.gain(len - 1 - i, length))
+                    }));
+                    held.clear();
+                }
+                return channel_buffer(&mut self.channels, &mut self.slices);
+            }
+            x => return x
+        }
+
+        // Yield everything except the most recent `length` samples.
+        let excess = self.held[0].len().saturating_sub(self.length);
+        for (held, out) in self.held.iter_mut().zip(self.channels.iter_mut()) {
+            out.clear();
+            out.extend(held[..excess].iter().cloned());
+            consume(held, excess);
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Fade<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.fade_out {
+            self.next_out()
+        } else {
+            self.next_in()
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CrossfadeState {
+    /// Playing the first source, holding back its most recent samples.
+    First,
+    /// The first source has ended, so the held samples are to be mixed with the second.
+    Overlap,
+    /// Playing the second source.
+    Second,
+}
+
+/// Plays one source then another, overlapping them with an equal-power crossfade.
+///
+/// Over the last `n` samples of `A`, `A` is ramped down while the first `n` samples of `B` are
+/// ramped up and mixed with it. As with a fade-out the end of `A` can't be known in advance, so
+/// its most recent `n` samples are held back until it ends, adding `n` samples of latency. If
+/// `A` is shorter than `n` the fade covers all of it, and if `B` ends during the fade it is
+/// treated as silence.
+///
+/// Both sources must have the same channel count and sample rate; there's no resampling, so a
+/// mismatch is a stream error. If `A` never reports a rate, `B`'s is passed on as the fade
+/// starts. Sample rate changes within `B` after the fade pass through.
+pub struct Crossfade<A, B> where A: Source {
+    a: A,
+    b: B,
+    length: usize,
+    state: CrossfadeState,
+    /// Most recent sample rate reported by `A`.
+    rate: Option<u32>,
+    /// Trailing samples of `A`.
+    held: Vec<Vec<A::Output>>,
+    /// Leading samples of `B` pulled for the fade but not yet yielded.
+    incoming: Vec<Vec<A::Output>>,
+    /// Whether `B` has yielded a buffer yet.
+    b_started: bool,
+    b_done: bool,
+    channels: Vec<Vec<A::Output>>,
+    slices: Vec<Slice<A::Output>>,
+}
+
+impl<A: Source, B: Source<Output=A::Output>> Crossfade<A, B> {
+    /// Play `a` then `b`, fading between them over `samples` samples.
+    pub fn new(a: A, b: B, samples: usize) -> Crossfade<A, B> {
+        Crossfade {
+            a: a,
+            b: b,
+            length: samples,
+            state: CrossfadeState::First,
+            rate: None,
+            held: Vec::new(),
+            incoming: Vec::new(),
+            b_started: false,
+            b_done: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+}
+
+impl<A: Source, B: Source<Output=A::Output>> Source for Crossfade<A, B> {
+    type Output = A::Output;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, A::Output> {
+        while self.state == CrossfadeState::First {
+            // As in Overlap, pulling again after a buffer that was entirely held back.
+            let a: *mut A = &mut self.a;
+            match unsafe { (*a).next() } {
+                SourceResult::Buffer(b) => {
+                    self.held.resize(b.len(), Vec::new());
+                    self.channels.resize(b.len(), Vec::new());
+                    for (held, src) in self.held.iter_mut().zip(b.iter()) {
+                        held.extend(src.iter().cloned());
+                    }
+
+                    // Yield everything except the most recent `length` samples, if there's
+                    // anything else.
+                    let excess = self.held[0].len().saturating_sub(self.length);
+                    if excess == 0 {
+                        continue;
+                    }
+                    for (held, out) in self.held.iter_mut().zip(self.channels.iter_mut()) {
+                        out.clear();
+                        out.extend(held[..excess].iter().cloned());
+                        consume(held, excess);
+                    }
+                    return channel_buffer(&mut self.channels, &mut self.slices);
+                }
+                SourceResult::SampleRate(r) => {
+                    self.rate = Some(r);
+                    return SourceResult::SampleRate(r);
+                }
+                SourceResult::EndOfStream => self.state = CrossfadeState::Overlap,
+                x => return x
+            }
+        }
+
+        if self.state == CrossfadeState::Overlap {
+            let len = self.held.get(0).map_or(0, |c| c.len());
+            // Pull enough of B to cover the fade, checking its format against A's.
+            while (!self.b_started || self.incoming[0].len() < len) && !self.b_done {
+                let b: *mut B = &mut self.b;
+                match unsafe { (*b).next() } {
+                    SourceResult::Buffer(buf) => {
+                        if !self.held.is_empty() && buf.len() != self.held.len() {
+                            return SourceResult::StreamError(StreamError::UnsupportedFormat(
+                                format!("Crossfade between sources with {} and {} channels",
+                                        self.held.len(), buf.len())));
+                        }
+                        self.b_started = true;
+                        self.incoming.resize(buf.len(), Vec::new());
+                        for (incoming, src) in self.incoming.iter_mut().zip(buf.iter()) {
+                            incoming.extend(src.iter().cloned());
+                        }
+                    }
+                    SourceResult::SampleRate(r) => match self.rate {
+                        Some(rate) if rate != r => {
+                            return SourceResult::StreamError(StreamError::UnsupportedFormat(
+                                format!("Crossfade between sources at {} Hz and {} Hz", rate, r)));
+                        }
+                        Some(_) => { }
+                        None => {
+                            // A never said, so this is news downstream.
+                            self.rate = Some(r);
+                            return SourceResult::SampleRate(r);
+                        }
+                    },
+                    SourceResult::EndOfStream => self.b_done = true,
+                    x => return x
+                }
+            }
+            self.state = CrossfadeState::Second;
+
+            if len > 0 {
+                let silence: A::Output = Sample::from_float(0f64);
+                self.channels.resize(self.held.len(), Vec::new());
+                for (c, out) in self.channels.iter_mut().enumerate() {
+                    let held = &self.held[c];
+                    let incoming = self.incoming.get(c).map_or(&[][..], |x| &x[..]);
+                    out.clear();
+                    out.extend((0..len).map(|i| {
+                        let a: f64 = Sample::to_float(held[i]);
+                        let b: f64 = Sample::to_float(*incoming.get(i).unwrap_or(&silence));
+                        let (gain_a, gain_b) = (FadeCurve::EqualPower.gain(len - i, len),
+                                                FadeCurve::EqualPower.gain(i, len));
+                        Sample::from_float(a * gain_a + b * gain_b)
+                    }));
+                }
+                for incoming in self.incoming.iter_mut() {
+                    let n = ::std::cmp::min(len, incoming.len());
+                    consume(incoming, n);
+                }
+                return channel_buffer(&mut self.channels, &mut self.slices);
+            }
+        }
+
+        // Yield the rest of whatever was pulled from B for the fade.
+        if self.incoming.get(0).map_or(false, |c| !c.is_empty()) {
+            mem::swap(&mut self.channels, &mut self.incoming);
+            for incoming in self.incoming.iter_mut() {
+                incoming.clear();
+            }
+            return channel_buffer(&mut self.channels, &mut self.slices);
+        }
+        if self.b_done {
+            return SourceResult::EndOfStream;
+        }
+        match self.b.next() {
+            SourceResult::EndOfStream => {
+                self.b_done = true;
+                SourceResult::EndOfStream
+            }
+            x => x
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        if self.state == CrossfadeState::First {
+            self.a.sample_rate()
+        } else {
+            self.b.sample_rate().or(self.rate)
+        }
+    }
+}
+
+/// Upstream state shared between the outputs of `split_channels`.
+struct SplitState<F, S> {
+    source: S,
+    /// Most recent buffer pulled from the source.
+    channels: Vec<Vec<F>>,
+    /// Whether each output has taken its channel of the current buffer.
+    consumed: Vec<bool>,
+    ended: bool,
+}
+
+impl<F: Sample, S: Source<Output=F>> SplitState<F, S> {
+    /// Pull a new buffer from upstream, returning false if the stream has ended.
+    fn pull(&mut self) -> bool {
+        loop {
+            match self.source.next() {
+                SourceResult::Buffer(b) => {
+                    self.channels.resize(b.len(), Vec::new());
+                    for (dst, src) in self.channels.iter_mut().zip(b.iter()) {
+                        dst.clear();
+                        dst.extend(src.iter().cloned());
+                    }
+                    for c in self.consumed.iter_mut() {
+                        *c = false;
+                    }
+                    return true;
+                }
+                SourceResult::SampleRate(_) => continue,
+                SourceResult::EndOfStream => { }
+                SourceResult::StreamError(e) => error!("split_channels upstream error: {}", e)
+            }
+            self.ended = true;
+            return false;
+        }
+    }
+}
+
+/// One channel of a `Source` split by `split_channels`.
+pub struct SplitChannel<F, S> {
+    shared: Rc<RefCell<SplitState<F, S>>>,
+    index: usize,
+    buf: Vec<F>,
+}
+
+/// Split a multi-channel `Source` into one `MonoSource` per channel.
+///
+/// The returned sources share a single upstream. A new buffer is only pulled from upstream once
+/// every output has taken its channel of the current buffer, so an output that gets ahead of its
+/// siblings yields empty buffers until they catch up. Consumers should therefore pull from all
+/// outputs in turn.
+///
+/// The first buffer is pulled immediately to determine the number of channels, so this returns
+/// no sources if the stream ends (or fails) before yielding any samples. If upstream changes its
+/// channel count mid-stream, channels with no corresponding output are discarded and outputs with
+/// no corresponding channel end.
+pub fn split_channels<F, S>(source: S) -> Vec<SplitChannel<F, S>> where
+        F: Sample, S: Source<Output=F> {
+    let mut state = SplitState {
+        source: source,
+        channels: Vec::new(),
+        consumed: Vec::new(),
+        ended: false
+    };
+    if !state.pull() {
+        return Vec::new();
+    }
+
+    let n = state.channels.len();
+    state.consumed = vec![false; n];
+    let shared = Rc::new(RefCell::new(state));
+    (0..n).map(|i| SplitChannel {
+        shared: shared.clone(),
+        index: i,
+        buf: Vec::new()
+    }).collect()
+}
+
+impl<F: Sample, S: Source<Output=F>> MonoSource for SplitChannel<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let mut state = self.shared.borrow_mut();
+        let state = &mut *state;
+
+        self.buf.clear();
+        if state.consumed[self.index] {
+            if !state.consumed.iter().all(|&c| c) {
+                // Waiting for other channels to catch up.
+                return Some(&mut self.buf);
+            }
+            if state.ended || !state.pull() {
+                return None;
+            }
+        }
+
+        state.consumed[self.index] = true;
+        match state.channels.get(self.index) {
+            Some(c) => self.buf.extend(c.iter().cloned()),
+            None => return None
+        }
+        Some(&mut self.buf)
+    }
+}
+
+/// Mixes two `MonoSource`s together.
+///
+/// The sources may yield buffers of different lengths. Samples from the source that got ahead are
+/// held until the other catches up, so no samples are lost and each output sample is the mix of
+/// samples at the same position in both inputs. Once one source ends, the remainder of the other
+/// passes through unmixed, and the mix ends when both have.
+pub struct Mix<A: MonoSource, B> {
+    sources: (A, B),
+    /// Samples pulled from each source but not yet mixed.
+    pending: (Vec<A::Output>, Vec<A::Output>),
+    /// Whether each source has ended.
+    ended: (bool, bool),
+    mode: MixMode,
+    out: Vec<A::Output>,
+}
+
+impl<A, B, F> Mix<A, B> where
+        A: MonoSource<Output=F>, B: MonoSource<Output=F> {
+    /// Create a mix of `a` and `b`.
+    pub fn new(a: A, b: B) -> Mix<A, B> {
+        Mix {
+            sources: (a, b),
+            pending: (Vec::new(), Vec::new()),
+            ended: (false, false),
+            mode: MixMode::Saturate,
+            out: Vec::new(),
+        }
+    }
+
+    /// Set the handling of overflow in hard-clipped formats, which saturates by default.
+    pub fn set_mode(&mut self, mode: MixMode) {
+        self.mode = mode;
+    }
+}
+
+/// Pull a buffer from `source` into `pending` if there are no samples pending.
+fn mix_pull<S, F>(source: &mut S, pending: &mut Vec<F>, ended: &mut bool) where
+        S: MonoSource<Output=F>, F: Copy {
+    if pending.is_empty() && !*ended {
+        match source.next() {
+            Some(b) => pending.extend(b.iter().cloned()),
+            None => *ended = true
+        }
+    }
+}
+
+/// Convert a `Duration` to seconds.
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+/// Remove the first `n` items from `v`.
+fn consume<F: Copy>(v: &mut Vec<F>, n: usize) {
+    let len = v.len();
+    for i in n..len {
+        v[i - n] = v[i];
+    }
+    v.truncate(len - n);
+}
+
+impl<A, B, F> MonoSource for Mix<A, B> where
+        A: MonoSource<Output=F>, B: MonoSource<Output=F>, F: Sample {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        mix_pull(&mut self.sources.0, &mut self.pending.0, &mut self.ended.0);
+        mix_pull(&mut self.sources.1, &mut self.pending.1, &mut self.ended.1);
+
+        let (ref mut a, ref mut b) = self.pending;
+        self.out.clear();
+        match self.ended {
+            (true, true) if a.is_empty() && b.is_empty() => return None,
+            // One source is exhausted, so pass the other through.
+            (true, _) if a.is_empty() => mem::swap(&mut self.out, b),
+            (_, true) if b.is_empty() => mem::swap(&mut self.out, a),
+            _ => {
+                let (n, mode) = (::std::cmp::min(a.len(), b.len()), self.mode);
+                self.out.extend(a[..n].iter().zip(b[..n].iter()).map(|(x, y)| x.mix_with(y, mode)));
+                consume(a, n);
+                consume(b, n);
+            }
+        }
+        Some(&mut self.out)
+    }
+}
+
+/// Mixes two multi-channel `Source`s together.
+///
+/// Channels are mixed pairwise, except that if either source has only one channel it is mixed
+/// into every channel of the other. Any other mismatch in channel count is a stream error, as are
+/// buffers of differing lengths. As with `Mix`, once one source ends the remainder of the other
+/// passes through unmixed, and the mix ends when both have.
+///
+/// Sample rates from both sources are forwarded, but if the sources report different rates the
+/// mix fails with a stream error since they cannot be meaningfully combined.
+pub struct MixSource<A: Source, B> {
+    sources: (A, B),
+    /// Most recent sample rate reported by each source.
+    rates: (Option<u32>, Option<u32>),
+    /// True if `channels` holds a buffer from `A` waiting for a buffer from `B`.
+    a_ready: bool,
+    /// Whether each source has ended.
+    ended: (bool, bool),
+    mode: MixMode,
+    channels: Vec<Vec<A::Output>>,
+    slices: Vec<Slice<A::Output>>,
+}
+
+impl<A, B, F> MixSource<A, B> where
+        A: Source<Output=F>, B: Source<Output=F> {
+    /// Create a mix of `a` and `b`.
+    pub fn new(a: A, b: B) -> MixSource<A, B> {
+        MixSource {
+            sources: (a, b),
+            rates: (None, None),
+            a_ready: false,
+            ended: (false, false),
+            mode: MixMode::Saturate,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Set the handling of overflow in hard-clipped formats, which saturates by default.
+    pub fn set_mode(&mut self, mode: MixMode) {
+        self.mode = mode;
+    }
+
+    fn check_rates<'a>(&self, rate: u32) -> SourceResult<'a, F> {
+        match self.rates {
+            (Some(a), Some(b)) if a != b => {
+                SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                    "Cannot mix sources with sample rates {} and {}", a, b)))
+            }
+            _ => SourceResult::SampleRate(rate)
+        }
+    }
+}
+
+impl<A, B, F> Source for MixSource<A, B> where
+        A: Source<Output=F>, B: Source<Output=F>, F: Sample {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if !self.a_ready && !self.ended.0 {
+            match self.sources.0.next() {
+                SourceResult::Buffer(b) => {
+                    if self.ended.1 {
+                        return SourceResult::Buffer(b);
+                    }
+                    self.channels.resize(b.len(), Vec::new());
+                    for (dst, src) in self.channels.iter_mut().zip(b.iter()) {
+                        dst.clear();
+                        dst.extend(src.iter().cloned());
+                    }
+                    self.a_ready = true;
+                }
+                SourceResult::SampleRate(r) => {
+                    self.rates.0 = Some(r);
+                    return self.check_rates(r);
+                }
+                SourceResult::EndOfStream => self.ended.0 = true,
+                x => return x
+            }
+        }
+        if self.ended.1 {
+            return SourceResult::EndOfStream;
+        }
+
+        let b = match self.sources.1.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.rates.1 = Some(r);
+                return self.check_rates(r);
+            }
+            SourceResult::EndOfStream => {
+                // Play out anything held from A, which continues alone.
+                self.ended.1 = true;
+                if !self.a_ready {
+                    return SourceResult::EndOfStream;
+                }
+                self.a_ready = false;
+                return channel_buffer(&mut self.channels, &mut self.slices);
+            }
+            x => return x
+        };
+        if self.ended.0 {
+            return SourceResult::Buffer(b);
+        }
+        self.a_ready = false;
+
+        // Broadcast a mono stream from `A` across all of `B`'s channels.
+        if self.channels.len() == 1 && b.len() > 1 {
+            let mono = self.channels[0].clone();
+            self.channels.resize(b.len(), mono);
+        }
+        if self.channels.len() != b.len() && b.len() != 1 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Cannot mix {} channels with {} channels", self.channels.len(), b.len())));
+        }
+        if self.channels[0].len() != b[0].len() {
+            return SourceResult::StreamError(StreamError::Other(format!(
+                "Cannot mix buffers of {} and {} samples", self.channels[0].len(), b[0].len())));
+        }
+
+        let mode = self.mode;
+        for (i, dst) in self.channels.iter_mut().enumerate() {
+            let src = &b[if b.len() == 1 { 0 } else { i }];
+            for (x, y) in dst.iter_mut().zip(src.iter()) {
+                *x = x.mix_with(y, mode);
+            }
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sources.0.sample_rate().or(self.sources.1.sample_rate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::{Sample, Source, SourceResult, MonoSource, Sink, Amplify};
+
+    /// Yields a copy of `data` from every call to `next`.
+    pub struct ConstantSource<F> {
+        pub data: Vec<F>,
+        pub sbuf: Vec<F>
+    }
+
+    impl<F: Sample + Clone> MonoSource for ConstantSource<F> {
+        type Output = F;
+
+        fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+            self.sbuf = self.data.clone();
+            Some(&mut self.sbuf)
+        }
+    }
+
+    impl<F> ::std::default::Default for ConstantSource<F> {
+        fn default() -> ConstantSource<F> {
+            ConstantSource {
+                data: vec![],
+                sbuf: vec![]
+            }
+        }
+    }
+
+    /// A single full-scale sample followed by silence, in buffers of `len` samples.
+    pub struct Impulse<F> {
+        buf: Vec<F>,
+        first: bool
+    }
+
+    impl<F: Sample> Impulse<F> {
+        pub fn new(len: usize) -> Impulse<F> {
+            Impulse {
+                buf: vec![Sample::from_float(0f64); len],
+                first: true
+            }
+        }
+    }
+
+    impl<F: Sample> MonoSource for Impulse<F> {
+        type Output = F;
+
+        fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+            for x in self.buf.iter_mut() {
+                *x = Sample::from_float(0f64);
+            }
+            if self.first {
+                self.buf[0] = Sample::from_float(1f64);
+                self.first = false;
+            }
+            Some(&mut self.buf)
+        }
+    }
+
+    /// Yields a copy of `data` from `count` calls to `next`, then ends.
+    pub struct FiniteSource<F> {
+        pub data: Vec<F>,
+        pub count: usize,
+        pub sbuf: Vec<F>
+    }
+
+    impl<F: Sample + Clone> MonoSource for FiniteSource<F> {
+        type Output = F;
+
+        fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+            if self.count == 0 {
+                return None;
+            }
+            self.count -= 1;
+            self.sbuf = self.data.clone();
+            Some(&mut self.sbuf)
+        }
+    }
+
+    #[test]
+    fn null_sink_counts_consumed() {
+        let src = super::CopyChannel::new(0, 1, FiniteSource {
+            data: vec![1i16, 2, 3],
+            count: 4,
+            sbuf: vec![]
+        }.adapt());
+        let mut sink = super::NullSink::new(src);
+        sink.run(&AtomicBool::new(false));
+        assert_eq!(sink.buffers(), 4);
+        assert_eq!(sink.samples(), 24);
+        assert_eq!(sink.run_once(), None);
+    }
+
+    #[bench]
+    fn null_sink_tone(b: &mut ::test::Bencher) {
+        use super::synth::Tone;
+
+        b.iter(|| {
+            let mut sink = super::NullSink::new(Tone::<i16>::new(4096, 100).adapt().take(65536));
+            sink.run(&AtomicBool::new(false));
+            sink.samples()
+        });
+    }
+
+    #[test]
+    fn test_chain() {
+        let a = FiniteSource { data: vec![1i16, 2], count: 2, sbuf: vec![] };
+        let b = FiniteSource { data: vec![3i16], count: 1, sbuf: vec![] };
+        let mut chain = a.adapt().chain(b.adapt());
+
+        let mut out = vec![];
+        loop {
+            match chain.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(out, vec![1, 2, 1, 2, 3]);
+        assert_eq!(chain.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn adapters_forward_sample_rate() {
+        use super::{BufferSource, Pan};
+
+        let a = BufferSource::new(vec![vec![1i16, 2]], 8000);
+        let b = BufferSource::new(vec![vec![3i16]], 16000);
+        let mut src: Box<Source<Output=f32>> =
+            Box::new(Pan::new(a.chain(b).amplify(0.5f32), 0f64).convert::<f32>());
+        assert_eq!(src.sample_rate(), Some(8000));
+        assert_eq!(src.next(), SourceResult::SampleRate(8000));
+        match src.next() {
+            SourceResult::Buffer(_) => { }
+            x => panic!("Unexpected result {:?}", x)
+        }
+        assert_eq!(src.next(), SourceResult::SampleRate(16000));
+        assert_eq!(src.sample_rate(), Some(16000));
+    }
+
+    #[test]
+    fn test_take() {
+        use super::synth::Tone;
+
+        let mut src = Tone::<i16>::new(300, 100).adapt().take(1000);
+        let mut lengths = vec![];
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => lengths.push(b[0].len()),
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(lengths, vec![300, 300, 300, 100]);
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn uninitialized_source_resizes() {
+        use super::UninitializedSource;
+
+        let mut src = UninitializedSource::<i16>::new(16);
+        assert_eq!(src.next().unwrap().len(), 16);
+        src.set_size(4);
+        assert_eq!(src.next().unwrap().len(), 4);
+        src.set_size(32);
+        assert_eq!(src.next().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let data: Vec<i16> = (0..2500).map(|x| x as i16).collect();
+        let mut src = super::from_iter(data.clone().into_iter());
+
+        let mut out = vec![];
+        let mut buffers = 0;
+        while let Some(b) = src.next() {
+            out.extend(b.iter().cloned());
+            buffers += 1;
+        }
+        assert_eq!(out, data);
+        assert_eq!(buffers, 3);
+    }
+
+    #[test]
+    fn buffer_source_plays_channels() {
+        use super::BufferSource;
+
+        let mut src = BufferSource::new(vec![vec![1i16, 2, 3, 4, 5], vec![-1, -2, -3, -4, -5]],
+                                        22050);
+        src.set_chunk(2);
+        assert_eq!(src.next(), SourceResult::SampleRate(22050));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [1i16, 2], &mut [-1i16, -2]]));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [3i16, 4], &mut [-3i16, -4]]));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [5i16], &mut [-5i16]]));
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+
+        let mut src = BufferSource::new(vec![vec![1i16, 2, 3]], 22050);
+        src.set_chunk(2);
+        src.set_looping(true);
+        assert_eq!(src.next(), SourceResult::SampleRate(22050));
+        for _ in 0..10 {
+            assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [1i16, 2]]));
+            assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [3i16]]));
+        }
+    }
+
+    #[test]
+    fn channel_source_plays_received_buffers() {
+        use std::sync::mpsc::channel;
+        use super::ChannelSource;
+
+        let (tx, rx) = channel();
+        tx.send(vec![vec![1i16, 2], vec![-1, -2]]).unwrap();
+        tx.send(vec![vec![3i16, 4, 5], vec![-3, -4, -5]]).unwrap();
+        drop(tx);
+
+        let mut src = ChannelSource::new(rx, 48000);
+        assert_eq!(src.next(), SourceResult::SampleRate(48000));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [1i16, 2], &mut [-1i16, -2]]));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [3i16, 4, 5],
+                                                          &mut [-3i16, -4, -5]]));
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let src = FiniteSource { data: vec![1i16, 2, 3], count: 1, sbuf: vec![] };
+        let mut repeat = src.repeat(Some(2));
+        let mut out = vec![];
+        while let Some(b) = repeat.next() {
+            out.extend(b.iter().cloned());
+        }
+        assert_eq!(out, vec![1, 2, 3, 1, 2, 3]);
+
+        let src = FiniteSource { data: vec![1i16, 2], count: 2, sbuf: vec![] };
+        let mut forever = src.repeat(None);
+        for _ in 0..10 {
+            assert_eq!(forever.next(), Some(&mut [1i16, 2][..]));
+        }
+    }
+
+    #[quickcheck]
+    fn mix_preserves_samples(a_len: u8, a_count: u8, b_len: u8, b_count: u8) -> bool {
+        let (a_count, b_count) = (a_count as usize % 8, b_count as usize % 8);
+        let a_total = a_len as usize * a_count;
+        let b_total = b_len as usize * b_count;
+
+        let mut mix = super::Mix::new(
+            FiniteSource { data: vec![1i16; a_len as usize], count: a_count, sbuf: vec![] },
+            FiniteSource { data: vec![2i16; b_len as usize], count: b_count, sbuf: vec![] }
+        );
+        let mut out = Vec::new();
+        while let Some(b) = mix.next() {
+            out.extend(b.iter().cloned());
+        }
+
+        let overlap = ::std::cmp::min(a_total, b_total);
+        out.len() == ::std::cmp::max(a_total, b_total)
+            && out[..overlap].iter().all(|&x| x == 3)
+            && out[overlap..].iter().all(|&x| x == if a_total > b_total { 1 } else { 2 })
+    }
+
+    #[quickcheck]
+    fn copychannel_copies_channels(xs: Vec<i16>) -> bool {
+        let mut src = super::CopyChannel::new(0, 1, ConstantSource::<i16> {
+            data: xs.clone(),
+            sbuf: vec![]
+        }.adapt());
+        if let SourceResult::Buffer(out) = src.next() {
+            out[1] == &xs[..] && out[0] == out[1]
+        } else {
+            unreachable!();
+        }
+    }
+
+    #[test]
+    fn copychannel_overwrites_in_place() {
+        use super::{CopyChannel, Pan};
+
+        let mono = ConstantSource::<i16> { data: vec![1, -2, 300], sbuf: vec![] }.adapt();
+        let mut src = CopyChannel::new(0, 1, Pan::new(mono, -1f64));
+        for _ in 0..3 {
+            assert_eq!(src.next(),
+                       SourceResult::Buffer(&mut [&mut [1i16, -2, 300], &mut [1i16, -2, 300]]));
+        }
+        // Nothing was copied through the scratch buffer.
+        assert_eq!(src.samples.capacity(), 0);
+    }
+
+    #[test]
+    fn convert_reaches_full_scale() {
+        use super::Convert;
+        use super::synth::Tone;
+
+        let mut src = Convert::<f32, i16, _>::new(Tone::<f32, f32>::new(100, 100).adapt());
+        match src.next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(b[0].len(), 100);
+                assert_eq!(b[0].iter().cloned().max(), Some(32767));
+                assert_eq!(b[0][0], 0);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn convert_to_same_format_is_exact() {
+        use super::Convert;
+
+        // Through f32, the low bits of these would be lost.
+        let src = ConstantSource::<i32> { data: vec![0x12345677, -0x7ffffffd], sbuf: vec![] };
+        let mut src = Convert::<i32, i32, _, f32>::new(src.adapt());
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [0x12345677i32, -0x7ffffffd]]));
+    }
+
+    #[test]
+    fn test_map() {
+        let mut src = ConstantSource::<i16> {
+            data: vec![0, 64, -128, 32767],
+            sbuf: vec![]
+        }.adapt().map(|x| -x);
+
+        assert_eq!(src.next(),
+                   SourceResult::Buffer(&mut [&mut [0i16, -64, 128, -32767]]));
+    }
+
+    #[test]
+    fn dynadapter_roundtrips_bytes() {
+        use super::{DynAdapter, DynamicSource};
+
+        let xs = vec![1i16, -2, 300, -32768];
+        let mut src = DynAdapter::from_source(ConstantSource::<i16> {
+            data: xs.clone(),
+            sbuf: vec![]
+        }.adapt());
+
+        let buf = src.next_dyn().unwrap();
+        assert_eq!(buf.sample_size, 16);
+        assert_eq!(buf.bytes.len(), 1);
+        assert_eq!(buf.bytes[0].len(), xs.len() * 2);
+
+        let samples = unsafe {
+            ::std::slice::from_raw_parts(buf.bytes[0].as_ptr() as *const i16, xs.len())
+        };
+        assert_eq!(samples, &xs[..]);
+    }
+
+    #[test]
+    fn mixsource_mixes_channels() {
+        use super::{CopyChannel, MixSource};
+
+        let stereo = |xs: Vec<i16>| {
+            CopyChannel::new(0, 1, ConstantSource { data: xs, sbuf: vec![] }.adapt())
+        };
+        let mut src = MixSource::new(stereo(vec![1, 2, 3]), stereo(vec![10, 20, 32767]));
+
+        assert_eq!(src.next(),
+                   SourceResult::Buffer(&mut [&mut [11i16, 22, 32767],
+                                              &mut [11i16, 22, 32767]]));
+    }
+
+    #[test]
+    fn mixsource_plays_out_longer_source() {
+        use super::MixSource;
+
+        let finite = |xs: Vec<i16>, n| FiniteSource { data: xs, count: n, sbuf: vec![] }.adapt();
+        for &(a, b) in [(1, 3), (3, 1)].iter() {
+            let mut src = MixSource::new(finite(vec![1, 2], a), finite(vec![10, 20], b));
+            assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [11i16, 22]]));
+            // The held buffer from A isn't lost when B ends first.
+            let rest = if a > b { [1i16, 2] } else { [10i16, 20] };
+            assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut rest.clone()[..]]));
+            assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut rest.clone()[..]]));
+            assert_eq!(src.next(), SourceResult::EndOfStream);
+        }
+    }
+
+    #[test]
+    fn pan_positions() {
+        use super::Pan;
+
+        let mono = || ConstantSource::<i16> { data: vec![0, 16384, -16384], sbuf: vec![] }.adapt();
+
+        let mut center = Pan::new(mono(), 0f64);
+        if let SourceResult::Buffer(b) = center.next() {
+            assert_eq!(b[0], b[1]);
+            assert!(b[0][1] > 0);
+        } else {
+            unreachable!();
+        }
+
+        let mut left = Pan::new(mono(), -1f64);
+        assert_eq!(left.next(),
+                   SourceResult::Buffer(&mut [&mut [0i16, 16384, -16384],
+                                              &mut [0i16, 0, 0]]));
+    }
+
+    #[test]
+    fn downmix_averages_channels() {
+        use super::{Pan, Downmix};
+
+        let mono = ConstantSource::<f32> { data: vec![0.0, 0.5, -0.5], sbuf: vec![] }.adapt();
+        let mut src = Downmix::new(Pan::new(mono, -1f64));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [0f32, 0.25, -0.25]]));
+    }
+
+    #[test]
+    fn channel_matrix_routes_channels() {
+        use super::{Pan, ChannelMatrix};
+
+        let stereo = || {
+            Pan::new(ConstantSource::<f32> { data: vec![0.0, 0.5, -0.5], sbuf: vec![] }.adapt(),
+                     -1f64)
+        };
+
+        let mut swap = ChannelMatrix::new(stereo(), vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+        assert_eq!(swap.next(),
+                   SourceResult::Buffer(&mut [&mut [0f32, 0.0, 0.0],
+                                              &mut [0f32, 0.5, -0.5]]));
+
+        let mut downmix = ChannelMatrix::new(stereo(), vec![vec![0.5, 0.5]]);
+        assert_eq!(downmix.next(), SourceResult::Buffer(&mut [&mut [0f32, 0.25, -0.25]]));
+
+        let mut mismatch = ChannelMatrix::new(stereo(), vec![vec![1.0, 0.0, 0.0]]);
+        match mismatch.next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected stream error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn split_channels_reconstructs_stream() {
+        use super::{Pan, split_channels};
+
+        let stereo = || {
+            Pan::new(ConstantSource::<f32> { data: vec![0.0, 0.5, -0.5], sbuf: vec![] }.adapt(),
+                     0.5)
+        };
+        let mut reference = stereo();
+        let mut split = split_channels(stereo());
+        assert_eq!(split.len(), 2);
+
+        for _ in 0..3 {
+            let expected = match reference.next() {
+                SourceResult::Buffer(b) => (b[0].to_vec(), b[1].to_vec()),
+                _ => unreachable!()
+            };
+            let left = split[0].next().unwrap().to_vec();
+            // Left is ahead of right, so must wait
+            assert_eq!(split[0].next().unwrap(), &[]);
+            let right = split[1].next().unwrap().to_vec();
+            assert_eq!((left, right), expected);
+        }
+    }
+
+    #[test]
+    fn fade_in_reaches_unity() {
+        use super::Fade;
+
+        let mut src = Fade::fade_in(ConstantSource { data: vec![1f32; 64], sbuf: vec![] }.adapt(),
+                                    100);
+        let mut out = Vec::new();
+        for _ in 0..2 {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                _ => unreachable!()
+            }
+        }
+
+        assert_eq!(out[0], 0f32);
+        assert_eq!(out[50], 0.5f32);
+        assert!(out[99] < 1f32);
+        assert!(out[100..].iter().all(|&x| x == 1f32));
+    }
+
+    #[test]
+    fn fade_out_ramps_to_zero() {
+        use super::Fade;
+
+        let mut src = Fade::fade_out(FiniteSource { data: vec![1f32; 10], count: 3, sbuf: vec![] }
+                                     .adapt(), 15);
+        let mut out = Vec::new();
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected {:?}", x)
+            }
+        }
+
+        assert_eq!(out.len(), 30);
+        assert!(out[..15].iter().all(|&x| x == 1f32));
+        assert!(out[15..].windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(out[29], 0f32);
+    }
+
+    #[test]
+    fn crossfade_keeps_power_and_samples() {
+        use super::Crossfade;
+
+        let run = |a: f32, b: f32| {
+            let a = FiniteSource { data: vec![a; 10], count: 3, sbuf: vec![] }.adapt();
+            let b = FiniteSource { data: vec![b; 10], count: 4, sbuf: vec![] }.adapt();
+            let mut src = Crossfade::new(a, b, 15);
+            let mut out = Vec::new();
+            loop {
+                match src.next() {
+                    SourceResult::Buffer(b) => {
+                        assert!(!b[0].is_empty(), "Crossfade yielded an empty buffer");
+                        out.extend(b[0].iter().cloned());
+                    }
+                    SourceResult::EndOfStream => break,
+                    x => panic!("Unexpected {:?}", x)
+                }
+            }
+            out
+        };
+        let a = run(1f32, 0f32);
+        let b = run(0f32, 1f32);
+
+        assert_eq!(a.len(), 30 + 40 - 15);
+        assert!(a[..15].iter().all(|&x| x == 1f32));
+        assert!(b[30..].iter().all(|&x| x == 1f32));
+        for i in 15..30 {
+            let power = a[i] * a[i] + b[i] * b[i];
+            assert!((power - 1f32).abs() < 1e-5, "Power at {} was {}", i, power);
+        }
+    }
+
+    #[test]
+    fn crossfade_reports_second_rate() {
+        use super::{Crossfade, BufferSource};
+
+        // A never reports a rate, so B's is passed on when the fade starts.
+        let a = FiniteSource { data: vec![1f32; 10], count: 2, sbuf: vec![] }.adapt();
+        let b = BufferSource::new(vec![vec![1f32; 10]], 22050);
+        let mut src = Crossfade::new(a, b, 5);
+        let mut events = Vec::new();
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => events.push(Ok(b[0].len())),
+                SourceResult::SampleRate(r) => events.push(Err(r)),
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected {:?}", x)
+            }
+        }
+        assert_eq!(events, vec![Ok(5), Ok(10), Err(22050), Ok(5), Ok(5)]);
+        assert_eq!(src.sample_rate(), Some(22050));
+    }
+
+    #[test]
+    fn crossfade_rejects_rate_mismatch() {
+        use super::{Crossfade, Endianness};
+        use super::raw::RawSource;
+
+        let bytes = [0u8; 20];
+        let a = RawSource::<i16, _>::new(&bytes[..], 1, 44100, Endianness::Little);
+        let b = RawSource::<i16, _>::new(&bytes[..], 1, 48000, Endianness::Little);
+        let mut src = Crossfade::new(a, b, 5);
+        assert_eq!(src.next(), SourceResult::SampleRate(44100));
+        assert!(match src.next() { SourceResult::Buffer(_) => true, _ => false });
+        match src.next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected stream error, got {:?}", x)
+        }
+    }
+
+    /// Sink counting calls, which sets a flag after `stop_at` calls and ends after `limit`.
+    struct CountingSink<'a> {
+        count: usize,
+        stop_at: usize,
+        limit: usize,
+        flag: &'a AtomicBool,
+    }
+
+    impl<'a> Sink for CountingSink<'a> {
+        fn run_once(&mut self) -> Option<()> {
+            self.count += 1;
+            if self.count == self.stop_at {
+                self.flag.store(true, Ordering::Release);
+            }
+            if self.count < self.limit { Some(()) } else { None }
+        }
+    }
+
+    #[test]
+    fn run_batched_checks_flag_between_batches() {
+        let flag = AtomicBool::new(false);
+        let mut sink = CountingSink { count: 0, stop_at: 6, limit: 100, flag: &flag };
+        sink.run_batched(&flag, 4);
+        assert_eq!(sink.count, 8);
+
+        let flag = AtomicBool::new(false);
+        let mut sink = CountingSink { count: 0, stop_at: 0, limit: 10, flag: &flag };
+        sink.run_batched(&flag, 4);
+        assert_eq!(sink.count, 10);
+
+        let flag = AtomicBool::new(false);
+        let mut sink = CountingSink { count: 0, stop_at: 6, limit: 100, flag: &flag };
+        sink.run(&flag);
+        assert_eq!(sink.count, 6);
+    }
+
+    #[test]
+    fn tee_consumers_see_same_buffers() {
+        use super::{Sink, Tee};
+        use std::cell::RefCell;
+
+        let seen = (RefCell::new(Vec::new()), RefCell::new(Vec::new()));
+        {
+            let mut n = 0;
+            let mut tee = Tee::new(FiniteSource { data: vec![1i16, 2, 3], count: 3, sbuf: vec![] }
+                                   .adapt()
+                                   .map(move |x| { n += 1; x * n }));
+            tee.add(|r: &SourceResult<i16>| {
+                if let SourceResult::Buffer(ref b) = *r {
+                    seen.0.borrow_mut().push(b[0].to_vec());
+                }
+                Some(())
+            });
+            tee.add(|r: &SourceResult<i16>| {
+                if let SourceResult::Buffer(ref b) = *r {
+                    seen.1.borrow_mut().push(b[0].to_vec());
+                }
+                Some(())
+            });
+
+            while tee.run_once().is_some() { }
+        }
+
+        assert_eq!(seen.0.borrow().len(), 3);
+        assert_eq!(seen.0, seen.1);
+    }
+
+    #[test]
+    fn test_amplify() {
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<i16> {
+                data: vec![0, 64, 128, 64, 0, -64, -128, -64, 0],
+                sbuf: vec![]
+            }.adapt(),
+            1.0
+        );
 
-    #[test]
-    fn test_amplify() {
-        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<i16> {
-                data: vec![0, 64, 128, 64, 0, -64, -128, -64, 0],
-                sbuf: vec![]
-            }.adapt(),
-            1.0
-        );
-
         assert_eq!(src.next(),
                    SourceResult::Buffer(
                        &mut [&mut [0i16, 64, 128, 64, 0, -64, -128, -64, 0]]
                    ));
+        assert_eq!(src.clip_count(), 0);
+    }
+
+    #[test]
+    fn amplify_counts_clips() {
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<i16> {
+                data: vec![32767, -32768, 16383, -16384, 0],
+                sbuf: vec![]
+            }.adapt(),
+            2.0
+        );
+        // -16384 doubles to just beyond -1, so it counts along with the extremes.
+        src.next();
+        assert_eq!(src.last_clip_count(), 3);
+        src.next();
+        assert_eq!(src.last_clip_count(), 3);
+        assert_eq!(src.clip_count(), 6);
+
+        // The most negative sample is beyond full scale even at unity gain.
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<i16> {
+                data: vec![32767, -32768, -32767],
+                sbuf: vec![]
+            }.adapt(),
+            1.0
+        );
+        src.next();
+        assert_eq!(src.last_clip_count(), 1);
+
+        // Overshoot is counted the same in both directions.
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<i16> {
+                data: vec![32767, -32767],
+                sbuf: vec![]
+            }.adapt(),
+            1.00002
+        );
+        src.next();
+        assert_eq!(src.last_clip_count(), 2);
+
+        // Soft-clipped formats can exceed full scale.
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<f32> {
+                data: vec![1.0, -1.0],
+                sbuf: vec![]
+            }.adapt(),
+            2.0
+        );
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [2.0f32, -2.0]]));
+        assert_eq!(src.clip_count(), 0);
+    }
+
+    #[test]
+    fn amplify_ramps_factor() {
+        let mut src = Amplify::<_, _, f32>::new(ConstantSource::<f32> {
+                data: vec![0.5; 48],
+                sbuf: vec![]
+            }.adapt(),
+            1.0
+        );
+        src.set_ramp_length(64);
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [0.5f32; 48]]));
+
+        // The ramp spans buffers, falling a little with every sample.
+        src.set_factor(0.0);
+        let mut out = vec![];
+        for _ in 0..2 {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert!(out[0] < 0.5 && out[0] > 0.49, "Jumped to {}", out[0]);
+        for w in out[..64].windows(2) {
+            assert!(w[1] < w[0], "Gain not decreasing: {:?}", w);
+        }
+        assert!(out[63].abs() < 1e-6);
+        assert!(out[64..].iter().all(|&x| x == 0.0));
     }
 }
@@ -0,0 +1,281 @@
+//! Level metering.
+
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{Sample, Source, SourceResult};
+
+/// Per-channel metering state.
+struct Channel {
+    /// Ring buffer of squared samples in the RMS window.
+    squares: Vec<f64>,
+    /// Next position to write in `squares`.
+    pos: usize,
+    /// Number of valid entries in `squares`.
+    filled: usize,
+    /// Running sum of `squares`.
+    sum: f64,
+    peak: f64,
+}
+
+impl Channel {
+    fn new(window: usize) -> Channel {
+        Channel {
+            squares: vec![0f64; window],
+            pos: 0,
+            filled: 0,
+            sum: 0f64,
+            peak: 0f64,
+        }
+    }
+
+    fn rms(&self) -> f64 {
+        if self.filled == 0 {
+            0f64
+        } else {
+            // Rounding error in the running sum can leave it slightly negative.
+            (self.sum.max(0f64) / self.filled as f64).sqrt()
+        }
+    }
+}
+
+/// Measures peak and RMS levels of a stream passing through it.
+///
+/// Samples are passed through unchanged. After each buffer the `(peak, rms)` level of every
+/// channel is published to the vector returned by `levels`, as a fraction of full scale.
+///
+/// RMS is computed over a sliding window of the most recent `window` samples in each channel,
+/// or all samples seen if fewer than that. Peak is the largest absolute sample in the most recent
+/// buffer, or the previous peak decayed by a per-sample factor (see `set_peak_decay`) if that is
+/// larger.
+pub struct LevelMeter<F, S> {
+    source: S,
+    window: usize,
+    peak_decay: f64,
+    channels: Vec<Channel>,
+    levels: Arc<RwLock<Vec<(f32, f32)>>>,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> LevelMeter<F, S> {
+    /// Construct a meter with a RMS window of `window` samples per channel.
+    ///
+    /// The peak initially doesn't decay, so it reflects only the most recent buffer.
+    pub fn new(source: S, window: usize) -> LevelMeter<F, S> {
+        assert!(window > 0, "RMS window must not be empty");
+        LevelMeter {
+            source: source,
+            window: window,
+            peak_decay: 0f64,
+            channels: Vec::new(),
+            levels: Arc::new(RwLock::new(Vec::new())),
+            format: PhantomData
+        }
+    }
+
+    /// Set the factor by which a held peak decays with each sample.
+    ///
+    /// 0 disables holding entirely, while 1 holds the maximum peak forever. A factor slightly
+    /// less than one gives a conventional falling peak indicator.
+    pub fn set_peak_decay(&mut self, decay: f64) {
+        self.peak_decay = decay;
+    }
+
+    /// Get a reference to the current levels.
+    ///
+    /// There is one `(peak, rms)` pair per channel in the stream.
+    pub fn levels(&self) -> Arc<RwLock<Vec<(f32, f32)>>> {
+        self.levels.clone()
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for LevelMeter<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        let window = self.window;
+        if self.channels.len() != buf.len() {
+            self.channels = (0..buf.len()).map(|_| Channel::new(window)).collect();
+        }
+
+        let decay = self.peak_decay.powi(buf[0].len() as i32);
+        for (samples, channel) in buf.iter().zip(self.channels.iter_mut()) {
+            let mut peak = 0f64;
+            for &sample in samples.iter() {
+                let x: f64 = Sample::to_float(sample);
+                let square = x * x;
+                channel.sum += square - channel.squares[channel.pos];
+                channel.squares[channel.pos] = square;
+                channel.pos = (channel.pos + 1) % window;
+                peak = peak.max(x.abs());
+            }
+            channel.filled = window.min(channel.filled + samples.len());
+            channel.peak = peak.max(channel.peak * decay);
+        }
+
+        {
+            let mut levels = self.levels.write().unwrap();
+            levels.clear();
+            levels.extend(self.channels.iter().map(|c| (c.peak as f32, c.rms() as f32)));
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Flags samples at or near full scale in a stream passing through it.
+///
+/// Samples are passed through unchanged. Whenever the absolute value of any sample, as a
+/// fraction of full scale, reaches the threshold, the flag returned by `clipped` is set. It stays
+/// set until no sample has reached the threshold for the hold time (see `set_hold`), so a UI
+/// polling it occasionally still sees brief clips.
+pub struct ClipDetect<F, S> {
+    source: S,
+    threshold: f64,
+    hold: usize,
+    /// Samples per channel remaining before the flag is cleared.
+    remaining: usize,
+    clipped: Arc<AtomicBool>,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> ClipDetect<F, S> {
+    /// Construct a detector flagging samples with magnitude of at least `threshold` times full
+    /// scale.
+    ///
+    /// There is initially no hold time, so the flag reflects only the most recent buffer.
+    pub fn new(source: S, threshold: f64) -> ClipDetect<F, S> {
+        ClipDetect {
+            source: source,
+            threshold: threshold,
+            hold: 0,
+            remaining: 0,
+            clipped: Arc::new(AtomicBool::new(false)),
+            format: PhantomData
+        }
+    }
+
+    /// Set how long the flag stays set after the last clip, in samples per channel.
+    ///
+    /// The hold is counted in stream time from the end of the buffer containing the clip.
+    pub fn set_hold(&mut self, samples: usize) {
+        self.hold = samples;
+    }
+
+    /// Get the clip flag.
+    ///
+    /// The flag is shared, so it may be read from another thread while the stream runs.
+    pub fn clipped(&self) -> Arc<AtomicBool> {
+        self.clipped.clone()
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for ClipDetect<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+
+        let threshold = self.threshold;
+        let clipped = buf.iter().any(|channel| channel.iter().any(|&x| {
+            Sample::to_float::<f64>(x).abs() >= threshold
+        }));
+        if clipped {
+            self.remaining = self.hold;
+        } else {
+            self.remaining = self.remaining.saturating_sub(buf[0].len());
+        }
+        self.clipped.store(clipped || self.remaining > 0, Ordering::Relaxed);
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::super::{Source, SourceResult, MonoSource};
+    use super::super::synth::Tone;
+    use super::super::tests::Impulse;
+    use super::{LevelMeter, ClipDetect};
+
+    #[test]
+    fn sine_levels() {
+        let mut meter = LevelMeter::new(Tone::<f64, f64>::new(1024, 100).adapt(), 1000);
+        let levels = meter.levels();
+        for _ in 0..4 {
+            assert!(match meter.next() {
+                SourceResult::Buffer(_) => true,
+                _ => false
+            });
+        }
+
+        let levels = levels.read().unwrap();
+        assert_eq!(levels.len(), 1);
+        let (peak, rms) = levels[0];
+        assert!((peak - 1f32).abs() < 1e-4, "Peak was {}", peak);
+        assert!((rms - 0.5f32.sqrt()).abs() < 1e-4, "RMS was {}", rms);
+    }
+
+    #[test]
+    fn peak_decays() {
+        let click = Impulse::<f64>::new(2);
+        let mut meter = LevelMeter::new(click.adapt(), 4);
+        meter.set_peak_decay(0.5);
+        let levels = meter.levels();
+
+        meter.next();
+        assert_eq!(levels.read().unwrap()[0], (1f32, 0.5f32.sqrt()));
+        // Two samples of decay
+        meter.next();
+        assert_eq!(levels.read().unwrap()[0], (0.25f32, 0.5f32));
+        meter.next();
+        assert_eq!(levels.read().unwrap()[0], (0.0625f32, 0f32));
+    }
+
+    #[test]
+    fn detects_full_scale() {
+        let mut detect = ClipDetect::new(Tone::<f64, f64>::new(100, 100).adapt(), 0.99);
+        let clipped = detect.clipped();
+        detect.next();
+        assert!(clipped.load(Ordering::Relaxed));
+
+        // -20 dB is well clear of the threshold.
+        let tone = Tone::<f64, f64>::new(100, 100).adapt().amplify(0.1f64);
+        let mut detect = ClipDetect::new(tone, 0.99);
+        let clipped = detect.clipped();
+        for _ in 0..4 {
+            detect.next();
+            assert!(!clipped.load(Ordering::Relaxed));
+        }
+    }
+
+    #[test]
+    fn holds_clip_flag() {
+        let click = Impulse::<f64>::new(2);
+        let mut detect = ClipDetect::new(click.adapt(), 1.0);
+        detect.set_hold(3);
+        let clipped = detect.clipped();
+
+        let mut flags = vec![];
+        for _ in 0..4 {
+            detect.next();
+            flags.push(clipped.load(Ordering::Relaxed));
+        }
+        assert_eq!(flags, vec![true, true, false, false]);
+    }
+}
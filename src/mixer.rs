@@ -0,0 +1,355 @@
+//! Multi-track mixing.
+//!
+//! `Mixer` sums any number of tracks, each with its own gain and mute and solo switches. The
+//! switches are held in a `MixerControls` which may be cloned and sent to another thread (such
+//! as a user interface), so tracks can be adjusted while the mixer runs.
+
+use num::NumCast;
+use std::raw::Slice;
+use std::sync::{Arc, Mutex};
+use super::{Sample, Source, SourceResult, StreamError, channel_buffer, consume};
+
+/// Settings for one track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TrackControls {
+    gain: f64,
+    mute: bool,
+    solo: bool,
+}
+
+/// Handle for adjusting a `Mixer`'s tracks.
+///
+/// Tracks are identified by the index returned from `Mixer::add_track`. Changes take effect from
+/// the next buffer the mixer yields.
+#[derive(Clone)]
+pub struct MixerControls {
+    tracks: Arc<Mutex<Vec<TrackControls>>>,
+}
+
+impl MixerControls {
+    fn update<G: FnOnce(&mut TrackControls)>(&self, track: usize, f: G) {
+        let mut tracks = self.tracks.lock().unwrap();
+        assert!(track < tracks.len(), "No track {} in mixer of {} tracks", track, tracks.len());
+        f(&mut tracks[track]);
+    }
+
+    /// Set the linear gain applied to a track.
+    pub fn set_gain(&self, track: usize, gain: f64) {
+        self.update(track, |t| t.gain = gain);
+    }
+
+    /// Silence a track, or restore it.
+    pub fn set_mute(&self, track: usize, mute: bool) {
+        self.update(track, |t| t.mute = mute);
+    }
+
+    /// Select whether a track is soloed.
+    ///
+    /// While any track is soloed, only soloed tracks are heard. Mute takes precedence over solo.
+    pub fn set_solo(&self, track: usize, solo: bool) {
+        self.update(track, |t| t.solo = solo);
+    }
+}
+
+struct Track<F> {
+    source: Box<Source<Output=F>>,
+    /// Samples received but not yet mixed, per channel.
+    pending: Vec<Vec<F>>,
+    ended: bool,
+}
+
+/// Sums several tracks into one stream.
+///
+/// Each track is scaled by its gain (applied in `f64`) and the results summed with
+/// `Sample::mix`, so hard-clipped formats saturate. Alternatively the mixer can average the
+/// tracks (see `set_average`), summing in `Sample::Wide` so nothing clips before the sum is
+/// scaled back down. The output has as many channels as the widest
+/// track; mono tracks are mixed into every channel, and any other mismatch in channel count is a
+/// stream error. Tracks may yield buffers of any length: samples are held until every track has
+/// reached the same point, so the mix stays aligned. Muted and silenced tracks are still pulled
+/// from, so they stay in time with the others.
+///
+/// Tracks which end fall silent while the rest continue, and the mix ends when every track has.
+/// All tracks must report the same sample rate, which is forwarded once.
+pub struct Mixer<F: Sample> {
+    tracks: Vec<Track<F>>,
+    controls: MixerControls,
+    average: bool,
+    rate: Option<u32>,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+    /// Running sums for averaging, and the number of tracks contributing to each.
+    sums: Vec<F::Wide>,
+    counts: Vec<usize>,
+}
+
+impl<F: Sample> Mixer<F> {
+    /// Construct a mixer with no tracks.
+    pub fn new() -> Mixer<F> {
+        Mixer {
+            tracks: Vec::new(),
+            controls: MixerControls { tracks: Arc::new(Mutex::new(Vec::new())) },
+            average: false,
+            rate: None,
+            channels: Vec::new(),
+            slices: Vec::new(),
+            sums: Vec::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    /// Add a track at unity gain, returning its index.
+    pub fn add_track<S>(&mut self, source: S) -> usize where S: Source<Output=F> + 'static {
+        self.tracks.push(Track {
+            source: Box::new(source),
+            pending: Vec::new(),
+            ended: false,
+        });
+        self.controls.tracks.lock().unwrap().push(TrackControls {
+            gain: 1f64,
+            mute: false,
+            solo: false,
+        });
+        self.tracks.len() - 1
+    }
+
+    /// Select whether to output the average of the audible tracks rather than their sum.
+    ///
+    /// Samples are summed in the format's wide type then divided by the number of tracks
+    /// contributing to the mix, so however many tracks there are the mix only clips if the
+    /// average does.
+    pub fn set_average(&mut self, average: bool) {
+        self.average = average;
+    }
+
+    /// Get a handle to the track controls.
+    pub fn controls(&self) -> MixerControls {
+        self.controls.clone()
+    }
+
+    /// Pull from track `i` until it has samples pending or ends.
+    ///
+    /// Returns a result to yield immediately, if any.
+    fn fill<'a>(&mut self, i: usize) -> Option<SourceResult<'a, F>> {
+        let track = &mut self.tracks[i];
+        while !track.ended && track.pending.first().map_or(true, |p| p.is_empty()) {
+            let buf = match track.source.next() {
+                SourceResult::Buffer(b) => b,
+                SourceResult::SampleRate(r) => {
+                    match self.rate {
+                        Some(rate) if rate != r => {
+                            return Some(SourceResult::StreamError(
+                                StreamError::UnsupportedFormat(format!(
+                                    "Cannot mix track at {} Hz with others at {} Hz", r, rate))));
+                        }
+                        Some(_) => continue,
+                        None => {
+                            self.rate = Some(r);
+                            return Some(SourceResult::SampleRate(r));
+                        }
+                    }
+                }
+                SourceResult::EndOfStream => {
+                    track.ended = true;
+                    break;
+                }
+                SourceResult::StreamError(e) => return Some(SourceResult::StreamError(e))
+            };
+
+            if track.pending.is_empty() {
+                track.pending = vec![Vec::new(); buf.len()];
+            } else if buf.len() != track.pending.len() {
+                return Some(SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                    "Mixer track {} got {} channels after {}", i, buf.len(),
+                    track.pending.len()))));
+            }
+            for (p, channel) in track.pending.iter_mut().zip(buf.iter()) {
+                p.extend(channel.iter().cloned());
+            }
+        }
+        None
+    }
+}
+
+impl<F: Sample> Source for Mixer<F> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        for i in 0..self.tracks.len() {
+            if let Some(result) = self.fill(i) {
+                return result;
+            }
+        }
+
+        let pending = |t: &Track<F>| t.pending.first().map_or(0, |p| p.len());
+        // Mix up to the end of the shortest live track, or drain whatever ended tracks left.
+        let n = match self.tracks.iter().filter(|t| !t.ended).map(&pending).min() {
+            Some(n) => n,
+            None => self.tracks.iter().map(&pending).max().unwrap_or(0)
+        };
+        if n == 0 {
+            return SourceResult::EndOfStream;
+        }
+
+        let width = self.tracks.iter().map(|t| t.pending.len()).max().unwrap_or(0);
+        let mismatched = |t: &&Track<F>| t.pending.len() > 1 && t.pending.len() != width;
+        if let Some(t) = self.tracks.iter().find(mismatched) {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Cannot mix {} channels with {} channels", t.pending.len(), width)));
+        }
+
+        let controls = self.controls.tracks.lock().unwrap().clone();
+        let soloed = controls.iter().any(|c| c.solo);
+        let silence: F = Sample::from_float(0f64);
+        let audible = |&(track, ctl): &(&Track<F>, &TrackControls)| {
+            !ctl.mute && (!soloed || ctl.solo) && !track.pending.is_empty()
+        };
+        let average = self.average;
+        self.channels.resize(width, Vec::new());
+        for (c, out) in self.channels.iter_mut().enumerate() {
+            out.clear();
+            out.resize(n, silence);
+            self.sums.clear();
+            self.sums.resize(n, silence.widen());
+            self.counts.clear();
+            self.counts.resize(n, 0);
+            for (track, ctl) in self.tracks.iter().zip(controls.iter()).filter(&audible) {
+                let src = &track.pending[if track.pending.len() == 1 { 0 } else { c }];
+                // An ended track may run out part way through, and only counts up to there.
+                let samples = out.iter_mut().zip(self.sums.iter_mut()).zip(self.counts.iter_mut());
+                for (((x, sum), count), &y) in samples.zip(src.iter()) {
+                    let y: F = Sample::from_float(Sample::to_float::<f64>(y) * ctl.gain);
+                    if average {
+                        *sum = *sum + y.widen();
+                        *count += 1;
+                    } else {
+                        *x = x.mix(&y);
+                    }
+                }
+            }
+            if average {
+                let sums = self.sums.iter().zip(self.counts.iter());
+                for (x, (&sum, &count)) in out.iter_mut().zip(sums) {
+                    if count > 0 {
+                        let count: F::Wide = NumCast::from(count).unwrap();
+                        *x = Sample::narrow(sum / count);
+                    }
+                }
+            }
+        }
+
+        for track in self.tracks.iter_mut() {
+            for p in track.pending.iter_mut() {
+                let k = ::std::cmp::min(n, p.len());
+                consume(p, k);
+            }
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Sample, Source, SourceResult, BufferSource};
+    use super::Mixer;
+
+    fn track<F: Sample>(data: Vec<Vec<F>>) -> BufferSource<F> {
+        let mut src = BufferSource::new(data, 44100);
+        src.set_chunk(3);
+        src
+    }
+
+    /// Mix to the end, returning each output channel.
+    fn run<F: Sample>(mixer: &mut Mixer<F>) -> Vec<Vec<F>> {
+        assert_eq!(mixer.next(), SourceResult::SampleRate(44100));
+        let mut out: Vec<Vec<F>> = vec![];
+        loop {
+            match mixer.next() {
+                SourceResult::Buffer(b) => {
+                    out.resize(b.len(), vec![]);
+                    for (o, c) in out.iter_mut().zip(b.iter()) {
+                        o.extend(c.iter().cloned());
+                    }
+                }
+                SourceResult::EndOfStream => return out,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+
+    /// A mono track and a stereo track.
+    fn two_tracks() -> Mixer<f64> {
+        let mut mixer = Mixer::new();
+        mixer.add_track(track(vec![vec![0.5, 0.25, 0.125, 0.0625]]));
+        mixer.add_track(track(vec![vec![0.25; 4], vec![-0.25; 4]]));
+        mixer
+    }
+
+    #[test]
+    fn sums_with_gain() {
+        let mut mixer = two_tracks();
+        mixer.add_track(track(vec![vec![0.125; 5]]));
+        mixer.controls().set_gain(0, 0.5);
+
+        // The mono tracks are broadcast, and the longer track continues alone.
+        assert_eq!(run(&mut mixer), vec![vec![0.625, 0.5, 0.4375, 0.40625, 0.125],
+                                         vec![0.125, 0.0, -0.0625, -0.09375, 0.125]]);
+
+        // Hard-clipped formats saturate.
+        let mut mixer = Mixer::new();
+        mixer.add_track(track(vec![vec![30000i16; 2]]));
+        mixer.add_track(track(vec![vec![30000i16; 2]]));
+        assert_eq!(run(&mut mixer), vec![vec![32767; 2]]);
+    }
+
+    #[test]
+    fn average_has_headroom() {
+        let mut mixer = Mixer::new();
+        for _ in 0..16 {
+            mixer.add_track(track(vec![vec![16384i16; 4], vec![-16384i16; 4]]));
+        }
+        mixer.set_average(true);
+        assert_eq!(run(&mut mixer), vec![vec![16384; 4], vec![-16384; 4]]);
+
+        // Muted tracks don't count towards the average.
+        let mut mixer = two_tracks();
+        mixer.set_average(true);
+        mixer.controls().set_mute(0, true);
+        assert_eq!(run(&mut mixer), vec![vec![0.25; 4], vec![-0.25; 4]]);
+
+        // Nor do tracks which have ended.
+        let mut mixer = Mixer::new();
+        mixer.add_track(track(vec![vec![0.5; 2]]));
+        mixer.add_track(track(vec![vec![0.25; 5]]));
+        mixer.set_average(true);
+        assert_eq!(run(&mut mixer), vec![vec![0.375, 0.375, 0.25, 0.25, 0.25]]);
+    }
+
+    #[test]
+    fn mute_silences_track() {
+        let mut mixer = two_tracks();
+        mixer.controls().set_mute(1, true);
+        assert_eq!(run(&mut mixer), vec![vec![0.5, 0.25, 0.125, 0.0625]; 2]);
+    }
+
+    #[test]
+    fn solo_silences_others() {
+        let mut mixer = two_tracks();
+        mixer.add_track(track(vec![vec![0.5; 4]]));
+        let controls = mixer.controls();
+        controls.set_solo(1, true);
+        controls.set_solo(2, true);
+        assert_eq!(run(&mut mixer), vec![vec![0.75; 4], vec![0.25; 4]]);
+
+        // Muting a soloed track still silences it.
+        let mut mixer = two_tracks();
+        let controls = mixer.controls();
+        controls.set_solo(1, true);
+        controls.set_mute(1, true);
+        assert_eq!(run(&mut mixer), vec![vec![0.0; 4]; 2]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Peak normalization.
+
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, StreamError, channel_buffer};
+
+/// Everything a source yielded, in order.
+enum Event<F> {
+    Rate(u32),
+    Buffer(Vec<Vec<F>>),
+    Error(StreamError),
+}
+
+/// Scales a whole stream so its largest sample reaches a target peak.
+///
+/// Because the gain depends on the peak of the entire stream, the source is read to its end on the
+/// first call to `next` and every sample is held in memory until it has been replayed. The memory
+/// cost is therefore the full decoded size of the stream: about 10 MB per minute of 16-bit
+/// stereo at 44.1 kHz. Sample rates and errors from the source are replayed in their original
+/// positions; an error ends reading, and the stream ends after it is replayed.
+///
+/// Gain is computed and applied in `f64`. Results beyond full scale are clipped in hard-clipped
+/// formats, which can only happen with a target over 1. A silent stream is left unchanged.
+pub struct Normalizer<F, S> {
+    source: Option<S>,
+    target: f64,
+    /// Events not yet replayed, in reverse order.
+    events: Vec<Event<F>>,
+    /// Most recent sample rate replayed.
+    rate: Option<u32>,
+    current: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Normalizer<F, S> {
+    /// Construct a normalizer scaling `source` to peak at `target`, a linear fraction of full scale.
+    ///
+    /// For a target in dBFS, use `10^(dB / 20)`.
+    pub fn new(source: S, target: f64) -> Normalizer<F, S> {
+        Normalizer {
+            source: Some(source),
+            target: target,
+            events: Vec::new(),
+            rate: None,
+            current: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Read the entire source and scale it.
+    fn scan(&mut self, mut source: S) {
+        let mut peak = 0f64;
+        loop {
+            let event = match source.next() {
+                SourceResult::Buffer(b) => {
+                    for channel in b.iter() {
+                        for &x in channel.iter() {
+                            peak = peak.max(Sample::to_float::<f64>(x).abs());
+                        }
+                    }
+                    Event::Buffer(b.iter().map(|c| c.to_vec()).collect())
+                }
+                SourceResult::SampleRate(r) => Event::Rate(r),
+                SourceResult::StreamError(e) => Event::Error(e),
+                SourceResult::EndOfStream => break,
+            };
+            let is_error = match event {
+                Event::Error(_) => true,
+                _ => false
+            };
+            self.events.push(event);
+            if is_error {
+                break;
+            }
+        }
+
+        if peak > 0f64 {
+            let gain = self.target / peak;
+            for event in self.events.iter_mut() {
+                if let Event::Buffer(ref mut channels) = *event {
+                    for channel in channels.iter_mut() {
+                        for x in channel.iter_mut() {
+                            *x = Sample::from_float(Sample::to_float::<f64>(*x) * gain);
+                        }
+                    }
+                }
+            }
+        }
+        self.events.reverse();
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Normalizer<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if let Some(source) = self.source.take() {
+            self.scan(source);
+        }
+
+        match self.events.pop() {
+            None => SourceResult::EndOfStream,
+            Some(Event::Rate(r)) => {
+                self.rate = Some(r);
+                SourceResult::SampleRate(r)
+            }
+            Some(Event::Error(e)) => SourceResult::StreamError(e),
+            Some(Event::Buffer(channels)) => {
+                // Frees the previous buffer as we go.
+                self.current = channels;
+                channel_buffer(&mut self.current, &mut self.slices)
+            }
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        match self.source {
+            Some(ref source) => source.sample_rate(),
+            None => self.rate
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Source, SourceResult, MonoSource};
+    use super::super::tests::FiniteSource;
+    use super::Normalizer;
+
+    #[test]
+    fn scales_to_full_scale() {
+        let src = FiniteSource { data: vec![0.25f64, -0.5, 0.1], count: 3, sbuf: vec![] };
+        let mut normalizer = Normalizer::new(src.adapt(), 1f64);
+
+        for _ in 0..3 {
+            assert_eq!(normalizer.next(), SourceResult::Buffer(&mut [&mut [0.5f64, -1.0, 0.2]]));
+        }
+        assert_eq!(normalizer.next(), SourceResult::EndOfStream);
+    }
+}
@@ -0,0 +1,125 @@
+//! Pacing streams to wall-clock time.
+
+use std::marker::PhantomData;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Sample, Source, SourceResult, seconds, DEFAULT_SAMPLE_RATE};
+
+/// Limits a stream to real-time speed.
+///
+/// Each buffer is held until the wall-clock time since the first buffer reaches the stream time
+/// at which it starts, computed from the sample rate and the lengths of the buffers before it.
+/// Other results pass through immediately.
+///
+/// Deadlines are measured against a monotonic clock from the start of the stream rather than by
+/// sleeping for each buffer's duration, so oversleeping on one buffer is made up on the next and
+/// timing errors don't accumulate. If the source or consumer can't keep up, buffers pass through
+/// without waiting until the stream catches up with the clock; `drift` reports how late the
+/// most recent buffer was.
+pub struct Throttle<F, S> {
+    source: S,
+    rate: u32,
+    /// Wall-clock time of the first buffer.
+    start: Option<Instant>,
+    /// Stream time in seconds up to the last rate change.
+    base: f64,
+    /// Frames emitted since the last rate change.
+    frames: u64,
+    drift: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Throttle<F, S> {
+    /// Construct a throttle pacing `source`.
+    pub fn new(source: S) -> Throttle<F, S> {
+        Throttle {
+            source: source,
+            rate: DEFAULT_SAMPLE_RATE,
+            start: None,
+            base: 0f64,
+            frames: 0,
+            drift: 0f64,
+            format: PhantomData
+        }
+    }
+
+    /// Get how late the most recent buffer was emitted, in seconds.
+    ///
+    /// This stays near zero while the stream keeps pace with the clock, and grows if it falls
+    /// behind.
+    pub fn drift(&self) -> f64 {
+        self.drift
+    }
+
+    fn stream_time(&self) -> f64 {
+        self.base + self.frames as f64 / self.rate as f64
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Throttle<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.base = self.stream_time();
+                self.frames = 0;
+                self.rate = r;
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        let start = match self.start {
+            Some(t) => t,
+            None => {
+                let now = Instant::now();
+                self.start = Some(now);
+                now
+            }
+        };
+        let deadline = self.stream_time();
+        let elapsed = seconds(start.elapsed());
+        if elapsed < deadline {
+            let wait = deadline - elapsed;
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1e9) as u32));
+        }
+        self.drift = (seconds(start.elapsed()) - deadline).max(0f64);
+
+        self.frames += buf[0].len() as u64;
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::super::{Source, SourceResult, BufferSource, seconds};
+    use super::Throttle;
+
+    #[test]
+    fn paces_to_real_time() {
+        let mut data = BufferSource::new(vec![vec![0f32; 48510]], 44100);
+        data.set_chunk(4410);
+        let mut src = Throttle::new(data);
+        assert_eq!(src.next(), SourceResult::SampleRate(44100));
+
+        // The first buffer starts the clock, so eleven buffers end a second later.
+        let start = Instant::now();
+        for _ in 0..11 {
+            match src.next() {
+                SourceResult::Buffer(b) => assert_eq!(b[0].len(), 4410),
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        let elapsed = seconds(start.elapsed());
+        assert!((elapsed - 1f64).abs() < 0.1, "Took {} seconds", elapsed);
+        assert!(src.drift() < 0.1, "Drifted by {} seconds", src.drift());
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+}
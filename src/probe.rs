@@ -0,0 +1,318 @@
+//! Container format detection.
+//!
+//! `open` recognizes a stream by its first few bytes and constructs the matching decoder, so
+//! callers handed an arbitrary file needn't know what it contains.
+
+use std::io::{self, Cursor, Read};
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, Endianness, Convert, I24, read_full, channel_buffer};
+use super::StreamError as Error;
+use super::raw::RawSource;
+use super::wav::WavSource;
+
+/// Number of bytes examined to identify a stream.
+const PROBE_LEN: usize = 12;
+
+/// Length of the fixed part of an AU header.
+const AU_HEADER_LEN: usize = 24;
+
+/// Container formats recognized by `detect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// RIFF WAVE.
+    Wav,
+    /// Ogg, assumed to contain Vorbis.
+    Ogg,
+    /// Sun/NeXT AU.
+    Au,
+}
+
+/// Identify a stream from its first bytes.
+///
+/// `prefix` should be at least 12 bytes, since shorter prefixes may not be recognized.
+pub fn detect(prefix: &[u8]) -> Option<Format> {
+    if prefix.len() >= 12 && &prefix[..4] == b"RIFF" && &prefix[8..12] == b"WAVE" {
+        Some(Format::Wav)
+    } else if prefix.starts_with(b"OggS") {
+        Some(Format::Ogg)
+    } else if prefix.starts_with(b".snd") {
+        Some(Format::Au)
+    } else {
+        None
+    }
+}
+
+/// Open a decoder for the stream read from `reader`, converting samples to `F`.
+///
+/// The format is identified with `detect`. Bytes read to identify it are replayed to the
+/// decoder, so `reader` needn't support seeking. Ogg streams require the `vorbisfile` feature.
+/// AU streams may be μ-law, or linear PCM in any of the formats with a `Sample` implementation;
+/// μ-law is expanded to 16 bits. Other AU encodings, such as A-law, are not supported.
+///
+/// Errors reading the prefix, and unrecognized formats, are returned immediately. Whether other
+/// errors are returned here or from the first call to `next` depends on the decoder: WAV headers
+/// aren't parsed until the stream is first read.
+pub fn open<F, R>(mut reader: R) -> Result<Box<Source<Output=F>>, Error> where
+        F: Sample,
+        R: Read + 'static {
+    let mut prefix = vec![0u8; PROBE_LEN];
+    let n = try!(read_full(&mut reader, &mut prefix).map_err(Error::Io));
+    prefix.truncate(n);
+    let format = detect(&prefix);
+    let reader = Cursor::new(prefix).chain(reader);
+
+    match format {
+        Some(Format::Wav) => Ok(Box::new(WavSource::<F, _>::open(reader))),
+        Some(Format::Ogg) => open_ogg(reader),
+        Some(Format::Au) => open_au(reader),
+        None => Err(Error::UnsupportedFormat("Unrecognized stream format".to_string()))
+    }
+}
+
+#[cfg(feature = "vorbisfile")]
+fn open_ogg<F, R>(reader: R) -> Result<Box<Source<Output=F>>, Error> where
+        F: Sample,
+        R: Read + 'static {
+    use super::vorbis::{VorbisStream, stream_error};
+
+    let stream = try!(VorbisStream::open(reader).map_err(stream_error));
+    Ok(Box::new(Convert::<f32, F, _>::new(stream)))
+}
+
+#[cfg(not(feature = "vorbisfile"))]
+fn open_ogg<F, R>(_: R) -> Result<Box<Source<Output=F>>, Error> where
+        F: Sample,
+        R: Read + 'static {
+    Err(Error::UnsupportedFormat("Ogg requires the vorbisfile feature".to_string()))
+}
+
+/// Read a big-endian `u32` from the start of `bytes`.
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+/// Parse an AU header and construct a PCM decoder for the data following it.
+fn open_au<F, R>(mut reader: R) -> Result<Box<Source<Output=F>>, Error> where
+        F: Sample,
+        R: Read + 'static {
+    let mut header = [0u8; AU_HEADER_LEN];
+    if try!(read_full(&mut reader, &mut header).map_err(Error::Io)) < AU_HEADER_LEN {
+        return Err(Error::Decode("AU header is truncated".to_string()));
+    }
+    let offset = be_u32(&header[4..]) as u64;
+    let size = be_u32(&header[8..]);
+    let encoding = be_u32(&header[12..]);
+    let rate = be_u32(&header[16..]);
+    let channels = be_u32(&header[20..]) as usize;
+    if offset < AU_HEADER_LEN as u64 {
+        return Err(Error::Decode(format!("AU data offset {} is within the header", offset)));
+    }
+    if channels == 0 {
+        return Err(Error::Decode("AU stream has no channels".to_string()));
+    }
+
+    // Skip the annotation between the header and the data.
+    let skip = offset - AU_HEADER_LEN as u64;
+    if try!(io::copy(&mut (&mut reader).take(skip), &mut io::sink()).map_err(Error::Io)) < skip {
+        return Err(Error::Decode("AU header is truncated".to_string()));
+    }
+
+    // A size of all ones means the length is unknown, so read to the end.
+    if size == !0 {
+        au_pcm(reader, encoding, channels, rate)
+    } else {
+        au_pcm(reader.take(size as u64), encoding, channels, rate)
+    }
+}
+
+/// Expand a G.711 μ-law byte to a linear 16-bit sample.
+fn expand_mulaw(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 7;
+    let mantissa = (byte & 0x0f) as i16;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Expands μ-law bytes, read as `u8` samples, to 16-bit linear PCM.
+struct MuLaw<S> {
+    source: S,
+    channels: Vec<Vec<i16>>,
+    slices: Vec<Slice<i16>>,
+}
+
+impl<S: Source<Output=u8>> Source for MuLaw<S> {
+    type Output = i16;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, i16> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => return SourceResult::SampleRate(r),
+            SourceResult::EndOfStream => return SourceResult::EndOfStream,
+            SourceResult::StreamError(e) => return SourceResult::StreamError(e)
+        };
+
+        self.channels.resize(buf.len(), Vec::new());
+        for (out, channel) in self.channels.iter_mut().zip(buf.iter()) {
+            out.clear();
+            out.extend(channel.iter().map(|&x| expand_mulaw(x)));
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+fn au_pcm<F, R>(reader: R, encoding: u32, channels: usize,
+                rate: u32) -> Result<Box<Source<Output=F>>, Error> where
+        F: Sample,
+        R: Read + 'static {
+    fn pcm<A: Sample, F: Sample, R: Read + 'static>(reader: R, channels: usize,
+                                                    rate: u32) -> Box<Source<Output=F>> {
+        let source = RawSource::<A, _>::new(reader, channels, rate, Endianness::Big);
+        Box::new(Convert::<A, F, _>::new(source))
+    }
+
+    Ok(match encoding {
+        1 => {
+            let source = MuLaw {
+                source: RawSource::<u8, _>::new(reader, channels, rate, Endianness::Big),
+                channels: Vec::new(),
+                slices: Vec::new(),
+            };
+            Box::new(Convert::<i16, F, _>::new(source))
+        }
+        2 => pcm::<i8, F, R>(reader, channels, rate),
+        3 => pcm::<i16, F, R>(reader, channels, rate),
+        4 => pcm::<I24, F, R>(reader, channels, rate),
+        5 => pcm::<i32, F, R>(reader, channels, rate),
+        6 => pcm::<f32, F, R>(reader, channels, rate),
+        7 => pcm::<f64, F, R>(reader, channels, rate),
+        e => return Err(Error::UnsupportedFormat(format!("AU encoding {} is not supported", e)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::super::{Source, SourceResult, Sample};
+    use super::{Format, detect, open};
+
+    /// Build a 16-bit PCM WAVE file.
+    fn wav_i16(channels: u16, rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data = samples.len() as u32 * 2;
+        let mut v = Vec::new();
+        v.extend(b"RIFF".iter().cloned());
+        v.extend((0..4).map(|i| ((36 + data) >> (8 * i)) as u8));
+        v.extend(b"WAVEfmt ".iter().cloned());
+        v.extend([16, 0, 0, 0, 1, 0, channels as u8, 0].iter().cloned());
+        v.extend((0..4).map(|i| (rate >> (8 * i)) as u8));
+        v.extend((0..4).map(|i| ((rate * channels as u32 * 2) >> (8 * i)) as u8));
+        v.extend([channels as u8 * 2, 0, 16, 0].iter().cloned());
+        v.extend(b"data".iter().cloned());
+        v.extend((0..4).map(|i| (data >> (8 * i)) as u8));
+        for &x in samples {
+            v.push(x as u8);
+            v.push((x >> 8) as u8);
+        }
+        v
+    }
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(detect(&wav_i16(1, 8000, &[])), Some(Format::Wav));
+        assert_eq!(detect(b"OggS\x00\x02\x00\x00\x00\x00\x00\x00"), Some(Format::Ogg));
+        assert_eq!(detect(b".snd\x00\x00\x00\x18\xff\xff\xff\xff"), Some(Format::Au));
+        assert_eq!(detect(b"RIFF\x00\x00\x00\x00AVI "), None);
+        assert_eq!(detect(b"ID3\x04\x00\x00\x00\x00\x00\x00\x00\x00"), None);
+        assert_eq!(detect(b"RIFF"), None);
+    }
+
+    #[test]
+    fn opens_wav() {
+        let file = wav_i16(2, 22050, &[100, -100, 200, -200]);
+        let mut src = open::<i16, _>(Cursor::new(file)).ok().expect("WAV was not opened");
+        assert_eq!(src.next(), SourceResult::SampleRate(22050));
+        assert_eq!(src.sample_rate(), Some(22050));
+        assert_eq!(src.next(),
+                   SourceResult::Buffer(&mut [&mut [100i16, 200], &mut [-100i16, -200]]));
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn opens_au() {
+        let mut file = b".snd".to_vec();
+        // Data offset 28 (four bytes of annotation), 8 bytes of 16-bit stereo at 8 kHz.
+        let header = [0, 0, 0, 28, 0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0x1f, 0x40, 0, 0, 0, 2];
+        file.extend(header.iter().cloned());
+        file.extend(b"note".iter().cloned());
+        file.extend([0x40, 0, 0xc0, 0, 0x20, 0, 0xe0, 0].iter().cloned());
+        // Trailing bytes beyond the declared size are ignored.
+        file.extend([0x7f, 0xff].iter().cloned());
+
+        let mut src = open::<f64, _>(Cursor::new(file)).ok().expect("AU was not opened");
+        assert_eq!(src.next(), SourceResult::SampleRate(8000));
+        let expected = |x: i16| -> f64 { Sample::to_float(x) };
+        match src.next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(&b[0][..], &[expected(0x4000), expected(0x2000)]);
+                assert_eq!(&b[1][..], &[expected(-0x4000), expected(-0x2000)]);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn opens_mulaw_au() {
+        let mut file = b".snd".to_vec();
+        // Data offset 24, unknown size, μ-law mono at 8 kHz.
+        let header = [0, 0, 0, 24, 0xff, 0xff, 0xff, 0xff,
+                      0, 0, 0, 1, 0, 0, 0x1f, 0x40, 0, 0, 0, 1];
+        file.extend(header.iter().cloned());
+        file.extend([0xff, 0x7f, 0x00, 0x80, 0xfe].iter().cloned());
+
+        let mut src = open::<i16, _>(Cursor::new(file)).ok().expect("AU was not opened");
+        assert_eq!(src.next(), SourceResult::SampleRate(8000));
+        assert_eq!(src.next(), SourceResult::Buffer(&mut [&mut [0i16, 0, -32124, 32124, 8]]));
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn rejects_unknown_format() {
+        match open::<f32, _>(Cursor::new(b"fLaC\x00\x00\x00\x22".to_vec())) {
+            Err(super::super::StreamError::UnsupportedFormat(_)) => { }
+            Err(e) => panic!("Unexpected error {:?}", e),
+            Ok(_) => panic!("Unknown format was opened")
+        }
+    }
+
+    #[cfg(feature = "vorbisfile")]
+    #[test]
+    fn opens_vorbis() {
+        use super::super::vorbis::tests::{encode, PACKET_SAMPLES};
+
+        let file = encode(1, 2, 22050, 4);
+        let mut src = open::<f32, _>(Cursor::new(file)).ok().expect("Ogg was not opened");
+        assert_eq!(src.sample_rate(), Some(22050));
+        assert_eq!(src.next(), SourceResult::SampleRate(22050));
+        let mut samples = 0;
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => {
+                    assert_eq!(b.len(), 2);
+                    samples += b[0].len();
+                }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(samples, 3 * PACKET_SAMPLES);
+    }
+}
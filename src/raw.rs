@@ -0,0 +1,257 @@
+//! Headerless interleaved PCM.
+
+use std::io::{Read, Write};
+use std::mem;
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, Sink, Endianness, channel_buffer, read_full};
+use super::SourceResult::{Buffer, SampleRate, StreamError, EndOfStream};
+use super::interleave::{Interleave, interleave_into};
+
+/// Number of frames `RawSource` reads at a time by default.
+pub const DEFAULT_FRAMES: usize = 4096;
+
+/// Append the bytes of `samples` to `out` in the specified byte order.
+fn serialize<F: Sample>(samples: &[F], endianness: Endianness, out: &mut Vec<u8>) {
+    let size = mem::size_of::<F>();
+    let start = out.len();
+    out.resize(start + samples.len() * size, 0);
+    for (sample, bytes) in samples.iter().zip(out[start..].chunks_mut(size)) {
+        sample.write_bytes(bytes, endianness);
+    }
+}
+
+/// Source reading interleaved samples from any `Read`er with no header.
+///
+/// The stream parameters must be specified since there is no header to read them from. The
+/// sample rate is reported by the first call to `next`, followed by channel-major buffers of a
+/// fixed number of frames. If the reader runs out partway through a buffer that buffer is
+/// truncated to the whole frames read and the stream ends; I/O errors are reported as
+/// `StreamError`.
+pub struct RawSource<F, R> {
+    reader: R,
+    channels: usize,
+    rate: u32,
+    endianness: Endianness,
+    frames: usize,
+    started: bool,
+    /// Set once the stream has ended or failed.
+    done: bool,
+    bytes: Vec<u8>,
+    buffers: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F, R> RawSource<F, R> where
+        F: Sample,
+        R: Read {
+    /// Construct a source reading `channels` channels of samples at `rate` Hz from `reader`.
+    pub fn new(reader: R, channels: usize, rate: u32,
+               endianness: Endianness) -> RawSource<F, R> {
+        assert!(channels > 0, "Raw PCM stream must have at least one channel");
+        RawSource {
+            reader: reader,
+            channels: channels,
+            rate: rate,
+            endianness: endianness,
+            frames: DEFAULT_FRAMES,
+            started: false,
+            done: false,
+            bytes: Vec::new(),
+            buffers: (0..channels).map(|_| Vec::new()).collect(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Set the number of frames read into each buffer.
+    pub fn set_frames(&mut self, frames: usize) {
+        assert!(frames > 0, "Buffers must contain at least one frame");
+        self.frames = frames;
+    }
+}
+
+impl<F, R> Source for RawSource<F, R> where
+        F: Sample,
+        R: Read {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.done {
+            return EndOfStream;
+        }
+        if !self.started {
+            self.started = true;
+            return SampleRate(self.rate);
+        }
+
+        let size = mem::size_of::<F>();
+        let frame_size = size * self.channels;
+        let want = self.frames * frame_size;
+        self.bytes.resize(want, 0);
+        let got = match read_full(&mut self.reader, &mut self.bytes) {
+            Ok(n) => n,
+            Err(e) => {
+                self.done = true;
+                return StreamError(e.into());
+            }
+        };
+        if got < want {
+            self.done = true;
+        }
+
+        let frames = got / frame_size;
+        if frames == 0 {
+            return EndOfStream;
+        }
+
+        let endianness = self.endianness;
+        for (c, channel) in self.buffers.iter_mut().enumerate() {
+            channel.clear();
+            channel.extend(self.bytes[..frames * frame_size].chunks(frame_size).map(|frame| {
+                Sample::read_bytes(&frame[c * size..], endianness)
+            }));
+        }
+        channel_buffer(&mut self.buffers, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.rate)
+    }
+}
+
+/// Sink writing interleaved samples to any `Write`r with no header.
+///
+/// This is the simplest way to get audio out of a pipeline, suitable for piping to tools like
+/// `aplay` or writing to a file for later import. Writing stops at end of stream or on an I/O
+/// error, and the writer is flushed when the stream ends.
+pub struct RawSink<F, W, R> {
+    dest: W,
+    source: R,
+    endianness: Endianness,
+    interleave_buf: Vec<F>,
+    bytes: Vec<u8>,
+}
+
+impl<F, W, R> RawSink<F, W, R> where
+        F: Sample + Interleave,
+        W: Write,
+        R: Source<Output=F> {
+    /// Construct a sink writing samples from `source` into `dest` with the specified byte order.
+    pub fn new(source: R, dest: W, endianness: Endianness) -> RawSink<F, W, R> {
+        RawSink {
+            dest: dest,
+            source: source,
+            endianness: endianness,
+            interleave_buf: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+
+    fn finish(&mut self) -> Option<()> {
+        if let Err(e) = self.dest.flush() {
+            error!("Failed to flush raw PCM output: {}", e);
+        }
+        None
+    }
+}
+
+impl<F, W, R> Sink for RawSink<F, W, R> where
+        F: Sample + Interleave,
+        W: Write,
+        R: Source<Output=F> {
+    fn run_once(&mut self) -> Option<()> {
+        let channels = match self.source.next() {
+            Buffer(channels) => channels,
+            SampleRate(_) => return Some(()),
+            EndOfStream => return self.finish(),
+            StreamError(e) => {
+                error!("Raw PCM sink stopping on stream error: {}", e);
+                return self.finish();
+            }
+        };
+
+        if let Err(e) = interleave_into(channels, &mut self.interleave_buf) {
+            error!("Raw PCM sink stopping on malformed buffer: {}", e);
+            return self.finish();
+        }
+
+        self.bytes.clear();
+        serialize(&self.interleave_buf, self.endianness, &mut self.bytes);
+        self.interleave_buf.truncate(0);
+
+        match self.dest.write_all(&self.bytes) {
+            Ok(()) => Some(()),
+            Err(e) => {
+                error!("Failed to write raw PCM output: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Sample, Source, SourceResult, Sink, MonoSource, CopyChannel, Endianness,
+                       BufferSource};
+    use super::super::tests::ConstantSource;
+    use super::{RawSink, RawSource};
+
+    #[test]
+    fn writes_interleaved_bytes() {
+        for &(endianness, expected) in [
+                (Endianness::Little, [0x01, 0x00, 0x01, 0x00, 0x00, 0x80, 0x00, 0x80]),
+                (Endianness::Big, [0x00, 0x01, 0x00, 0x01, 0x80, 0x00, 0x80, 0x00])].iter() {
+            let src = CopyChannel::new(0, 1, ConstantSource::<i16> {
+                data: vec![1, -32768],
+                sbuf: vec![]
+            }.adapt());
+
+            let mut out = Vec::new();
+            {
+                let mut sink = RawSink::new(src, &mut out, endianness);
+                assert_eq!(sink.run_once(), Some(()));
+            }
+            assert_eq!(&out[..], &expected[..]);
+        }
+    }
+
+    #[test]
+    fn writes_interleaved_f64() {
+        let src = BufferSource::new(vec![vec![0.5f64, -1.0], vec![0.25f64, 1.0]], 44100);
+        let mut out = Vec::new();
+        {
+            let mut sink = RawSink::new(src, &mut out, Endianness::Big);
+            while let Some(()) = sink.run_once() { }
+        }
+
+        let samples: Vec<f64> = out.chunks(8).map(|bytes| {
+            <f64 as Sample>::read_be(bytes)
+        }).collect();
+        assert_eq!(samples, vec![0.5, 0.25, -1.0, 1.0]);
+    }
+
+    #[test]
+    fn reads_interleaved_bytes() {
+        // Three stereo frames and a stray byte.
+        let bytes: &[u8] = &[0x01, 0x00, 0xff, 0xff, 0x00, 0x80, 0xff, 0x7f, 0x02, 0x00, 0x03, 0x00,
+                             0x04];
+        let mut src = RawSource::<i16, _>::new(bytes, 2, 48000, Endianness::Little);
+        src.set_frames(2);
+
+        assert_eq!(src.next(), SourceResult::SampleRate(48000));
+        match src.next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(&b[0][..], &[1, -32768][..]);
+                assert_eq!(&b[1][..], &[-1, 32767][..]);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+        match src.next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(&b[0][..], &[2][..]);
+                assert_eq!(&b[1][..], &[3][..]);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+}
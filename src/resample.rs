@@ -0,0 +1,440 @@
+//! Sample rate conversion.
+
+use std::f64::consts::PI;
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, channel_buffer, consume};
+
+/// Number of fractional positions between input samples at which `Sinc` precomputes filter
+/// coefficients. Positions in between are linearly interpolated.
+const SINC_PHASES: usize = 256;
+
+/// Linear-interpolating resampler.
+///
+/// Converts a stream to a fixed output sample rate by interpolating between adjacent input
+/// samples. This is cheap but attenuates high frequencies and permits some aliasing, so it is
+/// best suited to small rate changes or non-critical listening.
+///
+/// The first result is always `SampleRate(target_rate)`. The input rate is taken from the
+/// source's `SampleRate` results; if the source reports a new rate mid-stream the conversion
+/// ratio is updated and the target rate is reported again. Until the source reports a rate it is
+/// assumed to already be at the target rate.
+pub struct Linear<F, S> {
+    source: S,
+    target_rate: u32,
+    /// Input samples consumed per output sample.
+    step: f64,
+    /// Position of the next output sample relative to the start of the next input buffer.
+    ///
+    /// Ranges from -1 (the last sample of the previous buffer) upwards.
+    position: f64,
+    /// Last sample of the previous buffer for each channel.
+    carry: Vec<f64>,
+    started: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Linear<F, S> {
+    /// Create a resampler converting `source` to `target_rate` Hz.
+    pub fn new(source: S, target_rate: u32) -> Linear<F, S> {
+        Linear {
+            source: source,
+            target_rate: target_rate,
+            step: 1f64,
+            position: 0f64,
+            carry: Vec::new(),
+            started: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Linear<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if !self.started {
+            self.started = true;
+            return SourceResult::SampleRate(self.target_rate);
+        }
+
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.step = r as f64 / self.target_rate as f64;
+                return SourceResult::SampleRate(self.target_rate);
+            }
+            x => return x
+        };
+
+        let n = buf[0].len();
+        if self.carry.len() != buf.len() {
+            self.carry.resize(buf.len(), 0f64);
+            self.channels.resize(buf.len(), Vec::new());
+        }
+
+        let end = self.position;
+        for ((channel, out), carry) in buf.iter().zip(self.channels.iter_mut())
+                                                .zip(self.carry.iter_mut()) {
+            out.clear();
+            // Index -1 refers to the carried sample.
+            let prev = *carry;
+            let at = |i: isize| -> f64 {
+                if i < 0 { prev } else { Sample::to_float(channel[i as usize]) }
+            };
+
+            let mut position = end;
+            while position < n as f64 - 1f64 {
+                let i = position.floor();
+                let frac = position - i;
+                let i = i as isize;
+                out.push(Sample::from_float(at(i) * (1f64 - frac) + at(i + 1) * frac));
+                position += self.step;
+            }
+            if n > 0 {
+                *carry = Sample::to_float(channel[n - 1]);
+            }
+        }
+
+        // Every channel advanced by the same amount.
+        let mut position = end;
+        while position < n as f64 - 1f64 {
+            position += self.step;
+        }
+        self.position = position - n as f64;
+
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.target_rate)
+    }
+}
+
+/// Zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    // Power series; terms shrink quickly for the arguments used in Kaiser windows.
+    let mut sum = 1f64;
+    let mut term = 1f64;
+    let mut k = 1f64;
+    while term > sum * 1e-12 {
+        term *= (x / (2f64 * k)) * (x / (2f64 * k));
+        sum += term;
+        k += 1f64;
+    }
+    sum
+}
+
+/// Windowed-sinc resampler.
+///
+/// Converts a stream to a fixed output sample rate using a bank of Kaiser-windowed sinc filters
+/// precomputed at a number of fractional input positions, interpolating between adjacent
+/// positions to support arbitrary conversion ratios. More taps give a sharper transition band at
+/// the cost of computation and latency; a larger Kaiser `beta` trades a wider transition band for
+/// greater stopband attenuation. When downsampling, the cutoff is lowered to the output Nyquist
+/// frequency to prevent aliasing.
+///
+/// Sample rates are handled as in `Linear`. The output is delayed relative to the input by
+/// `group_delay` output samples, and the filter tail is flushed when the source ends.
+pub struct Sinc<F, S> {
+    source: S,
+    target_rate: u32,
+    taps: usize,
+    beta: f64,
+    step: f64,
+    /// Filter coefficients for each of `SINC_PHASES + 1` fractional positions.
+    bank: Vec<f64>,
+    /// Position of the next output sample in `history`.
+    position: f64,
+    /// Input samples still needed for each channel, in `f64`.
+    history: Vec<Vec<f64>>,
+    started: bool,
+    /// Set once the filter tail has been flushed at end of stream.
+    flushed: bool,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Sinc<F, S> {
+    /// Create a resampler converting `source` to `target_rate` Hz with a filter of `taps`
+    /// coefficients and Kaiser window parameter `beta`.
+    ///
+    /// `taps` is rounded up to an even number. Reasonable starting values are 32 taps with a beta
+    /// of 8, which gives roughly 80 dB of stopband attenuation.
+    pub fn new(source: S, target_rate: u32, taps: usize, beta: f64) -> Sinc<F, S> {
+        let taps = ::std::cmp::max(2, (taps + 1) & !1);
+        let mut sinc = Sinc {
+            source: source,
+            target_rate: target_rate,
+            taps: taps,
+            beta: beta,
+            step: 1f64,
+            bank: Vec::new(),
+            position: 0f64,
+            history: Vec::new(),
+            started: false,
+            flushed: false,
+            channels: Vec::new(),
+            slices: Vec::new(),
+        };
+        sinc.set_step(1f64);
+        sinc
+    }
+
+    /// Delay of the output relative to the input, in output samples.
+    ///
+    /// Callers requiring the output to be time-aligned with the input should discard this many
+    /// samples from the start of the stream.
+    ///
+    /// This depends on the ratio of input and output rates, so it's only meaningful once the
+    /// source's sample rate has been seen. Until then the input is assumed to be at the output
+    /// rate.
+    pub fn group_delay(&self) -> f64 {
+        (self.taps / 2) as f64 / self.step
+    }
+
+    fn set_step(&mut self, step: f64) {
+        self.step = step;
+        let n = self.taps;
+        let half = (n / 2) as f64;
+        let cutoff = if step > 1f64 { 1f64 / step } else { 1f64 };
+        let i0_beta = bessel_i0(self.beta);
+
+        self.bank.clear();
+        for p in 0..SINC_PHASES + 1 {
+            let frac = p as f64 / SINC_PHASES as f64;
+            let start = self.bank.len();
+            for k in 0..n {
+                let t = k as f64 - (half - 1f64) - frac;
+                let x = cutoff * t;
+                let sinc = if x == 0f64 { 1f64 } else { (PI * x).sin() / (PI * x) };
+                let w = t / half;
+                let window = bessel_i0(self.beta * (1f64 - w * w).max(0f64).sqrt()) / i0_beta;
+                self.bank.push(cutoff * sinc * window);
+            }
+            // Normalize for unity gain at DC.
+            let sum = self.bank[start..].iter().fold(0f64, |a, &x| a + x);
+            for h in self.bank[start..].iter_mut() {
+                *h /= sum;
+            }
+        }
+    }
+
+    /// Filter all available history into output samples, then drop history no longer needed.
+    fn process(&mut self) {
+        let n = self.taps;
+        let lead = n / 2 - 1;
+        let start = self.position;
+        let mut end = start;
+
+        for (history, out) in self.history.iter().zip(self.channels.iter_mut()) {
+            out.clear();
+            let mut position = start;
+            while (position.floor() as usize) + n / 2 < history.len() {
+                let i = position.floor();
+                let phase = (position - i) * SINC_PHASES as f64;
+                let p = phase.floor();
+                let frac = phase - p;
+                let p = p as usize;
+                let lo = &self.bank[p * n..(p + 1) * n];
+                let hi = &self.bank[(p + 1) * n..(p + 2) * n];
+
+                let base = i as usize - lead;
+                let mut y = 0f64;
+                for k in 0..n {
+                    y += history[base + k] * (lo[k] + (hi[k] - lo[k]) * frac);
+                }
+                out.push(Sample::from_float(y));
+                position += self.step;
+            }
+            end = position;
+        }
+
+        // Keep only what is needed to the left of the next output.
+        let drop = (end.floor() as usize).saturating_sub(lead);
+        for history in self.history.iter_mut() {
+            let drop = ::std::cmp::min(drop, history.len());
+            consume(history, drop);
+        }
+        self.position = end - drop as f64;
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Sinc<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if !self.started {
+            self.started = true;
+            return SourceResult::SampleRate(self.target_rate);
+        }
+        if self.flushed {
+            return SourceResult::EndOfStream;
+        }
+
+        let taps = self.taps;
+        match self.source.next() {
+            SourceResult::Buffer(b) => {
+                if self.history.len() != b.len() {
+                    // Prime history so the first output is centered a group delay before the
+                    // first input sample.
+                    self.history = vec![vec![0f64; taps - 1]; b.len()];
+                    self.channels.resize(b.len(), Vec::new());
+                    self.position = (self.taps / 2 - 1) as f64;
+                }
+                for (history, channel) in self.history.iter_mut().zip(b.iter()) {
+                    history.extend(channel.iter().map(|&x| Sample::to_float::<f64>(x)));
+                }
+            }
+            SourceResult::SampleRate(r) => {
+                let step = r as f64 / self.target_rate as f64;
+                self.set_step(step);
+                return SourceResult::SampleRate(self.target_rate);
+            }
+            SourceResult::EndOfStream => {
+                // Flush the filter tail.
+                self.flushed = true;
+                if self.history.is_empty() {
+                    return SourceResult::EndOfStream;
+                }
+                for history in self.history.iter_mut() {
+                    history.extend(::std::iter::repeat(0f64).take(taps));
+                }
+            }
+            x => return x
+        }
+
+        self.process();
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.target_rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::raw::Slice;
+    use super::super::{Source, SourceResult, channel_buffer};
+    use super::{Linear, Sinc};
+
+    /// Reports a sample rate, then yields a ramp in buffers of `size` until `len` samples.
+    pub struct Ramp {
+        rate: Option<u32>,
+        next: usize,
+        len: usize,
+        size: usize,
+        channels: Vec<Vec<f64>>,
+        slices: Vec<Slice<f64>>,
+    }
+
+    impl Ramp {
+        pub fn new(rate: u32, len: usize, size: usize) -> Ramp {
+            Ramp {
+                rate: Some(rate),
+                next: 0,
+                len: len,
+                size: size,
+                channels: vec![Vec::new()],
+                slices: Vec::new()
+            }
+        }
+    }
+
+    impl Source for Ramp {
+        type Output = f64;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, f64> {
+            if let Some(r) = self.rate.take() {
+                return SourceResult::SampleRate(r);
+            }
+            if self.next >= self.len {
+                return SourceResult::EndOfStream;
+            }
+
+            let end = ::std::cmp::min(self.next + self.size, self.len);
+            let len = self.len as f64;
+            self.channels[0] = (self.next..end).map(|i| i as f64 / len).collect();
+            self.next = end;
+            channel_buffer(&mut self.channels, &mut self.slices)
+        }
+    }
+
+    fn resample_ramp(from: u32, to: u32) -> Vec<f64> {
+        let mut src = Linear::new(Ramp::new(from, 4800, 100), to);
+        assert_eq!(src.next(), SourceResult::SampleRate(to));
+        assert_eq!(src.next(), SourceResult::SampleRate(to));
+
+        let mut out = Vec::new();
+        loop {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::EndOfStream => return out,
+                x => panic!("Unexpected {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn ramp_stays_monotonic() {
+        for &(from, to) in [(48000, 44100), (44100, 48000), (48000, 24000)].iter() {
+            let out = resample_ramp(from, to);
+            let expected = 4800 * to as usize / from as usize;
+            assert!((out.len() as isize - expected as isize).abs() <= 1,
+                    "Got {} samples from {} to {}, expected {}", out.len(), from, to, expected);
+
+            assert!(out.windows(2).all(|w| w[0] < w[1]), "Ramp not monotonic");
+            assert_eq!(out[0], 0f64);
+            let last_in = 4799f64 / 4800f64;
+            let step = from as f64 / to as f64 / 4800f64;
+            assert!(*out.last().unwrap() <= last_in && *out.last().unwrap() > last_in - step,
+                    "Final sample {} too far from {}", out.last().unwrap(), last_in);
+        }
+    }
+
+    /// Reports a sample rate before passing through another source.
+    struct WithRate<S> {
+        rate: Option<u32>,
+        source: S
+    }
+
+    impl<S: Source> Source for WithRate<S> {
+        type Output = S::Output;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, S::Output> {
+            match self.rate.take() {
+                Some(r) => SourceResult::SampleRate(r),
+                None => self.source.next()
+            }
+        }
+    }
+
+    #[test]
+    fn sinc_downsample_preserves_amplitude() {
+        use super::super::MonoSource;
+        use super::super::synth::Tone;
+
+        let tone = Tone::<f64, f64>::with_frequency(1000, 1000f64, 48000).adapt();
+        let mut src = Sinc::new(WithRate { rate: Some(48000), source: tone }, 24000, 32, 8f64);
+        // The output rate is reported first, then again once the input rate has been seen.
+        assert_eq!(src.group_delay(), 16f64);
+        assert_eq!(src.next(), SourceResult::SampleRate(24000));
+        assert_eq!(src.next(), SourceResult::SampleRate(24000));
+        assert_eq!(src.group_delay(), 8f64);
+
+        let mut out = Vec::new();
+        while out.len() < 4000 {
+            match src.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::SampleRate(r) => assert_eq!(r, 24000),
+                x => panic!("Unexpected {:?}", x)
+            }
+        }
+
+        // Skip the startup transient
+        let peak = out[100..].iter().fold(0f64, |a, &x| a.max(x.abs()));
+        assert!((peak - 1f64).abs() < 0.01, "Peak amplitude was {}", peak);
+    }
+}
@@ -0,0 +1,570 @@
+//! Reverberation.
+//!
+//! `Convolution` convolves a stream with a recorded impulse response, reproducing the acoustics
+//! of the space it was recorded in. Impulse responses are usually kept in audio files, so
+//! `read_impulse` collects one from any `Source`. `Freeverb` is a cheaper algorithmic reverb
+//! which needs no impulse response.
+
+use num::Complex;
+use std::marker::PhantomData;
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, StreamError, channel_buffer, consume,
+            DEFAULT_SAMPLE_RATE};
+use super::fft::{forward, inverse};
+
+/// Read all of `source` into memory for use as an impulse response.
+///
+/// Returns the samples of each channel, or the first stream error.
+pub fn read_impulse<F: Sample, S: Source<Output=F>>(mut source: S)
+        -> Result<Vec<Vec<f64>>, StreamError> {
+    let mut ir: Vec<Vec<f64>> = Vec::new();
+    loop {
+        match source.next() {
+            SourceResult::Buffer(b) => {
+                if ir.is_empty() {
+                    ir = vec![Vec::new(); b.len()];
+                } else if b.len() != ir.len() {
+                    return Err(StreamError::UnsupportedFormat(format!(
+                        "Impulse response changed from {} to {} channels", ir.len(), b.len())));
+                }
+                for (out, channel) in ir.iter_mut().zip(b.iter()) {
+                    out.extend(channel.iter().map(|&x| Sample::to_float::<f64>(x)));
+                }
+            }
+            SourceResult::SampleRate(_) => { }
+            SourceResult::EndOfStream => return Ok(ir),
+            SourceResult::StreamError(e) => return Err(e)
+        }
+    }
+}
+
+/// Convolves a stream with an impulse response.
+///
+/// The impulse response is split into partitions of `partition` samples, each convolved with the
+/// input by FFT and the results combined by overlap-add. Output is produced a partition at a
+/// time, so latency is bounded by the partition size however long the impulse response is, while
+/// larger partitions make long impulse responses cheaper to process. Each buffer yielded is one
+/// partition long; the output doesn't depend on the size of the source's buffers.
+///
+/// The output is a mix of the dry input and the convolved (wet) signal; it's entirely wet unless
+/// set otherwise with `set_mix`. When the source ends the reverb tail continues until the
+/// convolution has decayed completely, `len - 1` samples after the end of the input for an
+/// impulse response of `len` samples. Processing is done in `f64`, so the reverb can exceed full
+/// scale.
+///
+/// A mono impulse response is applied to every channel of the stream. Otherwise its channels
+/// apply to the corresponding channels of the stream, and a stream with a different number of
+/// channels is a stream error.
+pub struct Convolution<F, S> {
+    source: S,
+    partition: usize,
+    mix: f64,
+    /// Length of the impulse response.
+    ir_len: usize,
+    /// Spectra of each partition of the impulse response, zero-padded to twice the partition
+    /// size, per impulse response channel.
+    ir: Vec<Vec<Vec<Complex<f64>>>>,
+    /// Spectra of the most recent input blocks (the frequency-domain delay line), one per
+    /// impulse response partition, per channel.
+    history: Vec<Vec<Vec<Complex<f64>>>>,
+    /// Position of the most recent block in each `history`.
+    pos: usize,
+    /// Second half of the last convolved block, to overlap with the next, per channel.
+    overlap: Vec<Vec<f64>>,
+    /// Input samples not yet processed, per channel.
+    pending: Vec<Vec<f64>>,
+    /// Samples per channel received from and yielded to the source.
+    received: usize,
+    yielded: usize,
+    ended: bool,
+    cplx_in: Vec<Complex<f64>>,
+    cplx_out: Vec<Complex<f64>>,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Convolution<F, S> {
+    /// Construct a convolver with the impulse response `ir`, one vector of samples per channel.
+    ///
+    /// Panics if the impulse response is empty, its channels differ in length, or `partition`
+    /// is zero.
+    pub fn new(source: S, ir: Vec<Vec<f64>>, partition: usize) -> Convolution<F, S> {
+        assert!(partition > 0, "Partition size must be nonzero");
+        assert!(!ir.is_empty() && !ir[0].is_empty(), "Impulse response must not be empty");
+        assert!(ir.iter().all(|c| c.len() == ir[0].len()),
+                "Impulse response channels must all be the same length");
+
+        let zero = Complex::new(0f64, 0f64);
+        let mut cplx_in = vec![zero; 2 * partition];
+        let spectra = ir.iter().map(|channel| {
+            channel.chunks(partition).map(|part| {
+                for (i, x) in cplx_in.iter_mut().enumerate() {
+                    *x = Complex::new(part.get(i).cloned().unwrap_or(0f64), 0f64);
+                }
+                let mut spectrum = vec![zero; 2 * partition];
+                forward(&cplx_in, &mut spectrum);
+                spectrum
+            }).collect()
+        }).collect();
+
+        Convolution {
+            source: source,
+            partition: partition,
+            mix: 1f64,
+            ir_len: ir[0].len(),
+            ir: spectra,
+            history: Vec::new(),
+            pos: 0,
+            overlap: Vec::new(),
+            pending: Vec::new(),
+            received: 0,
+            yielded: 0,
+            ended: false,
+            cplx_in: cplx_in,
+            cplx_out: vec![zero; 2 * partition],
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Set the fraction of the output taken from the convolved signal, with the rest from the
+    /// dry input.
+    pub fn set_mix(&mut self, mix: f64) {
+        self.mix = mix;
+    }
+
+    /// Pull from the source until a whole partition is pending or the source ends.
+    ///
+    /// Returns a result to yield immediately, if any.
+    fn fill<'a>(&mut self) -> Option<SourceResult<'a, F>> {
+        while !self.ended && self.pending.first().map_or(true, |p| p.len() < self.partition) {
+            let buf = match self.source.next() {
+                SourceResult::Buffer(b) => b,
+                SourceResult::SampleRate(r) => return Some(SourceResult::SampleRate(r)),
+                SourceResult::EndOfStream => {
+                    self.ended = true;
+                    break;
+                }
+                SourceResult::StreamError(e) => return Some(SourceResult::StreamError(e))
+            };
+
+            if self.pending.is_empty() {
+                if self.ir.len() != 1 && self.ir.len() != buf.len() {
+                    return Some(SourceResult::StreamError(StreamError::UnsupportedFormat(
+                        format!("Cannot apply a {} channel impulse response to {} channels",
+                                self.ir.len(), buf.len()))));
+                }
+                let zero = Complex::new(0f64, 0f64);
+                let blocks = self.ir[0].len();
+                self.history = vec![vec![vec![zero; 2 * self.partition]; blocks]; buf.len()];
+                self.overlap = vec![vec![0f64; self.partition]; buf.len()];
+                self.pending = vec![Vec::new(); buf.len()];
+            } else if buf.len() != self.pending.len() {
+                return Some(SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                    "Convolution got {} channels after {}", buf.len(), self.pending.len()))));
+            }
+            for (p, channel) in self.pending.iter_mut().zip(buf.iter()) {
+                p.extend(channel.iter().map(|&x| Sample::to_float::<f64>(x)));
+            }
+            self.received += buf[0].len();
+        }
+        None
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Convolution<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if let Some(result) = self.fill() {
+            return result;
+        }
+
+        // Yield whole partitions until the source ends, then the remainder of the tail.
+        let p = self.partition;
+        let n = if self.ended {
+            if self.received == 0 {
+                return SourceResult::EndOfStream;
+            }
+            ::std::cmp::min(p, self.received + self.ir_len - 1 - self.yielded)
+        } else {
+            p
+        };
+        if n == 0 {
+            return SourceResult::EndOfStream;
+        }
+
+        let blocks = self.ir[0].len();
+        let pos = self.pos;
+        let mix = self.mix;
+        self.channels.resize(self.pending.len(), Vec::new());
+        for c in 0..self.pending.len() {
+            let dry = &self.pending[c];
+            for (i, x) in self.cplx_in.iter_mut().enumerate() {
+                *x = Complex::new(if i < p { dry.get(i).cloned().unwrap_or(0f64) } else { 0f64 },
+                                  0f64);
+            }
+            forward(&self.cplx_in, &mut self.history[c][pos]);
+
+            // Each earlier block meets a later partition of the impulse response.
+            let ir = &self.ir[if self.ir.len() == 1 { 0 } else { c }];
+            for x in self.cplx_out.iter_mut() {
+                *x = Complex::new(0f64, 0f64);
+            }
+            for (k, h) in ir.iter().enumerate() {
+                let x = &self.history[c][(pos + blocks - k) % blocks];
+                for ((y, &a), &b) in self.cplx_out.iter_mut().zip(x.iter()).zip(h.iter()) {
+                    *y = *y + a * b;
+                }
+            }
+            inverse(&self.cplx_out, &mut self.cplx_in);
+
+            let overlap = &mut self.overlap[c];
+            let out = &mut self.channels[c];
+            out.clear();
+            for i in 0..n {
+                let wet = self.cplx_in[i].re + overlap[i];
+                let x = dry.get(i).cloned().unwrap_or(0f64);
+                out.push(Sample::from_float(x * (1f64 - mix) + wet * mix));
+            }
+            for (o, y) in overlap.iter_mut().zip(self.cplx_in[p..].iter()) {
+                *o = y.re;
+            }
+        }
+        self.pos = (pos + 1) % blocks;
+        for pending in self.pending.iter_mut() {
+            let k = ::std::cmp::min(p, pending.len());
+            consume(pending, k);
+        }
+        self.yielded += n;
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Comb filter delays in samples at 44100 Hz.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+/// Allpass filter delays in samples at 44100 Hz.
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+/// Extra delay of the right channel's filters at 44100 Hz, which decorrelates the channels.
+const STEREO_SPREAD: usize = 23;
+/// Gain applied to the input of the filters, which otherwise sum to a very loud output.
+const INPUT_GAIN: f64 = 0.015;
+
+/// Feedback comb filter with a one-pole lowpass in the feedback path.
+struct Comb {
+    buffer: Vec<f64>,
+    pos: usize,
+    /// Output of the lowpass filter.
+    store: f64,
+}
+
+impl Comb {
+    fn process(&mut self, x: f64, feedback: f64, damping: f64) -> f64 {
+        let y = self.buffer[self.pos];
+        self.store = y * (1f64 - damping) + self.store * damping;
+        self.buffer[self.pos] = x + self.store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        y
+    }
+}
+
+/// Schroeder allpass filter with a fixed feedback of 0.5.
+struct Allpass {
+    buffer: Vec<f64>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, x: f64) -> f64 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = x + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - x
+    }
+}
+
+/// The filters for one output channel.
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Tank {
+    /// Construct the filters for `rate` Hz, each lengthened by `spread` samples at 44100 Hz.
+    fn new(rate: u32, spread: usize) -> Tank {
+        let scale = |n: usize| ::std::cmp::max(1, ((n + spread) as f64 * rate as f64 /
+                                                   DEFAULT_SAMPLE_RATE as f64).round() as usize);
+        Tank {
+            combs: COMB_TUNING.iter().map(|&n| {
+                Comb { buffer: vec![0f64; scale(n)], pos: 0, store: 0f64 }
+            }).collect(),
+            allpasses: ALLPASS_TUNING.iter().map(|&n| {
+                Allpass { buffer: vec![0f64; scale(n)], pos: 0 }
+            }).collect(),
+        }
+    }
+
+    fn process(&mut self, x: f64, feedback: f64, damping: f64) -> f64 {
+        let mut y = 0f64;
+        for comb in self.combs.iter_mut() {
+            y += comb.process(x, feedback, damping);
+        }
+        for allpass in self.allpasses.iter_mut() {
+            y = allpass.process(y);
+        }
+        y
+    }
+}
+
+/// Algorithmic reverb after Jezar's Freeverb.
+///
+/// Each output channel has eight parallel lowpass-feedback comb filters feeding four series
+/// allpass filters. The input channels are summed into every channel's filters, and the right
+/// channel's filters are slightly longer than the left's so the channels' reverb is
+/// decorrelated, giving a wide stereo image. Filter lengths are scaled to the stream's sample
+/// rate, or 44100 Hz if the source doesn't specify one before its first buffer; a change in
+/// sample rate clears the reverb.
+///
+/// The controls all range from 0 to 1. Room size sets the comb filters' feedback, so larger rooms
+/// decay more slowly. Damping sets how quickly high frequencies decay relative to low ones.
+/// Width selects how much of each channel's reverb goes to the other, where 0 is mono and 1 keeps
+/// them entirely separate. Mix is the fraction of the output taken from the reverb, with the rest
+/// from the dry input.
+///
+/// Processing is done in `f64`. Input with other than one or two channels is a stream error; mono
+/// input yields the left channel's reverb only.
+pub struct Freeverb<F, S> {
+    source: S,
+    room_size: f64,
+    damping: f64,
+    width: f64,
+    mix: f64,
+    rate: Option<u32>,
+    /// Filters per channel, built when the first buffer arrives.
+    tanks: Vec<Tank>,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Freeverb<F, S> {
+    /// Construct a reverb with medium room size and damping, full width and a mix of 0.3.
+    pub fn new(source: S) -> Freeverb<F, S> {
+        Freeverb {
+            source: source,
+            room_size: 0.5,
+            damping: 0.5,
+            width: 1f64,
+            mix: 0.3,
+            rate: None,
+            tanks: Vec::new(),
+            format: PhantomData
+        }
+    }
+
+    /// Set the room size.
+    pub fn set_room_size(&mut self, room_size: f64) {
+        self.room_size = room_size;
+    }
+
+    /// Set the damping of high frequencies.
+    pub fn set_damping(&mut self, damping: f64) {
+        self.damping = damping;
+    }
+
+    /// Set the stereo width of the reverb.
+    pub fn set_width(&mut self, width: f64) {
+        self.width = width;
+    }
+
+    /// Set the fraction of the output taken from the reverb.
+    pub fn set_mix(&mut self, mix: f64) {
+        self.mix = mix;
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Freeverb<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                if self.rate != Some(r) {
+                    self.rate = Some(r);
+                    self.tanks.clear();
+                }
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+        if buf.len() != 1 && buf.len() != 2 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Freeverb requires a mono or stereo source, but got {} channels", buf.len())));
+        }
+
+        if self.tanks.len() != buf.len() {
+            let rate = self.rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+            self.tanks = (0..buf.len()).map(|c| Tank::new(rate, c * STEREO_SPREAD)).collect();
+        }
+
+        // Scaled as in Freeverb, where a room size of 1 gives feedback of 0.98.
+        let feedback = self.room_size * 0.28 + 0.7;
+        let damping = self.damping * 0.4;
+        let mix = self.mix;
+        let same = self.width / 2f64 + 0.5;
+        let other = (1f64 - self.width) / 2f64;
+        let (left, right) = buf.split_at_mut(1);
+        match right.first_mut() {
+            Some(right) => {
+                for (l, r) in left[0].iter_mut().zip(right.iter_mut()) {
+                    let (x, y): (f64, f64) = (Sample::to_float(*l), Sample::to_float(*r));
+                    let input = (x + y) * INPUT_GAIN;
+                    let wet_l = self.tanks[0].process(input, feedback, damping);
+                    let wet_r = self.tanks[1].process(input, feedback, damping);
+                    *l = Sample::from_float(x * (1f64 - mix) +
+                                            (wet_l * same + wet_r * other) * mix);
+                    *r = Sample::from_float(y * (1f64 - mix) +
+                                            (wet_r * same + wet_l * other) * mix);
+                }
+            }
+            None => {
+                for l in left[0].iter_mut() {
+                    let x: f64 = Sample::to_float(*l);
+                    let wet = self.tanks[0].process(x * INPUT_GAIN * 2f64, feedback, damping);
+                    *l = Sample::from_float(x * (1f64 - mix) + wet * mix);
+                }
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Source, SourceResult, BufferSource};
+    use super::{Convolution, Freeverb, read_impulse};
+
+    /// Run `input` through a convolver, returning the first channel of the output.
+    fn convolve(input: Vec<f64>, chunk: usize, ir: Vec<f64>, partition: usize) -> Vec<f64> {
+        let mut src = BufferSource::new(vec![input], 44100);
+        src.set_chunk(chunk);
+        let mut reverb = Convolution::new(src, vec![ir], partition);
+        let mut out = vec![];
+        loop {
+            match reverb.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::SampleRate(r) => assert_eq!(r, 44100),
+                SourceResult::EndOfStream => return out,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (x, y)) in actual.iter().zip(expected.iter()).enumerate() {
+            assert!((x - y).abs() < 1e-9, "Sample {} was {}, expected {}", i, x, y);
+        }
+    }
+
+    #[test]
+    fn impulse_reproduces_ir() {
+        let ir: Vec<f64> = (0..11).map(|i| 0.9f64.powi(i) * if i % 2 == 0 { 1.0 } else { -1.0 })
+                                  .collect();
+        let mut impulse = vec![0f64; 20];
+        impulse[0] = 1f64;
+
+        // The tail continues past the end of the input.
+        let mut expected = ir.clone();
+        expected.resize(30, 0f64);
+        assert_close(&convolve(impulse, 20, ir, 4), &expected);
+    }
+
+    #[test]
+    fn block_size_does_not_alter_output() {
+        let input: Vec<f64> = (0..100).map(|i| ((i * 37) % 23) as f64 / 23.0 - 0.5).collect();
+        let ir: Vec<f64> = (0..19).map(|i| 1.0 / (i + 1) as f64).collect();
+
+        let mut direct = vec![0f64; input.len() + ir.len() - 1];
+        for (i, &x) in input.iter().enumerate() {
+            for (j, &h) in ir.iter().enumerate() {
+                direct[i + j] += x * h;
+            }
+        }
+
+        let reference = convolve(input.clone(), 7, ir.clone(), 8);
+        assert_close(&reference, &direct);
+        for &chunk in [1usize, 8, 64, 100].iter() {
+            assert_eq!(convolve(input.clone(), chunk, ir.clone(), 8), reference);
+        }
+    }
+
+    /// Energy of each channel in successive windows of `window` samples of a stereo impulse's
+    /// reverb, along with the reverb itself.
+    fn freeverb_impulse(room_size: f64, window: usize) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let mut impulse = vec![0f64; 44100];
+        impulse[0] = 1f64;
+        let mut reverb = Freeverb::new(BufferSource::new(vec![impulse.clone(), impulse], 44100));
+        reverb.set_room_size(room_size);
+        reverb.set_mix(1f64);
+
+        let mut out = vec![vec![]; 2];
+        loop {
+            match reverb.next() {
+                SourceResult::Buffer(b) => {
+                    for (o, c) in out.iter_mut().zip(b.iter()) {
+                        o.extend(c.iter().cloned());
+                    }
+                }
+                SourceResult::SampleRate(_) => { }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        let energy = out.iter().map(|c| {
+            c.chunks(window).map(|w| w.iter().fold(0f64, |a, x| a + x * x)).collect()
+        }).collect();
+        (energy, out)
+    }
+
+    #[test]
+    fn freeverb_tail_decays_diffusely() {
+        let (energy, out) = freeverb_impulse(0.5, 4410);
+        for channel in energy.iter() {
+            // After the early reflections have built up, each window is quieter than the last.
+            assert!(channel[1] > 0f64);
+            for w in channel[2..].windows(2) {
+                assert!(w[1] < w[0], "Energy rose from {} to {}", w[0], w[1]);
+            }
+        }
+
+        // The tail is dense rather than a few discrete echoes, and differs between channels.
+        let tail = &out[0][4410..8820];
+        let nonzero = tail.iter().filter(|x| x.abs() > 1e-12).count();
+        assert!(nonzero > tail.len() * 9 / 10, "Only {} nonzero samples", nonzero);
+        assert!(out[0] != out[1]);
+    }
+
+    #[test]
+    fn larger_room_decays_slower() {
+        let decay = |room_size| {
+            let (energy, _) = freeverb_impulse(room_size, 22050);
+            energy[0][1] / energy[0][0]
+        };
+        let (small, large) = (decay(0.3), decay(0.9));
+        assert!(large > small, "Decay of {} in large room, {} in small", large, small);
+    }
+
+    #[test]
+    fn reads_impulse_from_source() {
+        let src = BufferSource::new(vec![vec![0.5f64, 0.25], vec![-0.5, -0.25]], 44100);
+        assert_eq!(read_impulse(src), Ok(vec![vec![0.5, 0.25], vec![-0.5, -0.25]]));
+    }
+}
@@ -0,0 +1,276 @@
+//! Bounded queues for passing streams between threads.
+//!
+//! A ring is created with `ring`, which returns a `RingWriter` to move to the producing thread and
+//! a `RingSource` for the consuming thread. The producer wraps its pipeline and the writer in a
+//! `RingSink` and runs it, while the consumer reads from the `RingSource` like any other `Source`.
+//! Sample rate changes and stream errors are carried through the ring in order with the samples.
+//!
+//! The ring holds at most the specified number of sample frames. When it is full `RingSink`
+//! blocks until the consumer catches up, so the producer can never run unboundedly ahead. What
+//! `RingSource` does when the ring is empty is selected by `Underrun`.
+
+use std::collections::VecDeque;
+use std::raw::Slice;
+use std::sync::{Arc, Mutex, Condvar};
+use super::{Sample, Source, SourceResult, StreamError, Sink, channel_buffer};
+
+/// Behaviour of a `RingSource` when no samples are queued.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Underrun {
+    /// Wait until the producer queues more samples or ends the stream.
+    Block,
+    /// Immediately yield a buffer of silence with the specified number of frames.
+    ///
+    /// This suits real-time consumers such as audio devices, which must be fed regardless. The
+    /// silence has the channel count of the most recent buffer, or one channel if there hasn't
+    /// been one yet.
+    Silence(usize),
+}
+
+enum Chunk<F> {
+    Rate(u32),
+    Buffer(Vec<Vec<F>>),
+    Error(StreamError),
+}
+
+struct State<F> {
+    chunks: VecDeque<Chunk<F>>,
+    /// Number of frames in `chunks`.
+    frames: usize,
+    /// Set when the producer has ended the stream.
+    closed: bool,
+    /// Set when the `RingSource` has been dropped.
+    abandoned: bool,
+}
+
+struct Shared<F> {
+    state: Mutex<State<F>>,
+    /// Signalled whenever `state` changes.
+    changed: Condvar,
+    capacity: usize,
+}
+
+/// Create a ring holding up to `capacity` frames.
+///
+/// The `RingWriter` may be sent to another thread, where it becomes part of a `RingSink`.
+pub fn ring<F: Sample>(capacity: usize, underrun: Underrun) -> (RingWriter<F>, RingSource<F>) {
+    assert!(capacity > 0, "Ring must hold at least one frame");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            chunks: VecDeque::new(),
+            frames: 0,
+            closed: false,
+            abandoned: false,
+        }),
+        changed: Condvar::new(),
+        capacity: capacity,
+    });
+
+    let writer = RingWriter {
+        shared: shared.clone()
+    };
+    let source = RingSource {
+        shared: shared,
+        underrun: underrun,
+        channels: Vec::new(),
+        slices: Vec::new(),
+    };
+    (writer, source)
+}
+
+/// Producing end of a ring.
+///
+/// Dropping the writer ends the stream, so a consumer never waits on a producer that has gone
+/// away.
+pub struct RingWriter<F> {
+    shared: Arc<Shared<F>>,
+}
+
+impl<F: Sample> RingWriter<F> {
+    /// Queue a chunk, blocking while the ring is full.
+    ///
+    /// Returns false if the consumer has gone away.
+    fn push(&self, chunk: Chunk<F>) -> bool {
+        let frames = match chunk {
+            Chunk::Buffer(ref b) => b[0].len(),
+            _ => 0
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        // Always accept into an empty ring, so buffers larger than the capacity still pass.
+        while !state.abandoned && !state.chunks.is_empty()
+                && state.frames + frames > self.shared.capacity {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        if state.abandoned {
+            return false;
+        }
+        state.chunks.push_back(chunk);
+        state.frames += frames;
+        self.shared.changed.notify_all();
+        true
+    }
+
+    fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<F> Drop for RingWriter<F> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+/// Sink feeding a stream into a ring.
+///
+/// Each call to `run_once` moves one result from the source into the ring, blocking if the ring is
+/// full. The sink stops at the end of the stream, after passing any stream error on to the
+/// consumer, or when the `RingSource` has been dropped.
+pub struct RingSink<F, R> {
+    source: R,
+    writer: RingWriter<F>,
+}
+
+impl<F: Sample, R: Source<Output=F>> RingSink<F, R> {
+    /// Construct a sink writing buffers from `source` into the ring through `writer`.
+    pub fn new(source: R, writer: RingWriter<F>) -> RingSink<F, R> {
+        RingSink {
+            source: source,
+            writer: writer,
+        }
+    }
+}
+
+impl<F: Sample, R: Source<Output=F>> Sink for RingSink<F, R> {
+    fn run_once(&mut self) -> Option<()> {
+        let chunk = match self.source.next() {
+            SourceResult::Buffer(b) => Chunk::Buffer(b.iter().map(|c| c.to_vec()).collect()),
+            SourceResult::SampleRate(r) => Chunk::Rate(r),
+            SourceResult::EndOfStream => {
+                self.writer.close();
+                return None;
+            }
+            SourceResult::StreamError(e) => {
+                self.writer.push(Chunk::Error(e));
+                self.writer.close();
+                return None;
+            }
+        };
+
+        if self.writer.push(chunk) {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+/// Consuming end of a ring.
+///
+/// Yields everything the `RingSink` took from its source, in order. Once the stream has ended and
+/// the ring is drained this yields `EndOfStream`. Dropping the source releases a producer blocked
+/// on a full ring, which then stops.
+pub struct RingSource<F> {
+    shared: Arc<Shared<F>>,
+    underrun: Underrun,
+    channels: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+}
+
+impl<F: Sample> Source for RingSource<F> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            match state.chunks.pop_front() {
+                Some(Chunk::Buffer(b)) => {
+                    state.frames -= b[0].len();
+                    self.shared.changed.notify_all();
+                    self.channels = b;
+                    break;
+                }
+                Some(Chunk::Rate(r)) => return SourceResult::SampleRate(r),
+                Some(Chunk::Error(e)) => return SourceResult::StreamError(e),
+                None if state.closed => return SourceResult::EndOfStream,
+                None => match self.underrun {
+                    Underrun::Block => state = self.shared.changed.wait(state).unwrap(),
+                    Underrun::Silence(frames) => {
+                        let silence: F = Sample::from_float(0f64);
+                        let n = if self.channels.is_empty() { 1 } else { self.channels.len() };
+                        self.channels = vec![vec![silence; frames]; n];
+                        break;
+                    }
+                }
+            }
+        }
+        drop(state);
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+}
+
+impl<F> Drop for RingSource<F> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.abandoned = true;
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::super::{Source, SourceResult, Sink, Endianness};
+    use super::super::raw::RawSource;
+    use super::{RingSink, Underrun, ring};
+
+    #[test]
+    fn preserves_stream_across_threads() {
+        let bytes: Vec<u8> = (0..4000).map(|x| (x * 7) as u8).collect();
+        let expected = bytes.clone();
+        // Small enough that the producer must wait for the consumer.
+        let (writer, mut source) = ring::<i16>(16, Underrun::Block);
+
+        let producer = thread::spawn(move || {
+            let mut raw = RawSource::<i16, _>::new(&bytes[..], 2, 48000, Endianness::Little);
+            raw.set_frames(7);
+            let mut sink = RingSink::new(raw, writer);
+            while let Some(()) = sink.run_once() { }
+        });
+
+        assert_eq!(source.next(), SourceResult::SampleRate(48000));
+        let mut out = Vec::new();
+        loop {
+            match source.next() {
+                SourceResult::Buffer(b) => {
+                    assert_eq!(b.len(), 2);
+                    for i in 0..b[0].len() {
+                        for channel in b.iter() {
+                            out.push(channel[i] as u8);
+                            out.push((channel[i] >> 8) as u8);
+                        }
+                    }
+                }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn underrun_yields_silence() {
+        let (writer, mut source) = ring::<f32>(16, Underrun::Silence(4));
+        assert_eq!(source.next(), SourceResult::Buffer(&mut [&mut [0f32; 4]]));
+        drop(writer);
+        assert_eq!(source.next(), SourceResult::EndOfStream);
+    }
+}
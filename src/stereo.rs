@@ -0,0 +1,369 @@
+//! Stereo image processing.
+
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use super::{Sample, Source, SourceResult, StreamError};
+
+/// Adjusts stereo width with mid/side processing.
+///
+/// Each frame is split into mid `(L + R) / 2` and side `(L - R) / 2` components, the side is
+/// scaled by the width factor, and the result converted back to left and right. A width of 0
+/// collapses the stream to mono, 1 leaves it unchanged, and greater values exaggerate the
+/// difference between channels. Widening can push samples past full scale.
+///
+/// Processing is done in `f64`. Input with other than two channels is a stream error.
+pub struct Width<F, S> {
+    source: S,
+    width: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Width<F, S> {
+    /// Construct a width adjuster with the specified width factor.
+    pub fn new(source: S, width: f64) -> Width<F, S> {
+        Width {
+            source: source,
+            width: width,
+            format: PhantomData
+        }
+    }
+
+    /// Change the width factor.
+    pub fn set_width(&mut self, width: f64) {
+        self.width = width;
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Width<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() != 2 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Width requires a stereo source, but got {} channels", buf.len())));
+        }
+
+        let width = self.width;
+        let (left, right) = buf.split_at_mut(1);
+        for (l, r) in left[0].iter_mut().zip(right[0].iter_mut()) {
+            let (x, y): (f64, f64) = (Sample::to_float(*l), Sample::to_float(*r));
+            let mid = (x + y) / 2f64;
+            let side = (x - y) / 2f64 * width;
+            *l = Sample::from_float(mid + side);
+            *r = Sample::from_float(mid - side);
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Adjusts the balance between the channels of a stereo stream.
+///
+/// Unlike `Pan`, this doesn't reposition a signal with constant power; it only attenuates one
+/// side. A balance of 0 leaves the stream unchanged, and moving it towards -1 fades out the right
+/// channel while moving towards 1 fades out the left, linearly in amplitude. The other channel is
+/// always left at full level.
+///
+/// Processing is done in `f64`. Input with other than two channels is a stream error.
+pub struct Balance<F, S> {
+    source: S,
+    balance: f64,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Balance<F, S> {
+    /// Construct a balance control at `balance`, in [-1, 1].
+    pub fn new(source: S, balance: f64) -> Balance<F, S> {
+        Balance {
+            source: source,
+            balance: balance,
+            format: PhantomData
+        }
+    }
+
+    /// Change the balance.
+    pub fn set_balance(&mut self, balance: f64) {
+        self.balance = balance;
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Balance<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() != 2 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Balance requires a stereo source, but got {} channels", buf.len())));
+        }
+
+        let balance = self.balance.max(-1f64).min(1f64);
+        let gains = [1f64 - balance.max(0f64), 1f64 + balance.min(0f64)];
+        for (channel, &gain) in buf.iter_mut().zip(gains.iter()) {
+            if gain == 1f64 {
+                continue;
+            }
+            for x in channel.iter_mut() {
+                *x = Sample::from_float(Sample::to_float::<f64>(*x) * gain);
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+/// Measures the correlation between the channels of a stereo stream passing through it.
+///
+/// Samples are passed through unchanged. After each buffer the Pearson correlation coefficient
+/// of the left and right channels over a sliding window of the most recent `window` frames (or
+/// all frames seen if fewer than that) is published to the value returned by `correlation`. It
+/// is 1 for a mono signal, -1 when the channels are exactly out of phase, and near 0 when they
+/// are unrelated; a window in which either channel is constant reads 0.
+///
+/// Input with other than two channels is a stream error.
+pub struct Correlation<F, S> {
+    source: S,
+    /// Ring buffer of `(left, right)` frames in the window.
+    frames: Vec<(f64, f64)>,
+    /// Next position to write in `frames`.
+    pos: usize,
+    /// Number of valid entries in `frames`.
+    filled: usize,
+    /// Running sums of L, R, L², R² and LR over `frames`.
+    sums: [f64; 5],
+    correlation: Arc<RwLock<f32>>,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Correlation<F, S> {
+    /// Construct a meter over a window of `window` frames.
+    pub fn new(source: S, window: usize) -> Correlation<F, S> {
+        assert!(window > 0, "Correlation window must not be empty");
+        Correlation {
+            source: source,
+            frames: vec![(0f64, 0f64); window],
+            pos: 0,
+            filled: 0,
+            sums: [0f64; 5],
+            correlation: Arc::new(RwLock::new(0f32)),
+            format: PhantomData
+        }
+    }
+
+    /// Get a reference to the current correlation coefficient.
+    pub fn correlation(&self) -> Arc<RwLock<f32>> {
+        self.correlation.clone()
+    }
+
+    fn coefficient(&self) -> f64 {
+        let n = self.filled as f64;
+        let (sx, sy, sxx, syy, sxy) =
+            (self.sums[0], self.sums[1], self.sums[2], self.sums[3], self.sums[4]);
+        let var_x = n * sxx - sx * sx;
+        let var_y = n * syy - sy * sy;
+        // Rounding error in the running sums can leave the variances slightly negative.
+        if var_x <= 0f64 || var_y <= 0f64 {
+            return 0f64;
+        }
+        ((n * sxy - sx * sy) / (var_x * var_y).sqrt()).max(-1f64).min(1f64)
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Correlation<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            x => return x
+        };
+        if buf.len() != 2 {
+            return SourceResult::StreamError(StreamError::UnsupportedFormat(format!(
+                "Correlation requires a stereo source, but got {} channels", buf.len())));
+        }
+
+        let window = self.frames.len();
+        for (&l, &r) in buf[0].iter().zip(buf[1].iter()) {
+            let (x, y): (f64, f64) = (Sample::to_float(l), Sample::to_float(r));
+            let (ox, oy) = self.frames[self.pos];
+            self.sums[0] += x - ox;
+            self.sums[1] += y - oy;
+            self.sums[2] += x * x - ox * ox;
+            self.sums[3] += y * y - oy * oy;
+            self.sums[4] += x * y - ox * oy;
+            self.frames[self.pos] = (x, y);
+            self.pos = (self.pos + 1) % window;
+        }
+        self.filled = window.min(self.filled + buf[0].len());
+
+        *self.correlation.write().unwrap() = self.coefficient() as f32;
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::XorShiftRng;
+    use super::super::{Source, SourceResult, MonoSource, MonoAdapter, Pan, BufferSource};
+    use super::super::synth::WhiteNoise;
+    use super::super::tests::ConstantSource;
+    use super::{Width, Balance, Correlation};
+
+    fn stereo() -> Pan<f64, MonoAdapter<f64, ConstantSource<f64>>> {
+        Pan::new(ConstantSource { data: vec![0.0, 0.5, -0.25, 1.0], sbuf: vec![] }.adapt(), -0.5)
+    }
+
+    #[test]
+    fn zero_width_is_mono() {
+        let mut src = Width::new(stereo(), 0f64);
+        match src.next() {
+            SourceResult::Buffer(b) => assert_eq!(b[0], b[1]),
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn unity_width_is_transparent() {
+        let mut reference = stereo();
+        let mut src = Width::new(stereo(), 1f64);
+        let expected = match reference.next() {
+            SourceResult::Buffer(b) => (b[0].to_vec(), b[1].to_vec()),
+            x => panic!("Unexpected result {:?}", x)
+        };
+        match src.next() {
+            SourceResult::Buffer(b) => {
+                for (channel, expected) in b.iter().zip([expected.0, expected.1].iter()) {
+                    for (x, y) in channel.iter().zip(expected.iter()) {
+                        assert!((x - y).abs() < 1e-12, "Expected {}, got {}", y, x);
+                    }
+                }
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn rejects_mono() {
+        let mono = ConstantSource { data: vec![0f64; 4], sbuf: vec![] }.adapt();
+        match Width::new(mono, 1f64).next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected stream error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn zero_balance_is_identity() {
+        let mut reference = stereo();
+        let expected = match reference.next() {
+            SourceResult::Buffer(b) => (b[0].to_vec(), b[1].to_vec()),
+            x => panic!("Unexpected result {:?}", x)
+        };
+        match Balance::new(stereo(), 0f64).next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(b[0].to_vec(), expected.0);
+                assert_eq!(b[1].to_vec(), expected.1);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn full_balance_silences_one_side() {
+        let mut reference = stereo();
+        let expected = match reference.next() {
+            SourceResult::Buffer(b) => (b[0].to_vec(), b[1].to_vec()),
+            x => panic!("Unexpected result {:?}", x)
+        };
+        let silence = vec![0f64; expected.0.len()];
+
+        match Balance::new(stereo(), 1f64).next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(b[0].to_vec(), silence);
+                assert_eq!(b[1].to_vec(), expected.1);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+        match Balance::new(stereo(), -1f64).next() {
+            SourceResult::Buffer(b) => {
+                assert_eq!(b[0].to_vec(), expected.0);
+                assert_eq!(b[1].to_vec(), silence);
+            }
+            x => panic!("Unexpected result {:?}", x)
+        }
+    }
+
+    #[test]
+    fn balance_rejects_mono() {
+        let mono = ConstantSource { data: vec![0f64; 4], sbuf: vec![] }.adapt();
+        match Balance::new(mono, 0f64).next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected stream error, got {:?}", x)
+        }
+    }
+
+    /// Run `left` and `right` through a correlation meter, returning the final reading.
+    fn correlate(left: Vec<f64>, right: Vec<f64>) -> f32 {
+        let mut src = BufferSource::new(vec![left, right], 44100);
+        src.set_chunk(1000);
+        let mut meter = Correlation::new(src, 4096);
+        let correlation = meter.correlation();
+        loop {
+            match meter.next() {
+                SourceResult::Buffer(_) | SourceResult::SampleRate(_) => { }
+                SourceResult::EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        let c = *correlation.read().unwrap();
+        c
+    }
+
+    fn noise(n: usize) -> Vec<f64> {
+        WhiteNoise::new(n, XorShiftRng::new_unseeded()).next().unwrap().to_vec()
+    }
+
+    #[test]
+    fn correlation_of_mono_and_inverted() {
+        let x = noise(10000);
+        let c = correlate(x.clone(), x.clone());
+        assert!((c - 1f32).abs() < 1e-4, "Correlation was {}", c);
+
+        let inverted = x.iter().map(|&y| -y).collect();
+        let c = correlate(x, inverted);
+        assert!((c + 1f32).abs() < 1e-4, "Correlation was {}", c);
+    }
+
+    #[test]
+    fn correlation_of_independent_noise() {
+        let mut x = noise(20000);
+        let y = x.split_off(10000);
+        let c = correlate(x, y);
+        assert!(c.abs() < 0.1, "Correlation was {}", c);
+    }
+
+    #[test]
+    fn correlation_rejects_mono() {
+        let mono = ConstantSource { data: vec![0f64; 4], sbuf: vec![] }.adapt();
+        match Correlation::new(mono, 16).next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected stream error, got {:?}", x)
+        }
+    }
+}
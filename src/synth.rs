@@ -1,7 +1,7 @@
 //! Signal synthesizers.
 
 use num::{NumCast, Float, FromPrimitive};
-use super::{Sample, MonoSource, UninitializedSource};
+use super::{Sample, Source, SourceResult, MonoSource, UninitializedSource};
 use std::f64::consts::PI_2;
 use std::iter::Cycle;
 use std::marker::PhantomData;
@@ -25,6 +25,26 @@ impl<F: Sample> Null<F> {
             src: UninitializedSource::new(size)
         }
     }
+
+    /// Create a source of `seconds` of silence at `sample_rate`, for buffers of `size` samples.
+    ///
+    /// The stream ends after the duration rounded to the nearest sample, so the last buffer may
+    /// be short.
+    pub fn for_duration(size: usize, seconds: f64, sample_rate: u32) -> Null<F> {
+        let mut null = Null::new(size);
+        null.src.set_limit(Some(duration_samples(seconds, sample_rate)));
+        null
+    }
+
+    /// Change the number of samples in subsequent buffers.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.src.set_size(size);
+    }
+}
+
+/// Number of samples in `seconds` at `sample_rate`, rounded to the nearest sample.
+fn duration_samples(seconds: f64, sample_rate: u32) -> usize {
+    (seconds * sample_rate as f64).round() as usize
 }
 
 impl<F: Sample> MonoSource for Null<F> {
@@ -40,6 +60,18 @@ impl<F: Sample> MonoSource for Null<F> {
     }
 }
 
+#[test]
+fn null_for_duration_ends_exactly() {
+    let mut src = Null::<i16>::for_duration(1000, 0.5, 44100);
+    let mut samples = 0;
+    while let Some(b) = src.next() {
+        assert!(b.iter().all(|&x| x == 0));
+        samples += b.len();
+    }
+    assert_eq!(samples, 22050);
+    assert!(src.next().is_none());
+}
+
 #[bench]
 fn generate_silence(b: &mut Bencher) {
     let bufsize = 4096;
@@ -61,8 +93,10 @@ fn generate_silence(b: &mut Bencher) {
 /// required precision.
 pub struct Tone<F, P=f32> {
     src: UninitializedSource<F>,
-    timebase: Cycle<Range<usize>>,
-    period: usize,
+    /// Current position within the cycle, in [0, 1).
+    phase: f64,
+    /// Phase advance per sample.
+    increment: f64,
     gentype: PhantomData<P>
 }
 
@@ -72,11 +106,52 @@ impl<F: Sample, P = f32> Tone<F, P> {
     pub fn new(size: usize, period: usize) -> Tone<F, P> {
         Tone {
             src: UninitializedSource::new(size),
-            timebase: (0..period).cycle(),
-            period: period,
+            phase: 0.0,
+            increment: 1.0 / period as f64,
+            gentype: PhantomData
+        }
+    }
+
+    /// Create a pure tone generator with a specified period in samples, starting `phase` of the
+    /// way through a cycle, for buffers of `size` samples.
+    ///
+    /// `phase` is in range [0, 1); values outside it wrap. A phase of 0.25 yields a cosine.
+    pub fn with_phase(size: usize, period: usize, phase: f64) -> Tone<F, P> {
+        let mut tone = Tone::new(size, period);
+        tone.phase = phase - phase.floor();
+        tone
+    }
+
+    /// Create a pure tone generator at `freq_hz` for a stream with the
+    /// specified sample rate, for buffers of `size` samples.
+    ///
+    /// Unlike `new`, the period need not be a whole number of samples.
+    pub fn with_frequency(size: usize, freq_hz: f64, sample_rate: u32) -> Tone<F, P> {
+        Tone {
+            src: UninitializedSource::new(size),
+            phase: 0.0,
+            increment: freq_hz / sample_rate as f64,
             gentype: PhantomData
         }
     }
+
+    /// Create a tone generator as with `with_frequency` which ends after `seconds`.
+    ///
+    /// The stream ends after the duration rounded to the nearest sample, so the last buffer may
+    /// be short.
+    pub fn for_duration(size: usize, freq_hz: f64, seconds: f64,
+                        sample_rate: u32) -> Tone<F, P> {
+        let mut tone = Tone::with_frequency(size, freq_hz, sample_rate);
+        tone.src.set_limit(Some(duration_samples(seconds, sample_rate)));
+        tone
+    }
+
+    /// Change the number of samples in subsequent buffers.
+    ///
+    /// The phase is unaffected, so the signal remains continuous.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.src.set_size(size);
+    }
 }
 
 // TODO Float is kinda slow-feeling. Prefer a custom Sinusoid
@@ -90,16 +165,72 @@ impl<F: Sample, P: Sample+Float> MonoSource for Tone<F, P> {
             None => return None
         };
 
-        for (x, t) in buf.iter_mut().zip(self.timebase.by_ref()) {
-            let mut y: P = NumCast::from(t).unwrap();
+        for x in buf.iter_mut() {
+            let mut y: P = NumCast::from(self.phase).unwrap();
             y = y * NumCast::from(PI_2).unwrap();
-            y = y / NumCast::from(self.period).unwrap();
             *x = Sample::convert::<F>(y.sin());
+
+            self.phase += self.increment;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
         }
         Some(buf)
     }
 }
 
+#[test]
+fn tone_is_phase_continuous() {
+    let (size, freq, rate) = (100, 441.5, 44100);
+    let mut src = Tone::<f64, f64>::with_frequency(size, freq, rate);
+    let mut xs = src.next().unwrap().to_vec();
+    xs.extend(src.next().unwrap().iter().cloned());
+
+    for (n, x) in xs.iter().enumerate() {
+        let expected = (PI_2 * freq * n as f64 / rate as f64).sin();
+        assert!((x - expected).abs() < 1e-9, "Sample {} was {}, expected {}", n, x, expected);
+    }
+}
+
+#[test]
+fn tone_phase_offset() {
+    let mut sin = Tone::<f64, f64>::new(200, 100);
+    let mut cos = Tone::<f64, f64>::with_phase(200, 100, 0.25);
+    let (sin, cos) = (sin.next().unwrap().to_vec(), cos.next().unwrap().to_vec());
+
+    assert!((cos[0] - 1f64).abs() < 1e-9, "Cosine started at {}", cos[0]);
+    for n in 0..175 {
+        assert!((cos[n] - sin[n + 25]).abs() < 1e-9, "Sample {} was {}, expected {}",
+                n, cos[n], sin[n + 25]);
+    }
+}
+
+#[test]
+fn tone_buffer_resizes_continuously() {
+    let (freq, rate) = (441.5, 44100);
+    let mut src = Tone::<f64, f64>::with_frequency(100, freq, rate);
+    let mut xs = src.next().unwrap().to_vec();
+    src.set_buffer_size(37);
+    let b = src.next().unwrap().to_vec();
+    assert_eq!(b.len(), 37);
+    xs.extend(b.into_iter());
+
+    for (n, x) in xs.iter().enumerate() {
+        let expected = (PI_2 * freq * n as f64 / rate as f64).sin();
+        assert!((x - expected).abs() < 1e-9, "Sample {} was {}, expected {}", n, x, expected);
+    }
+}
+
+#[test]
+fn tone_for_duration_ends_exactly() {
+    let mut src = Tone::<f64, f64>::for_duration(64, 440.0, 0.01, 8000);
+    let mut lens = vec![];
+    while let Some(b) = src.next() {
+        lens.push(b.len());
+    }
+    assert_eq!(lens, vec![64, 16]);
+}
+
 #[bench]
 fn generate_a440_44100(b: &mut Bencher) {
     let bufsize = 4096;
@@ -110,6 +241,616 @@ fn generate_a440_44100(b: &mut Bencher) {
     });
 }
 
+/// A sum of harmonically related sine waves.
+///
+/// Each harmonic is a sine at a whole multiple of the fundamental frequency with some amplitude,
+/// all starting at zero phase. The sum is scaled by the total of the absolute amplitudes, so it
+/// can't exceed full scale even where every harmonic peaks together. Harmonics at or above the
+/// Nyquist frequency would alias and are ignored, including in the scaling.
+///
+/// As with `Tone`, `P` is the type each sine wave is generated in; they are summed in `f64`.
+pub struct Additive<F, P=f32> {
+    src: UninitializedSource<F>,
+    /// Harmonic numbers and amplitudes, with amplitudes normalized.
+    harmonics: Vec<(usize, f64)>,
+    /// Position within a cycle of the fundamental, in [0, 1).
+    phase: f64,
+    increment: f64,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> Additive<F, P> {
+    /// Create a generator with a fundamental period in samples and a list of
+    /// `(harmonic number, amplitude)` pairs, for buffers of `size` samples.
+    ///
+    /// Harmonic 1 is the fundamental.
+    pub fn new(size: usize, period: usize, harmonics: &[(usize, f64)]) -> Additive<F, P> {
+        let mut harmonics: Vec<(usize, f64)> = harmonics.iter().cloned().filter(|&(k, _)| {
+            k > 0 && 2 * k < period
+        }).collect();
+        let total = harmonics.iter().fold(0f64, |a, &(_, amplitude)| a + amplitude.abs());
+        if total > 0f64 {
+            for h in harmonics.iter_mut() {
+                h.1 /= total;
+            }
+        }
+
+        Additive {
+            src: UninitializedSource::new(size),
+            harmonics: harmonics,
+            phase: 0.0,
+            increment: 1.0 / period as f64,
+            gentype: PhantomData
+        }
+    }
+}
+
+impl<F: Sample, P: Sample+Float> MonoSource for Additive<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        let two_pi: P = NumCast::from(PI_2).unwrap();
+        for x in buf.iter_mut() {
+            let mut sum = 0f64;
+            for &(k, amplitude) in self.harmonics.iter() {
+                let p = k as f64 * self.phase;
+                let y: P = NumCast::from(p - p.floor()).unwrap();
+                let y: f64 = NumCast::from((y * two_pi).sin()).unwrap();
+                sum += amplitude * y;
+            }
+            *x = Sample::convert::<F>(sum);
+
+            self.phase += self.increment;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+            }
+        }
+        Some(buf)
+    }
+}
+
+#[test]
+fn additive_fundamental_is_tone() {
+    let mut tone = Tone::<f64, f64>::new(256, 100);
+    let mut additive = Additive::<f64, f64>::new(256, 100, &[(1, 1.0)]);
+    for _ in 0..3 {
+        let expected = tone.next().unwrap().to_vec();
+        let actual = additive.next().unwrap();
+        for (a, b) in actual.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-9, "Expected {}, got {}", b, a);
+        }
+    }
+}
+
+#[test]
+fn additive_odd_harmonics_approximate_square() {
+    let harmonics: Vec<_> = (0..25).map(|i| (2 * i + 1, 1f64 / (2 * i + 1) as f64)).collect();
+    let mut src = Additive::<f64, f64>::new(1000, 1000, &harmonics);
+    let xs = src.next().unwrap().to_vec();
+
+    assert!(xs.iter().all(|x| x.abs() <= 1f64));
+    // Flat plateaus of opposite sign away from the transitions.
+    let level = xs[250];
+    assert!(level > 0.2);
+    for n in 100..400 {
+        assert!((xs[n] - level).abs() < 0.05 * level, "Sample {} was {}", n, xs[n]);
+        assert!((xs[n + 500] + level).abs() < 0.05 * level, "Sample {} was {}", n + 500, xs[n + 500]);
+    }
+}
+
+/// Frequency progression of a `Chirp`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sweep {
+    /// Frequency changes by the same number of hertz every second.
+    Linear,
+    /// Frequency changes by the same ratio every second, so each octave takes the same time.
+    ///
+    /// Both frequencies must be positive.
+    Logarithmic,
+}
+
+/// A full-scale sine wave sweeping between two frequencies.
+///
+/// Phase is accumulated in `f64` so the signal stays continuous as the frequency changes, and
+/// starts at zero. Once the sweep is complete the stream ends, unless set to hold the final
+/// frequency. As with `Tone`, `P` is the type the sine wave is generated in.
+pub struct Chirp<F, P=f32> {
+    src: UninitializedSource<F>,
+    start: f64,
+    end: f64,
+    sweep: Sweep,
+    rate: u32,
+    /// Length of the sweep in samples.
+    length: u64,
+    /// Index of the next sample.
+    position: u64,
+    /// Current position within the cycle, in [0, 1).
+    phase: f64,
+    hold: bool,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> Chirp<F, P> {
+    /// Create a generator sweeping from `f_start` to `f_end` hertz over `duration` seconds, for
+    /// a stream with the specified sample rate and buffers of `size` samples.
+    pub fn new(size: usize, f_start: f64, f_end: f64, duration: f64, sample_rate: u32,
+               sweep: Sweep) -> Chirp<F, P> {
+        if sweep == Sweep::Logarithmic {
+            assert!(f_start > 0f64 && f_end > 0f64,
+                    "Logarithmic sweep frequencies must be positive");
+        }
+        Chirp {
+            src: UninitializedSource::new(size),
+            start: f_start,
+            end: f_end,
+            sweep: sweep,
+            rate: sample_rate,
+            length: (duration * sample_rate as f64).round() as u64,
+            position: 0,
+            phase: 0.0,
+            hold: false,
+            gentype: PhantomData
+        }
+    }
+
+    /// Select whether to continue at `f_end` after the sweep, rather than ending the stream.
+    pub fn set_hold(&mut self, hold: bool) {
+        self.hold = hold;
+    }
+
+    /// Instantaneous frequency in hertz at sample `n`.
+    pub fn frequency_at(&self, n: u64) -> f64 {
+        if n >= self.length {
+            return self.end;
+        }
+        let t = n as f64 / self.length as f64;
+        match self.sweep {
+            Sweep::Linear => self.start + (self.end - self.start) * t,
+            Sweep::Logarithmic => self.start * (self.end / self.start).powf(t),
+        }
+    }
+}
+
+impl<F: Sample, P: Sample+Float> MonoSource for Chirp<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        if !self.hold && self.position >= self.length {
+            return None;
+        }
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        let mut len = buf.len();
+        if !self.hold {
+            len = ::std::cmp::min(len as u64, self.length - self.position) as usize;
+        }
+        for x in buf[..len].iter_mut() {
+            let mut y: P = NumCast::from(self.phase).unwrap();
+            y = y * NumCast::from(PI_2).unwrap();
+            *x = Sample::convert::<F>(y.sin());
+
+            self.phase += self.frequency_at(self.position) / self.rate as f64;
+            self.phase -= self.phase.floor();
+            self.position += 1;
+        }
+        Some(&mut buf[..len])
+    }
+}
+
+/// Estimate the frequency of a sinusoid at sample `n` from its neighbours.
+///
+/// `xs[n]` should be well away from zero.
+#[cfg(test)]
+fn estimate_frequency(xs: &[f64], n: usize, rate: u32) -> f64 {
+    let cos = (xs[n - 1] + xs[n + 1]) / (2f64 * xs[n]);
+    cos.acos() * rate as f64 / PI_2
+}
+
+#[test]
+fn chirp_sweeps_between_frequencies() {
+    let rate = 44100;
+    for &sweep in [Sweep::Linear, Sweep::Logarithmic].iter() {
+        let mut src = Chirp::<f64, f64>::new(1000, 100f64, 2000f64, 1f64, rate, sweep);
+        let mut xs = vec![];
+        while let Some(b) = src.next() {
+            xs.extend(b.iter().cloned());
+        }
+        assert_eq!(xs.len(), 44100);
+
+        let first = (1..xs.len()).find(|&n| xs[n].abs() > 0.5).unwrap();
+        let last = (1..xs.len() - 1).rev().find(|&n| xs[n].abs() > 0.5).unwrap();
+        let (f0, f1) = (estimate_frequency(&xs, first, rate), estimate_frequency(&xs, last, rate));
+        assert!((f0 / 100f64 - 1f64).abs() < 0.01, "{:?} sweep started at {} Hz", sweep, f0);
+        assert!((f1 / 2000f64 - 1f64).abs() < 0.01, "{:?} sweep ended at {} Hz", sweep, f1);
+    }
+}
+
+#[test]
+fn chirp_holds_final_frequency() {
+    let mut src = Chirp::<f64, f64>::new(1000, 1000f64, 500f64, 0.01, 44100, Sweep::Linear);
+    src.set_hold(true);
+    let mut xs = vec![];
+    for _ in 0..10 {
+        xs.extend(src.next().unwrap().iter().cloned());
+    }
+    let n = (5000..xs.len()).find(|&n| xs[n].abs() > 0.5).unwrap();
+    let f = estimate_frequency(&xs, n, 44100);
+    assert!((f / 500f64 - 1f64).abs() < 0.001, "Held frequency was {} Hz", f);
+}
+
+/// Position of sample `t` within a cycle of `period` samples, in [0, 1).
+fn phase<P: Float + Sample>(t: usize, period: usize) -> P {
+    let t: P = NumCast::from(t).unwrap();
+    t / NumCast::from(period).unwrap()
+}
+
+/// A square wave.
+///
+/// The emitted signal is full-scale, high for the first `duty` fraction of each period and low
+/// for the remainder.
+pub struct Square<F, P=f32> {
+    src: UninitializedSource<F>,
+    timebase: Cycle<Range<usize>>,
+    period: usize,
+    duty: P
+}
+
+impl<F: Sample, P: Sample + Float> Square<F, P> {
+    /// Create a square wave generator with 50% duty cycle and the specified period in samples
+    /// for buffers of `size` samples.
+    pub fn new(size: usize, period: usize) -> Square<F, P> {
+        Square::with_duty(size, period, NumCast::from(0.5).unwrap())
+    }
+
+    /// Create a square wave generator with the specified duty cycle, in range [0, 1].
+    pub fn with_duty(size: usize, period: usize, duty: P) -> Square<F, P> {
+        Square {
+            src: UninitializedSource::new(size),
+            timebase: (0..period).cycle(),
+            period: period,
+            duty: duty
+        }
+    }
+}
+
+impl<F: Sample, P: Sample + Float> MonoSource for Square<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        for (x, t) in buf.iter_mut().zip(self.timebase.by_ref()) {
+            let y: P = if phase::<P>(t, self.period) < self.duty {
+                Sample::max()
+            } else {
+                Sample::min()
+            };
+            *x = Sample::convert::<F>(y);
+        }
+        Some(buf)
+    }
+}
+
+#[bench]
+fn generate_square_441_44100(b: &mut Bencher) {
+    let bufsize = 4096;
+    let mut src = Square::<i16>::new(bufsize, 100);
+    b.bytes = ::std::mem::size_of::<i16>() as u64 * bufsize as u64;
+    b.iter(|| {
+        src.next();
+    });
+}
+
+/// A sawtooth wave.
+///
+/// The emitted signal is full-scale, starting at zero and rising linearly to the maximum value
+/// at half a period, where it falls to the minimum and continues rising.
+pub struct Saw<F, P=f32> {
+    src: UninitializedSource<F>,
+    timebase: Cycle<Range<usize>>,
+    period: usize,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> Saw<F, P> {
+    /// Create a sawtooth generator with a specified period in samples for buffers of `size`
+    /// samples.
+    pub fn new(size: usize, period: usize) -> Saw<F, P> {
+        Saw {
+            src: UninitializedSource::new(size),
+            timebase: (0..period).cycle(),
+            period: period,
+            gentype: PhantomData
+        }
+    }
+}
+
+impl<F: Sample, P: Sample + Float> MonoSource for Saw<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        let two: P = NumCast::from(2).unwrap();
+        let half: P = NumCast::from(0.5).unwrap();
+        for (x, t) in buf.iter_mut().zip(self.timebase.by_ref()) {
+            let p = phase::<P>(t, self.period);
+            let y = if p < half { two * p } else { two * p - two };
+            *x = Sample::convert::<F>(y);
+        }
+        Some(buf)
+    }
+}
+
+#[bench]
+fn generate_saw_441_44100(b: &mut Bencher) {
+    let bufsize = 4096;
+    let mut src = Saw::<i16>::new(bufsize, 100);
+    b.bytes = ::std::mem::size_of::<i16>() as u64 * bufsize as u64;
+    b.iter(|| {
+        src.next();
+    });
+}
+
+/// A triangle wave.
+///
+/// The emitted signal is full-scale and starts at zero, reaching its maximum a quarter of the
+/// way through each period and its minimum three quarters of the way through.
+pub struct Triangle<F, P=f32> {
+    src: UninitializedSource<F>,
+    timebase: Cycle<Range<usize>>,
+    period: usize,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> Triangle<F, P> {
+    /// Create a triangle wave generator with a specified period in samples for buffers of
+    /// `size` samples.
+    pub fn new(size: usize, period: usize) -> Triangle<F, P> {
+        Triangle {
+            src: UninitializedSource::new(size),
+            timebase: (0..period).cycle(),
+            period: period,
+            gentype: PhantomData
+        }
+    }
+}
+
+impl<F: Sample, P: Sample + Float> MonoSource for Triangle<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        let two: P = NumCast::from(2).unwrap();
+        let four: P = NumCast::from(4).unwrap();
+        let quarter: P = NumCast::from(0.25).unwrap();
+        let three_quarters: P = NumCast::from(0.75).unwrap();
+        for (x, t) in buf.iter_mut().zip(self.timebase.by_ref()) {
+            let p = phase::<P>(t, self.period);
+            let y = if p < quarter {
+                four * p
+            } else if p < three_quarters {
+                two - four * p
+            } else {
+                four * p - four
+            };
+            *x = Sample::convert::<F>(y);
+        }
+        Some(buf)
+    }
+}
+
+#[bench]
+fn generate_triangle_441_44100(b: &mut Bencher) {
+    let bufsize = 4096;
+    let mut src = Triangle::<i16>::new(bufsize, 100);
+    b.bytes = ::std::mem::size_of::<i16>() as u64 * bufsize as u64;
+    b.iter(|| {
+        src.next();
+    });
+}
+
+/// PolyBLEP residual for a discontinuity at phase zero.
+///
+/// `t` is the phase in [0, 1) and `dt` the phase increment per sample. The residual is nonzero
+/// only within one sample either side of the discontinuity, where it smooths a unit step (from
+/// -1 to 1, falling as `t` wraps) into a polynomial approximation of a bandlimited step.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        2f64 * t - t * t - 1f64
+    } else if t > 1f64 - dt {
+        let t = (t - 1f64) / dt;
+        t * t + 2f64 * t + 1f64
+    } else {
+        0f64
+    }
+}
+
+/// A bandlimited sawtooth wave.
+///
+/// Follows the same shape as `Saw`, starting at zero and rising to full scale, but the frequency
+/// need not be a whole number of samples and the discontinuity is corrected with PolyBLEP, which
+/// greatly reduces aliasing at high frequencies. Phase is accumulated in `f64`; `P` is the type
+/// the output is converted through.
+pub struct BlepSaw<F, P=f32> {
+    src: UninitializedSource<F>,
+    /// Phase increment per sample, in cycles.
+    dt: f64,
+    /// Current position within the cycle, in [0, 1).
+    phase: f64,
+    rate: u32,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> BlepSaw<F, P> {
+    /// Create a sawtooth generator at `frequency` hertz for a stream with the specified sample
+    /// rate and buffers of `size` samples.
+    pub fn new(size: usize, frequency: f64, sample_rate: u32) -> BlepSaw<F, P> {
+        BlepSaw {
+            src: UninitializedSource::new(size),
+            dt: frequency / sample_rate as f64,
+            // The ramp crosses zero half way through the cycle.
+            phase: 0.5,
+            rate: sample_rate,
+            gentype: PhantomData
+        }
+    }
+
+    /// Change the frequency, continuing from the current phase.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.dt = frequency / self.rate as f64;
+    }
+}
+
+impl<F: Sample, P: Sample + Float> MonoSource for BlepSaw<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        for x in buf.iter_mut() {
+            let y = 2f64 * self.phase - 1f64 - poly_blep(self.phase, self.dt);
+            let y: P = NumCast::from(y).unwrap();
+            *x = Sample::convert::<F>(y);
+
+            self.phase += self.dt;
+            self.phase -= self.phase.floor();
+        }
+        Some(buf)
+    }
+}
+
+/// A bandlimited square wave.
+///
+/// Full-scale with a 50% duty cycle, high for the first half of each cycle. Both edges are
+/// corrected with PolyBLEP as for `BlepSaw`.
+pub struct BlepSquare<F, P=f32> {
+    src: UninitializedSource<F>,
+    /// Phase increment per sample, in cycles.
+    dt: f64,
+    /// Current position within the cycle, in [0, 1).
+    phase: f64,
+    rate: u32,
+    gentype: PhantomData<P>
+}
+
+impl<F: Sample, P = f32> BlepSquare<F, P> {
+    /// Create a square wave generator at `frequency` hertz for a stream with the specified
+    /// sample rate and buffers of `size` samples.
+    pub fn new(size: usize, frequency: f64, sample_rate: u32) -> BlepSquare<F, P> {
+        BlepSquare {
+            src: UninitializedSource::new(size),
+            dt: frequency / sample_rate as f64,
+            phase: 0.0,
+            rate: sample_rate,
+            gentype: PhantomData
+        }
+    }
+
+    /// Change the frequency, continuing from the current phase.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.dt = frequency / self.rate as f64;
+    }
+}
+
+impl<F: Sample, P: Sample + Float> MonoSource for BlepSquare<F, P> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [F]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        for x in buf.iter_mut() {
+            let mut y = if self.phase < 0.5 { 1f64 } else { -1f64 };
+            // Rising edge at phase zero, falling edge half way through.
+            y += poly_blep(self.phase, self.dt);
+            let falling = self.phase + 0.5;
+            y -= poly_blep(falling - falling.floor(), self.dt);
+            let y: P = NumCast::from(y).unwrap();
+            *x = Sample::convert::<F>(y);
+
+            self.phase += self.dt;
+            self.phase -= self.phase.floor();
+        }
+        Some(buf)
+    }
+}
+
+/// Energy in the spectrum of `xs` outside the first `n` harmonics of `bin`, as a fraction of the
+/// total.
+///
+/// `xs` must hold a whole number of periods, so any such energy is aliasing.
+#[cfg(test)]
+fn inharmonic_energy(xs: &[f64], bin: usize, n: usize) -> f64 {
+    use num::Complex;
+    use super::fft;
+
+    let input: Vec<_> = xs.iter().map(|&x| Complex::new(x, 0f64)).collect();
+    let mut output = vec![Complex::new(0f64, 0f64); xs.len()];
+    fft::forward(&input, &mut output);
+
+    let harmonic = |k: usize| (1..n + 1).any(|j| k == j * bin || k == xs.len() - j * bin);
+    let energy = |k: &usize| output[*k].norm_sqr();
+    let total: f64 = (1..xs.len()).map(|k| energy(&k)).fold(0f64, |a, b| a + b);
+    let inharmonic = (1..xs.len()).filter(|&k| !harmonic(k)).map(|k| energy(&k))
+                                  .fold(0f64, |a, b| a + b);
+    inharmonic / total
+}
+
+#[test]
+fn blep_saw_reduces_aliasing() {
+    // 313 cycles in 4096 samples, so each harmonic falls exactly on a bin and harmonics beyond the
+    // sixth alias to bins between them.
+    let (n, bin, rate) = (4096, 313, 40960);
+    let mut src = BlepSaw::<f64, f64>::new(n, bin as f64 * rate as f64 / n as f64, rate);
+    let blep = src.next().unwrap().to_vec();
+    assert!(blep[0].abs() < 1e-9);
+
+    let dt = bin as f64 / n as f64;
+    let naive: Vec<f64> = (0..n).map(|i| {
+        let p = 0.5 + i as f64 * dt;
+        2f64 * (p - p.floor()) - 1f64
+    }).collect();
+
+    let (aliased, naive_aliased) = (inharmonic_energy(&blep, bin, 6),
+                                    inharmonic_energy(&naive, bin, 6));
+    assert!(aliased < naive_aliased / 10f64,
+            "PolyBLEP aliasing {} is not far below naive {}", aliased, naive_aliased);
+}
+
+#[test]
+fn blep_square_is_full_scale() {
+    let mut src = BlepSquare::<f64, f64>::new(100, 441f64, 44100);
+    let xs = src.next().unwrap();
+    // Away from the edges, the wave is unaffected.
+    assert!(xs[2..48].iter().all(|&x| x == 1f64));
+    assert!(xs[52..98].iter().all(|&x| x == -1f64));
+}
+
 /// Pure Gaussian white noise.
 pub struct WhiteNoise<F, R> {
     rng: R,
@@ -126,6 +867,11 @@ impl<R: Rng> WhiteNoise<f64, R> {
             src: UninitializedSource::new(size)
         }
     }
+
+    /// Change the number of samples in subsequent buffers.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.src.set_size(size);
+    }
 }
 
 impl<R: Rng> MonoSource for WhiteNoise<f64, R> {
@@ -157,3 +903,341 @@ fn generate_xorshift_noise_44100(b: &mut Bencher) {
         src.next();
     });
 }
+
+/// Number of octave generators summed by `PinkNoise`.
+const PINK_ROWS: usize = 16;
+
+/// Gaussian pink (1/f) noise.
+///
+/// Generated with the Voss-McCartney algorithm: a set of white noise generators each updated
+/// half as often as the previous one are summed together with a generator updated every sample.
+pub struct PinkNoise<F, R> {
+    rng: R,
+    normal: Normal,
+    rows: [f64; PINK_ROWS],
+    counter: u32,
+    src: UninitializedSource<F>
+}
+
+impl<R: Rng> PinkNoise<f64, R> {
+    /// Create a pink noise generator for buffers of `size` samples.
+    pub fn new(size: usize, rng: R) -> PinkNoise<f64, R> {
+        PinkNoise {
+            rng: rng,
+            normal: Normal::new(0f64, 0.25),
+            rows: [0f64; PINK_ROWS],
+            counter: 0,
+            src: UninitializedSource::new(size)
+        }
+    }
+}
+
+impl<R: Rng> MonoSource for PinkNoise<f64, R> {
+    type Output = f64;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [f64]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        // Scale so the sum has the same variance as a single generator.
+        let scale = 1f64 / ((PINK_ROWS + 1) as f64).sqrt();
+        for x in buf.iter_mut() {
+            // Row n is updated every 2^(n+1) samples.
+            self.counter = self.counter.wrapping_add(1);
+            let row = self.counter.trailing_zeros() as usize;
+            if row < PINK_ROWS {
+                self.rows[row] = self.normal.ind_sample(&mut self.rng);
+            }
+
+            let sum = self.rows.iter().fold(self.normal.ind_sample(&mut self.rng),
+                                            |a, &b| a + b);
+            *x = (sum * scale).clip();
+        }
+        Some(buf)
+    }
+}
+
+/// Gaussian brown (1/f²) noise.
+///
+/// Generated by integrating white noise. The integrator is leaky to prevent the output from
+/// drifting without bound; a leak factor closer to 1 extends the 1/f² slope to lower frequencies.
+pub struct BrownNoise<F, R> {
+    rng: R,
+    normal: Normal,
+    leak: f64,
+    /// Integrator state.
+    level: f64,
+    src: UninitializedSource<F>
+}
+
+impl<R: Rng> BrownNoise<f64, R> {
+    /// Create a brown noise generator for buffers of `size` samples with a leak factor of 0.995.
+    pub fn new(size: usize, rng: R) -> BrownNoise<f64, R> {
+        BrownNoise::with_leak(size, rng, 0.995)
+    }
+
+    /// Create a brown noise generator with the specified leak factor, in range (0, 1).
+    pub fn with_leak(size: usize, rng: R, leak: f64) -> BrownNoise<f64, R> {
+        BrownNoise {
+            rng: rng,
+            normal: Normal::new(0f64, 0.25),
+            leak: leak,
+            level: 0f64,
+            src: UninitializedSource::new(size)
+        }
+    }
+}
+
+impl<R: Rng> MonoSource for BrownNoise<f64, R> {
+    type Output = f64;
+
+    fn next<'a>(&'a mut self) -> Option<&'a mut [f64]> {
+        let buf = match self.src.next() {
+            Some(b) => b,
+            None => return None
+        };
+
+        // Scale input so the output has the same variance as the input noise.
+        let gain = (1f64 - self.leak * self.leak).sqrt();
+        for x in buf.iter_mut() {
+            self.level = self.leak * self.level + gain * self.normal.ind_sample(&mut self.rng);
+            *x = self.level.clip();
+        }
+        Some(buf)
+    }
+}
+
+/// Ratio of the energy in the first difference of `xs` to that in `xs`.
+///
+/// The first difference is a simple high-pass filter, so this is small for signals dominated
+/// by low frequencies. For white noise the ratio is 2.
+#[cfg(test)]
+fn highpass_energy_ratio(xs: &[f64]) -> f64 {
+    let energy = xs.iter().fold(0f64, |a, &x| a + x * x);
+    let hf_energy = xs.windows(2).fold(0f64, |a, w| a + (w[1] - w[0]) * (w[1] - w[0]));
+    hf_energy / energy
+}
+
+#[test]
+fn noise_colors_have_decreasing_slope() {
+    use rand::XorShiftRng;
+
+    let n = 1 << 16;
+    let white = WhiteNoise::new(n, XorShiftRng::new_unseeded()).next().unwrap().to_vec();
+    let pink = PinkNoise::new(n, XorShiftRng::new_unseeded()).next().unwrap().to_vec();
+    let brown = BrownNoise::new(n, XorShiftRng::new_unseeded()).next().unwrap().to_vec();
+
+    let (w, p, b) = (highpass_energy_ratio(&white),
+                     highpass_energy_ratio(&pink),
+                     highpass_energy_ratio(&brown));
+    assert!((w - 2f64).abs() < 0.1, "White noise HF ratio was {}", w);
+    assert!(p < 0.5, "Pink noise HF ratio was {}", p);
+    assert!(b < p / 4f64, "Brown noise HF ratio was {}, pink was {}", b, p);
+}
+
+/// Stages of an `Adsr` envelope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdsrStage {
+    /// No note is playing and the envelope is at zero.
+    Idle,
+    /// Rising to full scale after `note_on`.
+    Attack,
+    /// Falling from full scale to the sustain level.
+    Decay,
+    /// Holding the sustain level until `note_off`.
+    Sustain,
+    /// Falling to zero after `note_off`.
+    Release,
+}
+
+/// Attack-decay-sustain-release envelope generator.
+///
+/// Produces a gain for each sample, following linear segments between stages. Attack, decay and
+/// release times are in seconds, resolved against the sample rate, and the sustain level is a
+/// linear gain. Triggering a stage partway through another starts it from the current level, so
+/// the envelope never jumps.
+pub struct Adsr {
+    attack: f64,
+    decay: f64,
+    sustain: f64,
+    release: f64,
+    rate: u32,
+    stage: AdsrStage,
+    level: f64,
+    /// Level at the start of the current stage.
+    from: f64,
+    /// Samples elapsed in the current stage.
+    pos: usize,
+}
+
+impl Adsr {
+    /// Create an idle envelope generator.
+    pub fn new(attack: f64, decay: f64, sustain: f64, release: f64, sample_rate: u32) -> Adsr {
+        Adsr {
+            attack: attack,
+            decay: decay,
+            sustain: sustain,
+            release: release,
+            rate: sample_rate,
+            stage: AdsrStage::Idle,
+            level: 0f64,
+            from: 0f64,
+            pos: 0,
+        }
+    }
+
+    /// Change the sample rate against which stage times are resolved.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.rate = sample_rate;
+    }
+
+    /// Begin the attack stage.
+    pub fn note_on(&mut self) {
+        self.begin(AdsrStage::Attack);
+    }
+
+    /// Begin the release stage, unless the envelope is idle.
+    pub fn note_off(&mut self) {
+        if self.stage != AdsrStage::Idle {
+            self.begin(AdsrStage::Release);
+        }
+    }
+
+    /// The current stage.
+    pub fn stage(&self) -> AdsrStage {
+        self.stage
+    }
+
+    /// Get the gain for the next sample and advance the envelope.
+    pub fn next_gain(&mut self) -> f64 {
+        let gain = self.level;
+
+        let (target, time, next) = match self.stage {
+            AdsrStage::Attack => (1f64, self.attack, AdsrStage::Decay),
+            AdsrStage::Decay => (self.sustain, self.decay, AdsrStage::Sustain),
+            AdsrStage::Release => (0f64, self.release, AdsrStage::Idle),
+            AdsrStage::Idle | AdsrStage::Sustain => return gain
+        };
+        let length = (time * self.rate as f64).round() as usize;
+
+        self.pos += 1;
+        if self.pos >= length {
+            self.level = target;
+            self.begin(next);
+        } else {
+            self.level = self.from + (target - self.from) * self.pos as f64 / length as f64;
+        }
+        gain
+    }
+
+    fn begin(&mut self, stage: AdsrStage) {
+        self.stage = stage;
+        self.from = self.level;
+        self.pos = 0;
+    }
+}
+
+/// Applies an `Adsr` envelope to a `Source`.
+///
+/// The same gain is applied to every channel of each frame. The envelope's sample rate is updated
+/// whenever the source reports one. Use `envelope_mut` to trigger notes; the stream continues
+/// after `note_off`, so the release stage plays out as long as the consumer keeps pulling.
+pub struct Envelope<F, S> {
+    source: S,
+    envelope: Adsr,
+    format: PhantomData<F>,
+}
+
+impl<F: Sample, S: Source<Output=F>> Envelope<F, S> {
+    /// Apply `envelope` to `source`.
+    pub fn new(source: S, envelope: Adsr) -> Envelope<F, S> {
+        Envelope {
+            source: source,
+            envelope: envelope,
+            format: PhantomData
+        }
+    }
+
+    /// The envelope generator.
+    pub fn envelope(&self) -> &Adsr {
+        &self.envelope
+    }
+
+    /// The envelope generator, for triggering notes.
+    pub fn envelope_mut(&mut self) -> &mut Adsr {
+        &mut self.envelope
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Envelope<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        let buf = match self.source.next() {
+            SourceResult::Buffer(b) => b,
+            SourceResult::SampleRate(r) => {
+                self.envelope.set_sample_rate(r);
+                return SourceResult::SampleRate(r);
+            }
+            x => return x
+        };
+
+        for i in 0..buf[0].len() {
+            let gain = self.envelope.next_gain();
+            for channel in buf.iter_mut() {
+                let x: f64 = Sample::to_float(channel[i]);
+                channel[i] = Sample::from_float(x * gain);
+            }
+        }
+        SourceResult::Buffer(buf)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[test]
+fn adsr_stages() {
+    let mut adsr = Adsr::new(0.01, 0.01, 0.5, 0.02, 1000);
+    assert_eq!(adsr.next_gain(), 0f64);
+
+    adsr.note_on();
+    for i in 0..10 {
+        assert_eq!(adsr.stage(), AdsrStage::Attack);
+        let gain = adsr.next_gain();
+        assert!((gain - i as f64 / 10f64).abs() < 1e-9, "Attack gain {} was {}", i, gain);
+    }
+    assert_eq!(adsr.stage(), AdsrStage::Decay);
+    for _ in 0..10 {
+        adsr.next_gain();
+    }
+    assert_eq!(adsr.stage(), AdsrStage::Sustain);
+    assert_eq!(adsr.next_gain(), 0.5);
+
+    adsr.note_off();
+    for i in 0..20 {
+        assert_eq!(adsr.stage(), AdsrStage::Release);
+        let gain = adsr.next_gain();
+        let expected = 0.5 - 0.5 * i as f64 / 20f64;
+        assert!((gain - expected).abs() < 1e-9, "Release gain {} was {}", i, gain);
+    }
+    assert_eq!(adsr.stage(), AdsrStage::Idle);
+    assert_eq!(adsr.next_gain(), 0f64);
+}
+
+#[test]
+fn envelope_shapes_source() {
+    use super::tests::ConstantSource;
+
+    let src = ConstantSource { data: vec![1f64; 4], sbuf: vec![] };
+    let mut env = Envelope::new(src.adapt(), Adsr::new(0.004, 0.0, 1.0, 0.004, 1000));
+    env.envelope_mut().note_on();
+    assert_eq!(env.next(), SourceResult::Buffer(&mut [&mut [0.0, 0.25, 0.5, 0.75]]));
+    assert_eq!(env.next(), SourceResult::Buffer(&mut [&mut [1.0; 4]]));
+    env.envelope_mut().note_off();
+    assert_eq!(env.next(), SourceResult::Buffer(&mut [&mut [1.0, 0.75, 0.5, 0.25]]));
+    assert_eq!(env.next(), SourceResult::Buffer(&mut [&mut [0.0; 4]]));
+}
@@ -0,0 +1,238 @@
+//! Removal of silence from stream boundaries.
+
+use std::raw::Slice;
+use super::{Sample, Source, SourceResult, channel_buffer, consume};
+
+/// Look-ahead used by `Silence::new`, in samples per channel.
+pub const DEFAULT_LOOKAHEAD: usize = 44100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// No signal yet, and the quiet run so far is shorter than the minimum.
+    Start,
+    /// Dropping leading silence.
+    Leading,
+    /// Signal has been seen.
+    Active,
+}
+
+/// Trims leading and trailing silence from a stream.
+///
+/// A frame is quiet if the magnitude of every channel is no more than the threshold, a linear
+/// fraction of full scale. A run of quiet frames at the start or end of the stream is silence if it
+/// is at least `min_run` frames long, and is dropped; shorter runs, and quiet regions within the
+/// stream, are passed through.
+///
+/// Because silence at the end of the stream can't be recognized until the stream ends, quiet
+/// frames following signal are held back in a look-ahead buffer. If a quiet run outgrows the
+/// look-ahead the oldest frames are released, so at most the look-ahead length is trimmed from the
+/// end of the stream.
+pub struct Silence<F, S> {
+    source: S,
+    threshold: f64,
+    min_run: usize,
+    lookahead: usize,
+    state: State,
+    /// The current quiet run, per channel.
+    held: Vec<Vec<F>>,
+    out: Vec<Vec<F>>,
+    slices: Vec<Slice<F>>,
+    ended: bool,
+}
+
+impl<F: Sample, S: Source<Output=F>> Silence<F, S> {
+    /// Construct a silence trimmer with the default look-ahead.
+    pub fn new(source: S, threshold: f64, min_run: usize) -> Silence<F, S> {
+        Silence {
+            source: source,
+            threshold: threshold,
+            min_run: min_run,
+            lookahead: DEFAULT_LOOKAHEAD,
+            state: State::Start,
+            held: Vec::new(),
+            out: Vec::new(),
+            slices: Vec::new(),
+            ended: false,
+        }
+    }
+
+    /// Set the maximum length of trailing silence that can be trimmed, in samples per channel.
+    ///
+    /// This is the bound on how much audio is held back, and is at least `min_run`.
+    pub fn set_lookahead(&mut self, lookahead: usize) {
+        self.lookahead = lookahead;
+    }
+}
+
+/// Append frames `from..to` of `channels` to `dest`.
+fn append<F: Copy>(dest: &mut [Vec<F>], channels: &[&mut [F]], from: usize, to: usize) {
+    for (d, c) in dest.iter_mut().zip(channels.iter()) {
+        d.extend(c[from..to].iter().cloned());
+    }
+}
+
+/// Move all of `src` to the end of `dest`.
+fn drain_into<F: Copy>(dest: &mut [Vec<F>], src: &mut [Vec<F>]) {
+    for (d, s) in dest.iter_mut().zip(src.iter_mut()) {
+        d.extend(s.iter().cloned());
+        s.clear();
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Silence<F, S> {
+    /// Pull from the source until there are frames in `out` to emit.
+    ///
+    /// Returns a result to yield immediately, if any. At the end of the stream, held frames too
+    /// few to be silence are moved to `out`.
+    fn fill<'a>(&mut self) -> Option<SourceResult<'a, F>> {
+        let threshold = self.threshold;
+        let min_run = self.min_run;
+        let lookahead = ::std::cmp::max(self.lookahead, min_run);
+
+        while self.out.is_empty() || self.out[0].is_empty() {
+            let buf = match self.source.next() {
+                SourceResult::Buffer(b) => b,
+                SourceResult::SampleRate(r) => return Some(SourceResult::SampleRate(r)),
+                SourceResult::EndOfStream => {
+                    self.ended = true;
+                    let held = if self.held.is_empty() { 0 } else { self.held[0].len() };
+                    if held == 0 || (self.state != State::Leading && held >= min_run) {
+                        return Some(SourceResult::EndOfStream);
+                    }
+                    // Too short to be silence.
+                    drain_into(&mut self.out, &mut self.held);
+                    return None;
+                }
+                SourceResult::StreamError(e) => return Some(SourceResult::StreamError(e))
+            };
+
+            if self.out.len() != buf.len() {
+                self.out = vec![Vec::new(); buf.len()];
+                self.held = vec![Vec::new(); buf.len()];
+            }
+
+            // Start of the pending run of loud frames in buf.
+            let mut loud_start = 0;
+            for i in 0..buf[0].len() {
+                let quiet = buf.iter().all(|c| {
+                    Sample::to_float::<f64>(c[i]).abs() <= threshold
+                });
+
+                if quiet {
+                    append(&mut self.out, buf, loud_start, i);
+                    loud_start = i + 1;
+                    if self.state != State::Leading {
+                        append(&mut self.held, buf, i, i + 1);
+                        if self.state == State::Start && self.held[0].len() >= min_run {
+                            for channel in self.held.iter_mut() {
+                                channel.clear();
+                            }
+                            self.state = State::Leading;
+                        }
+                    }
+                } else {
+                    if i == loud_start {
+                        // A quiet run just ended, so it wasn't silence.
+                        drain_into(&mut self.out, &mut self.held);
+                    }
+                    self.state = State::Active;
+                }
+            }
+            append(&mut self.out, buf, loud_start, buf[0].len());
+
+            // Release frames that don't fit in the look-ahead.
+            let held = self.held[0].len();
+            if self.state == State::Active && held > lookahead {
+                let excess = held - lookahead;
+                for (d, s) in self.out.iter_mut().zip(self.held.iter_mut()) {
+                    d.extend(s[..excess].iter().cloned());
+                    consume(s, excess);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<F: Sample, S: Source<Output=F>> Source for Silence<F, S> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.ended {
+            return SourceResult::EndOfStream;
+        }
+
+        for channel in self.out.iter_mut() {
+            channel.clear();
+        }
+        if let Some(result) = self.fill() {
+            return result;
+        }
+        channel_buffer(&mut self.out, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.source.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::raw::Slice;
+    use super::super::{Source, SourceResult, channel_buffer};
+    use super::Silence;
+
+    /// Emits each of `buffers` in turn as a mono buffer.
+    struct Script {
+        buffers: Vec<Vec<f64>>,
+        channels: Vec<Vec<f64>>,
+        slices: Vec<Slice<f64>>,
+    }
+
+    impl Source for Script {
+        type Output = f64;
+
+        fn next<'a>(&'a mut self) -> SourceResult<'a, f64> {
+            if self.buffers.is_empty() {
+                return SourceResult::EndOfStream;
+            }
+            self.channels = vec![self.buffers.remove(0)];
+            channel_buffer(&mut self.channels, &mut self.slices)
+        }
+    }
+
+    fn trim(buffers: Vec<Vec<f64>>, min_run: usize, lookahead: usize) -> Vec<f64> {
+        let script = Script { buffers: buffers, channels: vec![], slices: vec![] };
+        let mut trim = Silence::new(script, 0.01, min_run);
+        trim.set_lookahead(lookahead);
+
+        let mut out = vec![];
+        loop {
+            match trim.next() {
+                SourceResult::Buffer(b) => out.extend(b[0].iter().cloned()),
+                SourceResult::EndOfStream => return out,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn trims_silence_around_tone() {
+        let tone = vec![0.5, -0.5, 0.001, 0.5, -0.5];
+        let out = trim(vec![vec![0.0; 8], vec![0.0, 0.0, 0.5, -0.5],
+                            vec![0.001, 0.5, -0.5, 0.0], vec![0.0; 8]], 4, 16);
+        assert_eq!(out, tone);
+    }
+
+    #[test]
+    fn keeps_short_quiet_runs() {
+        assert_eq!(trim(vec![vec![0.0, 0.5, 0.0]], 2, 16), vec![0.0, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn trailing_trim_is_bounded() {
+        let out = trim(vec![vec![0.5], vec![0.0; 10]], 2, 4);
+        assert_eq!(out, vec![0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
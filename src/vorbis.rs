@@ -9,23 +9,365 @@
 
 extern crate vorbisfile;
 
-use std::io::Read;
-use super::{Source, SourceResult};
-use super::SourceResult::{Buffer, StreamError, EndOfStream};
-use self::vorbisfile::OVResult;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::raw::Slice;
+use std::rc::Rc;
+use super::{Source, SourceResult, consume, channel_buffer};
+use super::SourceResult::{Buffer, SampleRate, StreamError, EndOfStream};
+use super::StreamError as Error;
+use self::vorbisfile::{OVError, OVResult};
+
+/// Length of the fixed part of an Ogg page header.
+const OGG_HEADER_LEN: usize = 27;
+
+/// Parameters of one logical bitstream in a chained stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Link {
+    serial: u32,
+    rate: u32,
+    channels: usize,
+    /// Samples per channel, from the granule position of the link's last page once it's been read.
+    length: Option<u64>,
+}
+
+/// Incremental Ogg page parser picking out Vorbis identification headers.
+///
+/// vorbisfile doesn't expose `ov_info`, so we recover stream parameters by watching the compressed
+/// data on its way to the decoder. Each logical bitstream begins with a page flagged
+/// beginning-of-stream, the first packet of which is the identification header.
+///
+/// The decoder reads ahead, so headers are seen before the samples preceding them have been
+/// decoded. Links are queued as they're read and retired as the decoder reaches their ends, so the
+/// front of the queue always describes the samples being decoded.
+struct OggScanner {
+    /// Bytes of an incomplete page.
+    pending: Vec<u8>,
+    /// Links read but not yet entirely decoded, oldest first.
+    links: VecDeque<Link>,
+    /// Samples per channel decoded from the front link.
+    decoded: u64,
+    /// Bytes passed to the decoder.
+    read: u64,
+}
+
+impl OggScanner {
+    fn new() -> OggScanner {
+        OggScanner {
+            pending: Vec::new(),
+            links: VecDeque::new(),
+            decoded: 0,
+            read: 0,
+        }
+    }
+
+    /// The link currently being decoded, if its header has been read.
+    fn current(&self) -> Option<Link> {
+        self.links.front().cloned()
+    }
+
+    /// Account for a decoded buffer, returning the link it belongs to.
+    fn decoded(&mut self, channels: usize, samples: usize) -> Option<Link> {
+        // A different channel count means we missed the end of the previous link, so its length
+        // must not have matched its final granule position.
+        if self.links.len() > 1 && self.links.front().map_or(false, |l| l.channels != channels) {
+            self.retire();
+        }
+
+        let link = self.current();
+        self.decoded += samples as u64;
+        if link.and_then(|l| l.length).map_or(false, |n| self.decoded >= n) {
+            self.retire();
+        }
+        link
+    }
+
+    /// Move on from a completely decoded link.
+    fn retire(&mut self) {
+        self.links.pop_front();
+        self.decoded = 0;
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.read += data.len() as u64;
+        self.pending.extend(data.iter().cloned());
+
+        loop {
+            // Resynchronize on the capture pattern if we're not at a page boundary.
+            match self.pending.windows(4).position(|w| w == &b"OggS"[..]) {
+                Some(0) => { }
+                Some(n) => consume(&mut self.pending, n),
+                None => {
+                    // Retain a possible partial capture pattern.
+                    let keep = self.pending.len().saturating_sub(3);
+                    consume(&mut self.pending, keep);
+                    return;
+                }
+            }
+            if self.pending.len() < OGG_HEADER_LEN {
+                return;
+            }
+
+            let segments = self.pending[26] as usize;
+            let body_start = OGG_HEADER_LEN + segments;
+            if self.pending.len() < body_start {
+                return;
+            }
+            let body_len = self.pending[OGG_HEADER_LEN..body_start].iter()
+                                                                  .fold(0, |a, &x| a + x as usize);
+            if self.pending.len() < body_start + body_len {
+                return;
+            }
+
+            let beginning_of_stream = self.pending[5] & 2 != 0;
+            let end_of_stream = self.pending[5] & 4 != 0;
+            let serial = le_u32(&self.pending[14..18]);
+            if beginning_of_stream {
+                let body = &self.pending[body_start..body_start + body_len];
+                if body.len() >= 16 && body[0] == 1 && &body[1..7] == &b"vorbis"[..] {
+                    self.links.push_back(Link {
+                        serial: serial,
+                        rate: le_u32(&body[12..16]),
+                        channels: body[11] as usize,
+                        length: None,
+                    });
+                }
+            }
+            if end_of_stream {
+                let granule = le_u32(&self.pending[6..10]) as u64
+                            | (le_u32(&self.pending[10..14]) as u64) << 32;
+                // All ones means no packet ends on the page, so the length is still unknown.
+                if granule != !0 {
+                    if let Some(link) = self.links.iter_mut().rev().find(|l| l.serial == serial) {
+                        link.length = Some(granule);
+                    }
+                }
+            }
+            consume(&mut self.pending, body_start + body_len);
+        }
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Passes data through to the decoder while scanning it for stream headers.
+///
+/// The reader is shared so it can be rewound and handed to a new decoder.
+struct Tap<R> {
+    inner: Rc<RefCell<R>>,
+    scanner: Rc<RefCell<OggScanner>>,
+}
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.borrow_mut().read(buf));
+        self.scanner.borrow_mut().push(&buf[..n]);
+        Ok(n)
+    }
+}
 
 /// Ogg Vorbis decoder.
+///
+/// The stream's sample rate is reported before the first buffer. Chained streams may change rate
+/// at a link boundary, in which case the new rate is reported again immediately before the first
+/// buffer of the new link.
+///
+/// Buffers are channel-major with every channel the same length, in the order defined by the
+/// Vorbis I specification (section 4.3.9) for the stream's channel count:
+///
+///  * 1: mono
+///  * 2: left, right
+///  * 3: left, center, right
+///  * 4: front left, front right, rear left, rear right
+///  * 5: front left, center, front right, rear left, rear right
+///  * 6 (5.1): front left, center, front right, rear left, rear right, LFE
+///  * 7 (6.1): front left, center, front right, side left, side right, rear center, LFE
+///  * 8 (7.1): front left, center, front right, side left, side right, rear left, rear right, LFE
+///
+/// Ordering of more than eight channels is application-defined.
+///
+/// Seeking decodes and discards samples up to the requested position. Streams opened with
+/// `open_seekable` can also seek backwards, by rewinding the reader to the start of the stream and
+/// decoding forwards again.
 pub struct VorbisStream<R: Read> {
-    src: vorbisfile::VorbisFile<R>,
+    src: vorbisfile::VorbisFile<Tap<R>>,
+    scanner: Rc<RefCell<OggScanner>>,
+    reader: Rc<RefCell<R>>,
+    rewind: Option<Rewind<R>>,
+    /// Most recently reported sample rate.
+    rate: Option<u32>,
+    /// Channel count of the link being decoded.
+    channels: usize,
+    /// Index of the next sample to be emitted, per channel.
+    position: u64,
+    /// A decoded buffer not yet emitted: the remainder of the one a seek landed in, or one from a
+    /// new link whose rate had to be reported first.
+    held: Vec<Vec<f32>>,
+    /// Sample rate of the link `held` came from.
+    held_rate: u32,
+    held_pending: bool,
+    slices: Vec<Slice<f32>>,
+}
+
+/// Seeks a reader back by a number of bytes.
+type Rewind<R> = fn(&mut R, u64) -> io::Result<()>;
+
+fn rewind_by<R: Seek>(reader: &mut R, bytes: u64) -> io::Result<()> {
+    reader.seek(SeekFrom::Current(-(bytes as i64))).map(|_| ())
+}
+
+impl<R: Read + Seek> VorbisStream<R> {
+    /// Open a new decoder able to seek backwards.
+    ///
+    /// The stream must begin at the reader's current position.
+    pub fn open_seekable(reader: R) -> OVResult<VorbisStream<R>> {
+        VorbisStream::start(Rc::new(RefCell::new(reader)), Some(rewind_by::<R> as Rewind<R>))
+    }
 }
 
 impl<R: Read> VorbisStream<R> {
     /// Open a new decoder.
     pub fn open(reader: R) -> OVResult<VorbisStream<R>> {
+        VorbisStream::start(Rc::new(RefCell::new(reader)), None)
+    }
+
+    fn start(reader: Rc<RefCell<R>>, rewind: Option<Rewind<R>>) -> OVResult<VorbisStream<R>> {
+        let scanner = Rc::new(RefCell::new(OggScanner::new()));
+        let tap = Tap {
+            inner: reader.clone(),
+            scanner: scanner.clone(),
+        };
+
+        let src = try!(vorbisfile::VorbisFile::new(tap));
+        // Opening reads the first link's headers.
+        let channels = scanner.borrow().current().map_or(0, |l| l.channels);
         Ok(VorbisStream {
-            src: try!(vorbisfile::VorbisFile::new(reader))
+            src: src,
+            scanner: scanner,
+            reader: reader,
+            rewind: rewind,
+            rate: None,
+            channels: channels,
+            position: 0,
+            held: Vec::new(),
+            held_rate: 0,
+            held_pending: false,
+            slices: Vec::new(),
         })
     }
+
+    /// Position of the next sample to be emitted, counted in samples per channel from the start of
+    /// the stream.
+    pub fn pcm_tell(&self) -> u64 {
+        self.position
+    }
+
+    /// Rewind the reader and decode again from the start of the stream.
+    ///
+    /// The reported sample rate is kept, so it's only reported again if it differs at the start.
+    fn restart(&mut self) -> OVResult<()> {
+        let rewind = match self.rewind {
+            Some(f) => f,
+            None => return Err(OVError::NotSeekable)
+        };
+        let read = self.scanner.borrow().read;
+        try!(rewind(&mut *self.reader.borrow_mut(), read).map_err(|_| OVError::ReadError));
+
+        let rate = self.rate;
+        *self = try!(VorbisStream::start(self.reader.clone(), self.rewind));
+        self.rate = rate;
+        Ok(())
+    }
+
+    /// Seek to the specified sample, counted per channel from the start of the stream.
+    ///
+    /// Seeking backwards requires a stream opened with `open_seekable`, and otherwise returns
+    /// `NotSeekable`. If rewinding fails the position is unspecified. Seeking past the end of the
+    /// stream returns `EndOfStream` and leaves the stream at its end.
+    pub fn seek_pcm(&mut self, sample: u64) -> OVResult<()> {
+        if sample < self.position {
+            try!(self.restart());
+        }
+
+        if self.held_pending {
+            let len = self.held[0].len() as u64;
+            if sample < self.position + len {
+                let skip = (sample - self.position) as usize;
+                for channel in self.held.iter_mut() {
+                    consume(channel, skip);
+                }
+                self.position = sample;
+                return Ok(());
+            }
+            self.held_pending = false;
+            self.position += len;
+        }
+
+        while self.position < sample {
+            let buf = try!(self.src.decode());
+            let len = buf[0].len() as u64;
+            let link = self.scanner.borrow_mut().decoded(buf.len(), buf[0].len());
+            if self.position + len > sample {
+                // Keep the part of this buffer following the target.
+                let skip = (sample - self.position) as usize;
+                self.held.truncate(0);
+                self.held.extend(buf.iter().map(|c| c[skip..].to_vec()));
+                self.held_rate = link.map_or(self.rate.unwrap_or(0), |l| l.rate);
+                self.held_pending = true;
+                self.position = sample;
+            } else {
+                self.position += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Seek to the specified time in seconds, at the sample rate of the link being decoded.
+    ///
+    /// This is subject to the same restrictions as `seek_pcm`.
+    pub fn seek_time(&mut self, seconds: f64) -> OVResult<()> {
+        let rate = match self.current_rate() {
+            Some(r) => r,
+            None => return Err(OVError::InvalidArgument)
+        };
+        if !(seconds >= 0.0) {
+            return Err(OVError::InvalidArgument);
+        }
+        self.seek_pcm((seconds * rate as f64).round() as u64)
+    }
+
+    /// Sample rate of the link being decoded: the one most recently reported, or else that of the
+    /// first link.
+    fn current_rate(&self) -> Option<u32> {
+        match self.rate {
+            Some(r) => Some(r),
+            None => self.scanner.borrow().current().map(|l| l.rate)
+        }
+    }
+
+    /// Number of channels in the current logical bitstream.
+    ///
+    /// In a chained stream this changes at a link boundary, when the new link's sample rate is
+    /// reported.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+}
+
+/// Convert a decoder error to a `StreamError`, classified by cause.
+pub fn stream_error(e: OVError) -> Error {
+    let msg = format!("vorbisfile decoder: {}", e);
+    match e {
+        OVError::ReadError => Error::Io(io::Error::new(io::ErrorKind::Other, msg)),
+        OVError::StreamInterrupted | OVError::InvalidHeader | OVError::CorruptLink
+            | OVError::InternalFault => Error::Decode(msg),
+        OVError::NotVorbis | OVError::UnsupportedVersion
+            | OVError::NotImplemented => Error::UnsupportedFormat(msg),
+        _ => Error::Other(msg)
+    }
 }
 
 // The native result type for vorbis is a C float. ov_read() postprocesses into
@@ -34,12 +376,481 @@ impl<R: Read> Source for VorbisStream<R> {
     type Output = f32;
 
     fn next<'a>(&'a mut self) -> SourceResult<'a, f32> {
-        // TODO report sample rate
+        if self.held_pending {
+            if self.rate != Some(self.held_rate) {
+                self.rate = Some(self.held_rate);
+                return SampleRate(self.held_rate);
+            }
+            self.held_pending = false;
+            self.channels = self.held.len();
+            self.position += self.held[0].len() as u64;
+            return channel_buffer(&mut self.held, &mut self.slices);
+        }
+
+        let current = self.scanner.borrow().current();
+        if let Some(link) = current {
+            if self.rate != Some(link.rate) {
+                self.rate = Some(link.rate);
+                self.channels = link.channels;
+                return SampleRate(link.rate);
+            }
+        }
+
+        // vorbisfile hands us libvorbis' own per-channel buffers, which are already channel-major
+        // and of equal length.
         match self.src.decode() {
-            Ok(b) => Buffer(b),
-            // ??? => SampleRate(...),
+            Ok(b) => {
+                let link = self.scanner.borrow_mut().decoded(b.len(), b[0].len());
+                self.channels = b.len();
+                match link {
+                    Some(link) if self.rate != Some(link.rate) => {
+                        // This is the first buffer of a new link, which must follow its rate.
+                        self.held.truncate(0);
+                        self.held.extend(b.iter().map(|c| c.to_vec()));
+                        self.held_rate = link.rate;
+                        self.held_pending = true;
+                        self.rate = Some(link.rate);
+                        SampleRate(link.rate)
+                    }
+                    _ => {
+                        self.position += b[0].len() as u64;
+                        Buffer(b)
+                    }
+                }
+            }
             Err(vorbisfile::OVError::EndOfStream) => EndOfStream,
-            Err(e) => StreamError(format!("vorbisfile decoder: {}", e))
+            Err(e) => StreamError(stream_error(e))
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.current_rate()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{Cursor, Read};
+    use std::iter;
+    use super::{OggScanner, VorbisStream, stream_error};
+    use super::vorbisfile::OVError;
+    use super::super::{Source, SourceResult, StreamError};
+    use super::super::SourceResult::{Buffer, SampleRate, EndOfStream};
+
+    /// Ogg's CRC-32: polynomial 0x04c11db7, unreflected, zero initial value.
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for &b in data {
+            crc ^= (b as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x80000000 != 0 { crc << 1 ^ 0x04c11db7 } else { crc << 1 };
+            }
+        }
+        crc
+    }
+
+    /// Build an Ogg page of complete packets.
+    fn ogg_page(flags: u8, granule: u64, serial: u32, sequence: u32,
+                packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = b"OggS\0".to_vec();
+        out.push(flags);
+        for i in 0..8 {
+            out.push((granule >> (8 * i)) as u8);
+        }
+        for &x in [serial, sequence, 0].iter() {
+            for i in 0..4 {
+                out.push((x >> (8 * i)) as u8);
+            }
+        }
+        let mut lacing = vec![];
+        for packet in packets {
+            lacing.extend(iter::repeat(255u8).take(packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        out.push(lacing.len() as u8);
+        out.extend(lacing.into_iter());
+        for packet in packets {
+            out.extend(packet.iter().cloned());
+        }
+        let crc = crc(&out);
+        for i in 0..4 {
+            out[22 + i] = (crc >> (8 * i)) as u8;
+        }
+        out
+    }
+
+    /// Build an Ogg page containing a single packet.
+    fn page(flags: u8, packet: &[u8]) -> Vec<u8> {
+        ogg_page(flags, 0, 0, 0, &[packet.to_vec()])
+    }
+
+    /// Vorbis identification header for the specified stream parameters.
+    fn ident(channels: u8, rate: u32) -> Vec<u8> {
+        let mut out = b"\x01vorbis\0\0\0\0".to_vec();
+        out.push(channels);
+        for i in 0..4 {
+            out.push((rate >> (8 * i)) as u8);
+        }
+        // Bitrates, block sizes (256 and 2048) and framing flag.
+        out.extend([0u8; 12].iter().cloned());
+        out.push(0xb8);
+        out.push(1);
+        out
+    }
+
+    /// Packs bits least significant first, as Vorbis does.
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn new() -> BitWriter {
+            BitWriter { bytes: vec![], bits: 0 }
+        }
+
+        fn write(&mut self, value: u32, bits: usize) {
+            for i in 0..bits {
+                if self.bits % 8 == 0 {
+                    self.bytes.push(0);
+                }
+                if value >> i & 1 != 0 {
+                    *self.bytes.last_mut().unwrap() |= 1 << (self.bits % 8);
+                }
+                self.bits += 1;
+            }
+        }
+
+        /// Write a Huffman codeword, which the decoder reads most significant bit first.
+        fn codeword(&mut self, word: u32, bits: usize) {
+            for i in (0..bits).rev() {
+                self.write(word >> i, 1);
+            }
+        }
+
+        /// Start a codebook with the given codeword lengths.
+        fn codebook(&mut self, dimensions: u32, lengths: &[u32]) {
+            self.write(0x564342, 24);
+            self.write(dimensions, 16);
+            self.write(lengths.len() as u32, 24);
+            // Neither ordered nor sparse
+            self.write(0, 2);
+            for &length in lengths {
+                self.write(length - 1, 5);
+            }
+        }
+    }
+
+    /// Setup header for streams built by `encode`.
+    ///
+    /// This is about the simplest useful configuration: one mode using short blocks, with a flat
+    /// floor and residue coefficients of -1, 0 or +1.
+    fn setup() -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.write(1, 8);
+        // Codebook 0 classifies residue partitions, of which there is one class.
+        w.codebook(1, &[1, 1]);
+        w.write(0, 4);
+        // Codebook 1 codes 0 as 0, -1 as 10 and +1 as 11.
+        w.codebook(1, &[1, 2, 2]);
+        w.write(1, 4);
+        // Minimum -1 and delta 1, as 21-bit mantissas with an exponent biased by 788.
+        w.write(0x80000000 | 788 << 21 | 1, 32);
+        w.write(788 << 21 | 1, 32);
+        w.write(1, 4);
+        w.write(0, 1);
+        for &m in [1, 0, 2].iter() {
+            w.write(m, 2);
+        }
+        // Time domain transforms are placeholders.
+        w.write(0, 6);
+        w.write(0, 16);
+        // Floor 1 with no partitions and range 256: a line between values at each end.
+        w.write(0, 6);
+        w.write(1, 16);
+        w.write(0, 5);
+        w.write(0, 2);
+        w.write(7, 4);
+        // Residue 1 over all 128 coefficients of a short block, as a single partition.
+        w.write(0, 6);
+        w.write(1, 16);
+        w.write(0, 24);
+        w.write(128, 24);
+        w.write(127, 24);
+        w.write(0, 6);
+        w.write(0, 8);
+        w.write(1, 3);
+        w.write(0, 1);
+        w.write(1, 8);
+        // Mapping 0 with one submap and no coupling.
+        w.write(0, 6);
+        w.write(0, 16);
+        w.write(0, 4);
+        w.write(0, 24);
+        // One mode, short blocks.
+        w.write(0, 6);
+        w.write(0, 1);
+        w.write(0, 32);
+        w.write(0, 8);
+        w.write(1, 1);
+
+        let mut out = b"\x05vorbis".to_vec();
+        out.extend(w.bytes.into_iter());
+        out
+    }
+
+    /// Audio packet `index` of a stream with `channels` channels.
+    fn audio(channels: u8, index: usize) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        // Audio packet; there's only one mode, so no mode number.
+        w.write(0, 1);
+        for _ in 0..channels {
+            w.write(1, 1);
+            w.write(220, 8);
+            w.write(220, 8);
+        }
+        // Every partition is class 0.
+        for _ in 0..channels {
+            w.codeword(0, 1);
+        }
+        // Vary the spectrum from block to block and channel to channel, so each part of the
+        // stream decodes differently.
+        for c in 0..channels as usize {
+            let up = (index * 7 + c * 31) % 128;
+            let down = (index * 3 + c * 17 + 64) % 128;
+            for i in 0..128 {
+                match i {
+                    _ if i == up => w.codeword(0b11, 2),
+                    _ if i == down => w.codeword(0b10, 2),
+                    _ => w.codeword(0, 1)
+                }
+            }
+        }
+        w.bytes
+    }
+
+    /// Samples per channel decoded from each audio packet but the first.
+    pub const PACKET_SAMPLES: usize = 128;
+
+    /// Encode a Vorbis stream of `packets` audio packets.
+    ///
+    /// It decodes to `(packets - 1) * PACKET_SAMPLES` samples per channel. The signal is
+    /// deterministic but not easily predicted, so tests compare decodes with each other.
+    pub fn encode(serial: u32, channels: u8, rate: u32, packets: usize) -> Vec<u8> {
+        let mut comment = b"\x03vorbis".to_vec();
+        // No vendor string or comments, then the framing flag.
+        comment.extend([0u8; 8].iter().cloned());
+        comment.push(1);
+
+        let mut out = ogg_page(2, 0, serial, 0, &[ident(channels, rate)]);
+        out.extend(ogg_page(0, 0, serial, 1, &[comment, setup()]).into_iter());
+        let blocks: Vec<_> = (0..packets).map(|i| audio(channels, i)).collect();
+        for (i, chunk) in blocks.chunks(4).enumerate() {
+            let completed = i * 4 + chunk.len();
+            let flags = if completed == packets { 4 } else { 0 };
+            let granule = ((completed - 1) * PACKET_SAMPLES) as u64;
+            out.extend(ogg_page(flags, granule, serial, i as u32 + 2, chunk).into_iter());
+        }
+        out
+    }
+
+    #[test]
+    fn scanner_finds_rate() {
+        let mut data = page(2, &ident(2, 44100));
+        data.extend(page(0, &[0xaa; 300]).into_iter());
+
+        // Feed in small pieces to exercise resumption.
+        let mut scanner = OggScanner::new();
+        for chunk in data.chunks(7) {
+            scanner.push(chunk);
+        }
+        let link = scanner.current().unwrap();
+        assert_eq!(link.rate, 44100);
+        assert_eq!(link.channels, 2);
+        assert!(scanner.pending.is_empty());
+    }
+
+    #[test]
+    fn scanner_follows_chained_streams() {
+        let mut scanner = OggScanner::new();
+        scanner.push(&ogg_page(2, 0, 1, 0, &[ident(1, 44100)]));
+        scanner.push(&ogg_page(4, 300, 1, 1, &[b"\x01vorbis not a header".to_vec()]));
+        assert_eq!(scanner.links.len(), 1);
+        // Junk between pages is skipped.
+        scanner.push(b"junk");
+        scanner.push(&ogg_page(2, 0, 2, 0, &[ident(6, 22050)]));
+        assert_eq!(scanner.links.len(), 2);
+
+        // The first link stays current until all of it has been decoded.
+        assert_eq!(scanner.current().unwrap().length, Some(300));
+        assert_eq!(scanner.decoded(1, 200).unwrap().rate, 44100);
+        assert_eq!(scanner.decoded(1, 100).unwrap().rate, 44100);
+        let link = scanner.current().unwrap();
+        assert_eq!(link.rate, 22050);
+        assert_eq!(link.channels, 6);
+        assert_eq!(link.length, None);
+    }
+
+    #[test]
+    fn reports_sample_rate() {
+        let mut data = encode(1, 2, 22050, 4);
+        data.extend(encode(2, 1, 48000, 4).into_iter());
+        let mut src = VorbisStream::open(Cursor::new(data)).unwrap();
+        // The headers have been read, so the rate is known before it is reported.
+        assert_eq!(src.sample_rate(), Some(22050));
+        assert_eq!(src.next(), SampleRate(22050));
+        assert_eq!(src.sample_rate(), Some(22050));
+
+        // The rate changes when the second link's rate is reported, not before.
+        loop {
+            let changed = match src.next() {
+                Buffer(_) => None,
+                SampleRate(r) => Some(r),
+                x => panic!("Unexpected result {:?}", x)
+            };
+            match changed {
+                Some(r) => {
+                    assert_eq!(r, 48000);
+                    break;
+                }
+                None => assert_eq!(src.sample_rate(), Some(22050))
+            }
+        }
+        assert_eq!(src.sample_rate(), Some(48000));
+    }
+
+    #[test]
+    fn decodes_chained_streams() {
+        let mut data = encode(1, 1, 8000, 10);
+        data.extend(encode(2, 2, 16000, 6).into_iter());
+        let mut src = VorbisStream::open(Cursor::new(data)).unwrap();
+        assert_eq!(src.channels(), 1);
+        assert_eq!(src.next(), SampleRate(8000));
+
+        // Everything from the first link comes out before the second link's rate is reported.
+        let mut first = 0;
+        loop {
+            match src.next() {
+                Buffer(b) => {
+                    assert_eq!(b.len(), 1);
+                    first += b[0].len();
+                }
+                SampleRate(r) => {
+                    assert_eq!(r, 16000);
+                    break;
+                }
+                x => panic!("Unexpected result {:?}", x)
+            }
+            assert_eq!(src.channels(), 1);
+        }
+        assert_eq!(first, 9 * PACKET_SAMPLES);
+        assert_eq!(src.channels(), 2);
+
+        let mut second = 0;
+        loop {
+            match src.next() {
+                Buffer(b) => {
+                    assert_eq!(b.len(), 2);
+                    assert_eq!(b[0].len(), b[1].len());
+                    assert!(b[0].iter().any(|&x| x != 0.0));
+                    second += b[0].len();
+                }
+                EndOfStream => break,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+        assert_eq!(second, 5 * PACKET_SAMPLES);
+    }
+
+    /// Decode everything remaining in `src`, by channel.
+    fn decode_all<R: Read>(src: &mut VorbisStream<R>) -> Vec<Vec<f32>> {
+        let mut out = vec![];
+        loop {
+            match src.next() {
+                Buffer(b) => {
+                    out.resize(b.len(), vec![]);
+                    for (out, channel) in out.iter_mut().zip(b.iter()) {
+                        out.extend(channel.iter().cloned());
+                    }
+                }
+                SampleRate(_) => { }
+                EndOfStream => return out,
+                x => panic!("Unexpected result {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn seeks_to_midpoint() {
+        let data = encode(1, 2, 8000, 40);
+        let length = 39 * PACKET_SAMPLES;
+        let full = decode_all(&mut VorbisStream::open(Cursor::new(data.clone())).unwrap());
+        assert_eq!(full[0].len(), length);
+        // Not on a buffer boundary
+        let mid = length / 2;
+        assert!(mid % PACKET_SAMPLES != 0);
+        let tail: Vec<Vec<f32>> = full.iter().map(|c| c[mid..].to_vec()).collect();
+
+        let mut src = VorbisStream::open_seekable(Cursor::new(data)).unwrap();
+        src.seek_pcm(mid as u64).unwrap();
+        assert_eq!(src.pcm_tell(), mid as u64);
+        assert!(decode_all(&mut src) == tail);
+        assert_eq!(src.pcm_tell(), length as u64);
+
+        // Back from the end
+        src.seek_time(mid as f64 / 8000.0).unwrap();
+        assert_eq!(src.pcm_tell(), mid as u64);
+        assert!(decode_all(&mut src) == tail);
+    }
+
+    #[test]
+    fn unseekable_stream_only_seeks_forwards() {
+        let mut src = VorbisStream::open(Cursor::new(encode(1, 1, 8000, 10))).unwrap();
+        src.seek_pcm(500).unwrap();
+        assert_eq!(src.pcm_tell(), 500);
+        match src.seek_pcm(100) {
+            Err(OVError::NotSeekable) => { }
+            x => panic!("Expected NotSeekable, got {:?}", x)
+        }
+        assert_eq!(src.pcm_tell(), 500);
+    }
+
+    #[test]
+    fn corrupt_data_is_decode_error() {
+        // Which corruptions libvorbisfile reports, and how, depends on its version, so exercise
+        // the classification its errors go through instead.
+        for e in vec![OVError::StreamInterrupted, OVError::InvalidHeader, OVError::CorruptLink] {
+            match stream_error(e.clone()) {
+                StreamError::Decode(_) => { }
+                x => panic!("Expected decode error for {:?}, got {:?}", e, x)
+            }
+        }
+        match stream_error(OVError::NotVorbis) {
+            StreamError::UnsupportedFormat(_) => { }
+            x => panic!("Expected unsupported format, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn corrupt_audio_page_is_decode_error() {
+        let mut data = encode(1, 2, 44100, 12);
+        // Damage the end of the second of three audio pages, leaving its checksum stale. The page
+        // is dropped, leaving a hole in the stream.
+        let starts: Vec<usize> = (0..data.len() - 4).filter(|&i| &data[i..i + 4] == b"OggS")
+                                                       .collect();
+        assert_eq!(starts.len(), 5);
+        for b in data[starts[4] - 16..starts[4]].iter_mut() {
+            *b ^= 0xff;
+        }
+
+        let mut src = VorbisStream::open(Cursor::new(data)).unwrap();
+        for _ in 0..32 {
+            match src.next() {
+                Buffer(_) | SampleRate(_) => { }
+                SourceResult::StreamError(StreamError::Decode(_)) => return,
+                x => panic!("Expected a decode error, got {:?}", x)
+            }
         }
+        panic!("No error from corrupt stream");
     }
 }
@@ -0,0 +1,725 @@
+//! RIFF WAVE files.
+
+use std::any::TypeId;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::mem;
+use std::raw;
+use super::{Sample, Source, SourceResult, Sink, I24, channel_buffer, read_full,
+            DEFAULT_SAMPLE_RATE};
+use super::SourceResult::{Buffer, SampleRate, StreamError, EndOfStream};
+use super::StreamError as Error;
+use super::interleave::{Interleave, interleave_into};
+
+/// `fmt ` chunk format tag for integer PCM.
+const WAVE_FORMAT_PCM: u16 = 1;
+/// `fmt ` chunk format tag for IEEE floating-point samples.
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// Format tag indicating the real format is given by a subformat GUID.
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Number of sample frames decoded per buffer.
+const BUFFER_FRAMES: usize = 4096;
+/// Longest `fmt ` chunk accepted, that of `WAVE_FORMAT_EXTENSIBLE`.
+const MAX_FMT_LEN: u32 = 40;
+/// Most sample data `WavSink` writes, leaving room in the RIFF chunk size for the rest of the
+/// header and a pad byte.
+const MAX_DATA_LEN: u32 = !0 - 37;
+
+/// Sample formats that can be stored in a WAVE file.
+///
+/// Samples are stored little-endian, in `mem::size_of::<Self>()` bytes.
+pub trait WavSample : Sample {
+    /// The format tag identifying this format in a `fmt ` chunk.
+    fn format_tag() -> u16;
+    /// Number of bits used to store each sample.
+    fn bits_per_sample() -> u16;
+}
+
+impl WavSample for u8 {
+    fn format_tag() -> u16 { WAVE_FORMAT_PCM }
+    fn bits_per_sample() -> u16 { 8 }
+}
+
+impl WavSample for i16 {
+    fn format_tag() -> u16 { WAVE_FORMAT_PCM }
+    fn bits_per_sample() -> u16 { 16 }
+}
+
+impl WavSample for I24 {
+    fn format_tag() -> u16 { WAVE_FORMAT_PCM }
+    fn bits_per_sample() -> u16 { 24 }
+}
+
+impl WavSample for i32 {
+    fn format_tag() -> u16 { WAVE_FORMAT_PCM }
+    fn bits_per_sample() -> u16 { 32 }
+}
+
+impl WavSample for f32 {
+    fn format_tag() -> u16 { WAVE_FORMAT_IEEE_FLOAT }
+    fn bits_per_sample() -> u16 { 32 }
+}
+
+impl WavSample for f64 {
+    fn format_tag() -> u16 { WAVE_FORMAT_IEEE_FLOAT }
+    fn bits_per_sample() -> u16 { 64 }
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    le_u16(bytes) as u32 | (le_u16(&bytes[2..]) as u32) << 16
+}
+
+fn put_u16(out: &mut Vec<u8>, x: u16) {
+    out.push(x as u8);
+    out.push((x >> 8) as u8);
+}
+
+fn put_u32(out: &mut Vec<u8>, x: u32) {
+    put_u16(out, x as u16);
+    put_u16(out, (x >> 16) as u16);
+}
+
+/// Read exactly `buf.len()` bytes, treating a short read as an error.
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    match read_full(reader, buf) {
+        Ok(n) if n == buf.len() => Ok(()),
+        Ok(_) => Err(Error::Decode("unexpected end of WAV file".to_string())),
+        Err(e) => Err(Error::Io(e))
+    }
+}
+
+/// Stream parameters from the `fmt ` chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Format {
+    format_tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    block_align: u16,
+    bits_per_sample: u16,
+}
+
+/// Parse the RIFF header up to the start of sample data.
+///
+/// Returns the stream format and the length of the `data` chunk in bytes.
+fn read_header<R: Read>(reader: &mut R) -> Result<(Format, u32), Error> {
+    let mut riff = [0u8; 12];
+    try!(read_exact(reader, &mut riff));
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(Error::UnsupportedFormat("not a RIFF WAVE file".to_string()));
+    }
+
+    let mut format = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        try!(read_exact(reader, &mut chunk));
+        let size = le_u32(&chunk[4..]);
+
+        match &chunk[0..4] {
+            b"fmt " => {
+                if size < 16 {
+                    return Err(Error::Decode(format!("WAV fmt chunk too short ({} bytes)",
+                                                     size)));
+                }
+                // The size comes from the file, so don't trust it with a huge allocation.
+                if size > MAX_FMT_LEN {
+                    return Err(Error::Decode(format!("WAV fmt chunk too long ({} bytes)",
+                                                     size)));
+                }
+                let mut fmt = vec![0u8; size as usize + (size as usize & 1)];
+                try!(read_exact(reader, &mut fmt));
+
+                let mut format_tag = le_u16(&fmt[0..]);
+                if format_tag == WAVE_FORMAT_EXTENSIBLE {
+                    if size < 40 {
+                        return Err(Error::Decode("WAV extensible fmt chunk too short"
+                                                 .to_string()));
+                    }
+                    // First two bytes of the subformat GUID are the real format tag.
+                    format_tag = le_u16(&fmt[24..]);
+                }
+                format = Some(Format {
+                    format_tag: format_tag,
+                    channels: le_u16(&fmt[2..]),
+                    sample_rate: le_u32(&fmt[4..]),
+                    block_align: le_u16(&fmt[12..]),
+                    bits_per_sample: le_u16(&fmt[14..]),
+                });
+            }
+            b"data" => {
+                return match format {
+                    Some(f) => Ok((f, size)),
+                    None => Err(Error::Decode("WAV data chunk precedes fmt chunk".to_string()))
+                };
+            }
+            _ => {
+                // Skip unknown chunks, which are padded to even length.
+                let skip = size as u64 + (size as u64 & 1);
+                let skipped = try!(io::copy(&mut (&mut *reader).take(skip), &mut io::sink())
+                                     .map_err(Error::Io));
+                if skipped < skip {
+                    return Err(Error::Decode("unexpected end of WAV file".to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Ensure a stream format is sane and stores samples in a supported format.
+fn validate(format: &Format) -> Result<(), Error> {
+    if format.channels == 0 {
+        return Err(Error::Decode("WAV stream has no channels".to_string()));
+    }
+    match (format.format_tag, format.bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) | (WAVE_FORMAT_PCM, 16) |
+        (WAVE_FORMAT_PCM, 24) | (WAVE_FORMAT_PCM, 32) |
+        (WAVE_FORMAT_IEEE_FLOAT, 32) | (WAVE_FORMAT_IEEE_FLOAT, 64) => { }
+        (tag, bits) => {
+            return Err(Error::UnsupportedFormat(format!(
+                "WAV format {} with {} bits per sample", tag, bits)));
+        }
+    }
+    // A large channel count in a corrupt header would overflow a `u16` product.
+    let expected = (format.channels as u32).checked_mul(format.bits_per_sample as u32 / 8);
+    if expected != Some(format.block_align as u32) {
+        return Err(Error::Decode(format!(
+            "WAV block alignment {} is inconsistent with {} channels of {} bits",
+            format.block_align, format.channels, format.bits_per_sample)));
+    }
+    Ok(())
+}
+
+/// Decode frames of samples stored as `X` into per-channel buffers of `F`.
+///
+/// `bytes` must hold a whole number of frames of `block_align` bytes.
+fn decode<X: Sample, F: Sample>(bytes: &[u8], block_align: usize, channels: &mut [Vec<F>]) {
+    let width = mem::size_of::<X>();
+    // Conversion through floating-point isn't exact, so samples already of the requested type are
+    // read directly.
+    let same = TypeId::of::<X>() == TypeId::of::<F>();
+    for (c, channel) in channels.iter_mut().enumerate() {
+        channel.clear();
+        channel.extend(bytes.chunks(block_align).map(|frame| {
+            let bytes = &frame[c * width..];
+            if same {
+                Sample::read_le(bytes)
+            } else {
+                <X as Sample>::convert::<F, f64>(Sample::read_le(bytes))
+            }
+        }));
+    }
+}
+
+/// WAVE file decoder.
+///
+/// The header is parsed on the first call to `next`, which yields the stream's sample rate. Later
+/// calls yield channel-major buffers of samples until the `data` chunk is exhausted.
+///
+/// Since the file's sample format isn't known until the header is read, samples are decoded from
+/// whatever format the file contains (8-, 16-, 24- or 32-bit integer PCM, or 32- or 64-bit float)
+/// and converted to `F` with `Sample::convert`. Samples already of type `F` are passed through
+/// exactly.
+pub struct WavSource<F, R> {
+    reader: R,
+    /// Stream format, once the header has been read.
+    format: Option<Format>,
+    /// Bytes of sample data not yet read.
+    remaining: u32,
+    /// Set once the stream has ended or failed.
+    done: bool,
+    bytes: Vec<u8>,
+    channels: Vec<Vec<F>>,
+    slices: Vec<raw::Slice<F>>,
+}
+
+impl<F: Sample, R: Read> WavSource<F, R> {
+    /// Open a decoder reading from `reader`.
+    pub fn open(reader: R) -> WavSource<F, R> {
+        WavSource {
+            reader: reader,
+            format: None,
+            remaining: 0,
+            done: false,
+            bytes: Vec::new(),
+            channels: Vec::new(),
+            slices: Vec::new(),
+        }
+    }
+
+    /// Number of channels in the stream, if the header has been read.
+    pub fn channels(&self) -> Option<usize> {
+        self.format.map(|f| f.channels as usize)
+    }
+
+    fn fail<'a>(&mut self, e: Error) -> SourceResult<'a, F> {
+        self.done = true;
+        StreamError(e)
+    }
+}
+
+impl<F: Sample, R: Read> Source for WavSource<F, R> {
+    type Output = F;
+
+    fn next<'a>(&'a mut self) -> SourceResult<'a, F> {
+        if self.done {
+            return EndOfStream;
+        }
+
+        let format = match self.format {
+            Some(f) => f,
+            None => {
+                let (format, size) = match read_header(&mut self.reader) {
+                    Ok(x) => x,
+                    Err(e) => return self.fail(e)
+                };
+                if let Err(e) = validate(&format) {
+                    return self.fail(e);
+                }
+                self.format = Some(format);
+                self.remaining = size;
+                self.channels = (0..format.channels).map(|_| Vec::new()).collect();
+                return SampleRate(format.sample_rate);
+            }
+        };
+
+        let block_align = format.block_align as usize;
+        let want = ::std::cmp::min(self.remaining as usize, BUFFER_FRAMES * block_align);
+        self.bytes.resize(want, 0);
+        let got = match read_full(&mut self.reader, &mut self.bytes) {
+            Ok(n) => n,
+            Err(e) => return self.fail(Error::Io(e))
+        };
+        self.remaining -= got as u32;
+        // A truncated file ends the stream after whatever whole frames were read.
+        if got < want {
+            self.remaining = 0;
+        }
+
+        let frames = got / block_align;
+        if frames == 0 {
+            self.done = true;
+            return EndOfStream;
+        }
+
+        {
+            let data = &self.bytes[..frames * block_align];
+            let channels = &mut self.channels[..];
+            match (format.format_tag, format.bits_per_sample) {
+                (WAVE_FORMAT_PCM, 8) => decode::<u8, F>(data, block_align, channels),
+                (WAVE_FORMAT_PCM, 16) => decode::<i16, F>(data, block_align, channels),
+                (WAVE_FORMAT_PCM, 24) => decode::<I24, F>(data, block_align, channels),
+                (WAVE_FORMAT_PCM, 32) => decode::<i32, F>(data, block_align, channels),
+                (WAVE_FORMAT_IEEE_FLOAT, 32) => decode::<f32, F>(data, block_align, channels),
+                // `validate` permits nothing else.
+                _ => decode::<f64, F>(data, block_align, channels)
+            }
+        }
+        channel_buffer(&mut self.channels, &mut self.slices)
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.format.map(|f| f.sample_rate)
+    }
+}
+
+/// Sink writing a WAVE file.
+///
+/// The header is written when the first buffer arrives, using the channel count of that buffer
+/// and the most recent sample rate reported by the source (or 44100 Hz if none was). Chunk sizes
+/// in the header are placeholders until the sink is finalized, either explicitly with `finalize`,
+/// at end of stream, or when the sink is dropped. The file starts at `dest`'s position when the
+/// header is written, so it may follow other data.
+///
+/// Chunk sizes are 32 bits, so a WAVE file holds a little under 4 GiB of samples. A buffer that
+/// would take the file past that is an error: it is dropped, and the sink finalizes and stops.
+pub struct WavSink<F: WavSample, W: Write + Seek, R> {
+    dest: W,
+    source: R,
+    sample_rate: u32,
+    /// Number of channels in the stream, once the header has been written.
+    channels: Option<usize>,
+    /// Position in `dest` where the header was written, which need not be the start.
+    header_start: u64,
+    /// Bytes of sample data written so far.
+    data_len: u32,
+    finalized: bool,
+    interleave_buf: Vec<F>,
+    bytes: Vec<u8>,
+}
+
+impl<F, W, R> WavSink<F, W, R> where
+        F: WavSample + Interleave,
+        W: Write + Seek,
+        R: Source<Output=F> {
+    /// Construct a sink writing samples from `source` into `dest`.
+    pub fn new(source: R, dest: W) -> WavSink<F, W, R> {
+        WavSink {
+            dest: dest,
+            source: source,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: None,
+            header_start: 0,
+            data_len: 0,
+            finalized: false,
+            interleave_buf: Vec::new(),
+            bytes: Vec::new(),
+        }
+    }
+}
+
+impl<F: WavSample, W: Write + Seek, R> WavSink<F, W, R> {
+    fn write_header(&mut self, channels: usize) -> io::Result<()> {
+        let bits = <F as WavSample>::bits_per_sample();
+        let align = (channels as u32).checked_mul(bits as u32 / 8)
+                                     .and_then(|a| if a <= 0xffff { Some(a) } else { None });
+        let byte_rate = align.and_then(|a| self.sample_rate.checked_mul(a));
+        let (align, byte_rate) = match (align, byte_rate) {
+            (Some(a), Some(r)) => (a as u16, r),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "WAV format is too large for the header")),
+        };
+
+        let mut h = Vec::with_capacity(44);
+        h.extend(b"RIFF".iter().cloned());
+        put_u32(&mut h, 0);
+        h.extend(b"WAVEfmt ".iter().cloned());
+        put_u32(&mut h, 16);
+        put_u16(&mut h, <F as WavSample>::format_tag());
+        put_u16(&mut h, channels as u16);
+        put_u32(&mut h, self.sample_rate);
+        put_u32(&mut h, byte_rate);
+        put_u16(&mut h, align);
+        put_u16(&mut h, bits);
+        h.extend(b"data".iter().cloned());
+        put_u32(&mut h, 0);
+
+        self.header_start = try!(self.dest.seek(SeekFrom::Current(0)));
+        self.channels = Some(channels);
+        self.dest.write_all(&h)
+    }
+
+    /// Write correct chunk sizes into the header.
+    ///
+    /// Once finalized, no more samples will be written. This is done automatically at end of
+    /// stream or when the sink is dropped, but calling it explicitly allows errors to be observed.
+    pub fn finalize(&mut self) -> io::Result<()> {
+        if self.finalized {
+            return Ok(());
+        }
+        self.finalized = true;
+        if self.channels.is_none() {
+            try!(self.write_header(1));
+        }
+
+        // Chunks must be padded to even length.
+        let pad = self.data_len & 1;
+        if pad != 0 {
+            try!(self.dest.write_all(&[0]));
+        }
+
+        let mut size = Vec::with_capacity(4);
+        put_u32(&mut size, 36 + self.data_len + pad);
+        try!(self.dest.seek(SeekFrom::Start(self.header_start + 4)));
+        try!(self.dest.write_all(&size));
+
+        size.clear();
+        put_u32(&mut size, self.data_len);
+        try!(self.dest.seek(SeekFrom::Start(self.header_start + 40)));
+        try!(self.dest.write_all(&size));
+
+        try!(self.dest.seek(SeekFrom::End(0)));
+        self.dest.flush()
+    }
+
+    fn finish(&mut self) -> Option<()> {
+        if let Err(e) = self.finalize() {
+            error!("Failed to finalize WAV output: {}", e);
+        }
+        None
+    }
+}
+
+impl<F, W, R> Sink for WavSink<F, W, R> where
+        F: WavSample + Interleave,
+        W: Write + Seek,
+        R: Source<Output=F> {
+    fn run_once(&mut self) -> Option<()> {
+        if self.finalized {
+            return None;
+        }
+
+        let channels = match self.source.next() {
+            Buffer(channels) => channels,
+            SampleRate(r) => {
+                if self.channels.is_some() && r != self.sample_rate {
+                    warn!("Sample rate changed from {} to {} after WAV header was written",
+                          self.sample_rate, r);
+                } else {
+                    self.sample_rate = r;
+                }
+                return Some(());
+            }
+            EndOfStream => return self.finish(),
+            StreamError(e) => {
+                error!("WAV sink stopping on stream error: {}", e);
+                return self.finish();
+            }
+        };
+
+        let nch = channels.len();
+        if let Err(e) = interleave_into(channels, &mut self.interleave_buf) {
+            error!("WAV sink stopping on malformed buffer: {}", e);
+            return self.finish();
+        }
+
+        let width = mem::size_of::<F>();
+        self.bytes.resize(self.interleave_buf.len() * width, 0);
+        for (x, bytes) in self.interleave_buf.iter().zip(self.bytes.chunks_mut(width)) {
+            x.write_le(bytes);
+        }
+        self.interleave_buf.truncate(0);
+
+        let data_len = match self.data_len.checked_add(self.bytes.len() as u32) {
+            Some(n) if n <= MAX_DATA_LEN && self.bytes.len() <= MAX_DATA_LEN as usize => n,
+            _ => {
+                error!("WAV sink stopping: output would exceed the 4 GiB WAVE size limit");
+                return self.finish();
+            }
+        };
+
+        let result = match self.channels {
+            None => self.write_header(nch),
+            Some(n) if n != nch => {
+                error!("WAV sink got {} channels but stream started with {}", nch, n);
+                return self.finish();
+            }
+            Some(_) => Ok(())
+        }.and_then(|_| self.dest.write_all(&self.bytes));
+
+        match result {
+            Ok(()) => {
+                self.data_len = data_len;
+                Some(())
+            }
+            Err(e) => {
+                error!("Failed to write WAV output: {}", e);
+                self.finish()
+            }
+        }
+    }
+}
+
+impl<F: WavSample, W: Write + Seek, R> Drop for WavSink<F, W, R> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::super::{Source, SourceResult, Sink, StreamError};
+    use super::{WavSource, WavSink, MAX_DATA_LEN};
+    use super::super::tests::ConstantSource;
+    use super::super::MonoSource;
+
+    /// Build a WAVE file from a format tag, channel count, sample rate and sample bytes.
+    pub fn wav_bytes(tag: u16, channels: u16, rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+        fn u16le(v: &mut Vec<u8>, x: u16) {
+            v.push(x as u8);
+            v.push((x >> 8) as u8);
+        }
+        fn u32le(v: &mut Vec<u8>, x: u32) {
+            u16le(v, x as u16);
+            u16le(v, (x >> 16) as u16);
+        }
+
+        let align = channels * bits / 8;
+        let mut v = Vec::new();
+        v.extend(b"RIFF".iter().cloned());
+        u32le(&mut v, 36 + data.len() as u32);
+        v.extend(b"WAVEfmt ".iter().cloned());
+        u32le(&mut v, 16);
+        u16le(&mut v, tag);
+        u16le(&mut v, channels);
+        u32le(&mut v, rate);
+        u32le(&mut v, rate * align as u32);
+        u16le(&mut v, align);
+        u16le(&mut v, bits);
+        v.extend(b"data".iter().cloned());
+        u32le(&mut v, data.len() as u32);
+        v.extend(data.iter().cloned());
+        v
+    }
+
+    #[test]
+    fn decodes_stereo_i16() {
+        let data = [0x01, 0x00, 0xff, 0xff,
+                    0x00, 0x80, 0xff, 0x7f];
+        let file = wav_bytes(1, 2, 22050, 16, &data);
+        let mut src = WavSource::<i16, _>::open(Cursor::new(file));
+
+        assert_eq!(src.next(), SourceResult::SampleRate(22050));
+        assert_eq!(src.channels(), Some(2));
+        assert_eq!(src.next(),
+                   SourceResult::Buffer(&mut [&mut [1i16, -32768],
+                                              &mut [-1i16, 32767]]));
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn reports_sample_rate_after_header() {
+        let file = wav_bytes(1, 1, 48000, 16, &[0, 0, 0, 0]);
+        // Rate queries pass through adapters.
+        let mut src = WavSource::<i16, _>::open(Cursor::new(file)).take(2);
+        assert_eq!(src.sample_rate(), None);
+        assert_eq!(src.next(), SourceResult::SampleRate(48000));
+        assert_eq!(src.sample_rate(), Some(48000));
+    }
+
+    #[test]
+    fn converts_any_depth() {
+        // Half scale, then negative half scale, in each format.
+        let files = [
+            wav_bytes(1, 1, 44100, 8, &[0xc0, 0x40]),
+            wav_bytes(1, 1, 44100, 16, &[0x00, 0x40, 0x00, 0xc0]),
+            wav_bytes(1, 1, 44100, 24, &[0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]),
+            wav_bytes(1, 1, 44100, 32, &[0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0xc0]),
+            wav_bytes(3, 1, 44100, 32, &[0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0xbf]),
+        ];
+
+        for file in files.iter() {
+            let mut src = WavSource::<i16, _>::open(Cursor::new(file.clone()));
+            assert_eq!(src.next(), SourceResult::SampleRate(44100));
+            match src.next() {
+                SourceResult::Buffer(b) => {
+                    assert_eq!(b[0].len(), 2);
+                    assert!((b[0][0] as i32 - 16384).abs() < 256, "Got {:?}", b[0]);
+                    assert!((b[0][1] as i32 + 16384).abs() < 256, "Got {:?}", b[0]);
+                }
+                x => panic!("Unexpected result {:?}", x)
+            }
+            assert_eq!(src.next(), SourceResult::EndOfStream);
+        }
+    }
+
+    #[test]
+    fn unsupported_format_is_error() {
+        // 16-bit float isn't a WAV format.
+        let file = wav_bytes(3, 1, 44100, 16, &[0, 0, 0, 0]);
+        let mut src = WavSource::<i16, _>::open(Cursor::new(file));
+        match src.next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected StreamError, got {:?}", x)
+        }
+        assert_eq!(src.next(), SourceResult::EndOfStream);
+    }
+
+    #[test]
+    fn oversized_chunks_are_errors() {
+        // Neither chunk size is trusted with an allocation.
+        let files = [&b"RIFF\0\0\0\0WAVEfmt \xf0\xff\xff\xff\x01\0"[..],
+                     &b"RIFF\0\0\0\0WAVELIST\xf0\xff\xff\xffINFO"[..]];
+        for file in files.iter() {
+            let mut src = WavSource::<i16, _>::open(Cursor::new(file.to_vec()));
+            match src.next() {
+                SourceResult::StreamError(_) => { }
+                x => panic!("Expected StreamError, got {:?}", x)
+            }
+        }
+    }
+
+    #[test]
+    fn inconsistent_block_align_is_decode_error() {
+        // 32768 channels of 16 bits would make a block alignment of 0 if computed in 16 bits.
+        let mut file = wav_bytes(1, 1, 44100, 16, &[0, 0]);
+        file[22] = 0x00;
+        file[23] = 0x80;
+        file[32] = 0;
+        file[33] = 0;
+        let mut src = WavSource::<i16, _>::open(Cursor::new(file));
+        match src.next() {
+            SourceResult::StreamError(StreamError::Decode(_)) => { }
+            x => panic!("Expected a decode error, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn malformed_header_is_error() {
+        let mut src = WavSource::<i16, _>::open(Cursor::new(b"RIFF\0\0\0\0AIFF".to_vec()));
+        match src.next() {
+            SourceResult::StreamError(_) => { }
+            x => panic!("Expected StreamError, got {:?}", x)
+        }
+    }
+
+    #[test]
+    fn sink_roundtrips_source() {
+        let data: Vec<u8> = (0..64u8).collect();
+        let file = wav_bytes(1, 2, 48000, 16, &data);
+
+        let mut out = Cursor::new(Vec::new());
+        {
+            let src = WavSource::<i16, _>::open(Cursor::new(file.clone()));
+            let mut sink = WavSink::new(src, &mut out);
+            while sink.run_once().is_some() { }
+        }
+        assert_eq!(out.into_inner(), file);
+    }
+
+    #[test]
+    fn sink_stops_at_size_limit() {
+        let mut out = Cursor::new(Vec::new());
+        {
+            let src = ConstantSource { data: vec![1i16, 2], sbuf: vec![] }.adapt();
+            let mut sink = WavSink::new(src, &mut out);
+            assert_eq!(sink.run_once(), Some(()));
+            // Pretend most of the limit has been written already.
+            sink.data_len = MAX_DATA_LEN - 2;
+            assert_eq!(sink.run_once(), None);
+            assert_eq!(sink.run_once(), None);
+        }
+
+        let out = out.into_inner();
+        // The rejected buffer wasn't written, and the header has the sizes at the point it stopped.
+        assert_eq!(out.len(), 48);
+        let riff = out[4..8].iter().rev().fold(0u32, |n, &b| n << 8 | b as u32);
+        let data = out[40..44].iter().rev().fold(0u32, |n, &b| n << 8 | b as u32);
+        assert_eq!(data, MAX_DATA_LEN - 2);
+        assert_eq!(riff, 36 + data);
+    }
+
+    #[test]
+    fn sink_patches_header_after_existing_data() {
+        let data: Vec<u8> = (0..8u8).collect();
+        let file = wav_bytes(1, 1, 8000, 16, &data);
+
+        let mut out = Cursor::new(b"junk".to_vec());
+        out.set_position(4);
+        {
+            let src = WavSource::<i16, _>::open(Cursor::new(file.clone()));
+            let mut sink = WavSink::new(src, &mut out);
+            while sink.run_once().is_some() { }
+        }
+        let out = out.into_inner();
+        assert_eq!(&out[..4], &b"junk"[..]);
+        assert_eq!(&out[4..], &file[..]);
+    }
+
+    #[test]
+    fn sink_fails_on_oversized_byte_rate() {
+        let mut out = Cursor::new(Vec::new());
+        {
+            let src = ConstantSource { data: vec![1i16, 2], sbuf: vec![] }.adapt();
+            let mut sink = WavSink::new(src, &mut out);
+            // 2 bytes per frame at this rate doesn't fit the 32-bit byte rate.
+            sink.sample_rate = 0x80000000;
+            assert_eq!(sink.run_once(), None);
+        }
+        // Neither the header nor any samples could be written.
+        assert!(out.into_inner().is_empty());
+    }
+}